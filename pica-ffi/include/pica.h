@@ -0,0 +1,84 @@
+/*
+ * Copyright 2022 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+/*
+ * C API of Pica, the virtual UWB Controller.
+ *
+ * The simulation runs on background threads. The UCI packets of the
+ * devices are exchanged as raw bytes, framed the same way as on TCP
+ * connections. The functions of an instance must not be called
+ * concurrently.
+ */
+
+#ifndef PICA_H
+#define PICA_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define PICA_OK 0
+#define PICA_ERROR_INVALID_ARGUMENT -1
+#define PICA_ERROR_NOT_FOUND -2
+#define PICA_ERROR_COMMAND_FAILED -3
+#define PICA_ERROR_DISCONNECTED -4
+
+typedef struct PicaInstance PicaInstance;
+
+/* Create an instance, or return NULL on failure. */
+PicaInstance *pica_create(void);
+
+/* Close the device connections and destroy the instance. */
+void pica_destroy(PicaInstance *pica);
+
+/* Connect a new device, and write its identifier to `device`. */
+int32_t pica_connect(PicaInstance *pica, uint32_t *device);
+
+/* Disconnect the selected device. */
+int32_t pica_disconnect(PicaInstance *pica, uint32_t device);
+
+/* Send UCI bytes from the host to the selected device.
+ * Packets can be split across calls. */
+int32_t pica_push_uci(PicaInstance *pica, uint32_t device,
+                      const uint8_t *data, size_t len);
+
+/* Receive the UCI bytes sent by the selected device to the host, waiting
+ * at most `timeout_ms` milliseconds for the first one. Returns the number
+ * of bytes written to `buffer`, 0 on timeout, or a negative error code. */
+int32_t pica_pop_uci(PicaInstance *pica, uint32_t device, uint8_t *buffer,
+                     size_t capacity, uint32_t timeout_ms);
+
+/* Create an anchor at the selected position. */
+int32_t pica_create_anchor(PicaInstance *pica, const char *mac_address,
+                           int16_t x, int16_t y, int16_t z, int16_t yaw,
+                           int8_t pitch, int16_t roll);
+
+/* Destroy the selected anchor. */
+int32_t pica_destroy_anchor(PicaInstance *pica, const char *mac_address);
+
+/* Move the selected anchor or device. */
+int32_t pica_set_position(PicaInstance *pica, const char *mac_address,
+                          int16_t x, int16_t y, int16_t z, int16_t yaw,
+                          int8_t pitch, int16_t roll);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* PICA_H */
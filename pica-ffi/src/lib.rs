@@ -0,0 +1,327 @@
+// Copyright 2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! C API of Pica, declared in `include/pica.h`. The simulation runs on
+//! a background runtime; the UCI packets of the devices are exchanged
+//! as raw bytes, framed the same way as on TCP connections.
+//!
+//! The functions of an instance must not be called concurrently.
+
+use pica::{MacAddress, Pica, PicaCommand, PicaCommandStatus, Position};
+use std::collections::HashMap;
+use std::ffi::{c_char, CStr};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+use tokio::runtime::Runtime;
+use tokio::sync::{mpsc, oneshot};
+
+pub const PICA_OK: i32 = 0;
+pub const PICA_ERROR_INVALID_ARGUMENT: i32 = -1;
+pub const PICA_ERROR_NOT_FOUND: i32 = -2;
+pub const PICA_ERROR_COMMAND_FAILED: i32 = -3;
+pub const PICA_ERROR_DISCONNECTED: i32 = -4;
+
+/// Capacity of the device streams, in bytes.
+const STREAM_BUFFER_SIZE: usize = 4096;
+
+pub struct PicaInstance {
+    runtime: Runtime,
+    tx: mpsc::Sender<PicaCommand>,
+    devices: HashMap<u32, DuplexStream>,
+    next_device: u32,
+}
+
+impl PicaInstance {
+    fn command(
+        &self,
+        command: impl FnOnce(oneshot::Sender<PicaCommandStatus>) -> PicaCommand,
+    ) -> i32 {
+        let (status_tx, status_rx) = oneshot::channel();
+        let status = self.runtime.block_on(async {
+            self.tx.send(command(status_tx)).await.ok()?;
+            status_rx.await.ok()
+        });
+        match status {
+            Some(Ok(())) => PICA_OK,
+            Some(Err(_)) => PICA_ERROR_COMMAND_FAILED,
+            None => PICA_ERROR_DISCONNECTED,
+        }
+    }
+}
+
+/// # Safety
+/// `mac_address` must be null or point to a NUL terminated string.
+unsafe fn mac_address(mac_address: *const c_char) -> Option<MacAddress> {
+    if mac_address.is_null() {
+        return None;
+    }
+    let mac_address = CStr::from_ptr(mac_address).to_str().ok()?;
+    mac_address.parse().ok()
+}
+
+/// Create an instance, running until destroyed.
+/// Returns null if the runtime cannot be started.
+#[no_mangle]
+pub extern "C" fn pica_create() -> *mut PicaInstance {
+    let Ok(runtime) = Runtime::new() else {
+        return std::ptr::null_mut();
+    };
+    let mut pica = Pica::builder().build();
+    let tx = pica.tx();
+    runtime.spawn(async move { pica.run().await });
+    Box::into_raw(Box::new(PicaInstance {
+        runtime,
+        tx,
+        devices: HashMap::new(),
+        next_device: 0,
+    }))
+}
+
+/// Close the device connections and destroy the instance.
+///
+/// # Safety
+/// `pica` must be null or returned by `pica_create`, and not used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn pica_destroy(pica: *mut PicaInstance) {
+    if pica.is_null() {
+        return;
+    }
+    let pica = Box::from_raw(pica);
+    let (shutdown_tx, shutdown_rx) = oneshot::channel();
+    pica.runtime.block_on(async {
+        if pica
+            .tx
+            .send(PicaCommand::Shutdown(shutdown_tx))
+            .await
+            .is_ok()
+        {
+            let _ = shutdown_rx.await;
+        }
+    });
+}
+
+/// Connect a new device, and write its identifier to `device`.
+///
+/// # Safety
+/// `pica` must be returned by `pica_create`, `device` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn pica_connect(pica: *mut PicaInstance, device: *mut u32) -> i32 {
+    let (Some(pica), false) = (pica.as_mut(), device.is_null()) else {
+        return PICA_ERROR_INVALID_ARGUMENT;
+    };
+    let (host, stream) = tokio::io::duplex(STREAM_BUFFER_SIZE);
+    let connect = PicaCommand::Connect(Box::new(stream), "ffi".to_owned());
+    if pica.runtime.block_on(pica.tx.send(connect)).is_err() {
+        return PICA_ERROR_DISCONNECTED;
+    }
+    let id = pica.next_device;
+    pica.next_device += 1;
+    pica.devices.insert(id, host);
+    *device = id;
+    PICA_OK
+}
+
+/// Disconnect the selected device.
+///
+/// # Safety
+/// `pica` must be returned by `pica_create`.
+#[no_mangle]
+pub unsafe extern "C" fn pica_disconnect(pica: *mut PicaInstance, device: u32) -> i32 {
+    let Some(pica) = pica.as_mut() else {
+        return PICA_ERROR_INVALID_ARGUMENT;
+    };
+    match pica.devices.remove(&device) {
+        Some(_) => PICA_OK,
+        None => PICA_ERROR_NOT_FOUND,
+    }
+}
+
+/// Send UCI bytes from the host to the selected device. Packets can be
+/// split across calls.
+///
+/// # Safety
+/// `pica` must be returned by `pica_create`, `data` must be valid for
+/// reads of `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn pica_push_uci(
+    pica: *mut PicaInstance,
+    device: u32,
+    data: *const u8,
+    len: usize,
+) -> i32 {
+    let (Some(pica), false) = (pica.as_mut(), data.is_null() && len > 0) else {
+        return PICA_ERROR_INVALID_ARGUMENT;
+    };
+    let Some(stream) = pica.devices.get_mut(&device) else {
+        return PICA_ERROR_NOT_FOUND;
+    };
+    let data = if len > 0 {
+        std::slice::from_raw_parts(data, len)
+    } else {
+        &[]
+    };
+    match pica.runtime.block_on(stream.write_all(data)) {
+        Ok(()) => PICA_OK,
+        Err(_) => PICA_ERROR_DISCONNECTED,
+    }
+}
+
+/// Receive the UCI bytes sent by the selected device to the host, waiting
+/// at most `timeout_ms` milliseconds for the first one. Returns the number
+/// of bytes written to `buffer`, 0 on timeout, or a negative error code.
+///
+/// # Safety
+/// `pica` must be returned by `pica_create`, `buffer` must be valid for
+/// writes of `capacity` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn pica_pop_uci(
+    pica: *mut PicaInstance,
+    device: u32,
+    buffer: *mut u8,
+    capacity: usize,
+    timeout_ms: u32,
+) -> i32 {
+    let (Some(pica), false) = (pica.as_mut(), buffer.is_null() || capacity == 0) else {
+        return PICA_ERROR_INVALID_ARGUMENT;
+    };
+    let Some(stream) = pica.devices.get_mut(&device) else {
+        return PICA_ERROR_NOT_FOUND;
+    };
+    let buffer = std::slice::from_raw_parts_mut(buffer, capacity.min(i32::MAX as usize));
+    let timeout = Duration::from_millis(timeout_ms.into());
+    match pica
+        .runtime
+        .block_on(async { tokio::time::timeout(timeout, stream.read(buffer)).await })
+    {
+        Ok(Ok(0)) | Ok(Err(_)) => PICA_ERROR_DISCONNECTED,
+        Ok(Ok(len)) => len as i32,
+        Err(_) => 0,
+    }
+}
+
+/// Create an anchor at the selected position.
+///
+/// # Safety
+/// `pica` must be returned by `pica_create`, `mac_address` must point to
+/// a NUL terminated string.
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+pub unsafe extern "C" fn pica_create_anchor(
+    pica: *mut PicaInstance,
+    mac_address: *const c_char,
+    x: i16,
+    y: i16,
+    z: i16,
+    yaw: i16,
+    pitch: i8,
+    roll: i16,
+) -> i32 {
+    let (Some(pica), Some(mac_address)) = (pica.as_ref(), self::mac_address(mac_address)) else {
+        return PICA_ERROR_INVALID_ARGUMENT;
+    };
+    let position = Position::new(x, y, z, yaw, pitch, roll);
+    pica.command(|status_tx| PicaCommand::CreateAnchor(mac_address, position, status_tx))
+}
+
+/// Destroy the selected anchor.
+///
+/// # Safety
+/// `pica` must be returned by `pica_create`, `mac_address` must point to
+/// a NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn pica_destroy_anchor(
+    pica: *mut PicaInstance,
+    mac_address: *const c_char,
+) -> i32 {
+    let (Some(pica), Some(mac_address)) = (pica.as_ref(), self::mac_address(mac_address)) else {
+        return PICA_ERROR_INVALID_ARGUMENT;
+    };
+    pica.command(|status_tx| PicaCommand::DestroyAnchor(mac_address, status_tx))
+}
+
+/// Move the selected anchor or device.
+///
+/// # Safety
+/// `pica` must be returned by `pica_create`, `mac_address` must point to
+/// a NUL terminated string.
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+pub unsafe extern "C" fn pica_set_position(
+    pica: *mut PicaInstance,
+    mac_address: *const c_char,
+    x: i16,
+    y: i16,
+    z: i16,
+    yaw: i16,
+    pitch: i8,
+    roll: i16,
+) -> i32 {
+    let (Some(pica), Some(mac_address)) = (pica.as_ref(), self::mac_address(mac_address)) else {
+        return PICA_ERROR_INVALID_ARGUMENT;
+    };
+    let position = Position::new(x, y, z, yaw, pitch, roll);
+    pica.command(|status_tx| PicaCommand::SetPosition(mac_address, position, status_tx))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn device_and_anchors() {
+        unsafe {
+            let pica = pica_create();
+            let mut device = u32::MAX;
+            assert_eq!(pica_connect(pica, &mut device), PICA_OK);
+
+            // The device notifies its initial state and answers the
+            // GetDeviceInfo command.
+            let cmd = [0x20, 0x02, 0x00, 0x00];
+            assert_eq!(
+                pica_push_uci(pica, device, cmd.as_ptr(), cmd.len()),
+                PICA_OK
+            );
+            let mut received = Vec::new();
+            let mut buffer = [0; 256];
+            while !received.windows(2).any(|w| w == [0x40, 0x02]) {
+                let len = pica_pop_uci(pica, device, buffer.as_mut_ptr(), buffer.len(), 1000);
+                assert!(len > 0);
+                received.extend_from_slice(&buffer[..len as usize]);
+            }
+
+            let mac_address = c"00:01".as_ptr();
+            assert_eq!(
+                pica_create_anchor(pica, mac_address, 100, 0, 0, 0, 0, 0),
+                PICA_OK
+            );
+            assert_eq!(
+                pica_set_position(pica, mac_address, 0, 100, 0, 0, 0, 0),
+                PICA_OK
+            );
+            assert_eq!(pica_destroy_anchor(pica, mac_address), PICA_OK);
+            assert_eq!(
+                pica_destroy_anchor(pica, mac_address),
+                PICA_ERROR_COMMAND_FAILED
+            );
+            assert_eq!(
+                pica_destroy_anchor(pica, c"zz".as_ptr()),
+                PICA_ERROR_INVALID_ARGUMENT
+            );
+
+            assert_eq!(pica_disconnect(pica, device), PICA_OK);
+            assert_eq!(pica_disconnect(pica, device), PICA_ERROR_NOT_FOUND);
+            pica_destroy(pica);
+        }
+    }
+}
@@ -0,0 +1,271 @@
+// Copyright 2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Interactive console controlling the simulation, on the standard
+//! input or on a TCP port for telnet clients, e.g. `telnet localhost 3001`.
+
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::time::Duration;
+
+use anyhow::Result;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, oneshot};
+
+use pica::{MacAddress, PicaCommand, PicaCommandStatus, PicaState, Position};
+
+use crate::parse_session_id;
+
+const PROMPT: &str = "pica> ";
+
+const HELP: &str = "\
+Commands:
+  list                                    List the anchors and devices
+  create-anchor <mac> [<x> <y> <z>]       Create an anchor
+  destroy-anchor <mac>                    Destroy an anchor
+  move <mac> <x> <y> <z>                  Move an anchor or device
+  range <mac> <session-id> [<interval>]   Start ranging from an anchor,
+                                          every interval (ms, 200 by default)
+  stop <mac>                              Stop ranging from an anchor
+  help                                    Show this message
+  quit                                    Close the console
+";
+
+/// Command entered on the console.
+#[derive(Debug, PartialEq)]
+enum Command {
+    List,
+    CreateAnchor(MacAddress, (i16, i16, i16)),
+    DestroyAnchor(MacAddress),
+    Move(MacAddress, (i16, i16, i16)),
+    Range(MacAddress, u32, Duration),
+    Stop(MacAddress),
+    Help,
+    Quit,
+}
+
+fn mac_address(arg: Option<&str>) -> Result<MacAddress, String> {
+    let arg = arg.ok_or("missing MAC address")?;
+    arg.parse::<MacAddress>()
+        .map_err(|_| format!("invalid MAC address '{}'", arg))
+}
+
+fn coordinate(arg: &str) -> Result<i16, String> {
+    arg.parse()
+        .map_err(|_| format!("invalid coordinate '{}'", arg))
+}
+
+fn coordinates(args: &[&str]) -> Result<(i16, i16, i16), String> {
+    match args {
+        [x, y, z] => Ok((coordinate(x)?, coordinate(y)?, coordinate(z)?)),
+        _ => Err("expected the x, y and z coordinates (cm)".to_owned()),
+    }
+}
+
+/// Parse a line entered on the console, or return None if empty.
+fn parse(line: &str) -> Option<Result<Command, String>> {
+    let mut args = line.split_whitespace();
+    let name = args.next()?;
+    let mac = args.next();
+    let rest = args.collect::<Vec<_>>();
+    let command = (|| match (name, &rest[..]) {
+        ("list", _) => Ok(Command::List),
+        ("create-anchor", []) => Ok(Command::CreateAnchor(mac_address(mac)?, (0, 0, 0))),
+        ("create-anchor", rest) => Ok(Command::CreateAnchor(mac_address(mac)?, coordinates(rest)?)),
+        ("destroy-anchor", _) => Ok(Command::DestroyAnchor(mac_address(mac)?)),
+        ("move", rest) => Ok(Command::Move(mac_address(mac)?, coordinates(rest)?)),
+        ("range", [session_id, interval @ ..]) => {
+            let interval = match interval {
+                [] => 200,
+                [interval] => match interval.parse::<u64>() {
+                    Ok(interval) if interval > 0 => interval,
+                    _ => return Err(format!("invalid ranging interval '{}'", interval)),
+                },
+                _ => return Err("too many arguments".to_owned()),
+            };
+            Ok(Command::Range(
+                mac_address(mac)?,
+                parse_session_id(session_id)?,
+                Duration::from_millis(interval),
+            ))
+        }
+        ("range", []) => Err("missing session id".to_owned()),
+        ("stop", _) => Ok(Command::Stop(mac_address(mac)?)),
+        ("help", _) => Ok(Command::Help),
+        ("quit" | "exit", _) => Ok(Command::Quit),
+        (name, _) => Err(format!("unknown command '{}', try help", name)),
+    })();
+    Some(command)
+}
+
+/// Send a command to pica and format its status.
+async fn send(
+    tx: &mpsc::Sender<PicaCommand>,
+    command: impl FnOnce(oneshot::Sender<PicaCommandStatus>) -> PicaCommand,
+) -> String {
+    let (status_tx, status_rx) = oneshot::channel();
+    if tx.send(command(status_tx)).await.is_err() {
+        return "error: the simulation is shut down\n".to_owned();
+    }
+    match status_rx.await {
+        Ok(Ok(())) => "ok\n".to_owned(),
+        Ok(Err(err)) => format!("error: {}\n", err),
+        Err(_) => "error: no response\n".to_owned(),
+    }
+}
+
+async fn state(tx: &mpsc::Sender<PicaCommand>) -> Option<PicaState> {
+    let (state_tx, state_rx) = oneshot::channel();
+    tx.send(PicaCommand::GetState(state_tx)).await.ok()?;
+    state_rx.await.ok()
+}
+
+/// Execute a console command and return its output.
+async fn execute(tx: &mpsc::Sender<PicaCommand>, command: Command) -> String {
+    match command {
+        Command::List => match state(tx).await {
+            Some(state) => state
+                .devices
+                .iter()
+                .map(|(category, mac_address, position)| {
+                    format!("{:?} {} {}\n", category, mac_address, position)
+                })
+                .collect(),
+            None => "error: the simulation is shut down\n".to_owned(),
+        },
+        Command::CreateAnchor(mac_address, (x, y, z)) => {
+            send(tx, |status_tx| {
+                PicaCommand::CreateAnchor(mac_address, Position::new(x, y, z, 0, 0, 0), status_tx)
+            })
+            .await
+        }
+        Command::DestroyAnchor(mac_address) => {
+            send(tx, |status_tx| {
+                PicaCommand::DestroyAnchor(mac_address, status_tx)
+            })
+            .await
+        }
+        Command::Move(mac_address, (x, y, z)) => {
+            // The orientation of the anchor or device is preserved.
+            let position = state(tx)
+                .await
+                .and_then(|state| {
+                    state
+                        .devices
+                        .into_iter()
+                        .find(|(_, device, _)| *device == mac_address)
+                })
+                .map(|(_, _, position)| position)
+                .unwrap_or_default()
+                .with_coordinates(x, y, z);
+            send(tx, |status_tx| {
+                PicaCommand::SetPosition(mac_address, position, status_tx)
+            })
+            .await
+        }
+        Command::Range(mac_address, session_id, interval) => {
+            send(tx, |status_tx| {
+                PicaCommand::StartAnchorRanging(mac_address, session_id, interval, status_tx)
+            })
+            .await
+        }
+        Command::Stop(mac_address) => {
+            send(tx, |status_tx| {
+                PicaCommand::StopAnchorRanging(mac_address, status_tx)
+            })
+            .await
+        }
+        Command::Help => HELP.to_owned(),
+        Command::Quit => String::new(),
+    }
+}
+
+/// Run a console session until the input is closed or the quit
+/// command is entered.
+async fn session(
+    tx: mpsc::Sender<PicaCommand>,
+    input: impl AsyncRead + Unpin,
+    mut output: impl AsyncWrite + Unpin,
+) -> Result<()> {
+    let mut lines = BufReader::new(input).lines();
+    output.write_all(PROMPT.as_bytes()).await?;
+    output.flush().await?;
+    while let Some(line) = lines.next_line().await? {
+        let response = match parse(&line) {
+            None => String::new(),
+            Some(Ok(Command::Quit)) => break,
+            Some(Ok(command)) => execute(&tx, command).await,
+            Some(Err(err)) => format!("error: {}\n", err),
+        };
+        output.write_all(response.as_bytes()).await?;
+        output.write_all(PROMPT.as_bytes()).await?;
+        output.flush().await?;
+    }
+    Ok(())
+}
+
+/// Run the console on the standard input and output.
+pub async fn run_stdio(tx: mpsc::Sender<PicaCommand>) -> Result<()> {
+    session(tx, tokio::io::stdin(), tokio::io::stdout()).await
+}
+
+/// Serve the console to the telnet clients connecting to the selected port.
+pub async fn serve(tx: mpsc::Sender<PicaCommand>, console_port: u16) -> Result<()> {
+    let addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, console_port);
+    let listener = TcpListener::bind(addr).await?;
+    println!("Pica: Console listening on: {}", addr);
+
+    loop {
+        let (socket, addr) = listener.accept().await?;
+        println!("Console client addr: {}", addr);
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            let (input, output) = socket.into_split();
+            if let Err(err) = session(tx, input, output).await {
+                println!("Console session with {} failed: {}", addr, err);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_commands() {
+        let mac_address = MacAddress::Short([0, 1]);
+        assert_eq!(parse("  "), None);
+        assert_eq!(parse("list"), Some(Ok(Command::List)));
+        assert_eq!(
+            parse("create-anchor 00:01"),
+            Some(Ok(Command::CreateAnchor(mac_address, (0, 0, 0))))
+        );
+        assert_eq!(
+            parse("move 00:01 10 -20 30"),
+            Some(Ok(Command::Move(mac_address, (10, -20, 30))))
+        );
+        assert_eq!(
+            parse("range 00:01 0x10"),
+            Some(Ok(Command::Range(
+                mac_address,
+                0x10,
+                Duration::from_millis(200)
+            )))
+        );
+        assert!(matches!(parse("move 00:01 10 20"), Some(Err(_))));
+        assert!(matches!(parse("range 00:01 1 0"), Some(Err(_))));
+        assert!(matches!(parse("fly 00:01"), Some(Err(_))));
+    }
+}
@@ -0,0 +1,41 @@
+// Copyright 2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Output of the simulator logs, with a filter that can be replaced
+//! at runtime.
+
+use anyhow::Result;
+use std::io::IsTerminal;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, reload, EnvFilter, Registry};
+
+/// Handle replacing the filter of the logs.
+pub type LogFilter = reload::Handle<EnvFilter, Registry>;
+
+/// Parse a filter in the `RUST_LOG` syntax, e.g. `info` or
+/// `pica=debug,hyper=warn`.
+pub fn parse_filter(filter: &str) -> Result<EnvFilter> {
+    Ok(EnvFilter::try_new(filter)?)
+}
+
+/// Print the logs selected by the filter to the standard output.
+pub fn init(filter: &str) -> Result<LogFilter> {
+    let (filter, handle) = reload::Layer::new(parse_filter(filter)?);
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(fmt::layer().with_ansi(std::io::stdout().is_terminal()))
+        .try_init()?;
+    Ok(handle)
+}
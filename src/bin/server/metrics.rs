@@ -0,0 +1,73 @@
+// Copyright 2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Prometheus exporter of the simulator metrics.
+
+use std::convert::Infallible;
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+use anyhow::{Context, Result};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use tokio::sync::{mpsc, oneshot};
+
+use pica::PicaCommand;
+
+async fn handle(
+    req: Request<Body>,
+    tx: mpsc::Sender<PicaCommand>,
+) -> Result<Response<Body>, Infallible> {
+    if req.method() != Method::GET || req.uri().path() != "/metrics" {
+        return Ok(Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty())
+            .unwrap());
+    }
+    let (metrics_tx, metrics_rx) = oneshot::channel();
+    if tx.send(PicaCommand::GetMetrics(metrics_tx)).await.is_err() {
+        return Ok(Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .body(Body::empty())
+            .unwrap());
+    }
+    Ok(match metrics_rx.await {
+        Ok(metrics) => Response::builder()
+            .header("content-type", "text/plain; version=0.0.4")
+            .body(metrics.to_prometheus().into())
+            .unwrap(),
+        Err(_) => Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .body(Body::empty())
+            .unwrap(),
+    })
+}
+
+/// Serve the metrics at `/metrics` on the selected port.
+pub async fn serve(tx: mpsc::Sender<PicaCommand>, metrics_port: u16) -> Result<()> {
+    let addr = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, metrics_port);
+
+    let make_svc = make_service_fn(move |_conn| {
+        let tx = tx.clone();
+        async move { Ok::<_, Infallible>(service_fn(move |req| handle(req, tx.clone()))) }
+    });
+
+    let server = Server::bind(&addr.into()).serve(make_svc);
+
+    println!(
+        "Pica: Metrics exported on http://0.0.0.0:{}/metrics",
+        metrics_port
+    );
+
+    server.await.context("Metrics Server Error")
+}
@@ -17,33 +17,159 @@ extern crate num_derive;
 extern crate num_traits;
 extern crate thiserror;
 
+#[cfg(feature = "console")]
+mod console;
+mod log;
 #[cfg(feature = "web")]
-mod web;
+mod metrics;
+#[cfg(feature = "tls")]
+mod tls;
 
+#[cfg(feature = "serial")]
+use anyhow::Context;
 use anyhow::Result;
 use clap::Parser;
-use pica::{Pica, PicaCommand};
-use std::net::{Ipv4Addr, SocketAddrV4};
+use pica::{
+    AngleConvention, CaptureFilter, CaptureFormat, CaptureRotation, CapturedPackets, DeviceProfile,
+    FomModel, GeodeticPosition, InterferenceModel, MeasurementNoise, MeasurementOutliers,
+    Personality, Pica, PicaCommand, RssiModel, SimulatorInfo, TimeMode,
+};
+use std::fmt::Display;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
 use tokio::net::TcpListener;
-use tokio::sync::{broadcast, mpsc};
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
 use tokio::try_join;
 
 const DEFAULT_UCI_PORT: u16 = 7000;
 const DEFAULT_WEB_PORT: u16 = 3000;
 
-async fn accept_incoming(tx: mpsc::Sender<PicaCommand>, uci_port: u16) -> Result<()> {
-    let uci_socket = SocketAddrV4::new(Ipv4Addr::LOCALHOST, uci_port);
-    let uci_listener = TcpListener::bind(uci_socket).await?;
-    println!("Pica: Listening on: {}", uci_port);
+/// Endpoint accepting UCI connections.
+#[derive(Clone, Debug)]
+enum Endpoint {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl FromStr for Endpoint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("tcp", addr)) => addr
+                .parse()
+                .map(Endpoint::Tcp)
+                .map_err(|err| format!("invalid socket address '{}': {}", addr, err)),
+            #[cfg(unix)]
+            Some(("unix", path)) if !path.is_empty() => Ok(Endpoint::Unix(path.into())),
+            _ => Err(format!(
+                "unknown endpoint '{}', expected tcp:ADDRESS:PORT or unix:PATH",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for Endpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Endpoint::Tcp(addr) => write!(f, "tcp:{}", addr),
+            #[cfg(unix)]
+            Endpoint::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+async fn accept_incoming(
+    tx: mpsc::Sender<PicaCommand>,
+    endpoint: Endpoint,
+    #[cfg(feature = "tls")] tls_acceptor: Option<tokio_rustls::TlsAcceptor>,
+) -> Result<()> {
+    let origin = endpoint.to_string();
+    let uci_listener = match endpoint {
+        Endpoint::Tcp(addr) => TcpListener::bind(addr).await?,
+        #[cfg(unix)]
+        Endpoint::Unix(path) => return accept_unix(tx, path, origin).await,
+    };
+    println!("Pica: Listening on: {}", origin);
 
     loop {
         let (socket, addr) = uci_listener.accept().await?;
         println!("Uwb host addr: {}", addr);
-        tx.send(PicaCommand::Connect(socket)).await?
+        // The TLS handshake is completed in a separate task
+        // to keep accepting connections in the meantime.
+        #[cfg(feature = "tls")]
+        if let Some(acceptor) = &tls_acceptor {
+            let acceptor = acceptor.clone();
+            let tx = tx.clone();
+            let origin = origin.clone();
+            tokio::spawn(async move {
+                match tokio::time::timeout(tls::HANDSHAKE_TIMEOUT, acceptor.accept(socket)).await {
+                    Ok(Ok(stream)) => {
+                        let _ = tx
+                            .send(PicaCommand::Connect(Box::new(stream), origin))
+                            .await;
+                    }
+                    Ok(Err(err)) => println!("TLS handshake failed with {}: {}", addr, err),
+                    Err(_) => println!("TLS handshake timed out with {}", addr),
+                }
+            });
+            continue;
+        }
+        tx.send(PicaCommand::Connect(Box::new(socket), origin.clone()))
+            .await?
+    }
+}
+
+#[cfg(unix)]
+async fn accept_unix(tx: mpsc::Sender<PicaCommand>, path: PathBuf, origin: String) -> Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+    // Remove the socket left over by a previous instance,
+    // but never overwrite other files.
+    if std::fs::metadata(&path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+        std::fs::remove_file(&path)?;
+    }
+    let uci_listener = UnixListener::bind(&path)?;
+    println!("Pica: Listening on: {}", origin);
+
+    loop {
+        let (socket, _) = uci_listener.accept().await?;
+        println!("Uwb host connected to {}", origin);
+        tx.send(PicaCommand::Connect(Box::new(socket), origin.clone()))
+            .await?
     }
 }
 
+/// Attach a device to each of the serial ports, e.g. one end of a
+/// pseudo-terminal pair created with
+/// `socat pty,raw,echo=0 pty,raw,echo=0`. The UCI packets are framed
+/// the same way as on the TCP connections. The device is removed when
+/// the port is closed.
+#[cfg(feature = "serial")]
+async fn attach_serial(
+    tx: mpsc::Sender<PicaCommand>,
+    paths: Vec<PathBuf>,
+    baud_rate: u32,
+) -> Result<()> {
+    use tokio_serial::SerialPortBuilderExt;
+    for path in paths {
+        let port = tokio_serial::new(path.to_string_lossy(), baud_rate)
+            .open_native_async()
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        println!("Pica: Attached to serial port {}", path.display());
+        let origin = format!("serial:{}", path.display());
+        tx.send(PicaCommand::Connect(Box::new(port), origin))
+            .await?
+    }
+    Ok(())
+}
+
 #[derive(Parser, Debug)]
 #[command(name = "pica", about = "Virtual UWB subsystem")]
 struct Args {
@@ -52,35 +178,446 @@ struct Args {
     /// saved under the name `device-{handle}.pcapng`.
     #[arg(short, long, value_name = "PCAPNG_DIR")]
     pcapng_dir: Option<PathBuf>,
+    /// Sync .pcapng traces to the storage device after each packet,
+    /// so that captures are complete even after a system crash.
+    #[arg(long)]
+    pcapng_sync: bool,
+    /// Format of the traces saved to the pcapng directory: `pcapng`, or
+    /// `pcap` for the tools only supporting the classic format, in which
+    /// case the traces are named `device-{handle}.pcap`.
+    #[arg(long, value_name = "FORMAT", default_value_t = CaptureFormat::default())]
+    capture_format: CaptureFormat,
+    /// Packets saved to the pcapng traces: `all`, `control` or `data`.
+    #[arg(long, value_name = "PACKETS", default_value_t = CapturedPackets::default())]
+    capture_packets: CapturedPackets,
+    /// Groups of the control packets saved to the pcapng traces,
+    /// all if not provided.
+    #[arg(long, value_name = "GID", value_delimiter = ',')]
+    capture_gids: Vec<u8>,
+    /// Sessions of the packets saved to the pcapng traces, all if not
+    /// provided. The packets not related to a session are always saved.
+    #[arg(long, value_name = "SESSION_ID", value_delimiter = ',', value_parser = parse_session_id)]
+    capture_session_ids: Vec<u32>,
+    /// Continue the pcapng traces in a new file when the current file
+    /// exceeds this size (bytes). The traces are then named
+    /// `device-{handle}-{index}.pcapng`.
+    #[arg(long, value_name = "BYTES")]
+    capture_max_size: Option<u64>,
+    /// Continue the pcapng traces in a new file when the current file
+    /// covers more than this duration (seconds).
+    #[arg(long, value_name = "SECONDS")]
+    capture_max_duration: Option<u64>,
+    /// Output directory for storing human readable UCI traces.
+    /// If provided, the UCI packets of client connections are decoded
+    /// to files named `device-{handle}.log`, with or without pcapng traces.
+    #[arg(long, value_name = "TRACE_DIR")]
+    trace_dir: Option<PathBuf>,
+    /// Estimate the position of each device from its ranging measurements,
+    /// and report the estimation error in `position-estimated` events.
+    #[arg(long)]
+    position_solver: bool,
+    /// Retain the properties of the disconnected devices, restored when
+    /// a new connection is initialized with the same MAC address.
+    #[arg(long)]
+    persistent_identity: bool,
+    /// Convention of the azimuths reported in the ranging measurements:
+    /// `ned` (clockwise from the boresight, default) or `enu`
+    /// (counter-clockwise from the x axis).
+    #[arg(long, value_name = "CONVENTION")]
+    angle_convention: Option<AngleConvention>,
+    /// Standard deviation of the errors added to the measured distances, in cm.
+    #[arg(long, value_name = "CM", default_value_t = 0.0)]
+    distance_noise: f32,
+    /// Standard deviation of the errors added to the measured angles, in degrees.
+    #[arg(long, value_name = "DEGREES", default_value_t = 0.0)]
+    angle_noise: f32,
+    /// Probability that a measured distance is an outlier.
+    #[arg(long, value_name = "PROBABILITY", default_value_t = 0.0)]
+    distance_outlier_probability: f32,
+    /// Error added to the outlier distances, in cm.
+    #[arg(long, value_name = "CM", default_value_t = MeasurementOutliers::default().distance_offset)]
+    distance_outlier_offset: u16,
+    /// Probability that a measured azimuth is mirrored across the boresight.
+    #[arg(long, value_name = "PROBABILITY", default_value_t = 0.0)]
+    aoa_outlier_probability: f32,
+    /// Probability that a measurement is lost, for each session ranging
+    /// on the same channel in an overlapping ranging round.
+    #[arg(long, value_name = "PROBABILITY", default_value_t = 0.0)]
+    interference_drop_probability: f32,
+    /// Standard deviation of the distance errors added for each session
+    /// ranging on the same channel in an overlapping ranging round, in cm.
+    #[arg(long, value_name = "CM", default_value_t = 0.0)]
+    interference_noise: f32,
+    /// Signal strength received at 1 m, in dBm.
+    #[arg(long, value_name = "DBM", default_value_t = RssiModel::default().reference_rssi)]
+    reference_rssi: f32,
+    /// Path loss exponent of the signal strength model, 2.0 in free space.
+    #[arg(long, value_name = "EXPONENT", default_value_t = RssiModel::default().path_loss_exponent)]
+    path_loss_exponent: f32,
+    /// Standard deviation of the errors added to the signal strength, in dB.
+    #[arg(long, value_name = "DB", default_value_t = 0.0)]
+    rssi_noise: f32,
+    /// Loss of the angle of arrival figure of merit per degree off boresight.
+    #[arg(long, value_name = "FOM", default_value_t = FomModel::default().angle_loss)]
+    fom_angle_loss: f32,
+    /// Loss of the angle of arrival figure of merit per meter.
+    #[arg(long, value_name = "FOM", default_value_t = FomModel::default().distance_loss)]
+    fom_distance_loss: f32,
+    /// Factor applied to the angle of arrival figure of merit of the links
+    /// blocked by an obstacle.
+    #[arg(long, value_name = "FACTOR", default_value_t = FomModel::default().nlos_factor)]
+    nlos_fom_factor: f32,
+    /// Personalities exposed by the devices, behind a single UCI transport:
+    /// `ranging`, `radar` and `test`. Commands for the sessions and groups
+    /// of the other personalities are rejected.
+    #[arg(long, value_name = "PERSONALITY", value_delimiter = ',', default_values_t = DeviceProfile::default().personalities().to_vec())]
+    personalities: Vec<Personality>,
+    /// Seed of all the randomized behaviors: measurement errors, timer
+    /// jitter and notification latency. A random seed is selected if not
+    /// provided. The seed of the measurement errors of individual sessions
+    /// can be changed with the web API.
+    #[arg(long)]
+    seed: Option<u64>,
+    /// Speed of the simulated time, as a multiple of the wall-clock time.
+    /// Ranging rounds and motions run faster than real time above 1.
+    #[arg(long, value_name = "SPEED", default_value_t = 1.0, value_parser = parse_time_speed)]
+    time_speed: f64,
+    /// Only advance the simulated time when stepped with the web API.
+    #[arg(long, conflicts_with = "time_speed")]
+    stepped_time: bool,
+    /// JSONL file recording the control commands, e.g. received
+    /// from the web interface, with their simulated time.
+    #[arg(long, value_name = "COMMAND_LOG")]
+    command_log: Option<PathBuf>,
+    /// Replay the control commands recorded to this file with
+    /// `--command-log`, at their original simulated time.
+    #[arg(long, value_name = "COMMAND_LOG")]
+    replay: Option<PathBuf>,
+    /// SQLite database recording all events and ranging measurements.
+    /// Entries are appended if the database already exists.
+    #[cfg(feature = "sqlite")]
+    #[arg(long, value_name = "EVENT_LOG")]
+    event_log: Option<PathBuf>,
+    /// Maximum number of connected UCI devices.
+    /// Further connections are closed.
+    #[arg(long, value_name = "COUNT", default_value_t = SimulatorInfo::default().max_devices)]
+    max_devices: usize,
+    /// Maximum ranging distance (cm). The peers further away, or beyond
+    /// the range allowed by the TX power of the devices, are omitted
+    /// from the ranging measurements.
+    #[arg(long, value_name = "CM")]
+    max_range: Option<u16>,
     /// Configure the TCP port for the UCI server.
     #[arg(short, long, value_name = "UCI_PORT", default_value_t = DEFAULT_UCI_PORT)]
     uci_port: u16,
+    /// Endpoint accepting UCI connections, either `tcp:ADDRESS:PORT`
+    /// or `unix:PATH`. Can be repeated to accept connections on several
+    /// endpoints at once; the devices report the endpoint they are connected
+    /// through. If provided, replaces the endpoint selected with `--uci-port`
+    /// and `--uci-address`.
+    #[arg(long, value_name = "ENDPOINT")]
+    listen: Vec<Endpoint>,
+    /// Configure the address the UCI server listens on. Select an external
+    /// address to accept connections from other machines, preferably with TLS.
+    #[arg(long, value_name = "ADDRESS", default_value_t = IpAddr::V4(Ipv4Addr::LOCALHOST))]
+    uci_address: IpAddr,
+    /// Certificate chain presented to the UCI clients, in PEM format.
+    /// If provided, the UCI connections are secured with TLS.
+    #[cfg(feature = "tls")]
+    #[arg(long, value_name = "PEM_FILE", requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+    /// Private key of the certificate presented to the UCI clients, in PEM format.
+    #[cfg(feature = "tls")]
+    #[arg(long, value_name = "PEM_FILE", requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+    /// Certificate authorities of the UCI clients, in PEM format.
+    /// If provided, the UCI clients must authenticate with a certificate
+    /// issued by one of these authorities.
+    #[cfg(feature = "tls")]
+    #[arg(long, value_name = "PEM_FILE", requires = "tls_cert")]
+    tls_client_ca: Option<PathBuf>,
+    /// Serial port or pseudo-terminal to attach a device to,
+    /// e.g. `/dev/ttyUSB0` or `/dev/pts/3`. Can be repeated.
+    #[cfg(feature = "serial")]
+    #[arg(long, value_name = "PATH")]
+    serial: Vec<PathBuf>,
+    /// Baud rate of the serial ports.
+    #[cfg(feature = "serial")]
+    #[arg(long, value_name = "BAUD", default_value_t = 115200)]
+    serial_baud_rate: u32,
+    /// Export the simulator metrics for Prometheus at `/metrics`
+    /// on the selected HTTP port.
+    #[cfg(feature = "web")]
+    #[arg(long, value_name = "METRICS_PORT")]
+    metrics_port: Option<u16>,
+    /// Filter of the logs, in the `RUST_LOG` syntax: a level such as
+    /// `debug`, or directives such as `pica=debug,hyper=warn`.
+    /// The filter can be replaced at runtime with the web API.
+    #[arg(long, value_name = "FILTER", default_value = "info")]
+    log_filter: String,
+    /// Run the interactive console on the standard input.
+    #[cfg(feature = "console")]
+    #[arg(long)]
+    console: bool,
+    /// Serve the interactive console to the telnet clients connecting
+    /// to this local port.
+    #[cfg(feature = "console")]
+    #[arg(long, value_name = "PORT")]
+    console_port: Option<u16>,
+    /// Run the scenario described by this rhai script, e.g. to create
+    /// the anchors and move them over time.
+    #[cfg(feature = "scripting")]
+    #[arg(long, value_name = "SCRIPT")]
+    script: Option<PathBuf>,
+    /// Apply the position updates received as JSON datagrams on this
+    /// UDP address, e.g. to mirror a motion capture system.
+    #[arg(long, value_name = "ADDRESS")]
+    position_feed_udp: Option<SocketAddr>,
+    /// Apply the position updates published as JSON messages on this
+    /// MQTT topic, as `mqtt://host[:port]/topic`.
+    #[cfg(feature = "mqtt")]
+    #[arg(long, value_name = "URL")]
+    position_feed_mqtt: Option<pica::MqttEndpoint>,
+    /// Publish the events to this MQTT broker, as
+    /// `mqtt://host[:port]/topic`. The `{mac_address}` and `{kind}`
+    /// placeholders of the topic are replaced for each event, topics
+    /// without placeholders are prefixes of `{mac_address}/{kind}`.
+    #[cfg(feature = "mqtt")]
+    #[arg(long, value_name = "URL")]
+    mqtt_events: Option<pica::MqttEndpoint>,
     /// Configure the HTTP port for the web interface.
     #[arg(short, long, value_name = "WEB_PORT", default_value_t = DEFAULT_WEB_PORT)]
     web_port: u16,
+    /// Send the events of the web event stream in the untagged encoding
+    /// of the earlier versions, without the `kind` and `version` fields.
+    #[arg(long)]
+    untagged_events: bool,
+    /// Accept the positions of the web API in WGS84 coordinates, converted
+    /// to the tangent plane at this origin, as `latitude,longitude,altitude`.
+    #[arg(long, value_name = "ORIGIN", allow_hyphen_values = true)]
+    geodetic_origin: Option<GeodeticPosition>,
+    /// Print the OpenAPI document of the web API, generated from the
+    /// types of the request and response bodies, and exit.
+    #[cfg(feature = "schema")]
+    #[arg(long)]
+    openapi: bool,
+}
+
+/// Parse a session identifier, in decimal or hexadecimal with
+/// the `0x` prefix.
+fn parse_session_id(session_id: &str) -> Result<u32, String> {
+    match session_id.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => session_id.parse(),
+    }
+    .map_err(|_| format!("invalid session id {}", session_id))
+}
+
+fn parse_time_speed(speed: &str) -> Result<f64, String> {
+    match speed.parse::<f64>() {
+        Ok(speed) if speed.is_finite() && speed > 0.0 => Ok(speed),
+        _ => Err(format!("invalid time speed {}", speed)),
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Args::parse();
+    #[cfg(feature = "schema")]
+    if args.openapi {
+        println!("{:#}", pica::web::openapi());
+        return Ok(());
+    }
+    #[cfg_attr(not(feature = "web"), allow(unused_variables))]
+    let log_filter = log::init(&args.log_filter)?;
     assert_ne!(
         args.uci_port, args.web_port,
         "UCI port and Web port shall be different."
     );
-    let (event_tx, _) = broadcast::channel(16);
-
-    let mut pica = Pica::new(event_tx.clone(), args.pcapng_dir);
+    let seed = args.seed.unwrap_or_else(rand::random);
+    println!("Pica: Seed: {}", seed);
+    let mut builder = Pica::builder()
+        .max_devices(args.max_devices)
+        .position_solver(args.position_solver)
+        .persistent_identity(args.persistent_identity)
+        .angle_convention(args.angle_convention.unwrap_or_default())
+        .measurement_noise(
+            MeasurementNoise {
+                distance: args.distance_noise,
+                angle: args.angle_noise,
+            },
+            Some(seed),
+        )
+        .measurement_outliers(MeasurementOutliers {
+            distance_probability: args.distance_outlier_probability,
+            distance_offset: args.distance_outlier_offset,
+            aoa_probability: args.aoa_outlier_probability,
+        })
+        .interference_model(InterferenceModel {
+            drop_probability: args.interference_drop_probability,
+            distance_noise: args.interference_noise,
+        })
+        .rssi_model(RssiModel {
+            reference_rssi: args.reference_rssi,
+            path_loss_exponent: args.path_loss_exponent,
+            noise: args.rssi_noise,
+        })
+        .fom_model(FomModel {
+            angle_loss: args.fom_angle_loss,
+            distance_loss: args.fom_distance_loss,
+            nlos_factor: args.nlos_fom_factor,
+            ..Default::default()
+        })
+        .device_profile(DeviceProfile::new(&args.personalities))
+        .time_mode(if args.stepped_time {
+            TimeMode::Stepped
+        } else {
+            TimeMode::Scaled(args.time_speed)
+        });
+    if let Some(max_range) = args.max_range {
+        builder = builder.max_range(max_range);
+    }
+    if let Some(pcapng_dir) = args.pcapng_dir {
+        builder = builder
+            .pcapng_dir(pcapng_dir, args.pcapng_sync)
+            .capture_format(args.capture_format)
+            .capture_filter(CaptureFilter {
+                packets: args.capture_packets,
+                gids: args.capture_gids,
+                session_ids: args.capture_session_ids,
+            })
+            .capture_rotation(CaptureRotation {
+                max_size: args.capture_max_size,
+                max_duration: args.capture_max_duration.map(Duration::from_secs),
+            });
+    }
+    if let Some(trace_dir) = args.trace_dir {
+        builder = builder.trace_dir(trace_dir);
+    }
+    if let Some(path) = args.command_log {
+        builder = builder.command_log(pica::CommandLog::create(path)?);
+    }
+    #[cfg(feature = "sqlite")]
+    if let Some(path) = args.event_log {
+        builder = builder.event_log(pica::EventLog::open(path)?);
+    }
+    let mut pica = builder.build();
+    let replay = match args.replay {
+        Some(path) => Some(pica::CommandReplay::open(&pica, path)?),
+        None => None,
+    };
+    #[cfg(feature = "scripting")]
+    let script = match args.script {
+        Some(path) => Some(pica::Script::compile(
+            &pica,
+            &std::fs::read_to_string(path)?,
+        )?),
+        None => None,
+    };
+    #[cfg(feature = "web")]
+    let router = pica::web::Router::new(&pica)
+        .log_filter(log_filter)
+        .untagged_events(args.untagged_events);
+    #[cfg(feature = "web")]
+    let router = match args.geodetic_origin {
+        Some(origin) => router.geodetic_origin(origin),
+        None => router,
+    };
     let pica_tx = pica.tx();
 
+    let endpoints = if args.listen.is_empty() {
+        vec![Endpoint::Tcp(SocketAddr::new(
+            args.uci_address,
+            args.uci_port,
+        ))]
+    } else {
+        args.listen
+    };
+    #[cfg(feature = "tls")]
+    let tls_acceptor = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert), Some(key)) => Some(tls::acceptor(cert, key, args.tls_client_ca.as_deref())?),
+        _ => None,
+    };
+    let mut tasks = JoinSet::new();
+    for endpoint in endpoints {
+        tasks.spawn(accept_incoming(
+            pica_tx.clone(),
+            endpoint,
+            #[cfg(feature = "tls")]
+            tls_acceptor.clone(),
+        ));
+    }
+    #[cfg(feature = "serial")]
+    tasks.spawn(attach_serial(
+        pica_tx.clone(),
+        args.serial,
+        args.serial_baud_rate,
+    ));
     #[cfg(feature = "web")]
-    try_join!(
-        accept_incoming(pica_tx.clone(), args.uci_port),
-        pica.run(),
-        web::serve(pica_tx, event_tx, args.web_port)
-    )?;
+    if let Some(metrics_port) = args.metrics_port {
+        tasks.spawn(metrics::serve(pica_tx.clone(), metrics_port));
+    }
+    if let Some(replay) = replay {
+        tasks.spawn(replay.run());
+    }
+    #[cfg(feature = "scripting")]
+    if let Some(script) = script {
+        tasks.spawn(script.run());
+    }
+    if let Some(addr) = args.position_feed_udp {
+        tasks.spawn(pica::PositionFeed::new(&pica).listen_udp(addr));
+    }
+    #[cfg(feature = "mqtt")]
+    if let Some(endpoint) = args.position_feed_mqtt {
+        tasks.spawn(pica::PositionFeed::new(&pica).subscribe_mqtt(endpoint));
+    }
+    #[cfg(feature = "mqtt")]
+    if let Some(endpoint) = args.mqtt_events {
+        tasks.spawn(pica::EventPublisher::new(&pica, endpoint).run());
+    }
+    #[cfg(feature = "console")]
+    if args.console {
+        tasks.spawn(console::run_stdio(pica_tx.clone()));
+    }
+    #[cfg(feature = "console")]
+    if let Some(console_port) = args.console_port {
+        tasks.spawn(console::serve(pica_tx.clone(), console_port));
+    }
+    let incoming = async move {
+        while let Some(result) = tasks.join_next().await {
+            result??
+        }
+        Ok(())
+    };
+
+    let simulation = async move { pica.run().await.map_err(anyhow::Error::from) };
+
+    #[cfg(feature = "web")]
+    try_join!(incoming, simulation, router.serve(args.web_port))?;
 
     #[cfg(not(feature = "web"))]
-    try_join!(accept_incoming(pica_tx.clone(), args.uci_port), pica.run(),)?;
+    try_join!(incoming, simulation,)?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_endpoints() {
+        let endpoint: Endpoint = "tcp:127.0.0.1:7000".parse().unwrap();
+        assert_eq!(endpoint.to_string(), "tcp:127.0.0.1:7000");
+        #[cfg(unix)]
+        assert_eq!(
+            "unix:/tmp/pica.sock"
+                .parse::<Endpoint>()
+                .unwrap()
+                .to_string(),
+            "unix:/tmp/pica.sock"
+        );
+        assert!("tcp:7000".parse::<Endpoint>().is_err());
+        assert!("udp:127.0.0.1:7000".parse::<Endpoint>().is_err());
+    }
+}
@@ -0,0 +1,72 @@
+// Copyright 2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! TLS termination of the UCI connections.
+
+use anyhow::{anyhow, Context, Result};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_rustls::rustls::server::AllowAnyAuthenticatedClient;
+use tokio_rustls::rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+/// Maximum duration of the handshake, after which the connection is
+/// dropped to release the socket of the stalled clients.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn load_certs(path: &Path) -> Result<Vec<Certificate>> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .with_context(|| format!("Failed to parse {}", path.display()))?;
+    if certs.is_empty() {
+        return Err(anyhow!("No certificate found in {}", path.display()));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_key(path: &Path) -> Result<PrivateKey> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    rustls_pemfile::read_all(&mut BufReader::new(file))
+        .with_context(|| format!("Failed to parse {}", path.display()))?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| anyhow!("No private key found in {}", path.display()))
+}
+
+/// Create an acceptor presenting the selected certificate chain.
+/// If `client_ca` is set, clients must authenticate with a certificate
+/// issued by one of the authorities in this file.
+pub fn acceptor(cert: &Path, key: &Path, client_ca: Option<&Path>) -> Result<TlsAcceptor> {
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let builder = match client_ca {
+        Some(client_ca) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(client_ca)? {
+                roots.add(&cert)?;
+            }
+            builder.with_client_cert_verifier(Arc::new(AllowAnyAuthenticatedClient::new(roots)))
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let config = builder.with_single_cert(load_certs(cert)?, load_key(key)?)?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
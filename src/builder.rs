@@ -0,0 +1,376 @@
+// Copyright 2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Runtime configuration of the simulator.
+
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+use tokio::sync::{broadcast, mpsc};
+
+use crate::capture;
+use crate::device::MAX_DEVICE;
+use crate::session::MAX_SESSION;
+use crate::timeline::Timeline;
+#[cfg(feature = "sqlite")]
+use crate::EventLog;
+use crate::{
+    AngleConvention, CaptureFilter, CaptureFormat, CaptureRotation, CommandLog, DeviceProfile,
+    FomModel, InterferenceModel, MeasurementNoise, MeasurementOutliers, Metrics, Pica, RssiModel,
+    Scene, SequencedEvent, TimeMode, EVENT_HISTORY_SIZE, MAX_ANCHOR,
+};
+
+/// Default capacity of the event channel.
+const DEFAULT_EVENT_CAPACITY: usize = 16;
+
+/// Default capacity of the traffic tap channel.
+const DEFAULT_TAP_CAPACITY: usize = 256;
+
+/// Builder of [`Pica`] instances. The limits default to the values
+/// advertised by the UCI capabilities of the virtual devices.
+pub struct PicaBuilder {
+    event_tx: Option<broadcast::Sender<SequencedEvent>>,
+    event_capacity: usize,
+    tap_capacity: usize,
+    command_capacity: Option<usize>,
+    max_devices: usize,
+    max_sessions: usize,
+    max_anchors: usize,
+    max_range: u16,
+    pcapng_dir: Option<PathBuf>,
+    capture_config: capture::Config,
+    trace_dir: Option<PathBuf>,
+    position_solver: bool,
+    persistent_identity: bool,
+    angle_convention: AngleConvention,
+    noise: MeasurementNoise,
+    outliers: MeasurementOutliers,
+    interference: InterferenceModel,
+    seed: Option<u64>,
+    rssi_model: RssiModel,
+    fom_model: FomModel,
+    device_profile: DeviceProfile,
+    time_mode: TimeMode,
+    command_log: Option<CommandLog>,
+    #[cfg(feature = "sqlite")]
+    event_log: Option<EventLog>,
+}
+
+impl Default for PicaBuilder {
+    fn default() -> Self {
+        PicaBuilder {
+            event_tx: None,
+            event_capacity: DEFAULT_EVENT_CAPACITY,
+            tap_capacity: DEFAULT_TAP_CAPACITY,
+            command_capacity: None,
+            max_devices: MAX_DEVICE,
+            max_sessions: MAX_SESSION,
+            max_anchors: MAX_ANCHOR,
+            max_range: u16::MAX,
+            pcapng_dir: None,
+            capture_config: capture::Config::default(),
+            trace_dir: None,
+            position_solver: false,
+            persistent_identity: false,
+            angle_convention: AngleConvention::default(),
+            noise: MeasurementNoise::default(),
+            outliers: MeasurementOutliers::default(),
+            interference: InterferenceModel::default(),
+            seed: None,
+            rssi_model: RssiModel::default(),
+            fom_model: FomModel::default(),
+            device_profile: DeviceProfile::default(),
+            time_mode: TimeMode::default(),
+            command_log: None,
+            #[cfg(feature = "sqlite")]
+            event_log: None,
+        }
+    }
+}
+
+impl PicaBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Broadcast the events to an existing channel, instead of a channel
+    /// created with the selected event capacity.
+    pub fn event_sender(mut self, event_tx: broadcast::Sender<SequencedEvent>) -> Self {
+        self.event_tx = Some(event_tx);
+        self
+    }
+
+    /// Number of events retained for the slowest observer before it lags.
+    pub fn event_capacity(mut self, capacity: usize) -> Self {
+        self.event_capacity = capacity;
+        self
+    }
+
+    /// Number of UCI packets retained for the slowest subscriber of the
+    /// traffic tap before it lags.
+    pub fn tap_capacity(mut self, capacity: usize) -> Self {
+        self.tap_capacity = capacity;
+        self
+    }
+
+    /// Number of commands queued before the senders wait, by default
+    /// the maximum number of sessions of all devices.
+    pub fn command_capacity(mut self, capacity: usize) -> Self {
+        self.command_capacity = Some(capacity);
+        self
+    }
+
+    /// Maximum number of connected UCI devices. Further connections
+    /// are closed.
+    pub fn max_devices(mut self, max_devices: usize) -> Self {
+        self.max_devices = max_devices;
+        self
+    }
+
+    /// Maximum number of sessions per device.
+    pub fn max_sessions(mut self, max_sessions: usize) -> Self {
+        self.max_sessions = max_sessions;
+        self
+    }
+
+    /// Maximum number of anchors.
+    pub fn max_anchors(mut self, max_anchors: usize) -> Self {
+        self.max_anchors = max_anchors;
+        self
+    }
+
+    /// Maximum ranging distance (cm). The peers further away are omitted
+    /// from the measurements, as are the peers beyond the range allowed by
+    /// the TX power of the devices.
+    pub fn max_range(mut self, max_range: u16) -> Self {
+        self.max_range = max_range;
+        self
+    }
+
+    /// Save the UCI packets of each device to `device-{handle}.pcapng`
+    /// files in the selected directory. With `sync`, the files are synced
+    /// to the storage device after each packet, so that captures are
+    /// complete even after a system crash.
+    pub fn pcapng_dir(mut self, pcapng_dir: PathBuf, sync: bool) -> Self {
+        self.pcapng_dir = Some(pcapng_dir);
+        self.capture_config.sync = sync;
+        self
+    }
+
+    /// Select the format of the captures saved to the `pcapng_dir`
+    /// directory, named `device-{handle}.pcap` in the classic pcap format.
+    pub fn capture_format(mut self, capture_format: CaptureFormat) -> Self {
+        self.capture_config.format = capture_format;
+        self
+    }
+
+    /// Select the packets saved to the captures, e.g. the control
+    /// packets of a single session during data transfer soak tests.
+    pub fn capture_filter(mut self, capture_filter: CaptureFilter) -> Self {
+        self.capture_config.filter = capture_filter;
+        self
+    }
+
+    /// Continue the captures in new files, named `device-{handle}-{index}`,
+    /// when the current files exceed the selected size or duration.
+    pub fn capture_rotation(mut self, capture_rotation: CaptureRotation) -> Self {
+        self.capture_config.rotation = capture_rotation;
+        self
+    }
+
+    /// Record a human readable trace of the UCI packets of each device
+    /// to `device-{handle}.log` files in the selected directory.
+    pub fn trace_dir(mut self, trace_dir: PathBuf) -> Self {
+        self.trace_dir = Some(trace_dir);
+        self
+    }
+
+    /// Estimate the position of each device from the measurements of its
+    /// ranging rounds, and report the estimate along with the ground truth
+    /// in PositionEstimated events.
+    pub fn position_solver(mut self, enable: bool) -> Self {
+        self.position_solver = enable;
+        self
+    }
+
+    /// Retain the position, field of view, clock and notification latency
+    /// of the disconnected devices, and the statistics of their links with
+    /// the anchors. A device connecting later claims the address of a
+    /// disconnected device, and recovers its properties, when initialized
+    /// with this address.
+    pub fn persistent_identity(mut self, enable: bool) -> Self {
+        self.persistent_identity = enable;
+        self
+    }
+
+    /// Report the azimuths of the ranging measurements in the selected
+    /// convention, instead of clockwise from the boresight. The events
+    /// keep the default convention.
+    pub fn angle_convention(mut self, angle_convention: AngleConvention) -> Self {
+        self.angle_convention = angle_convention;
+        self
+    }
+
+    /// Add random errors to the ranging measurements. The errors of each
+    /// session are drawn from a generator derived from `seed`, which can
+    /// be overridden with [`crate::PicaCommand::SetSessionSeed`].
+    /// Same as selecting the seed with [`PicaBuilder::seed`].
+    pub fn measurement_noise(mut self, noise: MeasurementNoise, seed: Option<u64>) -> Self {
+        self.noise = noise;
+        self.seed = seed.or(self.seed);
+        self
+    }
+
+    /// Add gross errors to a fraction of the ranging measurements, to
+    /// exercise the outlier rejection of the host. The errors are drawn
+    /// from the same generator as the measurement errors.
+    pub fn measurement_outliers(mut self, outliers: MeasurementOutliers) -> Self {
+        self.outliers = outliers;
+        self
+    }
+
+    /// Seed of all the randomized behaviors: measurement errors,
+    /// shadowing, timer jitter and notification latency. The generator of
+    /// each device, session and anchor is derived from the seed, so that
+    /// scenarios are reproducible given the same sequence of commands.
+    /// A random seed is selected if not provided, and reported in the
+    /// [`crate::SimulatorInfo`].
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// Select the degradation of the measurements of the ranging rounds
+    /// overlapping the rounds of other sessions on the same channel. The
+    /// errors are drawn from the same generator as the measurement errors.
+    pub fn interference_model(mut self, interference: InterferenceModel) -> Self {
+        self.interference = interference;
+        self
+    }
+
+    /// Select the path loss model used to compute the signal strength
+    /// reported in the ranging measurements. The shadowing errors are drawn
+    /// from the same generator as the measurement errors.
+    pub fn rssi_model(mut self, rssi_model: RssiModel) -> Self {
+        self.rssi_model = rssi_model;
+        self
+    }
+
+    /// Select the model used to compute the figure of merit of the
+    /// angle of arrival results, from the geometry of the links.
+    pub fn fom_model(mut self, fom_model: FomModel) -> Self {
+        self.fom_model = fom_model;
+        self
+    }
+
+    /// Select the personalities exposed by the devices, e.g. ranging and
+    /// radar for multi-function chips. Devices keep their profile across
+    /// resets.
+    pub fn device_profile(mut self, device_profile: DeviceProfile) -> Self {
+        self.device_profile = device_profile;
+        self
+    }
+
+    /// Select the progression of the simulated time, by default
+    /// the wall-clock time. The mode can be changed at runtime with
+    /// [`crate::PicaCommand::SetTimeMode`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if the speed of a scaled mode is not strictly positive.
+    pub fn time_mode(mut self, time_mode: TimeMode) -> Self {
+        if let TimeMode::Scaled(speed) = time_mode {
+            assert!(
+                speed.is_finite() && speed > 0.0,
+                "invalid time speed {}",
+                speed
+            );
+        }
+        self.time_mode = time_mode;
+        self
+    }
+
+    /// Record the control commands to the selected log, to be replayed
+    /// with [`crate::CommandReplay`].
+    pub fn command_log(mut self, command_log: CommandLog) -> Self {
+        self.command_log = Some(command_log);
+        self
+    }
+
+    /// Record all the events and ranging measurements to the selected log.
+    #[cfg(feature = "sqlite")]
+    pub fn event_log(mut self, event_log: EventLog) -> Self {
+        self.event_log = Some(event_log);
+        self
+    }
+
+    pub fn build(self) -> Pica {
+        let (tx, rx) = mpsc::channel(
+            self.command_capacity
+                .unwrap_or(self.max_sessions * self.max_devices)
+                .max(1),
+        );
+        let (event_tx, event_capacity) = match self.event_tx {
+            Some(event_tx) => (event_tx, None),
+            None => {
+                let capacity = self.event_capacity.max(1);
+                (broadcast::channel(capacity).0, Some(capacity))
+            }
+        };
+        Pica {
+            devices: HashMap::new(),
+            device_handles: HashMap::new(),
+            anchors: HashMap::new(),
+            connections: HashMap::new(),
+            injected_packet_txs: HashMap::new(),
+            counter: 0,
+            rx,
+            tx,
+            event_tx,
+            event_capacity,
+            tap_tx: broadcast::channel(self.tap_capacity.max(1)).0,
+            sequence_number: 0,
+            event_history: VecDeque::with_capacity(EVENT_HISTORY_SIZE),
+            timeline: Timeline::new(self.time_mode),
+            max_devices: self.max_devices,
+            max_sessions: self.max_sessions,
+            max_anchors: self.max_anchors,
+            max_range: self.max_range,
+            pcapng_dir: self.pcapng_dir,
+            capture_config: self.capture_config,
+            trace_dir: self.trace_dir,
+            metrics: Metrics::default(),
+            statistics: HashMap::new(),
+            vendor_handlers: HashMap::new(),
+            command_hooks: Vec::new(),
+            position_solver: self.position_solver,
+            angle_convention: self.angle_convention,
+            noise: self.noise,
+            outliers: self.outliers,
+            interference: self.interference,
+            rssi_model: self.rssi_model,
+            fom_model: self.fom_model,
+            device_profile: self.device_profile,
+            scene: Scene::default(),
+            zones: Vec::new(),
+            occupied_zones: HashMap::new(),
+            motions: HashMap::new(),
+            seed: self.seed.unwrap_or_else(rand::random),
+            persistent_identity: self.persistent_identity,
+            retained_devices: HashMap::new(),
+            command_log: self.command_log,
+            #[cfg(feature = "sqlite")]
+            event_log: self.event_log,
+        }
+    }
+}
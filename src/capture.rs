@@ -0,0 +1,498 @@
+// Copyright 2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Captures of the UCI packets exchanged with the hosts, in the pcapng
+//! or classic pcap file format.
+
+use crate::trace;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tracing::info;
+
+/// Message type of the data packets.
+const MT_DATA: u8 = 0;
+/// Mask of the Packet Boundary Flag in the first octet of the header.
+const PBF_MASK: u8 = 0x10;
+
+/// Link type of the UCI packets.
+const LINKTYPE_FIRA_UCI: u16 = 293;
+
+/// Maximum length of the captured packets in the classic pcap format,
+/// which has no value for unlimited lengths.
+const PCAP_SNAPLEN: u32 = 0x40000;
+
+/// Kinds of packets saved to the capture files.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CapturedPackets {
+    #[default]
+    All,
+    /// Commands, responses and notifications only.
+    Control,
+    /// Data packets only.
+    Data,
+}
+
+impl Display for CapturedPackets {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            CapturedPackets::All => "all",
+            CapturedPackets::Control => "control",
+            CapturedPackets::Data => "data",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for CapturedPackets {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(CapturedPackets::All),
+            "control" => Ok(CapturedPackets::Control),
+            "data" => Ok(CapturedPackets::Data),
+            _ => Err(format!("Invalid captured packets: {}", s)),
+        }
+    }
+}
+
+/// Selection of the packets saved to the capture files, to keep the
+/// captures of long simulations manageable. All packets are captured
+/// by default.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CaptureFilter {
+    pub packets: CapturedPackets,
+    /// Groups of the captured control packets, all if empty.
+    pub gids: Vec<u8>,
+    /// Sessions of the captured packets, all if empty. The packets not
+    /// related to a session, e.g. the responses, are always captured.
+    pub session_ids: Vec<u32>,
+}
+
+impl CaptureFilter {
+    /// Return true if the first segment of a packet is captured.
+    fn matches(&self, segment: &[u8]) -> bool {
+        let Some(header) = segment.first() else {
+            return true;
+        };
+        let data = header >> 5 == MT_DATA;
+        let captured_kind = match self.packets {
+            CapturedPackets::All => true,
+            CapturedPackets::Control => !data,
+            CapturedPackets::Data => data,
+        };
+        captured_kind
+            && (data || self.gids.is_empty() || self.gids.contains(&(header & 0xf)))
+            && (self.session_ids.is_empty()
+                || trace::session_id(segment)
+                    .is_none_or(|session_id| self.session_ids.contains(&session_id)))
+    }
+}
+
+/// Format of the capture files.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CaptureFormat {
+    /// Classic pcap format, for the tools not supporting pcapng.
+    Pcap,
+    #[default]
+    Pcapng,
+}
+
+impl CaptureFormat {
+    /// Extension of the capture files.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            CaptureFormat::Pcap => "pcap",
+            CaptureFormat::Pcapng => "pcapng",
+        }
+    }
+}
+
+impl Display for CaptureFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.extension())
+    }
+}
+
+impl FromStr for CaptureFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pcap" => Ok(CaptureFormat::Pcap),
+            "pcapng" => Ok(CaptureFormat::Pcapng),
+            _ => Err(format!("Invalid capture format: {}", s)),
+        }
+    }
+}
+
+/// Limits after which the capture continues in a new file, so that
+/// long simulations produce captures of manageable size. The files are
+/// never rotated by default.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CaptureRotation {
+    /// Maximum size of the files (bytes).
+    pub max_size: Option<u64>,
+    /// Maximum duration covered by each file.
+    pub max_duration: Option<Duration>,
+}
+
+impl CaptureRotation {
+    fn is_enabled(&self) -> bool {
+        self.max_size.is_some() || self.max_duration.is_some()
+    }
+}
+
+/// Configuration of the capture files.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Config {
+    pub format: CaptureFormat,
+    pub filter: CaptureFilter,
+    pub rotation: CaptureRotation,
+    /// Force the file content to the storage device after each block.
+    pub sync: bool,
+}
+
+pub struct File {
+    /// Taken when the file is closed or dropped.
+    file: Option<tokio::fs::File>,
+    config: Config,
+    /// Directory and name of the files, without the extension and index.
+    dir: PathBuf,
+    name: String,
+    /// Index of the current file, when the files are rotated.
+    index: usize,
+    /// Size of the current file (bytes), and number of packets.
+    size: u64,
+    packets: usize,
+    /// Creation of the current file.
+    file_start: Instant,
+    /// Whether the packets being segmented in each direction are
+    /// captured, as decided from their first segment.
+    segmented: [Option<bool>; 2],
+    start_time: Instant,
+}
+
+pub enum Direction {
+    Rx,
+    Tx,
+}
+
+/// Header of the pcapng files: the Section Header Block, and the
+/// Interface Description Block used for all UCI records.
+fn pcapng_header() -> Vec<u8> {
+    let mut block = vec![];
+    block.extend(u32::to_le_bytes(0x0A0D0D0A)); // Block Type
+    block.extend(u32::to_le_bytes(28)); // Block Total Length
+    block.extend(u32::to_le_bytes(0x1A2B3C4D)); // Byte-Order Magic
+    block.extend(u16::to_le_bytes(1)); // Major Version
+    block.extend(u16::to_le_bytes(0)); // Minor Version
+    block.extend(u64::to_le_bytes(0xFFFFFFFFFFFFFFFF)); // Section Length (not specified)
+    block.extend(u32::to_le_bytes(28)); // Block Total Length
+
+    block.extend(u32::to_le_bytes(0x00000001)); // Block Type
+    block.extend(u32::to_le_bytes(20)); // Block Total Length
+    block.extend(u16::to_le_bytes(LINKTYPE_FIRA_UCI)); // LinkType
+    block.extend(u16::to_le_bytes(0)); // Reserved
+    block.extend(u32::to_le_bytes(0)); // SnapLen (no limit)
+    block.extend(u32::to_le_bytes(20)); // Block Total Length
+    block
+}
+
+/// Global header of the classic pcap files, with microsecond timestamps.
+fn pcap_header() -> Vec<u8> {
+    let mut header = vec![];
+    header.extend(u32::to_le_bytes(0xA1B2C3D4)); // Magic Number
+    header.extend(u16::to_le_bytes(2)); // Major Version
+    header.extend(u16::to_le_bytes(4)); // Minor Version
+    header.extend(i32::to_le_bytes(0)); // Time Zone Offset
+    header.extend(u32::to_le_bytes(0)); // Timestamp Accuracy
+    header.extend(u32::to_le_bytes(PCAP_SNAPLEN)); // SnapLen
+    header.extend(u32::to_le_bytes(LINKTYPE_FIRA_UCI as u32)); // LinkType
+    header
+}
+
+/// Enhanced Packet Block wrapping a packet captured at the selected
+/// timestamp (us).
+fn pcapng_record(packet: &[u8], timestamp: u128) -> Vec<u8> {
+    let packet_data_padding: usize = 4 - packet.len() % 4;
+    let block_total_length: u32 = packet.len() as u32 + packet_data_padding as u32 + 32;
+
+    let mut block = vec![];
+    block.extend(u32::to_le_bytes(0x00000006)); // Block Type
+    block.extend(u32::to_le_bytes(block_total_length));
+    block.extend(u32::to_le_bytes(0)); // Interface ID
+    block.extend(u32::to_le_bytes((timestamp >> 32) as u32)); // Timestamp (High)
+    block.extend(u32::to_le_bytes(timestamp as u32)); // Timestamp (Low)
+    block.extend(u32::to_le_bytes(packet.len() as u32)); // Captured Packet Length
+    block.extend(u32::to_le_bytes(packet.len() as u32)); // Original Packet Length
+    block.extend(packet);
+    block.extend(vec![0; packet_data_padding]);
+    block.extend(u32::to_le_bytes(block_total_length)); // Block Total Length
+    block
+}
+
+/// Record of a packet captured at the selected timestamp (us)
+/// in the classic pcap format.
+fn pcap_record(packet: &[u8], timestamp: u128) -> Vec<u8> {
+    let captured_length = packet.len().min(PCAP_SNAPLEN as usize);
+
+    let mut record = vec![];
+    record.extend(u32::to_le_bytes((timestamp / 1_000_000) as u32)); // Timestamp (Seconds)
+    record.extend(u32::to_le_bytes((timestamp % 1_000_000) as u32)); // Timestamp (Microseconds)
+    record.extend(u32::to_le_bytes(captured_length as u32)); // Captured Packet Length
+    record.extend(u32::to_le_bytes(packet.len() as u32)); // Original Packet Length
+    record.extend(&packet[..captured_length]);
+    record
+}
+
+impl File {
+    /// Create the capture file `{name}.{extension}` in the selected
+    /// directory, or `{name}-{index}.{extension}` when the files
+    /// are rotated.
+    pub async fn create(dir: &Path, name: String, config: Config) -> std::io::Result<File> {
+        let now = Instant::now();
+        let mut file = File {
+            file: None,
+            config,
+            dir: dir.to_path_buf(),
+            name,
+            index: 0,
+            size: 0,
+            packets: 0,
+            file_start: now,
+            segmented: [None; 2],
+            start_time: now,
+        };
+        file.open().await?;
+        Ok(file)
+    }
+
+    /// Open the current file and write its header.
+    async fn open(&mut self) -> std::io::Result<()> {
+        let extension = self.config.format.extension();
+        let path = if self.config.rotation.is_enabled() {
+            self.dir
+                .join(format!("{}-{}.{}", self.name, self.index, extension))
+        } else {
+            self.dir.join(format!("{}.{}", self.name, extension))
+        };
+        info!(
+            "Recording {} to file {}",
+            self.config.format,
+            path.display()
+        );
+        self.file = Some(tokio::fs::File::create(path).await?);
+        self.size = 0;
+        self.packets = 0;
+        self.file_start = Instant::now();
+
+        let header = match self.config.format {
+            CaptureFormat::Pcap => pcap_header(),
+            CaptureFormat::Pcapng => pcapng_header(),
+        };
+        self.write_block(&header).await
+    }
+
+    /// Continue the capture in the next file if the current file would
+    /// exceed its limits. Files hold at least one packet.
+    async fn rotate(&mut self, block_length: usize) -> std::io::Result<()> {
+        let rotation = self.config.rotation;
+        let full = rotation
+            .max_size
+            .is_some_and(|max_size| self.size + block_length as u64 > max_size);
+        let expired = rotation
+            .max_duration
+            .is_some_and(|max_duration| self.file_start.elapsed() >= max_duration);
+        if self.packets > 0 && (full || expired) {
+            if let Some(mut file) = self.file.take() {
+                file.flush().await?;
+                file.sync_all().await?;
+            }
+            self.index += 1;
+            self.open().await?;
+        }
+        Ok(())
+    }
+
+    /// Write a complete block to the file. Blocks are written in one go
+    /// and flushed immediately so that an abrupt termination of the
+    /// process never leaves a partially written block behind.
+    async fn write_block(&mut self, block: &[u8]) -> std::io::Result<()> {
+        // The file is only taken when closing or dropping `self`.
+        if let Some(file) = self.file.as_mut() {
+            file.write_all(block).await?;
+            file.flush().await?;
+            if self.config.sync {
+                file.sync_data().await?;
+            }
+            self.size += block.len() as u64;
+        }
+        Ok(())
+    }
+
+    pub async fn write(&mut self, packet: &[u8], dir: Direction) -> std::io::Result<()> {
+        // The segments following the first one are captured alike.
+        let segmented = &mut self.segmented[dir as usize];
+        let captured = segmented.unwrap_or_else(|| self.config.filter.matches(packet));
+        *segmented = packet
+            .first()
+            .is_some_and(|header| header & PBF_MASK != 0)
+            .then_some(captured);
+        if !captured {
+            return Ok(());
+        }
+
+        // The timestamps run on across the rotated files.
+        let timestamp = self.start_time.elapsed().as_micros();
+        let block = match self.config.format {
+            CaptureFormat::Pcap => pcap_record(packet, timestamp),
+            CaptureFormat::Pcapng => pcapng_record(packet, timestamp),
+        };
+        self.rotate(block.len()).await?;
+        self.packets += 1;
+        self.write_block(&block).await
+    }
+
+    /// Flush and close the file, waiting for the content to reach
+    /// the storage device.
+    pub async fn close(mut self) -> std::io::Result<()> {
+        if let Some(mut file) = self.file.take() {
+            file.flush().await?;
+            file.sync_all().await?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for File {
+    fn drop(&mut self) {
+        // The file was not closed explicitly, which happens when the owning
+        // task is aborted or panics. All blocks are already flushed, make a
+        // best effort attempt at syncing the file content to the disk,
+        // without blocking the runtime.
+        if let (Some(file), Ok(runtime)) = (self.file.take(), tokio::runtime::Handle::try_current())
+        {
+            if let Ok(file) = file.try_into_std() {
+                runtime.spawn_blocking(move || file.sync_all());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filter() {
+        let command = [0x21, 0x00, 0x00, 0x05, 0x01, 0x00, 0x00, 0x00, 0x00];
+        let response = [0x41, 0x00, 0x00, 0x01, 0x00];
+        let data = [0x01, 0x00, 0x04, 0x00, 0x02, 0x00, 0x00, 0x00];
+        let filter = CaptureFilter {
+            packets: CapturedPackets::Control,
+            gids: vec![0x1],
+            session_ids: vec![1],
+        };
+        assert!(filter.matches(&command));
+        assert!(filter.matches(&response));
+        assert!(!filter.matches(&data));
+        assert!(!filter.matches(&[0x20, 0x02, 0x00, 0x00]));
+        let filter = CaptureFilter {
+            session_ids: vec![2],
+            ..Default::default()
+        };
+        assert!(!filter.matches(&command));
+        assert!(filter.matches(&response));
+        assert!(filter.matches(&data));
+    }
+
+    #[test]
+    fn pcap_record() {
+        let record = super::pcap_record(&[0x20, 0x02, 0x00, 0x00], 2_000_005);
+        assert_eq!(
+            record,
+            [2, 0, 0, 0, 5, 0, 0, 0, 4, 0, 0, 0, 4, 0, 0, 0, 0x20, 0x02, 0x00, 0x00]
+        );
+        assert_eq!(pcap_header()[20..], [0x25, 0x01, 0x00, 0x00]);
+    }
+
+    #[tokio::test]
+    async fn rotation() {
+        let dir = std::env::temp_dir().join(format!("pica-capture-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let packet = [0x20, 0x02, 0x00, 0x00];
+        let config = Config {
+            format: CaptureFormat::Pcap,
+            rotation: CaptureRotation {
+                max_size: Some(24 + 2 * 20),
+                max_duration: None,
+            },
+            ..Default::default()
+        };
+        let mut file = File::create(&dir, "device-0".to_owned(), config)
+            .await
+            .unwrap();
+        for _ in 0..5 {
+            file.write(&packet, Direction::Tx).await.unwrap();
+        }
+        file.close().await.unwrap();
+        // Each file holds the header and at most two records.
+        let size = |index| {
+            std::fs::metadata(dir.join(format!("device-0-{}.pcap", index))).map(|m| m.len())
+        };
+        assert_eq!(size(0).unwrap(), 64);
+        assert_eq!(size(1).unwrap(), 64);
+        assert_eq!(size(2).unwrap(), 44);
+        assert!(size(3).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn close_and_drop() {
+        let dir = std::env::temp_dir().join(format!("pica-capture-close-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let packet = [0x20, 0x02, 0x00, 0x00];
+        let size = |name| {
+            std::fs::metadata(dir.join(format!("{}.pcap", name)))
+                .unwrap()
+                .len()
+        };
+        let config = Config {
+            format: CaptureFormat::Pcap,
+            ..Default::default()
+        };
+
+        // Closed files hold the header and all the records.
+        let mut file = File::create(&dir, "closed".to_owned(), config.clone())
+            .await
+            .unwrap();
+        file.write(&packet, Direction::Tx).await.unwrap();
+        file.write(&packet, Direction::Rx).await.unwrap();
+        file.close().await.unwrap();
+        assert_eq!(size("closed"), 24 + 2 * 20);
+
+        // Dropped files hold the blocks written until the drop.
+        let mut file = File::create(&dir, "dropped".to_owned(), config)
+            .await
+            .unwrap();
+        file.write(&packet, Direction::Tx).await.unwrap();
+        drop(file);
+        assert_eq!(size("dropped"), 24 + 20);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
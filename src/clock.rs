@@ -0,0 +1,116 @@
+// Copyright 2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Local clocks of the anchors and devices. The local time is offset
+//! from the simulation time and runs faster or slower by a constant
+//! drift rate; the timers of the ranging rounds are further delayed
+//! or advanced by a random jitter.
+
+use rand::rngs::StdRng;
+use rand::Rng;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Maximum drift rate of the local clocks, in parts per million.
+pub const MAX_DRIFT_PPM: f64 = 1000.0;
+
+/// Skew of a local clock. The default clock is exact.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Clock {
+    /// Local time at the reference instant (µs).
+    pub offset_us: u64,
+    /// Drift rate (ppm). The clock runs fast if positive.
+    pub drift_ppm: f64,
+    /// Maximum deviation of the ranging round timers (µs).
+    pub jitter_us: u32,
+}
+
+impl Clock {
+    fn rate(&self) -> f64 {
+        1.0 + self.drift_ppm.clamp(-MAX_DRIFT_PPM, MAX_DRIFT_PPM) / 1e6
+    }
+
+    /// Local time (µs) after the selected simulation time has elapsed
+    /// since the reference instant.
+    pub fn local_time_us(&self, elapsed: Duration) -> u64 {
+        self.offset_us
+            .saturating_add(self.local_duration(elapsed).as_micros() as u64)
+    }
+
+    /// Duration measured by the local clock for a simulation duration.
+    pub fn local_duration(&self, duration: Duration) -> Duration {
+        duration.mul_f64(self.rate())
+    }
+
+    /// Simulation duration of a duration measured by the local clock.
+    pub fn simulation_duration(&self, duration: Duration) -> Duration {
+        duration.div_f64(self.rate())
+    }
+
+    /// Instant a timer set to `offset` after `start` on the local clock
+    /// expires, including a jitter drawn uniformly from the generator.
+    pub fn deadline(&self, start: Instant, offset: Duration, rng: &mut StdRng) -> Instant {
+        let deadline = start + self.simulation_duration(offset);
+        if self.jitter_us == 0 {
+            return deadline;
+        }
+        let jitter = self.jitter_us as i64;
+        match rng.gen_range(-jitter..=jitter) {
+            jitter if jitter < 0 => deadline
+                .checked_sub(Duration::from_micros(jitter.unsigned_abs()))
+                .unwrap_or(deadline),
+            jitter => deadline + Duration::from_micros(jitter as u64),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+
+    #[test]
+    fn drift() {
+        let clock = Clock {
+            offset_us: 1_000,
+            drift_ppm: 100.0,
+            jitter_us: 0,
+        };
+        assert_eq!(clock.local_time_us(Duration::from_secs(10)), 10_002_000);
+        assert_eq!(
+            clock.simulation_duration(Duration::from_millis(200_020)),
+            Duration::from_millis(200_000)
+        );
+        assert_eq!(
+            Clock::default().local_time_us(Duration::from_millis(5)),
+            5_000
+        );
+    }
+
+    #[test]
+    fn jitter() {
+        let clock = Clock {
+            jitter_us: 500,
+            ..Default::default()
+        };
+        let mut rng = StdRng::seed_from_u64(0);
+        let start = Instant::now();
+        for _ in 0..100 {
+            let deadline = clock.deadline(start, Duration::from_millis(200), &mut rng);
+            let offset = deadline.duration_since(start);
+            assert!(offset >= Duration::from_micros(199_500));
+            assert!(offset <= Duration::from_micros(200_500));
+        }
+    }
+}
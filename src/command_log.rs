@@ -0,0 +1,446 @@
+// Copyright 2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Log of the control commands received by pica, one JSON object per
+//! line, replayed with the original timing to reproduce the scenarios
+//! of interactive sessions.
+//!
+//! The commands issued by the UCI hosts and the internal timers are not
+//! recorded: the hosts are expected to reconnect to the replayed
+//! simulation.
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{info, warn};
+
+use crate::timeline::Timeline;
+use crate::{
+    AoaCapability, CrashRecovery, MacAddress, Pica, PicaCommand, PicaCommandStatus, Position,
+    TimeMode,
+};
+
+/// Control command, as written to the log.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+enum LoggedCommand {
+    CreateAnchor {
+        mac_address: MacAddress,
+        #[serde(flatten)]
+        position: Position,
+    },
+    DestroyAnchor {
+        mac_address: MacAddress,
+    },
+    SetPosition {
+        mac_address: MacAddress,
+        #[serde(flatten)]
+        position: Position,
+    },
+    SetPositions {
+        positions: Vec<LoggedPosition>,
+    },
+    SetOrientation {
+        mac_address: MacAddress,
+        yaw: i16,
+        pitch: i8,
+        roll: i16,
+    },
+    SetAoaCapability {
+        mac_address: MacAddress,
+        aoa_capability: AoaCapability,
+    },
+    SetTxPower {
+        mac_address: MacAddress,
+        tx_power: Option<f32>,
+    },
+    InjectPacket {
+        mac_address: MacAddress,
+        /// Hexadecimal encoding of the packet.
+        packet: String,
+    },
+    InjectUciPacket {
+        device_handle: usize,
+        /// Hexadecimal encoding of the packet.
+        packet: String,
+    },
+    CrashDevice {
+        mac_address: MacAddress,
+        recovery: CrashRecovery,
+    },
+    StartAnchorRanging {
+        mac_address: MacAddress,
+        session_id: u32,
+        interval_ms: u64,
+    },
+    StopAnchorRanging {
+        mac_address: MacAddress,
+    },
+    SetSessionSeed {
+        mac_address: MacAddress,
+        session_id: u32,
+        seed: Option<u64>,
+    },
+    /// The simulated time is stepped when the speed is omitted.
+    SetTimeMode {
+        time_speed: Option<f64>,
+    },
+    AdvanceTime {
+        duration_us: u64,
+    },
+    /// Command whose arguments are not recorded, e.g. the replacement
+    /// of the scene. It is skipped when replaying the log.
+    Other {
+        name: String,
+    },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct LoggedPosition {
+    mac_address: MacAddress,
+    #[serde(flatten)]
+    position: Position,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    /// Simulated time elapsed since the start of pica (µs) when the
+    /// command was received.
+    time_us: u64,
+    #[serde(flatten)]
+    command: LoggedCommand,
+}
+
+impl LoggedCommand {
+    /// Select the arguments of a control command to be logged.
+    /// Return None for the commands issued by the hosts and timers.
+    fn new(command: &PicaCommand) -> Option<Self> {
+        use PicaCommand::*;
+        Some(match command {
+            CreateAnchor(mac_address, position, _) => LoggedCommand::CreateAnchor {
+                mac_address: *mac_address,
+                position: *position,
+            },
+            DestroyAnchor(mac_address, _) => LoggedCommand::DestroyAnchor {
+                mac_address: *mac_address,
+            },
+            SetPosition(mac_address, position, _) => LoggedCommand::SetPosition {
+                mac_address: *mac_address,
+                position: *position,
+            },
+            SetPositions(positions, _) => LoggedCommand::SetPositions {
+                positions: positions
+                    .iter()
+                    .map(|&(mac_address, position)| LoggedPosition {
+                        mac_address,
+                        position,
+                    })
+                    .collect(),
+            },
+            SetOrientation(mac_address, yaw, pitch, roll, _) => LoggedCommand::SetOrientation {
+                mac_address: *mac_address,
+                yaw: *yaw,
+                pitch: *pitch,
+                roll: *roll,
+            },
+            SetAoaCapability(mac_address, aoa_capability, _) => LoggedCommand::SetAoaCapability {
+                mac_address: *mac_address,
+                aoa_capability: *aoa_capability,
+            },
+            SetTxPower(mac_address, tx_power, _) => LoggedCommand::SetTxPower {
+                mac_address: *mac_address,
+                tx_power: *tx_power,
+            },
+            InjectPacket(mac_address, packet, _) => LoggedCommand::InjectPacket {
+                mac_address: *mac_address,
+                packet: hex::encode(packet),
+            },
+            InjectUciPacket(device_handle, packet, _) => LoggedCommand::InjectUciPacket {
+                device_handle: *device_handle,
+                packet: hex::encode(packet),
+            },
+            CrashDevice(mac_address, recovery, _) => LoggedCommand::CrashDevice {
+                mac_address: *mac_address,
+                recovery: *recovery,
+            },
+            StartAnchorRanging(mac_address, session_id, interval, _) => {
+                LoggedCommand::StartAnchorRanging {
+                    mac_address: *mac_address,
+                    session_id: *session_id,
+                    interval_ms: interval.as_millis() as u64,
+                }
+            }
+            StopAnchorRanging(mac_address, _) => LoggedCommand::StopAnchorRanging {
+                mac_address: *mac_address,
+            },
+            SetSessionSeed(mac_address, session_id, seed, _) => LoggedCommand::SetSessionSeed {
+                mac_address: *mac_address,
+                session_id: *session_id,
+                seed: *seed,
+            },
+            SetTimeMode(time_mode, _) => LoggedCommand::SetTimeMode {
+                time_speed: match time_mode {
+                    TimeMode::Scaled(speed) => Some(*speed),
+                    TimeMode::Stepped => None,
+                },
+            },
+            AdvanceTime(duration, _) => LoggedCommand::AdvanceTime {
+                duration_us: duration.as_micros() as u64,
+            },
+            InitUciDevice(..)
+            | SetFieldOfView(..)
+            | SetClock(..)
+            | SetNotificationLatency(..)
+            | SetResponseFaults(..)
+            | CreateConstellation(..)
+            | SetScene(..)
+            | SetZones(..)
+            | SetPath(..) => LoggedCommand::Other {
+                name: command.to_string(),
+            },
+            _ => return None,
+        })
+    }
+
+    /// Build the command replaying the logged command, or return None
+    /// if its arguments were not recorded.
+    fn into_command(
+        self,
+        status_tx: oneshot::Sender<PicaCommandStatus>,
+    ) -> Result<Option<PicaCommand>> {
+        Ok(Some(match self {
+            LoggedCommand::CreateAnchor {
+                mac_address,
+                position,
+            } => PicaCommand::CreateAnchor(mac_address, position, status_tx),
+            LoggedCommand::DestroyAnchor { mac_address } => {
+                PicaCommand::DestroyAnchor(mac_address, status_tx)
+            }
+            LoggedCommand::SetPosition {
+                mac_address,
+                position,
+            } => PicaCommand::SetPosition(mac_address, position, status_tx),
+            LoggedCommand::SetPositions { positions } => PicaCommand::SetPositions(
+                positions
+                    .into_iter()
+                    .map(|logged| (logged.mac_address, logged.position))
+                    .collect(),
+                status_tx,
+            ),
+            LoggedCommand::SetOrientation {
+                mac_address,
+                yaw,
+                pitch,
+                roll,
+            } => PicaCommand::SetOrientation(mac_address, yaw, pitch, roll, status_tx),
+            LoggedCommand::SetAoaCapability {
+                mac_address,
+                aoa_capability,
+            } => PicaCommand::SetAoaCapability(mac_address, aoa_capability, status_tx),
+            LoggedCommand::SetTxPower {
+                mac_address,
+                tx_power,
+            } => PicaCommand::SetTxPower(mac_address, tx_power, status_tx),
+            LoggedCommand::InjectPacket {
+                mac_address,
+                packet,
+            } => PicaCommand::InjectPacket(
+                mac_address,
+                Bytes::from(hex::decode(packet).context("Invalid packet")?),
+                status_tx,
+            ),
+            LoggedCommand::InjectUciPacket {
+                device_handle,
+                packet,
+            } => PicaCommand::InjectUciPacket(
+                device_handle,
+                Bytes::from(hex::decode(packet).context("Invalid packet")?),
+                status_tx,
+            ),
+            LoggedCommand::CrashDevice {
+                mac_address,
+                recovery,
+            } => PicaCommand::CrashDevice(mac_address, recovery, status_tx),
+            LoggedCommand::StartAnchorRanging {
+                mac_address,
+                session_id,
+                interval_ms,
+            } => PicaCommand::StartAnchorRanging(
+                mac_address,
+                session_id,
+                Duration::from_millis(interval_ms),
+                status_tx,
+            ),
+            LoggedCommand::StopAnchorRanging { mac_address } => {
+                PicaCommand::StopAnchorRanging(mac_address, status_tx)
+            }
+            LoggedCommand::SetSessionSeed {
+                mac_address,
+                session_id,
+                seed,
+            } => PicaCommand::SetSessionSeed(mac_address, session_id, seed, status_tx),
+            LoggedCommand::SetTimeMode { time_speed } => PicaCommand::SetTimeMode(
+                time_speed.map_or(TimeMode::Stepped, TimeMode::Scaled),
+                status_tx,
+            ),
+            LoggedCommand::AdvanceTime { duration_us } => {
+                PicaCommand::AdvanceTime(Duration::from_micros(duration_us), status_tx)
+            }
+            LoggedCommand::Other { name } => {
+                warn!("Command {} cannot be replayed, skipped", name);
+                return Ok(None);
+            }
+        }))
+    }
+}
+
+/// Log recording the control commands received by pica.
+pub struct CommandLog {
+    writer: BufWriter<File>,
+}
+
+impl CommandLog {
+    /// Create the log file, replacing the existing file if any.
+    pub fn create<P: AsRef<Path>>(path: P) -> std::io::Result<CommandLog> {
+        Ok(CommandLog {
+            writer: BufWriter::new(File::create(path)?),
+        })
+    }
+
+    /// Record the command if it is a control command. The entry is
+    /// flushed immediately, so that the log survives a crash.
+    pub(crate) fn record(&mut self, time: Duration, command: &PicaCommand) -> Result<()> {
+        let Some(command) = LoggedCommand::new(command) else {
+            return Ok(());
+        };
+        let entry = Entry {
+            time_us: time.as_micros() as u64,
+            command,
+        };
+        serde_json::to_writer(&mut self.writer, &entry)?;
+        self.writer.write_all(b"\n")?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Replay of a command log on a simulation.
+pub struct CommandReplay {
+    entries: Vec<Entry>,
+    tx: mpsc::Sender<PicaCommand>,
+    timeline: Timeline,
+}
+
+impl CommandReplay {
+    /// Read the command log to be replayed on the simulation.
+    pub fn open<P: AsRef<Path>>(pica: &Pica, path: P) -> Result<CommandReplay> {
+        let reader = BufReader::new(File::open(path)?);
+        let mut entries = vec![];
+        for (index, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            entries.push(
+                serde_json::from_str(&line)
+                    .with_context(|| format!("Invalid command log entry at line {}", index + 1))?,
+            );
+        }
+        Ok(CommandReplay {
+            entries,
+            tx: pica.tx(),
+            timeline: pica.timeline.clone(),
+        })
+    }
+
+    /// Execute the commands at their original time, relative to the
+    /// start of the replay. The replay continues when a command fails.
+    pub async fn run(self) -> Result<()> {
+        let start = self.timeline.now();
+        info!("Replaying {} commands", self.entries.len());
+        for entry in self.entries {
+            self.timeline
+                .sleep_until(start + Duration::from_micros(entry.time_us))
+                .await;
+            let (status_tx, status_rx) = oneshot::channel();
+            let Some(command) = entry.command.into_command(status_tx)? else {
+                continue;
+            };
+            let name = command.to_string();
+            self.tx
+                .send(command)
+                .await
+                .map_err(|_| anyhow!("The simulation is shut down"))?;
+            match status_rx.await {
+                Ok(Ok(())) => (),
+                Ok(Err(err)) => warn!("Replayed command {} failed: {}", name, err),
+                Err(_) => warn!("Replayed command {} got no response", name),
+            }
+        }
+        info!("Replay completed");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entry_encoding() {
+        let entry = Entry {
+            time_us: 1500,
+            command: LoggedCommand::CreateAnchor {
+                mac_address: MacAddress::Short([0x00, 0x01]),
+                position: Position::new(100, -50, 0, 90, 0, 0),
+            },
+        };
+        let line = serde_json::to_string(&entry).unwrap();
+        assert_eq!(
+            line,
+            r#"{"time_us":1500,"command":"create-anchor","mac_address":"00:01","x":100.0,"y":-50.0,"z":0.0,"yaw":90,"pitch":0,"roll":0}"#
+        );
+        let decoded: Entry = serde_json::from_str(&line).unwrap();
+        assert_eq!(decoded.time_us, 1500);
+        assert_eq!(
+            serde_json::to_string(&decoded).unwrap(),
+            line,
+            "decoded entry {:?}",
+            decoded
+        );
+
+        let entry = Entry {
+            time_us: 0,
+            command: LoggedCommand::SetPositions {
+                positions: vec![LoggedPosition {
+                    mac_address: MacAddress::Short([0x00, 0x02]),
+                    position: Position::new(0, 10, 0, 0, 0, 0),
+                }],
+            },
+        };
+        let line = serde_json::to_string(&entry).unwrap();
+        assert_eq!(
+            line,
+            r#"{"time_us":0,"command":"set-positions","positions":[{"mac_address":"00:02","x":0.0,"y":10.0,"z":0.0,"yaw":0,"pitch":0,"roll":0}]}"#
+        );
+        let decoded: Entry = serde_json::from_str(&line).unwrap();
+        assert_eq!(serde_json::to_string(&decoded).unwrap(), line);
+    }
+}
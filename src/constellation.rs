@@ -0,0 +1,143 @@
+// Copyright 2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Layouts of anchors created with a single command, as deployed
+//! in real time location systems.
+
+use serde::Deserialize;
+
+use crate::Position;
+
+/// Arrangement of the anchors in the horizontal (x, z) plane.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(tag = "shape", rename_all = "kebab-case")]
+pub enum Shape {
+    /// Anchors along the x axis, `spacing` cm apart.
+    Line { spacing: u16 },
+    /// Rows of `columns` anchors along the x axis, rows and columns
+    /// `spacing` cm apart.
+    Grid { columns: u16, spacing: u16 },
+    /// Anchors evenly spread on a circle of `radius` cm.
+    Circle { radius: u16 },
+}
+
+/// Anchors created in one command, with consecutive MAC addresses.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Constellation {
+    pub shape: Shape,
+    /// Number of anchors.
+    pub count: u16,
+    /// First anchor of lines and grids, or center of circles,
+    /// in the horizontal plane (cm).
+    pub origin: (i16, i16),
+    /// Height of the anchors, along the y axis (cm).
+    pub height: i16,
+}
+
+impl Constellation {
+    /// Positions of the anchors, or None if an anchor falls outside
+    /// of the range of the coordinates.
+    pub fn positions(&self) -> Option<Vec<Position>> {
+        let (x0, z0) = (self.origin.0 as f32, self.origin.1 as f32);
+        (0..self.count)
+            .map(|index| {
+                let index = index as f32;
+                let (x, z) = match self.shape {
+                    Shape::Line { spacing } => (x0 + index * spacing as f32, z0),
+                    Shape::Grid { columns, spacing } => {
+                        let columns = columns.max(1) as f32;
+                        (
+                            x0 + (index % columns) * spacing as f32,
+                            z0 + (index / columns).floor() * spacing as f32,
+                        )
+                    }
+                    Shape::Circle { radius } => {
+                        let angle = std::f32::consts::TAU * index / self.count as f32;
+                        (
+                            x0 + radius as f32 * angle.sin(),
+                            z0 + radius as f32 * angle.cos(),
+                        )
+                    }
+                };
+                let coordinate = |value: f32| {
+                    let value = value.round();
+                    (i16::MIN as f32..=i16::MAX as f32)
+                        .contains(&value)
+                        .then_some(value as i16)
+                };
+                Some(Position::new(
+                    coordinate(x)?,
+                    self.height,
+                    coordinate(z)?,
+                    0,
+                    0,
+                    0,
+                ))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn coordinates(constellation: Constellation) -> Option<Vec<String>> {
+        constellation.positions().map(|positions| {
+            positions
+                .iter()
+                .map(|position| position.to_string())
+                .collect()
+        })
+    }
+
+    #[test]
+    fn shapes() {
+        let grid = Constellation {
+            shape: Shape::Grid {
+                columns: 2,
+                spacing: 100,
+            },
+            count: 3,
+            origin: (10, 20),
+            height: 250,
+        };
+        assert_eq!(
+            coordinates(grid).unwrap(),
+            [
+                Position::new(10, 250, 20, 0, 0, 0).to_string(),
+                Position::new(110, 250, 20, 0, 0, 0).to_string(),
+                Position::new(10, 250, 120, 0, 0, 0).to_string(),
+            ]
+        );
+        let circle = Constellation {
+            shape: Shape::Circle { radius: 100 },
+            count: 4,
+            origin: (0, 0),
+            height: 0,
+        };
+        assert_eq!(
+            coordinates(circle).unwrap()[1],
+            Position::new(100, 0, 0, 0, 0, 0).to_string()
+        );
+        let line = Constellation {
+            shape: Shape::Line { spacing: 1000 },
+            count: 40,
+            origin: (0, 0),
+            height: 0,
+        };
+        assert_eq!(coordinates(line), None);
+    }
+}
@@ -12,8 +12,16 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::clock::Clock;
+use crate::fault::{ResponseAction, ResponseFault, ResponseFaults};
+use crate::latency::NotificationLatency;
 use crate::packets::uci::*;
-use crate::position::Position;
+use crate::position::{AoaCapability, FieldOfView, Position};
+use crate::power::PowerStatistics;
+use crate::profile::{DeviceProfile, Personality};
+use crate::regulatory;
+use crate::rf_test::RfTest;
+use crate::timeline::Timeline;
 use crate::MacAddress;
 use crate::PicaCommand;
 
@@ -21,16 +29,17 @@ use std::collections::HashMap;
 use std::iter::Extend;
 use std::time::Duration;
 
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, watch};
 use tokio::time;
+use tracing::{info, info_span, warn, Instrument, Span};
 
-use super::session::{Session, MAX_SESSION};
+use super::session::{HybridPhase, Session};
 
 pub const MAX_DEVICE: usize = 4;
-const UCI_VERSION: u16 = 0x0002; // Version 2.0
-const MAC_VERSION: u16 = 0x3001; // Version 1.3.0
-const PHY_VERSION: u16 = 0x3001; // Version 1.3.0
-const TEST_VERSION: u16 = 0x1001; // Version 1.1
+pub(crate) const UCI_VERSION: u16 = 0x0002; // Version 2.0
+pub(crate) const MAC_VERSION: u16 = 0x3001; // Version 1.3.0
+pub(crate) const PHY_VERSION: u16 = 0x3001; // Version 1.3.0
+pub(crate) const TEST_VERSION: u16 = 0x1001; // Version 1.1
 
 // Capabilities are vendor defined
 // Android compliant: FIRA-287 UCI_Generic_Specification controlee capabilities_r4
@@ -71,19 +80,63 @@ pub const DEFAULT_CAPS_INFO: &[(CapTlvType, &[u8])] = &[
     ),
 ];
 
+/// Address assigned to a device when it connects, before it is
+/// initialized with the address of its choice.
+fn handle_mac_address(device_handle: usize) -> MacAddress {
+    MacAddress::Short((device_handle as u16).to_be_bytes())
+}
+
+/// Span of the logs related to the selected device.
+pub(crate) fn device_span(device_handle: usize) -> Span {
+    info_span!("device", handle = device_handle)
+}
+
 pub struct Device {
     handle: usize,
     pub mac_address: MacAddress,
     pub position: Position,
+    /// Field of view of the angle of arrival measurements.
+    pub field_of_view: FieldOfView,
+    /// Angles of arrival measured by the antennas of the device.
+    pub aoa_capability: AoaCapability,
+    /// Mean EIRP of the transmitted frames (dBm/MHz), or None to transmit
+    /// at the limit of the country code. Kept across resets.
+    pub tx_power: Option<f32>,
+    /// Endpoint the device is connected through, kept across resets.
+    pub origin: Option<String>,
+    /// Skew of the local clock, kept across resets.
+    clock: Clock,
+    /// Reference instant of the UWBS timestamps.
+    clock_start: time::Instant,
+    timeline: Timeline,
+    /// Seed of the simulation, from which the session generators
+    /// are derived.
+    seed: u64,
+    /// Latency of the notifications sent to the host, applied by the
+    /// connection task and kept across resets.
+    notification_latency: watch::Sender<NotificationLatency>,
+    /// Faults injected in the responses to the next commands,
+    /// kept across resets.
+    response_faults: ResponseFaults,
     /// [UCI] 5. UWBS Device State Machine
     state: DeviceState,
+    /// The device is suspended in low power mode until
+    /// the next command.
+    suspended: bool,
+    /// Personalities hosted behind the UCI transport.
+    profile: DeviceProfile,
+    /// Maximum number of sessions.
+    max_sessions: usize,
     sessions: HashMap<u32, Session>,
     pub tx: mpsc::Sender<ControlPacket>,
     pica_tx: mpsc::Sender<PicaCommand>,
     config: HashMap<DeviceConfigId, Vec<u8>>,
     country_code: [u8; 2],
-
-    pub n_active_sessions: usize,
+    power_statistics: PowerStatistics,
+    rf_test: RfTest,
+    /// Number of active sessions of each personality. The device is active
+    /// as long as one of the personalities has an active session.
+    active_sessions: HashMap<Personality, usize>,
 }
 
 impl Device {
@@ -91,22 +144,39 @@ impl Device {
         device_handle: usize,
         tx: mpsc::Sender<ControlPacket>,
         pica_tx: mpsc::Sender<PicaCommand>,
+        profile: DeviceProfile,
+        max_sessions: usize,
+        timeline: Timeline,
+        seed: u64,
     ) -> Self {
-        let mac_address = {
-            let handle = device_handle as u16;
-            MacAddress::Short(handle.to_be_bytes())
-        };
+        let mac_address = handle_mac_address(device_handle);
         Device {
             handle: device_handle,
             mac_address,
             position: Position::default(),
+            field_of_view: FieldOfView::default(),
+            aoa_capability: AoaCapability::default(),
+            tx_power: None,
+            origin: None,
+            clock: Clock::default(),
+            clock_start: timeline.now(),
+            notification_latency: watch::channel(NotificationLatency::default()).0,
+            response_faults: ResponseFaults::default(),
             state: DeviceState::DeviceStateError, // Will be overwitten
+            suspended: false,
+            profile,
+            max_sessions,
             sessions: Default::default(),
-            tx,
+            tx: tx.clone(),
             pica_tx,
-            config: HashMap::new(),
+            // The low power mode is enabled by default.
+            config: HashMap::from([(DeviceConfigId::LowPowerMode, vec![1])]),
             country_code: Default::default(),
-            n_active_sessions: 0,
+            power_statistics: PowerStatistics::new(timeline.now()),
+            rf_test: RfTest::new(tx, timeline.clone()),
+            timeline,
+            seed,
+            active_sessions: HashMap::new(),
         }
     }
 
@@ -116,21 +186,131 @@ impl Device {
             return;
         }
 
+        if device_state == DeviceState::DeviceStateActive {
+            self.power_statistics.wake();
+        }
+
         // Send status notification
         self.state = device_state;
         let tx = self.tx.clone();
-        tokio::spawn(async move {
-            time::sleep(Duration::from_millis(5)).await;
-            tx.send(DeviceStatusNtfBuilder { device_state }.build().into())
-                .await
-                .unwrap()
-        });
+        tokio::spawn(
+            async move {
+                time::sleep(Duration::from_millis(5)).await;
+                tx.send(DeviceStatusNtfBuilder { device_state }.build().into())
+                    .await
+                    .unwrap_or_else(|err| {
+                        warn!("Failed to send device status notification: {}", err)
+                    })
+            }
+            .in_current_span(),
+        );
     }
 
     pub fn init(&mut self) {
         self.set_state(DeviceState::DeviceStateReady);
     }
 
+    fn low_power_mode(&self) -> bool {
+        self.config
+            .get(&DeviceConfigId::LowPowerMode)
+            .and_then(|value| value.first())
+            != Some(&0)
+    }
+
+    /// Wake the device up from suspension. The device reports that it
+    /// is ready again before answering the command waking it up.
+    fn resume(&mut self) {
+        info!("Resume");
+        self.suspended = false;
+        self.power_statistics.wake();
+        self.tx
+            .try_send(
+                DeviceStatusNtfBuilder {
+                    device_state: self.state,
+                }
+                .build()
+                .into(),
+            )
+            .unwrap_or_else(|err| warn!("Failed to send device status notification: {}", err));
+    }
+
+    /// Account for a session of the selected personality entering the
+    /// active state.
+    pub fn session_started(&mut self, personality: Personality) {
+        *self.active_sessions.entry(personality).or_default() += 1;
+        self.set_state(DeviceState::DeviceStateActive);
+    }
+
+    /// Account for a session of the selected personality leaving the
+    /// active state. The device returns to the ready state when no
+    /// personality has active sessions left.
+    pub fn session_stopped(&mut self, personality: Personality) {
+        if let Some(count) = self.active_sessions.get_mut(&personality) {
+            *count = count.saturating_sub(1);
+        }
+        if self.active_sessions.values().all(|count| *count == 0) {
+            self.set_state(DeviceState::DeviceStateReady);
+        }
+    }
+
+    /// Maximum range (cm) at the configured TX power, capped to the
+    /// limit of the configured country code.
+    pub fn max_range(&self) -> u16 {
+        regulatory::max_range_at_tx_power(self.country_code, self.tx_power)
+    }
+
+    pub fn handle(&self) -> usize {
+        self.handle
+    }
+
+    /// Account for the radio time of a ranging round initiated
+    /// by this device with `peers` responders.
+    pub fn record_ranging_round(&mut self, peers: usize) {
+        self.power_statistics.record_ranging_round(peers)
+    }
+
+    /// Receiver of the notification latency, updated when replaced.
+    pub fn notification_latency(&self) -> watch::Receiver<NotificationLatency> {
+        self.notification_latency.subscribe()
+    }
+
+    pub fn set_notification_latency(&mut self, latency: NotificationLatency) {
+        self.notification_latency.send_replace(latency);
+    }
+
+    pub fn set_response_faults(&mut self, faults: Vec<ResponseFault>) {
+        self.response_faults = ResponseFaults::new(faults);
+    }
+
+    /// Account for a command received from the host, and return the
+    /// alteration of its response if any.
+    pub fn response_fault(&mut self, gid: u8, opcode: u8) -> Option<ResponseAction> {
+        self.response_faults.next(gid, opcode)
+    }
+
+    pub fn clock(&self) -> Clock {
+        self.clock
+    }
+
+    /// Return true if the device still uses the address assigned
+    /// when it connected.
+    pub fn has_handle_mac_address(&self) -> bool {
+        self.mac_address == handle_mac_address(self.handle)
+    }
+
+    /// Replace the skew of the local clock. The timers of the active
+    /// sessions are rescheduled from the next ranging round.
+    pub fn set_clock(&mut self, clock: Clock) {
+        self.clock = clock;
+        for session in self.sessions.values_mut() {
+            session.set_clock(clock);
+        }
+    }
+
+    pub fn sessions(&self) -> impl Iterator<Item = &Session> {
+        self.sessions.values()
+    }
+
     pub fn get_session(&self, session_id: u32) -> Option<&Session> {
         self.sessions.get(&session_id)
     }
@@ -143,24 +323,93 @@ impl Device {
     // send a notification once the reset is done
     fn command_device_reset(&mut self, cmd: DeviceResetCmd) -> DeviceResetRsp {
         let reset_config = cmd.get_reset_config();
-        println!("[{}] DeviceReset", self.handle);
-        println!("  reset_config={:?}", reset_config);
+        info!(?reset_config, "DeviceReset");
 
         let status = match reset_config {
             ResetConfig::UwbsReset => StatusCode::UciStatusOk,
         };
-        *self = Device::new(self.handle, self.tx.clone(), self.pica_tx.clone());
+        // The sessions, configuration and pending data are cleared. The
+        // host is only notified of the device state, after the response.
+        for session in self.sessions.values_mut() {
+            session.deinit_on_reset();
+        }
+        let device = Device::new(
+            self.handle,
+            self.tx.clone(),
+            self.pica_tx.clone(),
+            self.profile.clone(),
+            self.max_sessions,
+            self.timeline.clone(),
+            self.seed,
+        );
+        let previous = std::mem::replace(self, device);
+        self.mac_address = previous.mac_address;
+        self.position = previous.position;
+        self.field_of_view = previous.field_of_view;
+        self.aoa_capability = previous.aoa_capability;
+        self.tx_power = previous.tx_power;
+        self.origin = previous.origin;
+        self.clock = previous.clock;
+        self.clock_start = previous.clock_start;
+        self.notification_latency = previous.notification_latency;
+        self.response_faults = previous.response_faults;
         self.init();
 
         DeviceResetRspBuilder { status }.build()
     }
 
+    /// Simulate a firmware crash: the sessions are lost without
+    /// notification, and the device reports the error state.
+    pub fn crash(&mut self) {
+        info!("Crash");
+        for session in self.sessions.values_mut() {
+            session.deinit_on_reset();
+        }
+        self.sessions.clear();
+        self.suspended = false;
+        self.set_state(DeviceState::DeviceStateError);
+    }
+
+    /// Suspend the device until the next command, if the low power mode
+    /// is enabled and no session is active. UCI defines no device state
+    /// for the suspension, which is only acknowledged by the response.
+    fn command_device_suspend(&mut self, _cmd: DeviceSuspendCmd) -> DeviceSuspendRsp {
+        info!("DeviceSuspend");
+        let status = match self.state {
+            DeviceState::DeviceStateActive => StatusCode::UciStatusActiveSessionsOngoing,
+            DeviceState::DeviceStateReady if self.low_power_mode() => {
+                self.suspended = true;
+                StatusCode::UciStatusOk
+            }
+            _ => StatusCode::UciStatusRejected,
+        };
+        DeviceSuspendRspBuilder { status }.build()
+    }
+
+    fn command_query_timestamp(&self, _cmd: CoreQueryTimeStampCmd) -> CoreQueryTimeStampRsp {
+        info!("QueryUwbsTimestamp");
+        CoreQueryTimeStampRspBuilder {
+            status: StatusCode::UciStatusOk,
+            timeStamp: self.uwbs_time_us(),
+        }
+        .build()
+    }
+
+    /// Current time of the local clock of the device (µs).
+    pub fn uwbs_time_us(&self) -> u64 {
+        self.clock
+            .local_time_us(self.timeline.now() - self.clock_start)
+    }
+
     fn command_get_device_info(&self, _cmd: GetDeviceInfoCmd) -> GetDeviceInfoRsp {
-        // TODO: Implement a fancy build time state machine instead of crash at runtime
-        println!("[{}] GetDeviceInfo", self.handle);
-        assert_eq!(self.state, DeviceState::DeviceStateReady);
+        info!("GetDeviceInfo");
+        let status = if self.state == DeviceState::DeviceStateReady {
+            StatusCode::UciStatusOk
+        } else {
+            StatusCode::UciStatusRejected
+        };
         GetDeviceInfoRspBuilder {
-            status: StatusCode::UciStatusOk,
+            status,
             uci_version: UCI_VERSION,
             mac_version: MAC_VERSION,
             phy_version: PHY_VERSION,
@@ -171,13 +420,25 @@ impl Device {
     }
 
     pub fn command_get_caps_info(&self, _cmd: GetCapsInfoCmd) -> GetCapsInfoRsp {
-        println!("[{}] GetCapsInfo", self.handle);
+        info!("GetCapsInfo");
 
+        // The angle of arrival capabilities follow the antennas.
         let caps = DEFAULT_CAPS_INFO
             .iter()
             .map(|(id, value)| CapTlv {
                 t: *id,
-                v: (*value).into(),
+                v: match (id, self.aoa_capability) {
+                    (
+                        CapTlvType::SupportedAoa
+                        | CapTlvType::SupportedAoaResultReqAntennaInterleaving,
+                        AoaCapability::None,
+                    ) => vec![0],
+                    // Clear the elevation bit.
+                    (CapTlvType::SupportedAoa, AoaCapability::Azimuth) => {
+                        vec![value[0] & !0x04]
+                    }
+                    _ => (*value).into(),
+                },
             })
             .collect();
 
@@ -189,8 +450,15 @@ impl Device {
     }
 
     pub fn command_set_config(&mut self, cmd: SetConfigCmd) -> SetConfigRsp {
-        println!("[{}] SetConfig", self.handle);
-        assert_eq!(self.state, DeviceState::DeviceStateReady); // UCI 6.3
+        info!("SetConfig");
+        if self.state != DeviceState::DeviceStateReady {
+            // UCI 6.3
+            return SetConfigRspBuilder {
+                cfg_status: Vec::new(),
+                status: StatusCode::UciStatusRejected,
+            }
+            .build();
+        }
 
         let (valid_parameters, invalid_config_status) = cmd.get_tlvs().iter().fold(
             (HashMap::new(), Vec::new()),
@@ -217,7 +485,7 @@ impl Device {
     }
 
     pub fn command_get_config(&self, cmd: GetConfigCmd) -> GetConfigRsp {
-        println!("[{}] GetConfig", self.handle);
+        info!("GetConfig");
 
         // TODO: do this config shall be set on device reset
         let ids = cmd.get_cfg_id();
@@ -240,7 +508,7 @@ impl Device {
                             v: Vec::new(),
                         }),
                     },
-                    Err(_) => println!("Failed to parse config id: {:?}", id),
+                    Err(_) => warn!("Failed to parse config id: {:?}", id),
                 }
 
                 (valid_parameters, invalid_parameters)
@@ -264,11 +532,18 @@ impl Device {
         let session_id = cmd.get_session_id();
         let session_type = cmd.get_session_type();
 
-        println!("[{}] Session init", self.handle);
-        println!("  session_id=0x{:x}", session_id);
-        println!("  session_type={:?}", session_type);
+        info!(
+            session_id = format_args!("0x{:x}", session_id),
+            ?session_type,
+            "Session init"
+        );
 
-        let status = if self.sessions.len() >= MAX_SESSION {
+        let status = if !self
+            .profile
+            .supports(Personality::of_session_type(session_type))
+        {
+            StatusCode::UciStatusInvalidParam
+        } else if self.sessions.len() >= self.max_sessions {
             StatusCode::UciStatusMaxSessionsExceeded
         } else {
             match self.sessions.insert(
@@ -279,12 +554,17 @@ impl Device {
                     self.handle,
                     self.tx.clone(),
                     self.pica_tx.clone(),
+                    self.timeline.clone(),
+                    self.seed,
                 ),
             ) {
                 Some(_) => StatusCode::UciStatusSessionDuplicate,
                 None => {
                     // Should not fail
-                    self.get_session_mut(session_id).unwrap().init();
+                    let clock = self.clock;
+                    let session = self.get_session_mut(session_id).unwrap();
+                    session.set_clock(clock);
+                    session.init();
                     StatusCode::UciStatusOk
                 }
             }
@@ -295,18 +575,22 @@ impl Device {
 
     fn command_session_deinit(&mut self, cmd: SessionDeinitCmd) -> SessionDeinitRsp {
         let session_id = cmd.get_session_token();
-        println!("[{}] Session deinit", self.handle);
-        println!("  session_id=0x{:x}", session_id);
+        info!(
+            session_id = format_args!("0x{:x}", session_id),
+            "Session deinit"
+        );
 
         let status = match self.sessions.get_mut(&session_id) {
             Some(session) => {
                 if session.state == SessionState::SessionStateActive {
-                    self.n_active_sessions -= 1;
-                    if self.n_active_sessions == 0 {
-                        self.set_state(DeviceState::DeviceStateReady);
-                    }
+                    let personality = Personality::of_session_type(session.session_type());
+                    self.session_stopped(personality);
                 }
                 self.sessions.remove(&session_id);
+                self.rf_test.remove_session(session_id);
+                for session in self.sessions.values_mut() {
+                    session.remove_hybrid_phase(session_id);
+                }
                 StatusCode::UciStatusOk
             }
             None => StatusCode::UciStatusSessionNotExist,
@@ -314,8 +598,110 @@ impl Device {
         SessionDeinitRspBuilder { status }.build()
     }
 
+    fn command_session_set_hybrid_controller_config(
+        &mut self,
+        cmd: SessionSetHybridConfigCmd,
+    ) -> SessionSetHybridConfigRsp {
+        let session_id = cmd.get_session_token();
+        info!(
+            session_id = format_args!("0x{:x}", session_id),
+            "Session set hybrid controller config"
+        );
+
+        let phases: Vec<_> = cmd
+            .get_phase_list()
+            .iter()
+            .map(|phase| HybridPhase {
+                session_id: phase.session_token,
+                slots: Some((phase.start_slot_index, phase.end_slot_index)),
+            })
+            .collect();
+        let status = if cmd.get_number_of_phases() as usize != phases.len() {
+            StatusCode::UciStatusInvalidParam
+        } else {
+            self.set_hybrid_phases(session_id, false, phases)
+        };
+        SessionSetHybridConfigRspBuilder { status }.build()
+    }
+
+    fn command_session_set_hybrid_controlee_config(
+        &mut self,
+        cmd: SessionSetHybridControleeConfigCmd,
+    ) -> SessionSetHybridControleeConfigRsp {
+        let session_id = cmd.get_session_token();
+        info!(
+            session_id = format_args!("0x{:x}", session_id),
+            "Session set hybrid controlee config"
+        );
+
+        let phases = cmd
+            .get_phase_list()
+            .iter()
+            .map(|phase| HybridPhase {
+                session_id: phase.session_token,
+                slots: None,
+            })
+            .collect();
+        let status = self.set_hybrid_phases(session_id, true, phases);
+        SessionSetHybridControleeConfigRspBuilder { status }.build()
+    }
+
+    /// Validate and record the phase list of a HUS primary session,
+    /// configured with the role of the device in the session.
+    /// The phases are scheduled in order, in disjoint ranges of slots
+    /// of the primary ranging rounds.
+    fn set_hybrid_phases(
+        &mut self,
+        session_id: u32,
+        controlee: bool,
+        phases: Vec<HybridPhase>,
+    ) -> StatusCode {
+        let Some(session) = self.sessions.get(&session_id) else {
+            return StatusCode::UciStatusSessionNotExist;
+        };
+        if !session.is_hybrid_primary()
+            || session.is_controlee() != controlee
+            || !matches!(
+                session.state,
+                SessionState::SessionStateIdle | SessionState::SessionStateActive
+            )
+        {
+            return StatusCode::UciStatusRejected;
+        }
+
+        let slots_per_rr = session.slots_per_rr() as u16;
+        let mut next_slot = 0;
+        for (index, phase) in phases.iter().enumerate() {
+            match self.sessions.get(&phase.session_id) {
+                None => return StatusCode::UciStatusSessionNotExist,
+                Some(phase_session) if !phase_session.is_hybrid_phase() => {
+                    return StatusCode::UciStatusInvalidParam
+                }
+                _ => (),
+            }
+            if phases[..index]
+                .iter()
+                .any(|other| other.session_id == phase.session_id)
+            {
+                return StatusCode::UciStatusInvalidParam;
+            }
+            if let Some((start, end)) = phase.slots {
+                if start < next_slot || end < start || end >= slots_per_rr {
+                    return StatusCode::UciStatusInvalidParam;
+                }
+                next_slot = end + 1;
+            }
+        }
+
+        // Should not fail
+        self.get_session_mut(session_id)
+            .unwrap()
+            .set_hybrid_phases(phases);
+        StatusCode::UciStatusOk
+    }
+
     fn command_session_get_count(&self, _cmd: SessionGetCountCmd) -> SessionGetCountRsp {
-        println!("[{}] Session get count", self.handle);
+        info!("Session get count");
 
         SessionGetCountRspBuilder {
             status: StatusCode::UciStatusOk,
@@ -329,12 +715,38 @@ impl Device {
         cmd: AndroidSetCountryCodeCmd,
     ) -> AndroidSetCountryCodeRsp {
         let country_code = *cmd.get_country_code();
-        println!("[{}] Set country code", self.handle);
-        println!("  country_code={},{}", country_code[0], country_code[1]);
-
         self.country_code = country_code;
+        info!(
+            ?country_code,
+            max_range_cm = self.max_range(),
+            "Set country code"
+        );
+
+        // Stop the active sessions on the channels prohibited
+        // in the new country.
+        let mut stopped_personalities = Vec::new();
+        for session in self.sessions.values_mut() {
+            if session.session_state() == SessionState::SessionStateActive
+                && !regulatory::is_channel_allowed(country_code, session.channel_number())
+            {
+                session.stop_ranging_task();
+                session.set_state(
+                    SessionState::SessionStateIdle,
+                    ReasonCode::StateChangeWithSessionManagementCommands,
+                );
+                stopped_personalities.push(Personality::of_session_type(session.session_type()));
+            }
+        }
+        for personality in stopped_personalities {
+            self.session_stopped(personality);
+        }
+
         AndroidSetCountryCodeRspBuilder {
-            status: StatusCode::UciStatusOk,
+            status: if regulatory::is_uwb_off(country_code) {
+                StatusCode::UciStatusRegulationUwbOff
+            } else {
+                StatusCode::UciStatusOk
+            },
         }
         .build()
     }
@@ -343,49 +755,131 @@ impl Device {
         &mut self,
         _cmd: AndroidGetPowerStatsCmd,
     ) -> AndroidGetPowerStatsRsp {
-        println!("[{}] Get power stats", self.handle);
-
-        // TODO
-        AndroidGetPowerStatsRspBuilder {
-            stats: PowerStats {
-                status: StatusCode::UciStatusOk,
-                idle_time_ms: 0,
-                tx_time_ms: 0,
-                rx_time_ms: 0,
-                total_wake_count: 0,
+        let stats = PowerStats {
+            status: StatusCode::UciStatusOk,
+            idle_time_ms: self.power_statistics.idle_time_ms(self.timeline.now()),
+            tx_time_ms: self.power_statistics.tx_time_ms(),
+            rx_time_ms: self.power_statistics.rx_time_ms(),
+            total_wake_count: self.power_statistics.wake_count(),
+        };
+        info!(
+            idle_time_ms = stats.idle_time_ms,
+            tx_time_ms = stats.tx_time_ms,
+            rx_time_ms = stats.rx_time_ms,
+            wake_count = stats.total_wake_count,
+            "Get power stats"
+        );
+        AndroidGetPowerStatsRspBuilder { stats }.build()
+    }
+
+    /// Return the identifier of the session opened in device test mode,
+    /// used by the RF test commands.
+    fn test_session_id(&self) -> Option<u32> {
+        self.sessions
+            .iter()
+            .find(|(_, session)| session.session_type() == SessionType::DeviceTestMode)
+            .map(|(session_id, _)| *session_id)
+    }
+
+    fn command_test(&mut self, test_command: TestCommand) -> UciResponse {
+        match test_command.specialize() {
+            TestCommandChild::TestConfigSetCmd(cmd) => {
+                let session_id = cmd.get_session_token();
+                if self.sessions.contains_key(&session_id) {
+                    self.rf_test.set_config(session_id, cmd.get_tlvs()).into()
+                } else {
+                    TestConfigSetRspBuilder {
+                        status: StatusCode::UciStatusSessionNotExist,
+                        cfg_status: Vec::new(),
+                    }
+                    .build()
+                    .into()
+                }
+            }
+            TestCommandChild::TestConfigGetCmd(cmd) => {
+                let session_id = cmd.get_session_token();
+                if self.sessions.contains_key(&session_id) {
+                    self.rf_test
+                        .get_config(session_id, cmd.get_test_cfg())
+                        .into()
+                } else {
+                    TestConfigGetRspBuilder {
+                        status: StatusCode::UciStatusSessionNotExist,
+                        tlvs: Vec::new(),
+                    }
+                    .build()
+                    .into()
+                }
+            }
+            TestCommandChild::TestPeriodicTxCmd(_) => match self.test_session_id() {
+                Some(session_id) => self.rf_test.periodic_tx(session_id).into(),
+                None => TestPeriodicTxRspBuilder {
+                    status: StatusCode::UciStatusSessionNotExist,
+                }
+                .build()
+                .into(),
             },
+            TestCommandChild::TestPerRxCmd(_) => match self.test_session_id() {
+                Some(session_id) => self.rf_test.per_rx(session_id).into(),
+                None => TestPerRxRspBuilder {
+                    status: StatusCode::UciStatusSessionNotExist,
+                }
+                .build()
+                .into(),
+            },
+            TestCommandChild::TestLoopbackCmd(cmd) => match self.test_session_id() {
+                Some(_) => self.rf_test.loopback(cmd.get_psdu_data()).into(),
+                None => TestLoopbackRspBuilder {
+                    status: StatusCode::UciStatusSessionNotExist,
+                }
+                .build()
+                .into(),
+            },
+            TestCommandChild::TestStopSessionCmd(_) => self.rf_test.stop().into(),
+            _ => unknown_command(GroupId::Test, test_command.get_opcode()),
         }
-        .build()
     }
 
-    pub fn data_message_snd(&mut self, data: DataPacket) -> SessionControlNotification {
+    pub fn data_message_snd(&mut self, data: DataPacket) -> Vec<SessionControlNotification> {
         match data.specialize() {
             DataPacketChild::DataMessageSnd(data_msg_snd) => {
                 let session_token = data_msg_snd.get_session_handle();
                 if let Some(session) = self.get_session_mut(session_token) {
                     session.data_message_snd(data_msg_snd)
                 } else {
-                    DataTransferStatusNtfBuilder {
+                    vec![DataTransferStatusNtfBuilder {
                         session_token,
                         status: DataTransferNtfStatusCode::UciDataTransferStatusErrorRejected,
                         tx_count: 1, // TODO: support for retries?
                         uci_sequence_number: 0,
                     }
                     .build()
-                    .into()
+                    .into()]
                 }
             }
             DataPacketChild::DataMessageRcv(data_msg_rcv) => {
                 // This function should not be passed anything besides DataMessageSnd
                 let session_token = data_msg_rcv.get_session_handle();
-                DataTransferStatusNtfBuilder {
+                vec![DataTransferStatusNtfBuilder {
                     session_token,
                     status: DataTransferNtfStatusCode::UciDataTransferStatusInvalidFormat,
                     tx_count: 1, // TODO: support for retries?
                     uci_sequence_number: 0,
                 }
                 .build()
-                .into()
+                .into()]
+            }
+            DataPacketChild::RadarDataRcv(radar_data_rcv) => {
+                // Radar data is only sent by the UWBS.
+                let session_token = radar_data_rcv.get_session_handle();
+                vec![DataTransferStatusNtfBuilder {
+                    session_token,
+                    status: DataTransferNtfStatusCode::UciDataTransferStatusInvalidFormat,
+                    tx_count: 1, // TODO: support for retries?
+                    uci_sequence_number: 0,
+                }
+                .build()
+                .into()]
             }
             _ => {
                 unimplemented!()
@@ -394,6 +888,23 @@ impl Device {
     }
 
     pub fn command(&mut self, cmd: UciCommand) -> UciResponse {
+        let (gid, opcode) = (cmd.get_gid(), cmd.get_opcode());
+        // The reset wakes the device up with its own status notification.
+        if self.suspended && (gid, opcode) != (GroupId::Core, u8::from(CoreOpCode::CoreDeviceReset))
+        {
+            self.resume();
+        }
+        // The device only accepts the reset after a crash.
+        if self.state == DeviceState::DeviceStateError
+            && (gid, opcode) != (GroupId::Core, u8::from(CoreOpCode::CoreDeviceReset))
+        {
+            return UciResponseBuilder {
+                gid,
+                opcode,
+                payload: Some(vec![u8::from(StatusCode::UciStatusFailed)].into()),
+            }
+            .build();
+        }
         match cmd.specialize() {
             // Handle commands for this device
             UciCommandChild::CoreCommand(core_command) => match core_command.specialize() {
@@ -402,7 +913,11 @@ impl Device {
                 CoreCommandChild::GetCapsInfoCmd(cmd) => self.command_get_caps_info(cmd).into(),
                 CoreCommandChild::SetConfigCmd(cmd) => self.command_set_config(cmd).into(),
                 CoreCommandChild::GetConfigCmd(cmd) => self.command_get_config(cmd).into(),
-                _ => panic!("Unsupported core command"),
+                CoreCommandChild::CoreQueryTimeStampCmd(cmd) => {
+                    self.command_query_timestamp(cmd).into()
+                }
+                CoreCommandChild::DeviceSuspendCmd(cmd) => self.command_device_suspend(cmd).into(),
+                _ => unknown_command(gid, opcode),
             },
             // Handle commands for session management
             UciCommandChild::SessionConfigCommand(session_command) => {
@@ -417,6 +932,14 @@ impl Device {
                     SessionConfigCommandChild::SessionGetCountCmd(cmd) => {
                         return self.command_session_get_count(cmd).into();
                     }
+                    SessionConfigCommandChild::SessionSetHybridConfigCmd(cmd) => {
+                        return self
+                            .command_session_set_hybrid_controller_config(cmd)
+                            .into();
+                    }
+                    SessionConfigCommandChild::SessionSetHybridControleeConfigCmd(cmd) => {
+                        return self.command_session_set_hybrid_controlee_config(cmd).into();
+                    }
                     _ => {}
                 }
 
@@ -432,7 +955,7 @@ impl Device {
                     SessionConfigCommandChild::SessionUpdateControllerMulticastListCmd(cmd) => {
                         cmd.get_session_token()
                     }
-                    _ => panic!("Unsupported session command type"),
+                    _ => return unknown_command(gid, opcode),
                 };
 
                 if let Some(session) = self.get_session_mut(session_id) {
@@ -445,7 +968,7 @@ impl Device {
                         | SessionConfigCommandChild::SessionUpdateControllerMulticastListCmd(_) => {
                             session.session_command(session_command).into()
                         }
-                        _ => panic!("Unsupported session command"),
+                        _ => unknown_command(gid, opcode),
                     }
                 } else {
                     // There is no session matching the session_id in the command
@@ -480,30 +1003,46 @@ impl Device {
                                 .build()
                                 .into()
                         }
-                        _ => panic!("Unsupported session command"),
+                        _ => unknown_command(gid, opcode),
                     }
                 }
             }
             UciCommandChild::SessionControlCommand(ranging_command) => {
                 let session_id = ranging_command.get_session_id();
+                let country_code = self.country_code;
                 if let Some(session) = self.get_session_mut(session_id) {
-                    // Forward to the proper session
-                    let response = session.ranging_command(ranging_command);
+                    // Forward to the proper session, unless starting
+                    // on a channel prohibited in the configured country.
+                    let personality = Personality::of_session_type(session.session_type());
+                    let response = match ranging_command.specialize() {
+                        SessionControlCommandChild::SessionStartCmd(_)
+                            if !regulatory::is_channel_allowed(
+                                country_code,
+                                session.channel_number(),
+                            ) =>
+                        {
+                            SessionStartRspBuilder {
+                                status: if regulatory::is_uwb_off(country_code) {
+                                    StatusCode::UciStatusRegulationUwbOff
+                                } else {
+                                    StatusCode::UciStatusRejected
+                                },
+                            }
+                            .build()
+                            .into()
+                        }
+                        _ => session.ranging_command(ranging_command),
+                    };
                     match response.specialize() {
                         SessionControlResponseChild::SessionStartRsp(rsp)
                             if rsp.get_status() == StatusCode::UciStatusOk =>
                         {
-                            self.n_active_sessions += 1;
-                            self.set_state(DeviceState::DeviceStateActive);
+                            self.session_started(personality);
                         }
                         SessionControlResponseChild::SessionStopRsp(rsp)
                             if rsp.get_status() == StatusCode::UciStatusOk =>
                         {
-                            assert!(self.n_active_sessions > 0);
-                            self.n_active_sessions -= 1;
-                            if self.n_active_sessions == 0 {
-                                self.set_state(DeviceState::DeviceStateReady);
-                            }
+                            self.session_stopped(personality);
                         }
                         _ => {}
                     }
@@ -522,7 +1061,7 @@ impl Device {
                                 .build()
                                 .into()
                         }
-                        _ => panic!("Unsupported ranging command"),
+                        _ => unknown_command(gid, opcode),
                     }
                 }
             }
@@ -535,7 +1074,41 @@ impl Device {
                     AndroidCommandChild::AndroidGetPowerStatsCmd(cmd) => {
                         self.command_get_power_stats(cmd).into()
                     }
-                    _ => panic!("Unsupported Android command"),
+                    AndroidCommandChild::AndroidRadarSetAppConfigCmd(cmd) => {
+                        match self.get_session_mut(cmd.get_session_token()) {
+                            Some(session) => session.command_radar_set_app_config(cmd).into(),
+                            None => AndroidRadarSetAppConfigRspBuilder {
+                                status: StatusCode::UciStatusSessionNotExist,
+                                cfg_status: Vec::new(),
+                            }
+                            .build()
+                            .into(),
+                        }
+                    }
+                    AndroidCommandChild::AndroidRadarGetAppConfigCmd(cmd) => {
+                        match self.get_session(cmd.get_session_token()) {
+                            Some(session) => session.command_radar_get_app_config(cmd).into(),
+                            None => AndroidRadarGetAppConfigRspBuilder {
+                                status: StatusCode::UciStatusSessionNotExist,
+                                tlvs: Vec::new(),
+                            }
+                            .build()
+                            .into(),
+                        }
+                    }
+                    _ => unknown_command(gid, opcode),
+                }
+            }
+            UciCommandChild::TestCommand(test_command) => {
+                if self.profile.supports(Personality::Test) {
+                    self.command_test(test_command)
+                } else {
+                    UciResponseBuilder {
+                        gid,
+                        opcode,
+                        payload: Some(vec![u8::from(StatusCode::UciStatusUnknownGid)].into()),
+                    }
+                    .build()
                 }
             }
             UciCommandChild::UciVendor_9_Command(vendor_command) => UciVendor_9_ResponseBuilder {
@@ -568,13 +1141,17 @@ impl Device {
             }
             .build()
             .into(),
-            // TODO: Handle properly without panic
-            _ => UciResponseBuilder {
-                gid: GroupId::Core,
-                opcode: 0,
-                payload: None,
-            }
-            .build(),
+            _ => unknown_command(gid, opcode),
         }
     }
 }
+
+/// Response to a command which is not supported by the device.
+fn unknown_command(gid: GroupId, opcode: u8) -> UciResponse {
+    UciResponseBuilder {
+        gid,
+        opcode,
+        payload: Some(vec![u8::from(StatusCode::UciStatusUnknownOid)].into()),
+    }
+    .build()
+}
@@ -0,0 +1,49 @@
+// Copyright 2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Errors reported to the applications embedding pica.
+
+use thiserror::Error;
+
+use crate::PicaCommandError;
+
+/// Errors reported by the simulation and the connections of the
+/// UCI devices.
+#[derive(Error, Debug)]
+pub enum PicaError {
+    /// The transport of a UCI device failed.
+    #[error("Transport error: {0}")]
+    Transport(#[from] std::io::Error),
+    /// The transport of a UCI device was closed by the host.
+    #[error("Connection closed")]
+    ConnectionClosed,
+    /// The capture or trace file of a UCI device could not be written.
+    #[error("Capture error: {0}")]
+    Capture(std::io::Error),
+    /// A packet received from a host could not be parsed.
+    #[error("{0}")]
+    Parse(String),
+    /// A packet received from a host is not allowed by the UCI protocol,
+    /// e.g. a response or notification.
+    #[error("Protocol violation: {0}")]
+    Protocol(String),
+    /// A command was rejected by the simulation.
+    #[error(transparent)]
+    Command(#[from] PicaCommandError),
+    /// The simulation is shut down, and accepts no more commands.
+    #[error("Simulation is shut down")]
+    ShutDown,
+}
+
+pub type PicaResult<T> = Result<T, PicaError>;
@@ -0,0 +1,276 @@
+// Copyright 2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Persistent log of the events and ranging measurements, stored in an
+//! SQLite database so that long simulations can be analyzed afterwards.
+
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rusqlite::{params, params_from_iter, Connection, Result, ToSql};
+
+use crate::{MacAddress, PicaEvent};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS events (
+        timestamp_ms INTEGER NOT NULL,
+        name TEXT NOT NULL,
+        mac_address TEXT,
+        peer_mac_address TEXT,
+        data TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS events_timestamp ON events (timestamp_ms);
+    CREATE TABLE IF NOT EXISTS measurements (
+        timestamp_ms INTEGER NOT NULL,
+        mac_address TEXT NOT NULL,
+        session_id INTEGER NOT NULL,
+        peer_mac_address TEXT NOT NULL,
+        status INTEGER NOT NULL,
+        distance INTEGER NOT NULL,
+        azimuth INTEGER NOT NULL,
+        elevation INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS measurements_timestamp ON measurements (timestamp_ms);
+";
+
+/// Selection of the log entries returned by queries.
+/// Unset fields match all entries.
+#[derive(Clone, Debug, Default)]
+pub struct EventLogFilter {
+    /// Earliest timestamp (ms since the Unix epoch), inclusive.
+    pub start_ms: Option<u64>,
+    /// Latest timestamp (ms since the Unix epoch), exclusive.
+    pub end_ms: Option<u64>,
+    /// Entries involving this device, either as source or peer.
+    pub mac_address: Option<MacAddress>,
+    /// Measurements of this session. Events are not associated with
+    /// a session and are not returned when this field is set.
+    pub session_id: Option<u32>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LoggedEvent {
+    pub timestamp_ms: u64,
+    /// Event name, as reported on the web event stream.
+    pub name: String,
+    /// JSON serialization of the event.
+    pub data: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LoggedMeasurement {
+    pub timestamp_ms: u64,
+    pub mac_address: String,
+    pub session_id: u32,
+    pub peer_mac_address: String,
+    /// UCI status of the measurement.
+    pub status: u8,
+    pub distance: u16,
+    pub azimuth: i16,
+    pub elevation: i8,
+}
+
+pub struct EventLog {
+    connection: Connection,
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// Build the WHERE clause matching the filter, and its parameters.
+fn where_clause(filter: &EventLogFilter) -> (String, Vec<Box<dyn ToSql>>) {
+    let mut conditions = vec!["1"];
+    let mut parameters: Vec<Box<dyn ToSql>> = vec![];
+    if let Some(start_ms) = filter.start_ms {
+        conditions.push("timestamp_ms >= ?");
+        parameters.push(Box::new(start_ms as i64));
+    }
+    if let Some(end_ms) = filter.end_ms {
+        conditions.push("timestamp_ms < ?");
+        parameters.push(Box::new(end_ms as i64));
+    }
+    if let Some(mac_address) = filter.mac_address {
+        conditions.push("(mac_address = ? OR peer_mac_address = ?)");
+        parameters.push(Box::new(String::from(mac_address)));
+        parameters.push(Box::new(String::from(mac_address)));
+    }
+    if let Some(session_id) = filter.session_id {
+        conditions.push("session_id = ?");
+        parameters.push(Box::new(session_id));
+    }
+    (conditions.join(" AND "), parameters)
+}
+
+impl EventLog {
+    /// Open the event log stored at `path`, creating the database if needed.
+    /// Entries are appended to an existing log.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let connection = Connection::open(path)?;
+        // Write ahead logging keeps the inserts cheap during long simulations.
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        connection.pragma_update(None, "synchronous", "NORMAL")?;
+        connection.execute_batch(SCHEMA)?;
+        Ok(EventLog { connection })
+    }
+
+    /// Record an event emitted at the selected time (ms since the Unix epoch).
+    pub fn record_event(&self, event: &PicaEvent, timestamp_ms: u64) -> Result<()> {
+        let (mac_address, peer_mac_address) = event.mac_addresses();
+        self.connection.execute(
+            "INSERT INTO events VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                timestamp_ms as i64,
+                event.name(),
+                String::from(mac_address),
+                peer_mac_address.map(String::from),
+                serde_json::to_string(event).unwrap(),
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn record_measurement(
+        &self,
+        mac_address: MacAddress,
+        session_id: u32,
+        peer_mac_address: MacAddress,
+        status: u8,
+        (distance, azimuth, elevation): (u16, i16, i8),
+    ) -> Result<()> {
+        self.connection.execute(
+            "INSERT INTO measurements VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                now_ms() as i64,
+                String::from(mac_address),
+                session_id,
+                String::from(peer_mac_address),
+                status,
+                distance,
+                azimuth,
+                elevation,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Return the events matching the filter, in chronological order.
+    pub fn events(&self, filter: &EventLogFilter) -> Result<Vec<LoggedEvent>> {
+        if filter.session_id.is_some() {
+            return Ok(vec![]);
+        }
+        let (clause, parameters) = where_clause(filter);
+        let mut statement = self.connection.prepare(&format!(
+            "SELECT timestamp_ms, name, data FROM events WHERE {} ORDER BY timestamp_ms",
+            clause
+        ))?;
+        let rows = statement.query_map(params_from_iter(parameters.iter()), |row| {
+            Ok(LoggedEvent {
+                timestamp_ms: row.get::<_, i64>(0)? as u64,
+                name: row.get(1)?,
+                data: row.get(2)?,
+            })
+        })?;
+        rows.collect()
+    }
+
+    /// Return the ranging measurements matching the filter,
+    /// in chronological order.
+    pub fn measurements(&self, filter: &EventLogFilter) -> Result<Vec<LoggedMeasurement>> {
+        let (clause, parameters) = where_clause(filter);
+        let mut statement = self.connection.prepare(&format!(
+            "SELECT * FROM measurements WHERE {} ORDER BY timestamp_ms",
+            clause
+        ))?;
+        let rows = statement.query_map(params_from_iter(parameters.iter()), |row| {
+            Ok(LoggedMeasurement {
+                timestamp_ms: row.get::<_, i64>(0)? as u64,
+                mac_address: row.get(1)?,
+                session_id: row.get(2)?,
+                peer_mac_address: row.get(3)?,
+                status: row.get(4)?,
+                distance: row.get(5)?,
+                azimuth: row.get(6)?,
+                elevation: row.get(7)?,
+            })
+        })?;
+        rows.collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Category, Position};
+
+    fn event(mac_address: MacAddress) -> PicaEvent {
+        PicaEvent::DeviceAdded {
+            category: Category::Uci,
+            mac_address,
+            position: Position::default(),
+            origin: None,
+        }
+    }
+
+    #[test]
+    fn query_by_device_and_session() {
+        let log = EventLog::open(":memory:").unwrap();
+        let a = MacAddress::Short([0, 1]);
+        let b = MacAddress::Short([0, 2]);
+        log.record_event(&event(a), now_ms()).unwrap();
+        log.record_event(&event(b), now_ms()).unwrap();
+        log.record_measurement(a, 1, b, 0, (100, 10, -5)).unwrap();
+        log.record_measurement(b, 2, a, 0, (100, -10, 5)).unwrap();
+
+        let filter = EventLogFilter {
+            mac_address: Some(a),
+            ..Default::default()
+        };
+        let events = log.events(&filter).unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].name, "device-added");
+        assert_eq!(log.measurements(&filter).unwrap().len(), 2);
+
+        let filter = EventLogFilter {
+            session_id: Some(2),
+            ..Default::default()
+        };
+        assert!(log.events(&filter).unwrap().is_empty());
+        let measurements = log.measurements(&filter).unwrap();
+        assert_eq!(measurements.len(), 1);
+        assert_eq!(measurements[0].azimuth, -10);
+        assert_eq!(measurements[0].elevation, 5);
+    }
+
+    #[test]
+    fn query_by_time_range() {
+        let log = EventLog::open(":memory:").unwrap();
+        log.record_event(&event(MacAddress::Short([0, 1])), now_ms())
+            .unwrap();
+
+        let filter = EventLogFilter {
+            end_ms: Some(0),
+            ..Default::default()
+        };
+        assert!(log.events(&filter).unwrap().is_empty());
+        let filter = EventLogFilter {
+            start_ms: Some(0),
+            ..Default::default()
+        };
+        assert_eq!(log.events(&filter).unwrap().len(), 1);
+    }
+}
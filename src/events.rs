@@ -0,0 +1,59 @@
+// Copyright 2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Streams of the events emitted by pica, adapting the broadcast channel.
+
+use thiserror::Error;
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::{Stream, StreamExt};
+use tracing::warn;
+
+use crate::{PicaEvent, SequencedEvent};
+
+/// Behaviour of an event stream whose subscriber is too slow to keep up
+/// with the events, which are then dropped from the channel.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LagPolicy {
+    /// Log the number of missed events and continue with the next
+    /// event available.
+    #[default]
+    Skip,
+    /// Report the number of missed events as an error item, then
+    /// continue with the next event available.
+    Error,
+}
+
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+#[error("Missed {0} events")]
+pub struct EventsLagged(pub u64);
+
+/// Stream of the events received from the channel. The stream ends
+/// when the simulation is shut down.
+pub(crate) fn event_stream(
+    receiver: broadcast::Receiver<SequencedEvent>,
+    lag_policy: LagPolicy,
+) -> impl Stream<Item = Result<PicaEvent, EventsLagged>> + Send + Unpin {
+    BroadcastStream::new(receiver).filter_map(move |result| match result {
+        Ok(event) => Some(Ok(event.event)),
+        Err(BroadcastStreamRecvError::Lagged(count)) => match lag_policy {
+            LagPolicy::Skip => {
+                warn!("Event stream missed {} events", count);
+                None
+            }
+            LagPolicy::Error => Some(Err(EventsLagged(count))),
+        },
+    })
+}
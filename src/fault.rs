@@ -0,0 +1,113 @@
+// Copyright 2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Faults injected in the UCI devices, to exercise the command timeouts,
+//! retries and crash recovery of the hosts.
+
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Recovery required from the host after a simulated firmware crash.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "lowercase")]
+pub enum CrashRecovery {
+    /// The device stays connected in the error state, and rejects the
+    /// commands until reset.
+    #[default]
+    Reset,
+    /// The connection is closed after the error state is reported.
+    Disconnect,
+}
+
+/// Alteration of the response to a command.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResponseAction {
+    /// The response is sent after the selected simulated delay.
+    Delay(Duration),
+    /// The response is never sent.
+    Withhold,
+}
+
+/// Fault injected in the response to the nth command of a group and
+/// opcode received after the fault is set, counting from 1.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ResponseFault {
+    pub gid: u8,
+    pub opcode: u8,
+    pub nth: u32,
+    pub action: ResponseAction,
+}
+
+/// Pending response faults, with the number of matching commands
+/// received so far. Each fault applies once.
+#[derive(Clone, Debug, Default)]
+pub struct ResponseFaults {
+    faults: Vec<(ResponseFault, u32)>,
+}
+
+impl ResponseFaults {
+    pub fn new(faults: Vec<ResponseFault>) -> Self {
+        ResponseFaults {
+            faults: faults.into_iter().map(|fault| (fault, 0)).collect(),
+        }
+    }
+
+    /// Account for a command received from the host, and return the
+    /// alteration of its response if any.
+    pub fn next(&mut self, gid: u8, opcode: u8) -> Option<ResponseAction> {
+        let mut action = None;
+        self.faults.retain_mut(|(fault, count)| {
+            if fault.gid != gid || fault.opcode != opcode {
+                return true;
+            }
+            *count += 1;
+            if *count < fault.nth {
+                return true;
+            }
+            action = action.or(Some(fault.action));
+            false
+        });
+        action
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nth_command() {
+        let delay = ResponseAction::Delay(Duration::from_millis(100));
+        let mut faults = ResponseFaults::new(vec![
+            ResponseFault {
+                gid: 1,
+                opcode: 0,
+                nth: 2,
+                action: ResponseAction::Withhold,
+            },
+            ResponseFault {
+                gid: 1,
+                opcode: 0,
+                nth: 3,
+                action: delay,
+            },
+        ]);
+        assert_eq!(faults.next(1, 0), None);
+        assert_eq!(faults.next(1, 1), None);
+        assert_eq!(faults.next(1, 0), Some(ResponseAction::Withhold));
+        assert_eq!(faults.next(1, 0), Some(delay));
+        assert_eq!(faults.next(1, 0), None);
+    }
+}
@@ -0,0 +1,225 @@
+// Copyright 2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Live position feed, mirroring the positions reported by an external
+//! system such as a motion capture system. Each message is a JSON object,
+//! or an array of JSON objects, e.g.
+//!
+//! ```json
+//! {"mac_address": "00:01", "x": 120.5, "y": 0.0, "z": -42.25, "yaw": 90}
+//! ```
+//!
+//! The coordinates are in centimeters, the optional yaw, pitch and roll
+//! in degrees.
+
+use std::net::SocketAddr;
+
+use anyhow::Result;
+use serde::Deserialize;
+use tokio::net::UdpSocket;
+use tracing::{info, warn};
+
+#[cfg(feature = "mqtt")]
+use crate::MqttEndpoint;
+use crate::{LengthUnit, MacAddress, Pica, PicaError, PicaHandle, Position};
+
+/// Maximum size of the UDP datagrams.
+const MAX_DATAGRAM_SIZE: usize = 65536;
+
+#[derive(Debug, Deserialize)]
+struct PositionUpdate {
+    mac_address: MacAddress,
+    x: f64,
+    y: f64,
+    z: f64,
+    #[serde(default)]
+    yaw: i16,
+    #[serde(default)]
+    pitch: i8,
+    #[serde(default)]
+    roll: i16,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum PositionUpdates {
+    One(PositionUpdate),
+    Many(Vec<PositionUpdate>),
+}
+
+impl PositionUpdate {
+    fn position(&self) -> Option<Position> {
+        let valid = [self.x, self.y, self.z].iter().all(|c| c.is_finite())
+            && (-180..=180).contains(&self.yaw)
+            && (-90..=90).contains(&self.pitch)
+            && (-180..=180).contains(&self.roll);
+        valid.then(|| {
+            Position::new(0, 0, 0, self.yaw, self.pitch, self.roll).with_coordinates_in(
+                self.x,
+                self.y,
+                self.z,
+                LengthUnit::Centimeter,
+            )
+        })
+    }
+}
+
+/// Source of position updates applied to the anchors and devices
+/// of a simulation.
+pub struct PositionFeed {
+    handle: PicaHandle,
+}
+
+impl PositionFeed {
+    pub fn new(pica: &Pica) -> Self {
+        PositionFeed {
+            handle: pica.handle(),
+        }
+    }
+
+    /// Apply the position updates received in the UDP datagrams,
+    /// one message per datagram.
+    pub async fn listen_udp(self, addr: SocketAddr) -> Result<()> {
+        let socket = UdpSocket::bind(addr).await?;
+        info!("Position feed listening on udp:{}", addr);
+        let mut buffer = vec![0; MAX_DATAGRAM_SIZE];
+        loop {
+            let (len, peer) = socket.recv_from(&mut buffer).await?;
+            self.apply(&buffer[..len], &peer.to_string()).await?;
+        }
+    }
+
+    /// Apply the position updates published on the topic of the
+    /// MQTT endpoint, one message per publication.
+    #[cfg(feature = "mqtt")]
+    pub async fn subscribe_mqtt(self, endpoint: MqttEndpoint) -> Result<()> {
+        use rumqttc::{Event, Packet, QoS};
+
+        let (client, mut eventloop) = endpoint.connect("pica-feed");
+        info!("Position feed subscribing to {}", endpoint);
+        loop {
+            match eventloop.poll().await {
+                // The subscription is renewed after each reconnection,
+                // the session is not persisted by the broker.
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    client.subscribe(&endpoint.topic, QoS::AtMostOnce).await?
+                }
+                Ok(Event::Incoming(Packet::Publish(publish))) => {
+                    self.apply(&publish.payload, &publish.topic).await?
+                }
+                Ok(_) => (),
+                Err(err) => {
+                    warn!("Position feed connection to {} failed: {}", endpoint, err);
+                    tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+                }
+            }
+        }
+    }
+
+    /// Apply the updates of a message at once. Invalid messages and
+    /// updates are dropped, and the messages updating unknown nodes are
+    /// rejected. The feed stops only when the simulation is shut down.
+    async fn apply(&self, message: &[u8], source: &str) -> Result<()> {
+        let updates = match serde_json::from_slice(message) {
+            Ok(PositionUpdates::One(update)) => vec![update],
+            Ok(PositionUpdates::Many(updates)) => updates,
+            Err(err) => {
+                warn!("Invalid position update from {}: {}", source, err);
+                return Ok(());
+            }
+        };
+        let positions = updates
+            .into_iter()
+            .filter_map(|update| match update.position() {
+                Some(position) => Some((update.mac_address, position)),
+                None => {
+                    warn!("Invalid position update from {}: {:?}", source, update);
+                    None
+                }
+            })
+            .collect();
+        match self.handle.set_positions(positions).await {
+            Ok(()) => Ok(()),
+            Err(PicaError::ShutDown) => Err(PicaError::ShutDown.into()),
+            Err(err) => {
+                warn!("Position update from {} rejected: {}", source, err);
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn apply_position_updates() {
+        let mut pica = Pica::builder().build();
+        let handle = pica.handle();
+        let feed = PositionFeed::new(&pica);
+        tokio::spawn(async move { pica.run().await });
+
+        let anchor_a = MacAddress::Short([0x00, 0x01]);
+        let anchor_b = MacAddress::Short([0x00, 0x02]);
+        for mac_address in [anchor_a, anchor_b] {
+            handle
+                .create_anchor(mac_address, Position::default())
+                .await
+                .unwrap();
+        }
+
+        feed.apply(
+            br#"{"mac_address": "00:01", "x": 120.5, "y": 0, "z": -42.25, "yaw": 90}"#,
+            "test",
+        )
+        .await
+        .unwrap();
+        // Unknown devices and invalid updates do not stop the feed.
+        feed.apply(
+            br#"{"mac_address": "00:03", "x": 1, "y": 1, "z": 1}"#,
+            "test",
+        )
+        .await
+        .unwrap();
+        feed.apply(
+            br#"[{"mac_address": "00:02", "x": 1, "y": 1, "z": 1, "pitch": 120},
+                 {"mac_address": "00:02", "x": 10, "y": 20, "z": 30}]"#,
+            "test",
+        )
+        .await
+        .unwrap();
+        feed.apply(b"not json", "test").await.unwrap();
+
+        let state = handle.get_state().await.unwrap();
+        let position = |mac_address| {
+            state
+                .devices
+                .iter()
+                .find(|(_, mac, _)| *mac == mac_address)
+                .map(|(_, _, position)| *position)
+                .unwrap()
+        };
+        assert_eq!(
+            position(anchor_a).to_string(),
+            Position::new(0, 0, 0, 90, 0, 0)
+                .with_coordinates_in(120.5, 0.0, -42.25, LengthUnit::Centimeter)
+                .to_string()
+        );
+        assert_eq!(
+            position(anchor_b).coordinates(LengthUnit::Centimeter),
+            (10.0, 20.0, 30.0)
+        );
+    }
+}
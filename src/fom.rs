@@ -0,0 +1,96 @@
+// Copyright 2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Figure of merit of the angle of arrival results, derived from the
+//! geometry of the link.
+
+/// Figure of merit of the angle of arrival results outside
+/// the field of view of the receiver.
+pub(crate) const OUT_OF_FOV_FOM: u8 = 0;
+
+/// Linear figure of merit model:
+/// `FOM = max_fom - angle_loss * θ - distance_loss * d`
+/// where `θ` is the angle between the transmitter and the boresight of
+/// the receiver (degrees), and `d` the distance (m), scaled by
+/// `nlos_factor` when the link is blocked by an obstacle. Results in
+/// the field of view have a figure of merit of at least 1.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FomModel {
+    /// Figure of merit at boresight and short distance, in line of sight.
+    pub max_fom: u8,
+    /// Loss of figure of merit per degree off boresight.
+    pub angle_loss: f32,
+    /// Loss of figure of merit per meter.
+    pub distance_loss: f32,
+    /// Factor applied to the figure of merit of the links blocked by
+    /// an obstacle.
+    pub nlos_factor: f32,
+}
+
+impl Default for FomModel {
+    fn default() -> Self {
+        FomModel {
+            max_fom: 100,
+            angle_loss: 0.5,
+            distance_loss: 1.0,
+            nlos_factor: 0.5,
+        }
+    }
+}
+
+impl FomModel {
+    /// Figure of merit of the angle of arrival of a transmitter at the
+    /// selected distance (cm), azimuth and elevation (degrees).
+    pub fn fom(
+        &self,
+        (distance, azimuth, elevation): (u16, i16, i8),
+        nlos: bool,
+        in_fov: bool,
+    ) -> u8 {
+        if !in_fov {
+            return OUT_OF_FOV_FOM;
+        }
+        let (azimuth, elevation) = (
+            (azimuth as f32).to_radians(),
+            (elevation as f32).to_radians(),
+        );
+        let off_boresight = (azimuth.cos() * elevation.cos())
+            .clamp(-1.0, 1.0)
+            .acos()
+            .to_degrees();
+        let fom = self.max_fom as f32
+            - self.angle_loss * off_boresight
+            - self.distance_loss * distance as f32 / 100.0;
+        let fom = if nlos { fom * self.nlos_factor } else { fom };
+        fom.round().clamp(1.0, self.max_fom.max(1) as f32) as u8
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn geometry() {
+        let model = FomModel::default();
+        assert_eq!(model.fom((0, 0, 0), false, true), 100);
+        assert_eq!(model.fom((1000, 0, 0), false, true), 90);
+        assert_eq!(model.fom((0, 60, 0), false, true), 70);
+        assert_eq!(model.fom((0, 0, -60), false, true), 70);
+        assert_eq!(model.fom((1000, 0, 0), true, true), 45);
+        assert_eq!(model.fom((1000, 0, 0), false, false), OUT_OF_FOV_FOM);
+        // Results in the field of view keep a non-zero figure of merit.
+        assert_eq!(model.fom((u16::MAX, 180, 0), false, true), 1);
+    }
+}
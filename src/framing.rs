@@ -0,0 +1,122 @@
+// Copyright 2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Framing of the UCI packets received on the transport, independent
+//! of the transport itself so that arbitrary byte sequences can be
+//! replayed, e.g. by fuzz targets.
+
+use crate::HEADER_SIZE;
+
+/// Mask of the Packet Boundary Flag in the first octet of the header.
+const PBF_MASK: u8 = 0x10;
+/// Message type of the data packets.
+const MT_DATA: u8 = 0;
+
+/// Splits the bytes received from the host into UCI segments, and
+/// reassembles the segmented control packets.
+///
+/// For each segment of a Control Message, the header of the Control
+/// Packet SHALL contain the same MT, GID and OID values: only the
+/// header of the last segment is kept in the reassembled packet.
+/// Data packet fragments are returned immediately, as each fragment
+/// needs to be acknowledged by a credit notification.
+/// Interspersing data and control segments is not supported.
+#[derive(Debug, Default)]
+pub struct PacketReassembler {
+    /// Received bytes not yet split into segments.
+    buffer: Vec<u8>,
+    /// Payload of the segments of the packet being reassembled.
+    payload: Vec<u8>,
+}
+
+impl PacketReassembler {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Append bytes received from the host.
+    pub fn extend_from_slice(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes)
+    }
+
+    /// Extract the next segment from the received bytes,
+    /// or return None if the segment is not complete yet.
+    pub fn next_segment(&mut self) -> Option<Vec<u8>> {
+        let header = self.buffer.get(0..HEADER_SIZE)?;
+        let payload_length = if header[0] >> 5 == MT_DATA {
+            u16::from_le_bytes([header[2], header[3]]) as usize
+        } else {
+            header[3] as usize
+        };
+        let segment_length = HEADER_SIZE + payload_length;
+        (self.buffer.len() >= segment_length).then(|| self.buffer.drain(..segment_length).collect())
+    }
+
+    /// Add a segment to the packet being reassembled,
+    /// and return the packet if the segment is the last one.
+    pub fn push_segment(&mut self, segment: &[u8]) -> Option<Vec<u8>> {
+        let (header, payload) = segment.split_at(HEADER_SIZE.min(segment.len()));
+        self.payload.extend_from_slice(payload);
+        if header.len() < HEADER_SIZE || (header[0] >> 5 != MT_DATA && header[0] & PBF_MASK != 0) {
+            return None;
+        }
+        let mut packet = header.to_vec();
+        packet.append(&mut self.payload);
+        Some(packet)
+    }
+
+    /// Return the next complete packet, or None if more bytes
+    /// need to be received.
+    pub fn next_packet(&mut self) -> Option<Vec<u8>> {
+        while let Some(segment) = self.next_segment() {
+            if let Some(packet) = self.push_segment(&segment) {
+                return Some(packet);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reassembly() {
+        let mut reassembler = PacketReassembler::new();
+        // First segment of a command, with the PBF set.
+        reassembler.extend_from_slice(&[0x31, 0x03, 0x00, 0x02, 0x01, 0x02, 0x21]);
+        assert_eq!(reassembler.next_packet(), None);
+        // Last segment of the command.
+        reassembler.extend_from_slice(&[0x03, 0x00, 0x01, 0x03]);
+        assert_eq!(
+            reassembler.next_packet(),
+            Some(vec![0x21, 0x03, 0x00, 0x01, 0x01, 0x02, 0x03])
+        );
+        assert_eq!(reassembler.next_packet(), None);
+    }
+
+    #[test]
+    fn data_fragments() {
+        let mut reassembler = PacketReassembler::new();
+        // Data fragments are not reassembled, and the payload length
+        // is encoded on two octets.
+        let fragment = [0x11, 0x00, 0x02, 0x00, 0xaa, 0xbb];
+        reassembler.extend_from_slice(&fragment);
+        reassembler.extend_from_slice(&fragment);
+        assert_eq!(reassembler.next_packet(), Some(fragment.to_vec()));
+        assert_eq!(reassembler.next_packet(), Some(fragment.to_vec()));
+        assert_eq!(reassembler.next_packet(), None);
+    }
+}
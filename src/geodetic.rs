@@ -0,0 +1,198 @@
+// Copyright 2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Conversion of the WGS84 geographic coordinates to the local Cartesian
+//! frame of the simulation.
+
+use std::fmt::Display;
+use std::str::FromStr;
+
+use glam::{DMat3, DVec3};
+use serde::{Deserialize, Serialize};
+
+use crate::Position;
+
+/// Semi-major axis of the WGS84 ellipsoid (m).
+const SEMI_MAJOR_AXIS: f64 = 6_378_137.0;
+/// Flattening of the WGS84 ellipsoid.
+const FLATTENING: f64 = 1.0 / 298.257_223_563;
+/// Square of the first eccentricity of the WGS84 ellipsoid.
+const ECCENTRICITY_SQUARED: f64 = FLATTENING * (2.0 - FLATTENING);
+
+/// Geographic coordinates on the WGS84 ellipsoid.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct GeodeticPosition {
+    /// Latitude in degrees, positive north of the equator.
+    pub latitude: f64,
+    /// Longitude in degrees, positive east of the prime meridian.
+    pub longitude: f64,
+    /// Height above the ellipsoid (m).
+    pub altitude: f64,
+}
+
+impl GeodeticPosition {
+    /// Earth-centered, earth-fixed coordinates (m).
+    fn ecef(&self) -> DVec3 {
+        let (sin_lat, cos_lat) = self.latitude.to_radians().sin_cos();
+        let (sin_lon, cos_lon) = self.longitude.to_radians().sin_cos();
+        let normal = SEMI_MAJOR_AXIS / (1.0 - ECCENTRICITY_SQUARED * sin_lat * sin_lat).sqrt();
+        DVec3::new(
+            (normal + self.altitude) * cos_lat * cos_lon,
+            (normal + self.altitude) * cos_lat * sin_lon,
+            (normal * (1.0 - ECCENTRICITY_SQUARED) + self.altitude) * sin_lat,
+        )
+    }
+
+    fn from_ecef(ecef: DVec3) -> Self {
+        let longitude = ecef.y.atan2(ecef.x);
+        let p = ecef.x.hypot(ecef.y);
+        // The latitude converges to sub-millimeter accuracy in a few
+        // iterations away from the poles.
+        let mut latitude = ecef.z.atan2(p * (1.0 - ECCENTRICITY_SQUARED));
+        let mut altitude = 0.0;
+        for _ in 0..5 {
+            let sin_lat = latitude.sin();
+            let normal = SEMI_MAJOR_AXIS / (1.0 - ECCENTRICITY_SQUARED * sin_lat * sin_lat).sqrt();
+            altitude = p / latitude.cos() - normal;
+            latitude = ecef
+                .z
+                .atan2(p * (1.0 - ECCENTRICITY_SQUARED * normal / (normal + altitude)));
+        }
+        GeodeticPosition {
+            latitude: latitude.to_degrees(),
+            longitude: longitude.to_degrees(),
+            altitude,
+        }
+    }
+}
+
+impl Display for GeodeticPosition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{},{},{}", self.latitude, self.longitude, self.altitude)
+    }
+}
+
+impl FromStr for GeodeticPosition {
+    type Err = String;
+
+    /// Parse the `latitude,longitude,altitude` coordinates.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let coordinates = s
+            .split(',')
+            .map(|coordinate| coordinate.trim().parse::<f64>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| format!("Invalid geodetic position: {}", s))?;
+        match coordinates[..] {
+            [latitude, longitude, altitude]
+                if (-90.0..=90.0).contains(&latitude) && (-180.0..=180.0).contains(&longitude) =>
+            {
+                Ok(GeodeticPosition {
+                    latitude,
+                    longitude,
+                    altitude,
+                })
+            }
+            _ => Err(format!("Invalid geodetic position: {}", s)),
+        }
+    }
+}
+
+/// Local East-North-Up tangent plane at a geographic origin. The origin
+/// of the tangent plane is the origin of the simulation frame.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TangentPlane {
+    origin: GeodeticPosition,
+    origin_ecef: DVec3,
+    /// Rotation from the ECEF axes to the ENU axes.
+    rotation: DMat3,
+}
+
+impl TangentPlane {
+    pub fn new(origin: GeodeticPosition) -> Self {
+        let (sin_lat, cos_lat) = origin.latitude.to_radians().sin_cos();
+        let (sin_lon, cos_lon) = origin.longitude.to_radians().sin_cos();
+        let east = DVec3::new(-sin_lon, cos_lon, 0.0);
+        let north = DVec3::new(-sin_lat * cos_lon, -sin_lat * sin_lon, cos_lat);
+        let up = DVec3::new(cos_lat * cos_lon, cos_lat * sin_lon, sin_lat);
+        TangentPlane {
+            origin,
+            origin_ecef: origin.ecef(),
+            rotation: DMat3::from_cols(east, north, up).transpose(),
+        }
+    }
+
+    pub fn origin(&self) -> GeodeticPosition {
+        self.origin
+    }
+
+    /// Position without rotation in the simulation frame.
+    pub fn to_local(&self, position: &GeodeticPosition) -> Position {
+        let enu = self.rotation * (position.ecef() - self.origin_ecef);
+        Position::from_enu(enu.x, enu.y, enu.z)
+    }
+
+    /// Geographic coordinates of a position of the simulation frame.
+    pub fn to_geodetic(&self, position: &Position) -> GeodeticPosition {
+        let (east, north, up) = position.enu();
+        let ecef = self.rotation.transpose() * DVec3::new(east, north, up) + self.origin_ecef;
+        GeodeticPosition::from_ecef(ecef)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::LengthUnit;
+
+    const ORIGIN: GeodeticPosition = GeodeticPosition {
+        latitude: 48.8584,
+        longitude: 2.2945,
+        altitude: 35.0,
+    };
+
+    #[test]
+    fn tangent_plane() {
+        let plane = TangentPlane::new(ORIGIN);
+        let origin = plane.to_local(&ORIGIN);
+        assert!(origin.range(&Position::default(), LengthUnit::Millimeter) < 1.0);
+
+        // One arc second of latitude is about 30.9 m at this latitude.
+        let north = GeodeticPosition {
+            latitude: ORIGIN.latitude + 1.0 / 3600.0,
+            ..ORIGIN
+        };
+        let (east, north_m, up) = plane.to_local(&north).enu();
+        assert!(east.abs() < 1e-3);
+        assert!((north_m - 30.9).abs() < 0.1, "{}", north_m);
+        assert!(up.abs() < 0.01);
+
+        let position = Position::from_enu(120.5, -80.25, 12.0);
+        let geodetic = plane.to_geodetic(&position);
+        assert!(
+            plane
+                .to_local(&geodetic)
+                .range(&position, LengthUnit::Millimeter)
+                < 1.0
+        );
+    }
+
+    #[test]
+    fn parse_geodetic_position() {
+        assert_eq!("48.8584,2.2945,35".parse(), Ok(ORIGIN));
+        assert_eq!(ORIGIN.to_string().parse(), Ok(ORIGIN));
+        assert!("48.8584,2.2945".parse::<GeodeticPosition>().is_err());
+        assert!("91,0,0".parse::<GeodeticPosition>().is_err());
+    }
+}
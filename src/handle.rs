@@ -0,0 +1,208 @@
+// Copyright 2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Typed interface to a running simulation, wrapping the command channel.
+
+use std::time::Duration;
+
+use bytes::Bytes;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio_stream::Stream;
+
+use crate::events::event_stream;
+use crate::{
+    EventsLagged, Kinematics, LagPolicy, MacAddress, MotionPath, PicaCommand, PicaCommandStatus,
+    PicaError, PicaEvent, PicaResult, PicaState, Position, RecordedTrajectory, SequencedEvent,
+    SimulatorInfo, TimeMode,
+};
+
+/// Handle sending commands to a [`crate::Pica`] instance and waiting
+/// for their completion. Handles are cheap to clone and can be moved
+/// to other tasks.
+#[derive(Clone)]
+pub struct PicaHandle {
+    tx: mpsc::Sender<PicaCommand>,
+    event_tx: broadcast::Sender<SequencedEvent>,
+}
+
+impl PicaHandle {
+    pub(crate) fn new(
+        tx: mpsc::Sender<PicaCommand>,
+        event_tx: broadcast::Sender<SequencedEvent>,
+    ) -> Self {
+        PicaHandle { tx, event_tx }
+    }
+
+    /// Send a command and wait for its reply.
+    async fn request<T>(
+        &self,
+        command: impl FnOnce(oneshot::Sender<T>) -> PicaCommand,
+    ) -> PicaResult<T> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.tx
+            .send(command(reply_tx))
+            .await
+            .map_err(|_| PicaError::ShutDown)?;
+        reply_rx.await.map_err(|_| PicaError::ShutDown)
+    }
+
+    /// Send a command and wait for its status, for the commands
+    /// without a dedicated method.
+    pub async fn command(
+        &self,
+        command: impl FnOnce(oneshot::Sender<PicaCommandStatus>) -> PicaCommand,
+    ) -> PicaResult<()> {
+        Ok(self.request(command).await??)
+    }
+
+    pub async fn create_anchor(
+        &self,
+        mac_address: MacAddress,
+        position: Position,
+    ) -> PicaResult<()> {
+        self.command(|status_tx| PicaCommand::CreateAnchor(mac_address, position, status_tx))
+            .await
+    }
+
+    pub async fn destroy_anchor(&self, mac_address: MacAddress) -> PicaResult<()> {
+        self.command(|status_tx| PicaCommand::DestroyAnchor(mac_address, status_tx))
+            .await
+    }
+
+    /// Move the anchor or device.
+    pub async fn set_position(
+        &self,
+        mac_address: MacAddress,
+        position: Position,
+    ) -> PicaResult<()> {
+        self.command(|status_tx| PicaCommand::SetPosition(mac_address, position, status_tx))
+            .await
+    }
+
+    /// Move several anchors or devices at once. The positions are all
+    /// applied, or none of them if a node is not found.
+    pub async fn set_positions(&self, positions: Vec<(MacAddress, Position)>) -> PicaResult<()> {
+        self.command(|status_tx| PicaCommand::SetPositions(positions, status_tx))
+            .await
+    }
+
+    /// Change the orientation (yaw, pitch, roll) of the anchor or device,
+    /// keeping its coordinates.
+    pub async fn set_orientation(
+        &self,
+        mac_address: MacAddress,
+        yaw: i16,
+        pitch: i8,
+        roll: i16,
+    ) -> PicaResult<()> {
+        self.command(|status_tx| {
+            PicaCommand::SetOrientation(mac_address, yaw, pitch, roll, status_tx)
+        })
+        .await
+    }
+
+    /// Move the anchor or device along a path, or stop its motion if None.
+    pub async fn set_path(
+        &self,
+        mac_address: MacAddress,
+        path: Option<MotionPath>,
+    ) -> PicaResult<()> {
+        self.command(|status_tx| PicaCommand::SetPath(mac_address, path, status_tx))
+            .await
+    }
+
+    /// Move the anchor or device with a velocity and acceleration, or stop
+    /// its motion if None.
+    pub async fn set_kinematics(
+        &self,
+        mac_address: MacAddress,
+        kinematics: Option<Kinematics>,
+    ) -> PicaResult<()> {
+        self.command(|status_tx| PicaCommand::SetKinematics(mac_address, kinematics, status_tx))
+            .await
+    }
+
+    /// Play back a recorded trajectory with the anchor or device, or stop
+    /// its motion if None.
+    pub async fn set_trajectory(
+        &self,
+        mac_address: MacAddress,
+        trajectory: Option<RecordedTrajectory>,
+    ) -> PicaResult<()> {
+        self.command(|status_tx| PicaCommand::SetTrajectory(mac_address, trajectory, status_tx))
+            .await
+    }
+
+    /// Start ranging from the anchor as controller of the selected session.
+    pub async fn start_anchor_ranging(
+        &self,
+        mac_address: MacAddress,
+        session_id: u32,
+        interval: Duration,
+    ) -> PicaResult<()> {
+        self.command(|status_tx| {
+            PicaCommand::StartAnchorRanging(mac_address, session_id, interval, status_tx)
+        })
+        .await
+    }
+
+    pub async fn stop_anchor_ranging(&self, mac_address: MacAddress) -> PicaResult<()> {
+        self.command(|status_tx| PicaCommand::StopAnchorRanging(mac_address, status_tx))
+            .await
+    }
+
+    /// Send arbitrary bytes to the host of the UCI device.
+    pub async fn inject_packet(&self, mac_address: MacAddress, bytes: Bytes) -> PicaResult<()> {
+        self.command(|status_tx| PicaCommand::InjectPacket(mac_address, bytes, status_tx))
+            .await
+    }
+
+    pub async fn set_time_mode(&self, time_mode: TimeMode) -> PicaResult<()> {
+        self.command(|status_tx| PicaCommand::SetTimeMode(time_mode, status_tx))
+            .await
+    }
+
+    /// Advance the simulated time, in the stepped time mode.
+    pub async fn advance_time(&self, duration: Duration) -> PicaResult<()> {
+        self.command(|status_tx| PicaCommand::AdvanceTime(duration, status_tx))
+            .await
+    }
+
+    /// Snapshot of the devices, anchors and sessions.
+    pub async fn get_state(&self) -> PicaResult<PicaState> {
+        self.request(PicaCommand::GetState).await
+    }
+
+    pub async fn get_simulator_info(&self) -> PicaResult<SimulatorInfo> {
+        self.request(PicaCommand::GetSimulatorInfo).await
+    }
+
+    /// Subscribe to the events emitted from now on.
+    pub fn subscribe_events(&self) -> broadcast::Receiver<SequencedEvent> {
+        self.event_tx.subscribe()
+    }
+
+    /// Stream of the events emitted from now on, see [`crate::Pica::events`].
+    pub fn events(
+        &self,
+        lag_policy: LagPolicy,
+    ) -> impl Stream<Item = Result<PicaEvent, EventsLagged>> + Send + Unpin {
+        event_stream(self.event_tx.subscribe(), lag_policy)
+    }
+
+    /// Disconnect the devices and stop the simulation.
+    pub async fn shutdown(&self) -> PicaResult<()> {
+        self.request(PicaCommand::Shutdown).await
+    }
+}
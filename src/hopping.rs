@@ -0,0 +1,77 @@
+// Copyright 2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Channel and preamble code hopping of the ranging rounds, when the
+//! HOPPING_MODE app config parameter is enabled. The hop sequence is
+//! shared by the participants of a session: peers hopping together keep
+//! ranging, while peers with hopping disabled only take part in the
+//! rounds hopping to their configured channel and preamble code.
+
+use crate::seed::{derived_rng, Stream};
+use rand::seq::SliceRandom;
+
+/// Channels of the hop sequence, supported by most UWB devices.
+const HOPPING_CHANNELS: &[u8] = &[5, 9];
+
+/// Preamble codes of the base pulse repetition frequency.
+const BPRF_PREAMBLE_CODES: &[u8] = &[9, 10, 11, 12];
+
+/// Preamble codes of the higher pulse repetition frequency.
+const HPRF_PREAMBLE_CODES: &[u8] = &[25, 26, 27, 28, 29, 30, 31, 32];
+
+/// Channel and preamble code of a ranging round.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Hop {
+    pub channel_number: u8,
+    pub preamble_code_index: u8,
+}
+
+/// Hop of the selected ranging block of a session. The preamble code
+/// hops among the codes of the pulse repetition frequency of the
+/// configured code.
+pub fn hop(seed: u64, session_id: u32, block_index: u32, preamble_code_index: u8) -> Hop {
+    let mut rng = derived_rng(
+        seed,
+        Stream::Hopping,
+        &[session_id as u64, block_index as u64],
+    );
+    let preamble_codes = if HPRF_PREAMBLE_CODES.contains(&preamble_code_index) {
+        HPRF_PREAMBLE_CODES
+    } else {
+        BPRF_PREAMBLE_CODES
+    };
+    Hop {
+        channel_number: *HOPPING_CHANNELS.choose(&mut rng).unwrap(),
+        preamble_code_index: *preamble_codes.choose(&mut rng).unwrap(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hop_sequence() {
+        assert_eq!(hop(42, 1, 7, 10), hop(42, 1, 7, 10));
+        let hops = (0..64).map(|block_index| hop(42, 1, block_index, 10));
+        let channels = hops.clone().map(|hop| hop.channel_number);
+        assert!(HOPPING_CHANNELS
+            .iter()
+            .all(|channel| channels.clone().any(|hopped| hopped == *channel)));
+        assert!(hops
+            .map(|hop| hop.preamble_code_index)
+            .all(|code| BPRF_PREAMBLE_CODES.contains(&code)));
+        assert!(HPRF_PREAMBLE_CODES.contains(&hop(42, 1, 7, 25).preamble_code_index));
+    }
+}
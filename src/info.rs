@@ -0,0 +1,132 @@
+// Copyright 2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Description of the capabilities of the running pica build.
+
+use serde::Serialize;
+
+use crate::device::{MAC_VERSION, MAX_DEVICE, PHY_VERSION, TEST_VERSION, UCI_VERSION};
+use crate::regulatory;
+use crate::session::{MAX_DATA_CREDITS, MAX_SESSION};
+use crate::MAX_ANCHOR;
+
+/// Cargo features enabled in this build.
+const FEATURES: &[(&str, bool)] = &[
+    ("web", cfg!(feature = "web")),
+    ("sqlite", cfg!(feature = "sqlite")),
+    ("tls", cfg!(feature = "tls")),
+    ("serial", cfg!(feature = "serial")),
+    ("python", cfg!(feature = "python")),
+    ("console", cfg!(feature = "console")),
+    ("schema", cfg!(feature = "schema")),
+    ("scripting", cfg!(feature = "scripting")),
+    ("gpx", cfg!(feature = "gpx")),
+    ("mqtt", cfg!(feature = "mqtt")),
+];
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct SimulatorInfo {
+    /// Version of the pica crate.
+    pub version: &'static str,
+    /// Enabled cargo features.
+    pub features: Vec<&'static str>,
+    pub uci_version: String,
+    pub mac_version: String,
+    pub phy_version: String,
+    pub test_version: String,
+    /// Maximum number of connected UCI devices.
+    pub max_devices: usize,
+    /// Maximum number of anchors.
+    pub max_anchors: usize,
+    /// Maximum number of sessions per device.
+    pub max_sessions: usize,
+    /// Number of data credits per session.
+    pub max_data_credits: usize,
+    /// Maximum ranging distance (cm) without a country code.
+    pub max_range: u16,
+    /// Seed of the random generators of the simulation.
+    pub seed: u64,
+}
+
+/// Format a version reported in CORE_GET_DEVICE_INFO_RSP:
+/// the first octet is the major version, the second octet holds
+/// the minor version and maintenance number.
+fn format_version(version: u16) -> String {
+    let [major, minor] = version.to_le_bytes();
+    format!("{}.{}.{}", major, minor >> 4, minor & 0xf)
+}
+
+impl SimulatorInfo {
+    pub fn new() -> Self {
+        SimulatorInfo {
+            version: env!("CARGO_PKG_VERSION"),
+            features: FEATURES
+                .iter()
+                .filter(|(_, enabled)| *enabled)
+                .map(|(name, _)| *name)
+                .collect(),
+            uci_version: format_version(UCI_VERSION),
+            mac_version: format_version(MAC_VERSION),
+            phy_version: format_version(PHY_VERSION),
+            test_version: format_version(TEST_VERSION),
+            max_devices: MAX_DEVICE,
+            max_anchors: MAX_ANCHOR,
+            max_sessions: MAX_SESSION,
+            max_data_credits: MAX_DATA_CREDITS,
+            max_range: regulatory::default_max_range(),
+            seed: 0,
+        }
+    }
+}
+
+impl Default for SimulatorInfo {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn versions() {
+        assert_eq!(format_version(0x0002), "2.0.0");
+        assert_eq!(format_version(0x3001), "1.3.0");
+        assert_eq!(format_version(0x2101), "1.2.1");
+    }
+
+    #[test]
+    fn features_are_listed() {
+        // Every feature of the manifest is reported when enabled.
+        let manifest = include_str!("../Cargo.toml");
+        let features = manifest
+            .split("[features]")
+            .nth(1)
+            .unwrap()
+            .lines()
+            .skip(1)
+            .take_while(|line| !line.trim().is_empty() && !line.starts_with('['))
+            .filter_map(|line| line.split('=').next().map(str::trim))
+            .filter(|name| *name != "default");
+        for feature in features {
+            assert!(
+                FEATURES.iter().any(|(name, _)| *name == feature),
+                "feature {} is not listed",
+                feature
+            );
+        }
+    }
+}
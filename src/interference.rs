@@ -0,0 +1,71 @@
+// Copyright 2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Interference between the sessions ranging on the same channel
+//! in overlapping ranging rounds.
+
+use rand::rngs::StdRng;
+use rand::Rng;
+
+use crate::noise;
+
+/// Degradation of the measurements of a ranging round for each session
+/// interfering with the round. Sessions do not interfere with the
+/// default model.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct InterferenceModel {
+    /// Probability that a measurement is lost, for each interfering session.
+    pub drop_probability: f32,
+    /// Standard deviation of the distance error added to the measurements
+    /// which are not lost, for each interfering session (cm).
+    pub distance_noise: f32,
+}
+
+impl InterferenceModel {
+    pub fn is_enabled(&self) -> bool {
+        self.drop_probability > 0.0 || self.distance_noise > 0.0
+    }
+
+    /// Draw whether a measurement is lost to the interfering sessions.
+    pub fn drops(&self, rng: &mut StdRng, interferers: usize) -> bool {
+        let delivered = (1.0 - self.drop_probability.clamp(0.0, 1.0)).powi(interferers as i32);
+        interferers > 0 && rng.gen::<f32>() >= delivered
+    }
+
+    /// Add the distance error caused by the interfering sessions.
+    pub fn degrade(&self, rng: &mut StdRng, interferers: usize, distance: u16) -> u16 {
+        let std_dev = self.distance_noise * (interferers as f32).sqrt();
+        (distance as f32 + noise::sample(rng, std_dev)).clamp(0.0, u16::MAX as f32) as u16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+
+    #[test]
+    fn interferers() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let model = InterferenceModel {
+            drop_probability: 0.5,
+            distance_noise: 10.0,
+        };
+        assert!(!model.drops(&mut rng, 0));
+        assert_eq!(model.degrade(&mut rng, 0, 100), 100);
+        let dropped = (0..1000).filter(|_| model.drops(&mut rng, 2)).count();
+        assert!((700..800).contains(&dropped), "{}", dropped);
+        assert!(!InterferenceModel::default().is_enabled());
+    }
+}
@@ -0,0 +1,100 @@
+// Copyright 2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Latency added to the delivery of the UCI notifications, to exercise
+//! the timeouts of the hosts.
+
+use rand::rngs::StdRng;
+use rand::Rng;
+use rand_distr::{Distribution, Exp, Normal};
+use serde::Deserialize;
+use std::time::Duration;
+
+/// Distribution of the random part of the notification latency.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "lowercase")]
+pub enum JitterDistribution {
+    /// Uniform between zero and the jitter.
+    #[default]
+    Uniform,
+    /// Absolute value of a gaussian with the jitter as standard deviation.
+    Normal,
+    /// Exponential with the jitter as mean.
+    Exponential,
+}
+
+/// Delay between the simulated events and the delivery of the
+/// notifications to the host. Notifications are delivered immediately
+/// with the default latency.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct NotificationLatency {
+    /// Fixed part of the latency.
+    pub delay: Duration,
+    /// Scale of the random part of the latency.
+    pub jitter: Duration,
+    pub distribution: JitterDistribution,
+}
+
+impl NotificationLatency {
+    /// Draw the latency of a notification.
+    pub fn sample(&self, rng: &mut StdRng) -> Duration {
+        let jitter = self.jitter.as_secs_f64();
+        if jitter == 0.0 {
+            return self.delay;
+        }
+        let jitter = match self.distribution {
+            JitterDistribution::Uniform => rng.gen_range(0.0..=jitter),
+            JitterDistribution::Normal => Normal::new(0.0, jitter).unwrap().sample(rng).abs(),
+            JitterDistribution::Exponential => Exp::new(1.0 / jitter).unwrap().sample(rng),
+        };
+        self.delay + Duration::from_secs_f64(jitter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+
+    #[test]
+    fn sample() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let delay = Duration::from_millis(20);
+        let fixed = NotificationLatency {
+            delay,
+            ..Default::default()
+        };
+        assert_eq!(fixed.sample(&mut rng), delay);
+
+        for distribution in [
+            JitterDistribution::Uniform,
+            JitterDistribution::Normal,
+            JitterDistribution::Exponential,
+        ] {
+            let latency = NotificationLatency {
+                delay,
+                jitter: Duration::from_millis(5),
+                distribution,
+            };
+            assert!((0..100).all(|_| latency.sample(&mut rng) >= delay));
+        }
+        let uniform = NotificationLatency {
+            delay,
+            jitter: Duration::from_millis(5),
+            distribution: JitterDistribution::Uniform,
+        };
+        assert!((0..100).all(|_| uniform.sample(&mut rng) <= Duration::from_millis(25)));
+    }
+}
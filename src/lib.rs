@@ -12,22 +12,62 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use anyhow::Result;
 use bytes::Bytes;
 use pdl_runtime::Packet;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::fmt::Display;
 use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use thiserror::Error;
-use tokio::io::AsyncReadExt;
-use tokio::net::TcpStream;
+use tokio::io::{
+    AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter, DuplexStream, ReadHalf,
+    WriteHalf,
+};
 use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio::time;
+use tokio_stream::Stream;
+use tracing::{debug, error, info, warn, Instrument};
 
-mod pcapng;
+mod capture;
+pub use capture::{CaptureFilter, CaptureFormat, CaptureRotation, CapturedPackets};
+
+mod clock;
+pub use clock::{Clock, MAX_DRIFT_PPM};
+
+mod latency;
+pub use latency::{JitterDistribution, NotificationLatency};
+
+mod fault;
+pub use fault::{CrashRecovery, ResponseAction, ResponseFault};
+
+mod timeline;
+pub use timeline::TimeMode;
+use timeline::Timeline;
+
+mod seed;
+
+mod hopping;
+use seed::derived_rng;
+
+mod trace;
+
+mod tap;
+use tap::Tap;
+pub use tap::{PacketDirection, TappedPacket};
+
+mod framing;
+pub use framing::PacketReassembler;
+
+mod geodetic;
+pub use geodetic::{GeodeticPosition, TangentPlane};
 
 mod position;
-pub use position::Position;
+pub use position::{
+    AngleConvention, AoaCapability, AzimuthReference, FieldOfView, Handedness, LengthUnit,
+    Obstacle, Position, Scene, Zone,
+};
 
 mod packets;
 
@@ -35,35 +75,186 @@ use packets::uci::StatusCode as UciStatusCode;
 use packets::uci::*;
 
 mod device;
-use device::{Device, MAX_DEVICE};
+use device::{device_span, Device};
 
 mod session;
-use session::{AppConfig, MAX_SESSION};
+pub use session::SessionInfo;
+use session::{AppConfig, Session};
 
 mod mac_address;
 pub use mac_address::MacAddress;
 
+mod regulatory;
+
+mod rf_test;
+
+mod power;
+
+mod radar;
+
+mod scheduler;
+
+mod builder;
+pub use builder::PicaBuilder;
+
+mod profile;
+pub use profile::{DeviceProfile, Personality};
+
+mod noise;
+pub use noise::{MeasurementNoise, MeasurementOutliers};
+
+mod interference;
+pub use interference::InterferenceModel;
+
+mod rssi;
+pub use rssi::RssiModel;
+
+mod fom;
+pub use fom::FomModel;
+use fom::OUT_OF_FOV_FOM;
+
+mod motion;
+pub use motion::{Kinematics, MotionPath, PathMode};
+use motion::{Motion, Trajectory, MOTION_UPDATE_INTERVAL};
+
+mod trajectory;
+pub use trajectory::RecordedTrajectory;
+
+mod info;
+pub use info::SimulatorInfo;
+
+mod constellation;
+pub use constellation::{Constellation, Shape};
+
+mod solver;
+
+mod command_log;
+pub use command_log::{CommandLog, CommandReplay};
+
+mod error;
+pub use error::{PicaError, PicaResult};
+
+mod handle;
+pub use handle::PicaHandle;
+
+mod events;
+pub use events::{EventsLagged, LagPolicy};
+
+#[cfg(feature = "sqlite")]
+mod event_log;
+#[cfg(feature = "sqlite")]
+pub use event_log::{EventLog, EventLogFilter, LoggedEvent, LoggedMeasurement};
+
+#[cfg(feature = "python")]
+mod python;
+
+#[cfg(feature = "web")]
+pub mod web;
+
+mod feed;
+pub use feed::PositionFeed;
+
+#[cfg(feature = "mqtt")]
+mod mqtt;
+#[cfg(feature = "mqtt")]
+pub use mqtt::{EventPublisher, MqttEndpoint};
+
+#[cfg(feature = "scripting")]
+mod script;
+#[cfg(feature = "scripting")]
+pub use script::Script;
+
+mod metrics;
+pub use metrics::Metrics;
+
+mod statistics;
+use statistics::LinkStatistics;
+pub use statistics::LinkSummary;
+
 use crate::session::RangeDataNtfConfig;
 
-/// Size of UCI packet first octet
-const COMMON_HEADER_SIZE: usize = 1;
 /// Size of UCI packet headers.
 const HEADER_SIZE: usize = 4;
 /// Maximum size of an UCI control packet payload.
 const MAX_CTRL_PACKET_PAYLOAD_SIZE: usize = 255;
 /// Maximum size of an UCI data packet payload.
 const MAX_DATA_PACKET_PAYLOAD_SIZE: usize = 1024;
+/// Maximum number of anchors.
+pub(crate) const MAX_ANCHOR: usize = 256;
+/// Capacity of the channels of the bytes injected into the connections.
+const INJECTED_PACKET_CAPACITY: usize = 16;
+/// Capacity of the channels of the packets sent by the devices to their
+/// connection. The packets are dropped when the host stops reading and
+/// the channel is full.
+const DEVICE_PACKET_CAPACITY: usize = 1024;
+/// Capacity of the write queues of the connections, in segments. The
+/// device task waits for the host to read when its queue is full.
+const WRITE_QUEUE_CAPACITY: usize = 64;
+/// Maximum duration of the writes of the queued bytes when closing
+/// the connections, after which the hosts not reading are dropped.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+/// Capacity of the in-process streams, in bytes.
+const IN_PROCESS_BUFFER_SIZE: usize = 4096;
+/// Number of recent events retained for the subscribers catching up.
+pub const EVENT_HISTORY_SIZE: usize = 256;
+/// Version of the JSON encoding of the events, incremented on
+/// incompatible changes. Version 1 is the untagged encoding, where
+/// the kind of an event is only known from its name.
+pub const EVENT_VERSION: u32 = 2;
+
+/// Byte stream carrying the UCI packets exchanged with a device,
+/// e.g. a TCP connection.
+pub trait Transport: AsyncRead + AsyncWrite + std::fmt::Debug + Send + Sync + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + std::fmt::Debug + Send + Sync + Unpin> Transport for T {}
 
 struct Connection {
-    socket: TcpStream,
-    pcapng_file: Option<pcapng::File>,
+    reader: ReadHalf<Box<dyn Transport>>,
+    /// Queue of the bytes written to the host by the writer task,
+    /// taken when the connection is closed.
+    write_tx: Option<mpsc::Sender<Vec<u8>>>,
+    /// Aborted when the connection is dropped.
+    writer: JoinHandle<()>,
+    reassembler: PacketReassembler,
+    capture_file: Option<capture::File>,
+    trace_file: Option<trace::File>,
+    tap: Tap,
 }
 
 impl Connection {
-    fn new(socket: TcpStream, pcapng_file: Option<pcapng::File>) -> Self {
+    fn new(
+        socket: Box<dyn Transport>,
+        capture_file: Option<capture::File>,
+        trace_file: Option<trace::File>,
+        tap: Tap,
+    ) -> Self {
+        let (reader, writer) = tokio::io::split(socket);
+        let (write_tx, write_rx) = mpsc::channel(WRITE_QUEUE_CAPACITY);
         Connection {
-            socket,
-            pcapng_file,
+            reader,
+            write_tx: Some(write_tx),
+            writer: tokio::spawn(write_queued(writer, write_rx).in_current_span()),
+            reassembler: PacketReassembler::new(),
+            capture_file,
+            trace_file,
+            tap,
+        }
+    }
+
+    /// Close the connection, after the queued bytes are written or the
+    /// host stopped reading for [`CLOSE_TIMEOUT`], finalizing the capture
+    /// file if any.
+    async fn close(mut self) {
+        self.write_tx.take();
+        match time::timeout(CLOSE_TIMEOUT, &mut self.writer).await {
+            Ok(result) => result.unwrap_or_else(|err| warn!("Connection writer failed: {}", err)),
+            Err(_) => warn!("Host not reading, dropping the queued bytes"),
+        }
+        if let Some(capture_file) = self.capture_file.take() {
+            capture_file
+                .close()
+                .await
+                .unwrap_or_else(|err| warn!("Failed to close capture file: {}", err));
         }
     }
 
@@ -71,64 +262,71 @@ impl Connection {
     /// Control packets are automatically re-assembled if segmented on the UCI transport.
     /// Data packets fragments are returned immediately, as each fragment needs to be
     /// acknowledged by a credit notification.
-    /// Interspersing data and control segments is not supported.
-    async fn read(&mut self) -> Result<Vec<u8>> {
-        let mut complete_packet = vec![0; HEADER_SIZE];
-
-        // Note on reassembly:
-        // For each segment of a Control Message, the
-        // header of the Control Packet SHALL contain the same MT, GID and OID
-        // values.
-        // It is correct to keep only the last header of the segmented packet.
+    /// The bytes received past the packet are kept for the next read, so that the
+    /// read can be cancelled without losing data.
+    async fn read(&mut self) -> PicaResult<Vec<u8>> {
         loop {
-            // Read the common packet header.
-            self.socket
-                .read_exact(&mut complete_packet[0..HEADER_SIZE])
-                .await?;
-            let common_packet_header =
-                PacketHeader::parse(&complete_packet[0..COMMON_HEADER_SIZE])?;
-
-            // Read the packet payload.
-            let payload_length = match common_packet_header.get_mt() {
-                MessageType::Data => {
-                    let data_packet_header =
-                        DataPacketHeader::parse(&complete_packet[0..HEADER_SIZE])?;
-                    data_packet_header.get_payload_length() as usize
+            while let Some(segment) = self.reassembler.next_segment() {
+                let packet = self.reassembler.push_segment(&segment);
+                if let Some(ref mut capture_file) = self.capture_file {
+                    capture_file
+                        .write(&segment, capture::Direction::Tx)
+                        .await
+                        .map_err(PicaError::Capture)?;
                 }
-                _ => {
-                    let control_packet_header =
-                        ControlPacketHeader::parse(&complete_packet[0..HEADER_SIZE])?;
-                    control_packet_header.get_payload_length() as usize
+                if let Some(ref mut trace_file) = self.trace_file {
+                    trace_file
+                        .write(&segment, capture::Direction::Tx)
+                        .await
+                        .map_err(PicaError::Capture)?;
+                }
+                self.tap.send(PacketDirection::HostToDevice, &segment);
+                if let Some(packet) = packet {
+                    return Ok(packet);
                 }
-            };
-            let mut payload_bytes = vec![0; payload_length];
-            self.socket.read_exact(&mut payload_bytes).await?;
-            complete_packet.extend(&payload_bytes);
-
-            if let Some(ref mut pcapng_file) = self.pcapng_file {
-                let mut packet_bytes = vec![];
-                packet_bytes.extend(&complete_packet[0..HEADER_SIZE]);
-                packet_bytes.extend(&payload_bytes);
-                pcapng_file
-                    .write(&packet_bytes, pcapng::Direction::Tx)
-                    .await?;
             }
 
-            if common_packet_header.get_mt() == MessageType::Data {
-                return Ok(complete_packet);
+            let mut bytes = [0; HEADER_SIZE + MAX_DATA_PACKET_PAYLOAD_SIZE];
+            match self.reader.read(&mut bytes).await? {
+                0 => return Err(PicaError::ConnectionClosed),
+                length => self.reassembler.extend_from_slice(&bytes[..length]),
             }
+        }
+    }
 
-            // Check the Packet Boundary Flag.
-            match common_packet_header.get_pbf() {
-                PacketBoundaryFlag::Complete => return Ok(complete_packet),
-                PacketBoundaryFlag::NotComplete => (),
-            }
+    /// Write bytes to the socket verbatim, without segmentation, e.g.
+    /// deliberately malformed packets.
+    async fn write_raw(&mut self, bytes: &[u8]) -> PicaResult<()> {
+        if let Some(ref mut capture_file) = self.capture_file {
+            capture_file
+                .write(bytes, capture::Direction::Rx)
+                .await
+                .map_err(PicaError::Capture)?
+        }
+        if let Some(ref mut trace_file) = self.trace_file {
+            trace_file
+                .write(bytes, capture::Direction::Rx)
+                .await
+                .map_err(PicaError::Capture)?
         }
+        self.tap.send(PacketDirection::DeviceToHost, bytes);
+        self.enqueue(bytes.to_vec()).await
+    }
+
+    /// Queue bytes for the writer task, waiting while the queue is full.
+    async fn enqueue(&mut self, bytes: Vec<u8>) -> PicaResult<()> {
+        let Some(write_tx) = &self.write_tx else {
+            return Err(PicaError::ConnectionClosed);
+        };
+        write_tx
+            .send(bytes)
+            .await
+            .map_err(|_| PicaError::ConnectionClosed)
     }
 
     /// Write a single UCI packet to the writer. The packet is automatically
     /// segmented if the payload exceeds the maximum size limit.
-    async fn write(&mut self, mut packet: &[u8]) -> Result<()> {
+    async fn write(&mut self, mut packet: &[u8]) -> PicaResult<()> {
         let mut header_bytes = [packet[0], packet[1], packet[2], 0];
         packet = &packet[HEADER_SIZE..];
 
@@ -159,18 +357,27 @@ impl Connection {
                 _ => header_bytes[3] = chunk_length as u8,
             }
 
-            if let Some(ref mut pcapng_file) = self.pcapng_file {
-                let mut packet_bytes = vec![];
-                packet_bytes.extend(&header_bytes);
-                packet_bytes.extend(&packet[..chunk_length]);
-                pcapng_file
-                    .write(&packet_bytes, pcapng::Direction::Rx)
-                    .await?
+            let mut packet_bytes = Vec::with_capacity(HEADER_SIZE + chunk_length);
+            packet_bytes.extend(&header_bytes);
+            packet_bytes.extend(&packet[..chunk_length]);
+            if self.capture_file.is_some() || self.trace_file.is_some() || self.tap.is_active() {
+                if let Some(ref mut capture_file) = self.capture_file {
+                    capture_file
+                        .write(&packet_bytes, capture::Direction::Rx)
+                        .await
+                        .map_err(PicaError::Capture)?
+                }
+                if let Some(ref mut trace_file) = self.trace_file {
+                    trace_file
+                        .write(&packet_bytes, capture::Direction::Rx)
+                        .await
+                        .map_err(PicaError::Capture)?
+                }
+                self.tap.send(PacketDirection::DeviceToHost, &packet_bytes);
             }
 
-            // Write the header and payload segment bytes.
-            self.socket.try_write(&header_bytes)?;
-            self.socket.try_write(&packet[..chunk_length])?;
+            // Queue the header and payload segment bytes.
+            self.enqueue(packet_bytes).await?;
             packet = &packet[chunk_length..];
 
             if packet.is_empty() {
@@ -180,6 +387,37 @@ impl Connection {
     }
 }
 
+impl Drop for Connection {
+    fn drop(&mut self) {
+        // Stop writing to a host not reading when the connection task
+        // is aborted, the default behaviour when dropping a task handle
+        // is to detach the task.
+        self.writer.abort();
+    }
+}
+
+/// Write the queued bytes to the host, in order, until the queue is closed
+/// or the host stops reading. The writes are flushed when the queue is empty.
+/// Each write waits for the host to make room in the transport, unlike the
+/// non-blocking writes of the TCP sockets which failed when the socket
+/// buffer was full; the queue keeps the device task from waiting as well.
+async fn write_queued(
+    writer: WriteHalf<Box<dyn Transport>>,
+    mut write_rx: mpsc::Receiver<Vec<u8>>,
+) {
+    let mut writer = BufWriter::new(writer);
+    while let Some(bytes) = write_rx.recv().await {
+        let mut result = writer.write_all(&bytes).await;
+        if result.is_ok() && write_rx.is_empty() {
+            result = writer.flush().await;
+        }
+        if let Err(err) = result {
+            debug!("Closing connection writer: {}", err);
+            return;
+        }
+    }
+}
+
 // Extract the message type from the first 3 bits of the passed (header) byte
 fn get_message_type(byte: u8) -> MessageType {
     MessageType::try_from((byte >> 5) & 0x7).unwrap_or(MessageType::Command)
@@ -187,61 +425,278 @@ fn get_message_type(byte: u8) -> MessageType {
 
 pub type PicaCommandStatus = Result<(), PicaCommandError>;
 
+/// First vendor reserved UCI group identifier.
+const VENDOR_GID_MIN: u8 = 0x9;
+/// Last vendor reserved UCI group identifier.
+const VENDOR_GID_MAX: u8 = 0xf;
+
+/// Packets returned by a vendor command handler.
+/// The packets are unframed: segmentation is performed by pica.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct VendorCommandOutput {
+    /// Response to the vendor command.
+    pub response: Vec<u8>,
+    /// Notifications sent after the response.
+    pub notifications: Vec<Vec<u8>>,
+}
+
+/// Handler for vendor commands, invoked with the handle of the device
+/// which received the command and the raw command bytes.
+pub type VendorCommandHandler = Box<dyn FnMut(usize, &[u8]) -> VendorCommandOutput + Send>;
+
+/// Action selected by a command hook for a UCI command.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CommandHookAction {
+    /// Process the command unchanged.
+    Continue,
+    /// Process the selected command instead, e.g. the command with
+    /// modified parameters.
+    Replace(Vec<u8>),
+    /// Answer the command with the selected response and notifications,
+    /// without processing it.
+    Respond(VendorCommandOutput),
+    /// Drop the command without response.
+    Drop,
+}
+
+/// Hook invoked for each UCI command received from the hosts before it is
+/// processed, with the handle of the device which received the command
+/// and the raw command bytes.
+pub type CommandHook = Box<dyn FnMut(usize, &[u8]) -> CommandHookAction + Send>;
+
 #[derive(Error, Debug, Clone, PartialEq, Eq)]
 pub enum PicaCommandError {
     #[error("Device already exists: {0}")]
     DeviceAlreadyExists(MacAddress),
     #[error("Device not found: {0}")]
     DeviceNotFound(MacAddress),
+    #[error("Session not found: {0}:0x{1:x}")]
+    SessionNotFound(MacAddress, u32),
+    #[error("Invalid position: {0}")]
+    InvalidPosition(String),
+    #[error("Invalid MAC address: {0}")]
+    InvalidMacFormat(String),
+    #[error("Limit exceeded: at most {1} {0}")]
+    LimitExceeded(&'static str, usize),
+    #[error("Invalid argument: {0}")]
+    InvalidArgument(String),
 }
 
 #[derive(Debug)]
 pub enum PicaCommand {
-    // Connect a new device.
-    Connect(TcpStream),
+    // Connect a new device. The origin describes the endpoint the
+    // device is connected through, e.g. `tcp:127.0.0.1:7000`.
+    Connect(Box<dyn Transport>, String),
     // Disconnect the selected device.
     Disconnect(usize),
     // Execute ranging command for selected device and session.
     Ranging(usize, u32),
-    // Send an in-band request to stop ranging to a peer controlee identified by address and session id.
-    StopRanging(MacAddress, u32),
+    // Generate the next burst of sweeps of a radar session
+    // of the selected device.
+    RadarBurst(usize, u32),
+    // Report the state transition of a session of the selected device.
+    SessionUpdated(usize, u32, SessionState, ReasonCode),
+    // Send an in-band request to stop ranging from a controller to a peer controlee,
+    // identified by their addresses and session id.
+    StopRanging(MacAddress, MacAddress, u32),
     // Execute data message send for selected device and data.
     UciData(usize, DataPacket),
     // Execute UCI command received for selected device.
     UciCommand(usize, UciCommand),
-    // Init Uci Device
-    InitUciDevice(MacAddress, Position, oneshot::Sender<PicaCommandStatus>),
+    // Report a malformed UCI packet received from the selected device.
+    MalformedPacket(usize, String),
+    // Init Uci Device. The position of a device claiming the address of
+    // a disconnected device is restored if None.
+    InitUciDevice(
+        MacAddress,
+        Option<Position>,
+        oneshot::Sender<PicaCommandStatus>,
+    ),
     // Set Position
     SetPosition(MacAddress, Position, oneshot::Sender<PicaCommandStatus>),
+    // Set the positions of several anchors or devices at once, e.g. the
+    // nodes tracked in a frame of a motion capture system.
+    SetPositions(
+        Vec<(MacAddress, Position)>,
+        oneshot::Sender<PicaCommandStatus>,
+    ),
+    // Change the orientation (yaw, pitch, roll) of the anchor or device,
+    // keeping its coordinates.
+    SetOrientation(MacAddress, i16, i8, i16, oneshot::Sender<PicaCommandStatus>),
+    // Select the field of view of the angle of arrival measurements
+    // of the anchor or device.
+    SetFieldOfView(MacAddress, FieldOfView, oneshot::Sender<PicaCommandStatus>),
+    // Select the angles of arrival measured by the antennas
+    // of the anchor or device.
+    SetAoaCapability(
+        MacAddress,
+        AoaCapability,
+        oneshot::Sender<PicaCommandStatus>,
+    ),
+    // Select the skew of the local clock of the anchor or device.
+    SetClock(MacAddress, Clock, oneshot::Sender<PicaCommandStatus>),
+    // Select the TX power (dBm/MHz) of the anchor or device, or None
+    // to transmit at the regulatory limit.
+    SetTxPower(MacAddress, Option<f32>, oneshot::Sender<PicaCommandStatus>),
+    // Select the latency of the notifications sent by the device.
+    SetNotificationLatency(
+        MacAddress,
+        NotificationLatency,
+        oneshot::Sender<PicaCommandStatus>,
+    ),
+    // Send arbitrary bytes to the host of the UCI device, as if sent by
+    // the controller. The bytes are neither validated nor segmented.
+    InjectPacket(MacAddress, Bytes, oneshot::Sender<PicaCommandStatus>),
+    // Same as InjectPacket, for the UCI device with the selected handle,
+    // e.g. the handle reported by the traffic tap.
+    InjectUciPacket(usize, Bytes, oneshot::Sender<PicaCommandStatus>),
+    // Replace the faults injected in the responses to the next commands
+    // received by the UCI device.
+    SetResponseFaults(
+        MacAddress,
+        Vec<ResponseFault>,
+        oneshot::Sender<PicaCommandStatus>,
+    ),
+    // Simulate a firmware crash of the UCI device, which reports the error
+    // state and either requires a reset or closes its connection.
+    CrashDevice(
+        MacAddress,
+        CrashRecovery,
+        oneshot::Sender<PicaCommandStatus>,
+    ),
     // Create Anchor
     CreateAnchor(MacAddress, Position, oneshot::Sender<PicaCommandStatus>),
+    // Create anchors at the positions of the constellation, with consecutive
+    // addresses starting from the selected one.
+    CreateConstellation(
+        MacAddress,
+        Constellation,
+        oneshot::Sender<PicaCommandStatus>,
+    ),
     // Destroy Anchor
     DestroyAnchor(MacAddress, oneshot::Sender<PicaCommandStatus>),
+    // Start ranging from the anchor as controller of the selected session,
+    // with the selected ranging interval.
+    StartAnchorRanging(
+        MacAddress,
+        u32,
+        Duration,
+        oneshot::Sender<PicaCommandStatus>,
+    ),
+    // Stop the ranging rounds initiated by the anchor.
+    StopAnchorRanging(MacAddress, oneshot::Sender<PicaCommandStatus>),
+    // Execute a ranging round initiated by the selected anchor.
+    AnchorRanging(MacAddress),
+    // Replace the obstacles of the simulated environment.
+    SetScene(Scene, oneshot::Sender<PicaCommandStatus>),
+    // Replace the zones reporting the nodes entering and leaving them.
+    SetZones(Vec<Zone>, oneshot::Sender<PicaCommandStatus>),
+    // Move the anchor or device along a path, or stop its motion if None.
+    SetPath(
+        MacAddress,
+        Option<MotionPath>,
+        oneshot::Sender<PicaCommandStatus>,
+    ),
+    // Move the anchor or device with a velocity and acceleration, or stop
+    // its motion if None.
+    SetKinematics(
+        MacAddress,
+        Option<Kinematics>,
+        oneshot::Sender<PicaCommandStatus>,
+    ),
+    // Play back a recorded trajectory with the anchor or device, or stop
+    // its motion if None.
+    SetTrajectory(
+        MacAddress,
+        Option<RecordedTrajectory>,
+        oneshot::Sender<PicaCommandStatus>,
+    ),
+    // Update the position of a moving node.
+    UpdateMotion(MacAddress),
+    // Select the seed of the measurement errors of a session, identified by
+    // the device address and session id, or use the global generator if None.
+    SetSessionSeed(
+        MacAddress,
+        u32,
+        Option<u64>,
+        oneshot::Sender<PicaCommandStatus>,
+    ),
+    // Change the progression of the simulated time.
+    SetTimeMode(TimeMode, oneshot::Sender<PicaCommandStatus>),
+    // Advance the simulated time by the selected duration.
+    AdvanceTime(Duration, oneshot::Sender<PicaCommandStatus>),
     // Get State
-    GetState(oneshot::Sender<Vec<(Category, MacAddress, Position)>>),
+    GetState(oneshot::Sender<PicaState>),
+    // Get the retained events with a sequence number greater than the
+    // selected one, in emission order
+    GetEventHistory(u64, oneshot::Sender<Vec<SequencedEvent>>),
+    // Get the rolling ranging statistics of every link
+    GetLinkStatistics(oneshot::Sender<Vec<(MacAddress, MacAddress, LinkSummary)>>),
+    // Get the version, features and limits of the simulator
+    GetSimulatorInfo(oneshot::Sender<SimulatorInfo>),
+    // Get the operational metrics of the simulator
+    GetMetrics(oneshot::Sender<Metrics>),
+    // Close all device connections and return from Pica::run.
+    // The reply is sent once the capture files are flushed.
+    Shutdown(oneshot::Sender<()>),
 }
 
 impl Display for PicaCommand {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let cmd = match self {
-            PicaCommand::Connect(_) => "Connect",
+            PicaCommand::Connect(..) => "Connect",
             PicaCommand::Disconnect(_) => "Disconnect",
             PicaCommand::Ranging(_, _) => "Ranging",
-            PicaCommand::StopRanging(_, _) => "StopRanging",
+            PicaCommand::RadarBurst(_, _) => "RadarBurst",
+            PicaCommand::SessionUpdated(_, _, _, _) => "SessionUpdated",
+            PicaCommand::StopRanging(_, _, _) => "StopRanging",
             PicaCommand::UciData(_, _) => "UciData",
             PicaCommand::UciCommand(_, _) => "UciCommand",
+            PicaCommand::MalformedPacket(_, _) => "MalformedPacket",
             PicaCommand::InitUciDevice(_, _, _) => "InitUciDevice",
             PicaCommand::SetPosition(_, _, _) => "SetPosition",
+            PicaCommand::SetPositions(_, _) => "SetPositions",
+            PicaCommand::SetOrientation(_, _, _, _, _) => "SetOrientation",
+            PicaCommand::SetFieldOfView(_, _, _) => "SetFieldOfView",
+            PicaCommand::SetAoaCapability(_, _, _) => "SetAoaCapability",
+            PicaCommand::SetClock(_, _, _) => "SetClock",
+            PicaCommand::SetTxPower(_, _, _) => "SetTxPower",
+            PicaCommand::SetNotificationLatency(_, _, _) => "SetNotificationLatency",
+            PicaCommand::InjectPacket(_, _, _) => "InjectPacket",
+            PicaCommand::InjectUciPacket(_, _, _) => "InjectUciPacket",
+            PicaCommand::SetResponseFaults(_, _, _) => "SetResponseFaults",
+            PicaCommand::CrashDevice(_, _, _) => "CrashDevice",
             PicaCommand::CreateAnchor(_, _, _) => "CreateAnchor",
+            PicaCommand::CreateConstellation(_, _, _) => "CreateConstellation",
             PicaCommand::DestroyAnchor(_, _) => "DestroyAnchor",
+            PicaCommand::StartAnchorRanging(_, _, _, _) => "StartAnchorRanging",
+            PicaCommand::StopAnchorRanging(_, _) => "StopAnchorRanging",
+            PicaCommand::AnchorRanging(_) => "AnchorRanging",
+            PicaCommand::SetScene(_, _) => "SetScene",
+            PicaCommand::SetZones(_, _) => "SetZones",
+            PicaCommand::SetPath(_, _, _) => "SetPath",
+            PicaCommand::SetKinematics(_, _, _) => "SetKinematics",
+            PicaCommand::SetTrajectory(_, _, _) => "SetTrajectory",
+            PicaCommand::UpdateMotion(_) => "UpdateMotion",
+            PicaCommand::SetSessionSeed(_, _, _, _) => "SetSessionSeed",
+            PicaCommand::SetTimeMode(_, _) => "SetTimeMode",
+            PicaCommand::AdvanceTime(_, _) => "AdvanceTime",
             PicaCommand::GetState(_) => "GetState",
+            PicaCommand::GetEventHistory(_, _) => "GetEventHistory",
+            PicaCommand::GetLinkStatistics(_) => "GetLinkStatistics",
+            PicaCommand::GetSimulatorInfo(_) => "GetSimulatorInfo",
+            PicaCommand::GetMetrics(_) => "GetMetrics",
+            PicaCommand::Shutdown(_) => "Shutdown",
         };
         write!(f, "{}", cmd)
     }
 }
 
+/// Event emitted by pica, encoded in JSON as an object tagged
+/// with the `kind` of the event, e.g. `device-added`.
 #[derive(Clone, Debug, Serialize)]
-#[serde(untagged)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(tag = "kind", rename_all = "kebab-case")]
 pub enum PicaEvent {
     // A Device was added
     DeviceAdded {
@@ -249,6 +704,9 @@ pub enum PicaEvent {
         mac_address: MacAddress,
         #[serde(flatten)]
         position: Position,
+        /// Endpoint the UCI device is connected through.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        origin: Option<String>,
     },
     // A Device was removed
     DeviceRemoved {
@@ -261,6 +719,9 @@ pub enum PicaEvent {
         mac_address: MacAddress,
         #[serde(flatten)]
         position: Position,
+        /// Velocity and acceleration of a moving node.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        kinematics: Option<Kinematics>,
     },
     NeighborUpdated {
         source_category: Category,
@@ -271,136 +732,882 @@ pub enum PicaEvent {
         azimuth: i16,
         elevation: i8,
     },
+    // Periodic summary of the ranging statistics of a link
+    LinkStatisticsUpdated {
+        source_mac_address: MacAddress,
+        destination_mac_address: MacAddress,
+        #[serde(flatten)]
+        summary: LinkSummary,
+    },
+    // In-band ranging control message sent by a controller to a controlee
+    RangingControlMessage {
+        source_mac_address: MacAddress,
+        destination_mac_address: MacAddress,
+        session_id: u32,
+        control: RangingControl,
+        // Whether the controlee was ranging in the session and decoded the message.
+        received: bool,
+    },
+    // Position of a device estimated from its ranging measurements
+    PositionEstimated {
+        mac_address: MacAddress,
+        // Orientation is not estimated, only the coordinates are valid.
+        estimate: Position,
+        ground_truth: Position,
+        // Distance between the estimate and the ground truth (cm).
+        error: f32,
+    },
+    // Channel and preamble code of a ranging round of a session
+    // with the hopping mode enabled
+    ChannelHop {
+        mac_address: MacAddress,
+        session_id: u32,
+        block_index: u32,
+        channel_number: u8,
+        preamble_code_index: u8,
+    },
+    // Sessions ranging on the same channel in rounds overlapping
+    // a ranging round of a session
+    Interference {
+        mac_address: MacAddress,
+        session_id: u32,
+        channel_number: u8,
+        interfering_sessions: Vec<u32>,
+        // Peers whose measurements were lost to the interference.
+        lost_peers: Vec<MacAddress>,
+    },
+    // Malformed UCI packet received from a device
+    MalformedPacket {
+        mac_address: MacAddress,
+        reason: String,
+    },
+    // Measurements of a ranging round, as reported to a participating device
+    RangingData {
+        mac_address: MacAddress,
+        session_id: u32,
+        sequence_number: u32,
+        measurements: Vec<RangingMeasurement>,
+    },
+    // A session of a UCI device changed state
+    SessionUpdated {
+        mac_address: MacAddress,
+        session_id: u32,
+        // One of init, deinit, active, idle.
+        state: &'static str,
+        // Reason code of the session status notification.
+        reason: String,
+    },
+    // An anchor or device entered or left a zone
+    ZoneTransition {
+        category: Category,
+        mac_address: MacAddress,
+        zone: String,
+        // One of enter, leave.
+        transition: &'static str,
+    },
+}
+
+/// Measurement to a peer in a ranging round. The distance and angles
+/// are omitted when the peer did not respond, and the angles
+/// in CCC sessions.
+#[derive(Clone, Debug, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct RangingMeasurement {
+    pub mac_address: MacAddress,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub distance: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub azimuth: Option<i16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub elevation: Option<i8>,
+}
+
+/// Event broadcast by pica, numbered in emission order.
+/// Sequence numbers are contiguous and start at 1, so that observers can
+/// detect missed events from a gap between two consecutive numbers.
+#[derive(Clone, Debug)]
+pub struct SequencedEvent {
+    pub sequence_number: u64,
+    /// Time elapsed since pica was started (µs), from a monotonic clock:
+    /// it never decreases, even when the system clock is adjusted.
+    pub monotonic_us: u64,
+    /// Wall-clock time of the event (ms since the Unix epoch),
+    /// for correlation with external logs.
+    pub timestamp_ms: u64,
+    pub event: PicaEvent,
+}
+
+/// Snapshot of the scene returned by the GetState command.
+#[derive(Clone, Debug)]
+pub struct PicaState {
+    /// Sequence number of the last event sent before the snapshot was taken,
+    /// or 0 if no event was sent. Observers apply only the events with
+    /// a greater sequence number on top of the snapshot.
+    pub sequence_number: u64,
+    pub devices: Vec<(Category, MacAddress, Position)>,
+    /// Sessions of the UCI devices, ordered by device and session identifier.
+    pub sessions: Vec<(MacAddress, SessionInfo)>,
+    /// Endpoints the UCI devices are connected through.
+    pub origins: Vec<(MacAddress, String)>,
+    /// Time of the snapshot, on the clocks of the events.
+    pub monotonic_us: u64,
+    pub timestamp_ms: u64,
+}
+
+impl PicaState {
+    /// Synthetic `DeviceAdded` events recreating the scene of the snapshot,
+    /// numbered with the sequence number of the snapshot so that the
+    /// following live events continue the sequence.
+    pub fn events(&self) -> Vec<SequencedEvent> {
+        self.devices
+            .iter()
+            .map(|(category, mac_address, position)| SequencedEvent {
+                sequence_number: self.sequence_number,
+                monotonic_us: self.monotonic_us,
+                timestamp_ms: self.timestamp_ms,
+                event: PicaEvent::DeviceAdded {
+                    category: *category,
+                    mac_address: *mac_address,
+                    position: *position,
+                    origin: self
+                        .origins
+                        .iter()
+                        .find(|(device, _)| device == mac_address)
+                        .map(|(_, origin)| origin.clone()),
+                },
+            })
+            .collect()
+    }
+}
+
+impl PicaEvent {
+    /// Name of the event, as reported on the web event stream.
+    pub fn name(&self) -> &'static str {
+        match self {
+            PicaEvent::DeviceAdded { .. } => "device-added",
+            PicaEvent::DeviceRemoved { .. } => "device-removed",
+            PicaEvent::DeviceUpdated { .. } => "device-updated",
+            PicaEvent::NeighborUpdated { .. } => "neighbor-updated",
+            PicaEvent::LinkStatisticsUpdated { .. } => "link-statistics-updated",
+            PicaEvent::RangingControlMessage { .. } => "ranging-control-message",
+            PicaEvent::PositionEstimated { .. } => "position-estimated",
+            PicaEvent::ChannelHop { .. } => "channel-hop",
+            PicaEvent::Interference { .. } => "interference",
+            PicaEvent::MalformedPacket { .. } => "malformed-packet",
+            PicaEvent::SessionUpdated { .. } => "session-updated",
+            PicaEvent::RangingData { .. } => "ranging-data",
+            PicaEvent::ZoneTransition { .. } => "zone-transition",
+        }
+    }
+
+    /// Return the address of the device which the event is about,
+    /// and the address of its peer for link events.
+    pub fn mac_addresses(&self) -> (MacAddress, Option<MacAddress>) {
+        match self {
+            PicaEvent::DeviceAdded { mac_address, .. }
+            | PicaEvent::DeviceRemoved { mac_address, .. }
+            | PicaEvent::DeviceUpdated { mac_address, .. }
+            | PicaEvent::PositionEstimated { mac_address, .. }
+            | PicaEvent::ChannelHop { mac_address, .. }
+            | PicaEvent::Interference { mac_address, .. }
+            | PicaEvent::MalformedPacket { mac_address, .. }
+            | PicaEvent::SessionUpdated { mac_address, .. }
+            | PicaEvent::RangingData { mac_address, .. }
+            | PicaEvent::ZoneTransition { mac_address, .. } => (*mac_address, None),
+            PicaEvent::NeighborUpdated {
+                source_mac_address,
+                destination_mac_address,
+                ..
+            }
+            | PicaEvent::LinkStatisticsUpdated {
+                source_mac_address,
+                destination_mac_address,
+                ..
+            }
+            | PicaEvent::RangingControlMessage {
+                source_mac_address,
+                destination_mac_address,
+                ..
+            } => (*source_mac_address, Some(*destination_mac_address)),
+        }
+    }
+}
+
+/// Selection of the events sent to a subscriber, e.g. only the
+/// `neighbor-updated` events of one device. Empty lists select all
+/// the events.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct EventFilter {
+    /// Names of the selected events, e.g. `session-updated`.
+    pub kinds: Vec<String>,
+    /// Devices the selected events are about. Link events are selected
+    /// when either end of the link is listed.
+    pub mac_addresses: Vec<MacAddress>,
+}
+
+impl EventFilter {
+    pub fn matches(&self, event: &PicaEvent) -> bool {
+        let (mac_address, peer_mac_address) = event.mac_addresses();
+        (self.kinds.is_empty() || self.kinds.iter().any(|kind| kind == event.name()))
+            && (self.mac_addresses.is_empty()
+                || self.mac_addresses.contains(&mac_address)
+                || peer_mac_address.is_some_and(|peer| self.mac_addresses.contains(&peer)))
+    }
+}
+
+/// Decoded content of an in-band ranging control message (RCM).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "kebab-case")]
+pub enum RangingControl {
+    /// First RCM of a ranging session, starting the ranging rounds.
+    Start,
+    /// RCM with the "Stop Ranging" bit set.
+    Stop,
+    /// RCM carrying updated ranging parameters.
+    Reconfigure,
 }
 
-#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub enum Category {
     Uci,
     Anchor,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug)]
 struct Anchor {
     mac_address: MacAddress,
     position: Position,
+    /// Field of view of the angle of arrival measurements.
+    field_of_view: FieldOfView,
+    /// Angles of arrival measured by the antennas of the anchor.
+    aoa_capability: AoaCapability,
+    /// Skew of the local clock.
+    clock: Clock,
+    /// Mean EIRP of the transmitted frames (dBm/MHz), or None to transmit
+    /// at the default power limit.
+    tx_power: Option<f32>,
+    /// Ranging rounds initiated by the anchor, when it is configured
+    /// as an active controller.
+    controller: Option<AnchorController>,
+}
+
+/// Properties of a disconnected UCI device, restored when a new
+/// connection claims its address.
+#[derive(Debug)]
+struct RetainedDevice {
+    position: Position,
+    field_of_view: FieldOfView,
+    aoa_capability: AoaCapability,
+    clock: Clock,
+    notification_latency: NotificationLatency,
+    tx_power: Option<f32>,
+}
+
+/// Session of an anchor acting as controller, and task triggering
+/// its ranging rounds.
+#[derive(Debug)]
+struct AnchorController {
+    session_id: u32,
+    /// Interval between the ranging rounds, on the local clock.
+    interval: Duration,
+    task: JoinHandle<()>,
+}
+
+impl AnchorController {
+    fn new(
+        tx: mpsc::Sender<PicaCommand>,
+        mac_address: MacAddress,
+        session_id: u32,
+        interval: Duration,
+        clock: Clock,
+        timeline: Timeline,
+        seed: u64,
+    ) -> Self {
+        let mut rng = derived_rng(seed, seed::Stream::RoundJitter, &[u64::from(&mac_address)]);
+        let task = tokio::spawn(async move {
+            let start = timeline.now();
+            for round_index in 0.. {
+                timeline
+                    .sleep_until(clock.deadline(start, interval * round_index, &mut rng))
+                    .await;
+                if tx
+                    .send(PicaCommand::AnchorRanging(mac_address))
+                    .await
+                    .is_err()
+                {
+                    // Pica is shutting down.
+                    break;
+                }
+            }
+        });
+        AnchorController {
+            session_id,
+            interval,
+            task,
+        }
+    }
+}
+
+impl Drop for AnchorController {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
 }
 
 pub struct Pica {
     devices: HashMap<usize, Device>,
+    /// Handles of the UCI devices indexed by MAC address, updated when
+    /// the devices connect, claim an address, and disconnect.
+    device_handles: HashMap<MacAddress, usize>,
     anchors: HashMap<MacAddress, Anchor>,
+    /// Connection handling tasks indexed by device handle.
+    connections: HashMap<usize, JoinHandle<()>>,
+    /// Senders of the bytes injected into the connections,
+    /// indexed by device handle.
+    injected_packet_txs: HashMap<usize, mpsc::Sender<Bytes>>,
     counter: usize,
     rx: mpsc::Receiver<PicaCommand>,
     tx: mpsc::Sender<PicaCommand>,
-    event_tx: broadcast::Sender<PicaEvent>,
+    event_tx: broadcast::Sender<SequencedEvent>,
+    /// Capacity of the event channel, if created by the builder.
+    event_capacity: Option<usize>,
+    /// Copy of the UCI packets exchanged with the hosts.
+    tap_tx: broadcast::Sender<TappedPacket>,
+    /// Sequence number of the last event sent.
+    sequence_number: u64,
+    /// Most recent events, replayed to the subscribers catching up.
+    event_history: VecDeque<SequencedEvent>,
+    /// Simulated timeline, reference of the monotonic event timestamps.
+    timeline: Timeline,
+    /// Maximum number of connected UCI devices.
+    max_devices: usize,
+    /// Maximum number of sessions per device.
+    max_sessions: usize,
+    /// Maximum number of anchors.
+    max_anchors: usize,
+    /// Maximum ranging distance (cm), beyond the range allowed by the
+    /// TX power of the peers.
+    max_range: u16,
     pcapng_dir: Option<PathBuf>,
+    /// Format, filter and rotation of the capture files.
+    capture_config: capture::Config,
+    /// Output directory of the decoded UCI traces.
+    trace_dir: Option<PathBuf>,
+    /// Cumulative counters of the operational metrics.
+    metrics: Metrics,
+    /// Rolling ranging statistics indexed by (source, destination) link.
+    statistics: HashMap<(MacAddress, MacAddress), LinkStatistics>,
+    /// Vendor command handlers indexed by group identifier.
+    vendor_handlers: HashMap<u8, VendorCommandHandler>,
+    /// Hooks invoked for each UCI command, in order of registration.
+    command_hooks: Vec<CommandHook>,
+    /// Estimate device positions from the ranging measurements.
+    position_solver: bool,
+    /// Convention of the azimuths reported in the ranging measurements.
+    angle_convention: AngleConvention,
+    /// Errors added to the ranging measurements.
+    noise: MeasurementNoise,
+    /// Gross errors added to a fraction of the ranging measurements.
+    outliers: MeasurementOutliers,
+    /// Degradation of the measurements by the sessions ranging
+    /// on the same channel at the same time.
+    interference: InterferenceModel,
+    /// Obstacles blocking the line of sight between the nodes.
+    scene: Scene,
+    /// Zones reporting the nodes entering and leaving them.
+    zones: Vec<Zone>,
+    /// Names of the zones occupied by each node.
+    occupied_zones: HashMap<MacAddress, Vec<String>>,
+    /// Paths followed by the moving anchors and devices.
+    motions: HashMap<MacAddress, Motion>,
+    /// Model of the signal strength reported in the ranging measurements.
+    rssi_model: RssiModel,
+    /// Model of the figure of merit of the angle of arrival results.
+    fom_model: FomModel,
+    /// Personalities exposed by the devices connected from now on.
+    device_profile: DeviceProfile,
+    /// Seed of the simulation, from which the generators of the
+    /// randomized behaviors are derived.
+    seed: u64,
+    /// Retain the properties of the disconnected devices.
+    persistent_identity: bool,
+    /// Properties of the disconnected devices indexed by address.
+    retained_devices: HashMap<MacAddress, RetainedDevice>,
+    /// Log of the control commands.
+    command_log: Option<CommandLog>,
+    /// Persistent log of the events and measurements.
+    #[cfg(feature = "sqlite")]
+    event_log: Option<EventLog>,
 }
 
 /// Result of UCI packet parsing.
-enum UciParseResult {
+pub enum UciParseResult {
     UciCommand(UciCommand),
     UciData(DataPacket),
+    /// The command is not supported: the error response is returned
+    /// to the host.
     Err(Bytes),
-    Skip,
+    /// The packet is malformed: the response or notification is returned
+    /// to the host, along with the parse error.
+    Malformed(Bytes, PicaError),
+    /// The packet is ignored, e.g. responses and notifications, which
+    /// are protocol violations.
+    Ignored(PicaError),
+}
+
+/// Return true if pica decodes the command identified by the group and
+/// opcode. Commands of vendor reserved groups are not validated, since
+/// they can be handled by vendor command handlers, except for the Android
+/// commands implemented by pica.
+fn is_decoded_command(gid: GroupId, opcode: u8) -> bool {
+    let opcodes: &[u8] = match gid {
+        GroupId::Core => &[0x0, 0x2, 0x3, 0x4, 0x5, 0x6, 0x8],
+        GroupId::SessionConfig => &[0x0, 0x1, 0x3, 0x4, 0x5, 0x6, 0x7, 0x9, 0xb, 0xc, 0xd],
+        GroupId::SessionControl => &[0x0, 0x1, 0x3],
+        GroupId::Test => &[0x0, 0x1, 0x2, 0x3, 0x6, 0x7],
+        GroupId::VendorAndroid => &[0x0, 0x1, 0x11, 0x12],
+        _ => &[],
+    };
+    opcodes.contains(&opcode)
+}
+
+/// Check that the command was decoded to a command packet, and that
+/// the payload does not contain any bytes past the decoded fields.
+fn validate_command(cmd: &UciCommand, size: usize) -> std::result::Result<(), String> {
+    let decoded = match cmd.specialize() {
+        UciCommandChild::CoreCommand(cmd) => !matches!(
+            cmd.specialize(),
+            CoreCommandChild::Payload(_) | CoreCommandChild::None
+        ),
+        UciCommandChild::SessionConfigCommand(cmd) => !matches!(
+            cmd.specialize(),
+            SessionConfigCommandChild::Payload(_) | SessionConfigCommandChild::None
+        ),
+        UciCommandChild::SessionControlCommand(cmd) => !matches!(
+            cmd.specialize(),
+            SessionControlCommandChild::Payload(_) | SessionControlCommandChild::None
+        ),
+        UciCommandChild::TestCommand(cmd) => !matches!(
+            cmd.specialize(),
+            TestCommandChild::Payload(_) | TestCommandChild::None
+        ),
+        UciCommandChild::AndroidCommand(cmd) => !matches!(
+            cmd.specialize(),
+            AndroidCommandChild::Payload(_) | AndroidCommandChild::None
+        ),
+        UciCommandChild::Payload(_) | UciCommandChild::None => false,
+        _ => true,
+    };
+    if !decoded {
+        return Err("payload is too short".to_owned());
+    }
+    let decoded_size = cmd.clone().to_bytes().len();
+    if decoded_size != size {
+        return Err(format!(
+            "payload length is {} bytes, expected {} bytes",
+            size - HEADER_SIZE,
+            decoded_size - HEADER_SIZE
+        ));
+    }
+    Ok(())
+}
+
+/// Build the response to a command which could not be processed.
+fn make_error_response(group_id: u8, opcode_id: u8, status: UciStatusCode) -> Bytes {
+    // The PDL generated code cannot be used to generate
+    // responses with invalid group identifiers.
+    vec![
+        (u8::from(MessageType::Response) << 5) | group_id,
+        opcode_id,
+        0,
+        1,
+        status.into(),
+    ]
+    .into()
+}
+
+/// Build the notification reporting a malformed data packet.
+fn make_data_error_notification() -> Bytes {
+    GenericErrorBuilder {
+        status: UciStatusCode::UciStatusSyntaxError,
+    }
+    .build()
+    .to_bytes()
 }
 
 /// Parse incoming UCI packets.
 /// Handle parsing errors by crafting a suitable error response packet.
-fn parse_uci_packet(bytes: &[u8]) -> UciParseResult {
+/// The length of the payload is checked against the decoded fields of
+/// the packet: malformed commands are answered with STATUS_SYNTAX_ERROR,
+/// and malformed data packets with a CORE_GENERIC_ERROR_NTF.
+/// The parsing does not depend on the state of the simulator, and accepts
+/// arbitrary bytes, e.g. packets reassembled by a [`PacketReassembler`].
+pub fn parse_uci_packet(bytes: &[u8]) -> UciParseResult {
+    if bytes.len() < HEADER_SIZE {
+        return UciParseResult::Ignored(PicaError::Parse(format!(
+            "packet of {} bytes is shorter than the header",
+            bytes.len()
+        )));
+    }
     let message_type = get_message_type(bytes[0]);
     match message_type {
         MessageType::Data => match DataPacket::parse(bytes) {
+            Ok(packet) if packet.clone().to_bytes().len() != bytes.len() => {
+                UciParseResult::Malformed(
+                    make_data_error_notification(),
+                    PicaError::Parse(format!(
+                        "data packet payload length is {} bytes, expected {} bytes",
+                        bytes.len() - HEADER_SIZE,
+                        packet.to_bytes().len() - HEADER_SIZE
+                    )),
+                )
+            }
             Ok(packet) => UciParseResult::UciData(packet),
-            Err(_) => UciParseResult::Skip,
+            Err(err) => UciParseResult::Malformed(
+                make_data_error_notification(),
+                PicaError::Parse(format!("data packet: {}", err)),
+            ),
         },
         _ => {
+            let group_id = bytes[0] & 0xf;
+            let opcode_id = bytes[1] & 0x3f;
+            let decoded = message_type == MessageType::Command
+                && GroupId::try_from(group_id).is_ok_and(|gid| is_decoded_command(gid, opcode_id));
+
             match ControlPacket::parse(bytes) {
                 // Parsing error. Determine what error response should be
                 // returned to the host:
                 // - response and notifications are ignored, no response
                 // - if the group id is not known, STATUS_UNKNOWN_GID,
-                // - otherwise, and to simplify the code, STATUS_UNKNOWN_OID is
-                //      always returned. That means that malformed commands
-                //      get the same status code, instead of
-                //      STATUS_SYNTAX_ERROR.
+                // - if the command is decoded by pica, STATUS_SYNTAX_ERROR,
+                // - otherwise STATUS_UNKNOWN_OID.
+                Err(err) if decoded => UciParseResult::Malformed(
+                    make_error_response(group_id, opcode_id, UciStatusCode::UciStatusSyntaxError),
+                    PicaError::Parse(format!("command {:x}:{:x}: {}", group_id, opcode_id, err)),
+                ),
                 Err(_) => {
-                    let group_id = bytes[0] & 0xf;
-                    let opcode_id = bytes[1] & 0x3f;
-
                     let status = match (message_type, GroupId::try_from(group_id)) {
                         (MessageType::Command, Ok(_)) => UciStatusCode::UciStatusUnknownOid,
                         (MessageType::Command, Err(_)) => UciStatusCode::UciStatusUnknownGid,
-                        _ => return UciParseResult::Skip,
+                        _ => {
+                            return UciParseResult::Ignored(PicaError::Protocol(format!(
+                                "{:?} sent by the host",
+                                message_type
+                            )))
+                        }
                     };
-                    // The PDL generated code cannot be used to generate
-                    // responses with invalid group identifiers.
-                    let response = vec![
-                        (u8::from(MessageType::Response) << 5) | group_id,
-                        opcode_id,
-                        0,
-                        1,
-                        status.into(),
-                    ];
-                    UciParseResult::Err(response.into())
+                    UciParseResult::Err(make_error_response(group_id, opcode_id, status))
                 }
 
                 // Parsing success, ignore non command packets.
-                Ok(packet) => {
-                    if let Ok(cmd) = packet.try_into() {
-                        UciParseResult::UciCommand(cmd)
-                    } else {
-                        UciParseResult::Skip
-                    }
-                }
+                Ok(packet) => match packet.try_into() {
+                    Ok(cmd) if decoded => match validate_command(&cmd, bytes.len()) {
+                        Ok(()) => UciParseResult::UciCommand(cmd),
+                        Err(err) => UciParseResult::Malformed(
+                            make_error_response(
+                                group_id,
+                                opcode_id,
+                                UciStatusCode::UciStatusSyntaxError,
+                            ),
+                            PicaError::Parse(format!(
+                                "command {:x}:{:x}: {}",
+                                group_id, opcode_id, err
+                            )),
+                        ),
+                    },
+                    Ok(cmd) => UciParseResult::UciCommand(cmd),
+                    Err(_) => UciParseResult::Ignored(PicaError::Protocol(format!(
+                        "{:?} sent by the host",
+                        message_type
+                    ))),
+                },
             }
         }
     }
 }
 
+/// Distance, azimuth and elevation measured to a peer during a ranging
+/// round, or None if the measurement was lost.
+type RangingOutcome = Option<(u16, i16, i8)>;
+
+/// Range between the device and a peer reached in a ranging round.
+struct PeerRange {
+    /// Distance, azimuth and elevation of the peer seen from the device.
+    local: (u16, i16, i8),
+    /// Distance, azimuth and elevation of the device seen from the peer.
+    remote: (u16, i16, i8),
+    /// Sum of the biases of the obstacles crossed by the link,
+    /// None if the link is in line of sight.
+    nlos_bias: Option<u16>,
+    /// Whether the peer is in the field of view of the device.
+    local_in_fov: bool,
+    /// Whether the device is in the field of view of the peer.
+    remote_in_fov: bool,
+    /// Angles of arrival measured by the device and by the peer.
+    aoa: (AoaCapability, AoaCapability),
+    /// Location of the peer.
+    translation: glam::Vec3,
+}
+
+impl PeerRange {
+    fn new(
+        device: (&Position, &FieldOfView, AoaCapability),
+        peer: (&Position, &FieldOfView, AoaCapability),
+        scene: &Scene,
+    ) -> Self {
+        let local = device.0.compute_range_azimuth_elevation(peer.0);
+        let remote = peer.0.compute_range_azimuth_elevation(device.0);
+        assert!(local.0 == remote.0);
+        PeerRange {
+            local,
+            remote,
+            nlos_bias: scene.nlos_bias(device.0, peer.0),
+            local_in_fov: device.1.contains(local.1, local.2),
+            remote_in_fov: peer.1.contains(remote.1, remote.2),
+            aoa: (device.2, peer.2),
+            translation: peer.0.translation(),
+        }
+    }
+}
+
+/// Send the packets returned by a vendor handler or command hook
+/// to the host of the device.
+fn send_output(device: &Device, output: VendorCommandOutput, source: &str) {
+    for bytes in std::iter::once(output.response).chain(output.notifications) {
+        match ControlPacket::parse(&bytes) {
+            Ok(packet) => device
+                .tx
+                .try_send(packet)
+                .unwrap_or_else(|err| warn!("Failed to send {} packet: {}", source, err)),
+            Err(err) => warn!("Invalid packet returned by {}: {}", source, err),
+        }
+    }
+}
+
 fn make_measurement(
     mac_address: &MacAddress,
     local: (u16, i16, i8),
     remote: (u16, i16, i8),
+    rssi: u8,
+    nlos: bool,
+    fom: (u8, u8),
+    aoa: (AoaCapability, AoaCapability),
+) -> ExtendedAddressTwoWayRangingMeasurement {
+    let (local_fom, remote_fom) = fom;
+    let (local_aoa, remote_aoa) = aoa;
+    // The angles not measured by the antennas are reported as zero.
+    let angle = |measured: bool, angle: i16, fom: u8| {
+        if measured {
+            (angle as u16, fom)
+        } else {
+            (0, 0)
+        }
+    };
+    let (aoa_azimuth, aoa_azimuth_fom) = angle(local_aoa.azimuth(), local.1, local_fom);
+    let (aoa_elevation, aoa_elevation_fom) =
+        angle(local_aoa.elevation(), local.2 as i16, local_fom);
+    let (aoa_destination_azimuth, aoa_destination_azimuth_fom) =
+        angle(remote_aoa.azimuth(), remote.1, remote_fom);
+    let (aoa_destination_elevation, aoa_destination_elevation_fom) =
+        angle(remote_aoa.elevation(), remote.2 as i16, remote_fom);
+    ExtendedAddressTwoWayRangingMeasurement {
+        mac_address: mac_address.into(),
+        status: UciStatusCode::UciStatusOk,
+        nlos: nlos.into(),
+        distance: local.0,
+        aoa_azimuth,
+        aoa_azimuth_fom,
+        aoa_elevation,
+        aoa_elevation_fom,
+        aoa_destination_azimuth,
+        aoa_destination_azimuth_fom,
+        aoa_destination_elevation,
+        aoa_destination_elevation_fom,
+        slot_index: 0,
+        rssi,
+    }
+}
+
+/// Build the measurement reported for a peer that could not be reached.
+fn make_lost_measurement(
+    mac_address: &MacAddress,
+    status: UciStatusCode,
+) -> ExtendedAddressTwoWayRangingMeasurement {
+    ExtendedAddressTwoWayRangingMeasurement {
+        mac_address: mac_address.into(),
+        status,
+        nlos: 0,
+        distance: 0,
+        aoa_azimuth: 0,
+        aoa_azimuth_fom: 0,
+        aoa_elevation: 0,
+        aoa_elevation_fom: 0,
+        aoa_destination_azimuth: 0,
+        aoa_destination_azimuth_fom: 0,
+        aoa_destination_elevation: 0,
+        aoa_destination_elevation_fom: 0,
+        slot_index: 0,
+        rssi: u8::MAX,
+    }
+}
+
+/// Convert a measurement to the short address format.
+/// The address must be a short address, i.e. fit in 16 bits.
+fn make_short_measurement(
+    measurement: ExtendedAddressTwoWayRangingMeasurement,
 ) -> ShortAddressTwoWayRangingMeasurement {
-    if let MacAddress::Short(address) = mac_address {
-        ShortAddressTwoWayRangingMeasurement {
-            mac_address: u16::from_le_bytes(*address),
-            status: UciStatusCode::UciStatusOk,
-            nlos: 0, // in Line Of Sight
-            distance: local.0,
-            aoa_azimuth: local.1 as u16,
-            aoa_azimuth_fom: 100, // Yup, pretty sure about this
-            aoa_elevation: local.2 as u16,
-            aoa_elevation_fom: 100, // Yup, pretty sure about this
-            aoa_destination_azimuth: remote.1 as u16,
-            aoa_destination_azimuth_fom: 100,
-            aoa_destination_elevation: remote.2 as u16,
-            aoa_destination_elevation_fom: 100,
-            slot_index: 0,
-            rssi: u8::MAX,
-        }
-    } else {
-        panic!("Extended address is not supported.")
+    ShortAddressTwoWayRangingMeasurement {
+        mac_address: measurement.mac_address as u16,
+        status: measurement.status,
+        nlos: measurement.nlos,
+        distance: measurement.distance,
+        aoa_azimuth: measurement.aoa_azimuth,
+        aoa_azimuth_fom: measurement.aoa_azimuth_fom,
+        aoa_elevation: measurement.aoa_elevation,
+        aoa_elevation_fom: measurement.aoa_elevation_fom,
+        aoa_destination_azimuth: measurement.aoa_destination_azimuth,
+        aoa_destination_azimuth_fom: measurement.aoa_destination_azimuth_fom,
+        aoa_destination_elevation: measurement.aoa_destination_elevation,
+        aoa_destination_elevation_fom: measurement.aoa_destination_elevation_fom,
+        slot_index: measurement.slot_index,
+        rssi: measurement.rssi,
+    }
+}
+
+/// Convert a measurement to the one-way AoA format, keeping only the
+/// angle of arrival of the advertisement at the device.
+fn make_owr_aoa_measurement(
+    measurement: ExtendedAddressTwoWayRangingMeasurement,
+    frame_sequence_number: u8,
+    block_index: u16,
+) -> ExtendedAddressOwrAoaRangingMeasurement {
+    ExtendedAddressOwrAoaRangingMeasurement {
+        mac_address: measurement.mac_address,
+        status: measurement.status,
+        nlos: measurement.nlos,
+        frame_sequence_number,
+        block_index,
+        aoa_azimuth: measurement.aoa_azimuth,
+        aoa_azimuth_fom: measurement.aoa_azimuth_fom,
+        aoa_elevation: measurement.aoa_elevation,
+        aoa_elevation_fom: measurement.aoa_elevation_fom,
+    }
+}
+
+/// Convert a one-way AoA measurement to the short address format.
+/// The address must be a short address, i.e. fit in 16 bits.
+fn make_short_owr_aoa_measurement(
+    measurement: ExtendedAddressOwrAoaRangingMeasurement,
+) -> ShortAddressOwrAoaRangingMeasurement {
+    ShortAddressOwrAoaRangingMeasurement {
+        mac_address: measurement.mac_address as u16,
+        status: measurement.status,
+        nlos: measurement.nlos,
+        frame_sequence_number: measurement.frame_sequence_number,
+        block_index: measurement.block_index,
+        aoa_azimuth: measurement.aoa_azimuth,
+        aoa_azimuth_fom: measurement.aoa_azimuth_fom,
+        aoa_elevation: measurement.aoa_elevation,
+        aoa_elevation_fom: measurement.aoa_elevation_fom,
     }
 }
 
+/// Remove the angle of arrival results from a measurement.
+fn strip_aoa(measurement: &mut ExtendedAddressTwoWayRangingMeasurement) {
+    measurement.aoa_azimuth = 0;
+    measurement.aoa_azimuth_fom = 0;
+    measurement.aoa_elevation = 0;
+    measurement.aoa_elevation_fom = 0;
+    measurement.aoa_destination_azimuth = 0;
+    measurement.aoa_destination_azimuth_fom = 0;
+    measurement.aoa_destination_elevation = 0;
+    measurement.aoa_destination_elevation_fom = 0;
+}
+
 impl Pica {
-    pub fn new(event_tx: broadcast::Sender<PicaEvent>, pcapng_dir: Option<PathBuf>) -> Self {
-        let (tx, rx) = mpsc::channel(MAX_SESSION * MAX_DEVICE);
-        Pica {
-            devices: HashMap::new(),
-            anchors: HashMap::new(),
-            counter: 0,
-            rx,
-            tx,
-            event_tx,
-            pcapng_dir,
-        }
+    pub fn builder() -> PicaBuilder {
+        PicaBuilder::new()
     }
 
     pub fn tx(&self) -> mpsc::Sender<PicaCommand> {
         self.tx.clone()
     }
 
+    pub fn event_tx(&self) -> broadcast::Sender<SequencedEvent> {
+        self.event_tx.clone()
+    }
+
+    /// Stream of the events emitted from now on, handling the events
+    /// missed by slow subscribers according to the lag policy.
+    pub fn events(
+        &self,
+        lag_policy: LagPolicy,
+    ) -> impl Stream<Item = Result<PicaEvent, EventsLagged>> + Send + Unpin {
+        events::event_stream(self.event_tx.subscribe(), lag_policy)
+    }
+
+    /// Typed handle sending commands to this instance, an alternative
+    /// to building the [`PicaCommand`]s sent to [`Pica::tx`].
+    pub fn handle(&self) -> PicaHandle {
+        PicaHandle::new(self.tx(), self.event_tx())
+    }
+
+    /// Channel broadcasting a copy of the UCI packets exchanged with the
+    /// hosts, including the packets of the devices connected later on.
+    /// The packets are only copied while there are subscribers.
+    pub fn tap_tx(&self) -> broadcast::Sender<TappedPacket> {
+        self.tap_tx.clone()
+    }
+
+    /// Connect a device through an in-process byte stream, and return
+    /// the host end of the stream. The UCI packets are framed the same
+    /// way as on TCP connections. The device is created when the command
+    /// is processed by [`Pica::run`]; it is removed when the stream is
+    /// dropped. The connection fails with a transport error when the
+    /// command channel is full.
+    pub fn connect_in_process(&self) -> PicaResult<DuplexStream> {
+        let (host, device) = tokio::io::duplex(IN_PROCESS_BUFFER_SIZE);
+        self.tx
+            .try_send(PicaCommand::Connect(
+                Box::new(device),
+                "in-process".to_owned(),
+            ))
+            .map_err(|err| match err {
+                mpsc::error::TrySendError::Full(_) => {
+                    PicaError::Transport(std::io::ErrorKind::WouldBlock.into())
+                }
+                mpsc::error::TrySendError::Closed(_) => PicaError::ShutDown,
+            })?;
+        Ok(host)
+    }
+
+    /// Install a handler for the commands of a vendor reserved group.
+    /// The handler replaces the default behaviour of pica for this group,
+    /// and any previously registered handler. Malformed Android commands
+    /// implemented by pica are still answered with STATUS_SYNTAX_ERROR.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `gid` is not a vendor reserved group identifier (9 to 15).
+    pub fn register_vendor_handler(&mut self, gid: u8, handler: VendorCommandHandler) {
+        assert!(
+            (VENDOR_GID_MIN..=VENDOR_GID_MAX).contains(&gid),
+            "GID {} is not vendor reserved",
+            gid
+        );
+        self.vendor_handlers.insert(gid, handler);
+    }
+
+    /// Install a hook invoked for each UCI command before it is processed,
+    /// including the vendor commands. The hooks are invoked in order of
+    /// registration with the command replaced by the previous hooks, until
+    /// a hook answers or drops the command.
+    pub fn register_command_hook(&mut self, hook: CommandHook) {
+        self.command_hooks.push(hook);
+    }
+
     fn get_device_mut(&mut self, device_handle: usize) -> Option<&mut Device> {
         self.devices.get_mut(&device_handle)
     }
@@ -412,11 +1619,7 @@ impl Pica {
     fn get_category(&self, mac_address: &MacAddress) -> Option<Category> {
         if self.anchors.contains_key(mac_address) {
             Some(Category::Anchor)
-        } else if self
-            .devices
-            .iter()
-            .any(|(_, device)| device.mac_address == *mac_address)
-        {
+        } else if self.device_handles.contains_key(mac_address) {
             Some(Category::Uci)
         } else {
             None
@@ -424,26 +1627,56 @@ impl Pica {
     }
 
     fn get_device_mut_by_mac(&mut self, mac_address: MacAddress) -> Option<&mut Device> {
-        self.devices
-            .values_mut()
-            .find(|d| d.mac_address == mac_address)
+        let device_handle = self.device_handles.get(&mac_address)?;
+        self.devices.get_mut(device_handle)
     }
 
+    /// Return the peer device using the selected address in its active
+    /// session `session_id`. The device indexed with this address is
+    /// looked up first; the other devices are only searched when the
+    /// hosts configure their sessions with an address different from
+    /// the address of their device.
     fn get_device_by_mac(
         &self,
         mac_address: &MacAddress,
         local_app_config: &AppConfig,
         session_id: u32,
     ) -> Option<&Device> {
-        self.devices.values().find(|device| {
-            if let Some(session) = device.get_session(session_id) {
-                session.app_config.device_mac_address == *mac_address
-                    && local_app_config.can_start_ranging_with_peer(&session.app_config)
-                    && session.session_state() == SessionState::SessionStateActive
-            } else {
-                false
-            }
-        })
+        let indexed_handle = self.device_handles.get(mac_address);
+        let indexed_device =
+            indexed_handle.and_then(|device_handle| self.devices.get(device_handle));
+        let other_devices = self
+            .devices
+            .iter()
+            .filter(|(device_handle, _)| Some(*device_handle) != indexed_handle)
+            .map(|(_, device)| device);
+        indexed_device
+            .into_iter()
+            .chain(other_devices)
+            .find(|device| {
+                let Some(session) = device.get_session(session_id) else {
+                    return false;
+                };
+                if session.app_config.device_mac_address != *mac_address
+                    || session.session_state() != SessionState::SessionStateActive
+                {
+                    return false;
+                }
+                // The peer does not receive the rounds of a misconfigured
+                // session, which are reported as timeouts like on hardware.
+                match local_app_config.can_start_ranging_with_peer(&session.app_config) {
+                    Ok(()) => true,
+                    Err(mismatch) => {
+                        debug!(
+                            peer = %mac_address,
+                            session_id = format_args!("0x{:x}", session_id),
+                            "Cannot range with peer: {}",
+                            mismatch
+                        );
+                        false
+                    }
+                }
+            })
     }
 
     fn get_device_mut_by_mac_and_session_id(
@@ -461,46 +1694,203 @@ impl Pica {
         })
     }
 
-    fn send_event(&self, event: PicaEvent) {
+    /// Current time on the monotonic clock (µs) and the wall clock (ms)
+    /// of the events.
+    fn event_time(&self) -> (u64, u64) {
+        let monotonic_us = self.timeline.elapsed().as_micros() as u64;
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        (monotonic_us, timestamp_ms)
+    }
+
+    fn send_event(&mut self, event: PicaEvent) {
+        let (monotonic_us, timestamp_ms) = self.event_time();
+        // The zone transitions are reported after the events adding,
+        // moving or removing the nodes.
+        let node = match &event {
+            PicaEvent::DeviceAdded {
+                category,
+                mac_address,
+                position,
+                ..
+            }
+            | PicaEvent::DeviceUpdated {
+                category,
+                mac_address,
+                position,
+                ..
+            } => Some((*category, *mac_address, Some(*position))),
+            PicaEvent::DeviceRemoved {
+                category,
+                mac_address,
+            } => Some((*category, *mac_address, None)),
+            _ => None,
+        };
+        #[cfg(feature = "sqlite")]
+        if let Some(event_log) = &self.event_log {
+            event_log
+                .record_event(&event, timestamp_ms)
+                .unwrap_or_else(|err| warn!("Failed to log event: {}", err));
+        }
+        // The sequence number is incremented even without receivers,
+        // to remain consistent with the snapshots returned by GetState.
+        self.sequence_number += 1;
+        let event = SequencedEvent {
+            sequence_number: self.sequence_number,
+            monotonic_us,
+            timestamp_ms,
+            event,
+        };
+        if self.event_history.len() == EVENT_HISTORY_SIZE {
+            self.event_history.pop_front();
+        }
+        self.event_history.push_back(event.clone());
+        // The oldest queued event is evicted when the channel is full,
+        // before the slowest subscriber could receive it.
+        if self
+            .event_capacity
+            .is_some_and(|capacity| self.event_tx.len() >= capacity)
+        {
+            self.metrics.dropped_events += 1;
+        }
         // An error here means that we have
         // no receivers, so ignore it
         let _ = self.event_tx.send(event);
-    }
 
-    async fn connect(&mut self, stream: TcpStream) {
-        let (packet_tx, mut packet_rx) = mpsc::channel(MAX_SESSION);
+        if let Some((category, mac_address, position)) = node {
+            self.update_zones(category, mac_address, position);
+        }
+    }
+
+    /// Report the zones entered and left by the node at its new position,
+    /// or left by the node if it was removed.
+    fn update_zones(
+        &mut self,
+        category: Category,
+        mac_address: MacAddress,
+        position: Option<Position>,
+    ) {
+        let zones: Vec<String> = position
+            .map(|position| {
+                self.zones
+                    .iter()
+                    .filter(|zone| zone.contains(&position))
+                    .map(|zone| zone.name().to_owned())
+                    .collect()
+            })
+            .unwrap_or_default();
+        let previous_zones = if zones.is_empty() {
+            self.occupied_zones.remove(&mac_address)
+        } else {
+            self.occupied_zones.insert(mac_address, zones.clone())
+        }
+        .unwrap_or_default();
+
+        for zone in previous_zones.iter().filter(|zone| !zones.contains(zone)) {
+            self.send_event(PicaEvent::ZoneTransition {
+                category,
+                mac_address,
+                zone: zone.clone(),
+                transition: "leave",
+            });
+        }
+        for zone in zones.iter().filter(|zone| !previous_zones.contains(zone)) {
+            self.send_event(PicaEvent::ZoneTransition {
+                category,
+                mac_address,
+                zone: zone.clone(),
+                transition: "enter",
+            });
+        }
+    }
+
+    async fn connect(&mut self, stream: Box<dyn Transport>, origin: String) {
+        if self.devices.len() >= self.max_devices {
+            warn!(
+                "Closing connection, the limit of {} devices is reached",
+                self.max_devices
+            );
+            return;
+        }
+        let (packet_tx, mut packet_rx) = mpsc::channel(DEVICE_PACKET_CAPACITY);
+        let (injected_packet_tx, mut injected_packet_rx) =
+            mpsc::channel::<Bytes>(INJECTED_PACKET_CAPACITY);
         let device_handle = self.counter;
         let pica_tx = self.tx.clone();
         let pcapng_dir = self.pcapng_dir.clone();
+        let capture_config = self.capture_config.clone();
+        let trace_dir = self.trace_dir.clone();
+        let tap = Tap::new(device_handle, self.tap_tx.clone(), self.timeline.clone());
 
-        println!("[{}] Connecting device", device_handle);
+        info!(device = device_handle, %origin, "Connecting device");
 
         self.counter += 1;
-        let mut device = Device::new(device_handle, packet_tx, self.tx.clone());
+        let mut device = Device::new(
+            device_handle,
+            packet_tx,
+            self.tx.clone(),
+            self.device_profile.clone(),
+            self.max_sessions,
+            self.timeline.clone(),
+            self.seed,
+        );
+        device.origin = Some(origin.clone());
         device.init();
+        let latency_rx = device.notification_latency();
+        let mut latency_rng = derived_rng(
+            self.seed,
+            seed::Stream::NotificationLatency,
+            &[device_handle as u64],
+        );
+        let timeline = self.timeline.clone();
 
         self.send_event(PicaEvent::DeviceAdded {
             category: Category::Uci,
             mac_address: device.mac_address,
             position: device.position,
+            origin: Some(origin),
         });
 
+        self.device_handles
+            .insert(device.mac_address, device_handle);
         self.devices.insert(device_handle, device);
 
-        // Spawn and detach the connection handling task.
+        // Spawn the connection handling task.
         // The task notifies pica when exiting to let it clean
-        // the state.
-        tokio::spawn(async move {
-            let pcapng_file: Option<pcapng::File> = if let Some(dir) = pcapng_dir {
-                let full_path = dir.join(format!("device-{}.pcapng", device_handle));
-                println!("Recording pcapng to file {}", full_path.as_path().display());
-                Some(pcapng::File::create(full_path).await.unwrap())
+        // the state, and exits when the device is removed.
+        let connection_task = tokio::spawn(async move {
+            let capture_file: Option<capture::File> = if let Some(dir) = pcapng_dir {
+                capture::File::create(&dir, format!("device-{}", device_handle), capture_config)
+                    .await
+                    .map_err(|err| warn!("Failed to create capture file: {}", err))
+                    .ok()
+            } else {
+                None
+            };
+
+            let trace_file: Option<trace::File> = if let Some(dir) = trace_dir {
+                let full_path = dir.join(format!("device-{}.log", device_handle));
+                info!("Recording trace to file {}", full_path.as_path().display());
+                trace::File::create(full_path)
+                    .await
+                    .map_err(|err| warn!("Failed to create trace file: {}", err))
+                    .ok()
             } else {
                 None
             };
 
-            let mut connection = Connection::new(stream, pcapng_file);
+            let mut connection = Connection::new(stream, capture_file, trace_file, tap);
+            // Notifications waiting for the expiration of their latency,
+            // in order of delivery.
+            let mut delayed_notifications: VecDeque<(time::Instant, ControlPacket)> =
+                VecDeque::new();
             'outer: loop {
+                let next_delivery = delayed_notifications
+                    .front()
+                    .map(|(deadline, _)| *deadline)
+                    .unwrap_or_else(|| timeline.now());
                 tokio::select! {
                     // Read command packet sent from connected UWB host.
                     // Run associated command.
@@ -509,34 +1899,101 @@ impl Pica {
                             Ok(packet) =>
                                 match parse_uci_packet(&packet) {
                                     UciParseResult::UciCommand(cmd) => {
-                                        pica_tx.send(PicaCommand::UciCommand(device_handle, cmd)).await.unwrap()
+                                        if pica_tx.send(PicaCommand::UciCommand(device_handle, cmd)).await.is_err() {
+                                            break 'outer
+                                        }
                                     },
                                     UciParseResult::UciData(data) => {
-                                        pica_tx.send(PicaCommand::UciData(device_handle, data)).await.unwrap()
+                                        if pica_tx.send(PicaCommand::UciData(device_handle, data)).await.is_err() {
+                                            break 'outer
+                                        }
                                     },
                                     UciParseResult::Err(response) =>
-                                        connection.write(&response).await.unwrap(),
-                                    UciParseResult::Skip => (),
+                                        if connection.write(&response).await.is_err() {
+                                            break 'outer
+                                        },
+                                    UciParseResult::Malformed(response, err) => {
+                                        if connection.write(&response).await.is_err()
+                                            || pica_tx.send(PicaCommand::MalformedPacket(device_handle, err.to_string())).await.is_err() {
+                                            break 'outer
+                                        }
+                                    },
+                                    UciParseResult::Ignored(err) => debug!("Packet ignored: {}", err),
                                 },
-                            Err(_) => break 'outer
+                            Err(err) => {
+                                debug!("Closing connection: {}", err);
+                                break 'outer
+                            }
                         },
 
                     // Send response packets to the connected UWB host.
-                    Some(packet) = packet_rx.recv() =>
+                    // The channel is closed when the device is removed.
+                    packet = packet_rx.recv() =>
+                        match packet {
+                            Some(packet) if packet.get_mt() == MessageType::Notification
+                                && (!delayed_notifications.is_empty()
+                                    || *latency_rx.borrow() != NotificationLatency::default()) => {
+                                // Notifications are delivered in order, regardless
+                                // of the latency drawn for each.
+                                let latency = latency_rx.borrow().sample(&mut latency_rng);
+                                let deadline = delayed_notifications
+                                    .back()
+                                    .map(|(deadline, _)| *deadline)
+                                    .into_iter()
+                                    .fold(timeline.now() + latency, std::cmp::max);
+                                delayed_notifications.push_back((deadline, packet));
+                            }
+                            Some(packet) => if connection.write(&packet.to_bytes()).await.is_err() {
+                                break 'outer
+                            },
+                            None => break 'outer,
+                        },
+
+                    // Send the injected bytes to the connected UWB host.
+                    Some(bytes) = injected_packet_rx.recv() =>
+                        if connection.write_raw(&bytes).await.is_err() {
+                            break 'outer
+                        },
+
+                    // Deliver the notifications whose latency has expired.
+                    _ = timeline.sleep_until(next_delivery), if !delayed_notifications.is_empty() => {
+                        let (_, packet) = delayed_notifications.pop_front().unwrap();
                         if connection.write(&packet.to_bytes()).await.is_err() {
                             break 'outer
                         }
+                    }
                 }
             }
-            pica_tx
-                .send(PicaCommand::Disconnect(device_handle))
-                .await
-                .unwrap()
+            connection.close().await;
+            // Pica is not listening anymore when shutting down.
+            let _ = pica_tx.send(PicaCommand::Disconnect(device_handle)).await;
+        }
+        .instrument(device_span(device_handle)));
+        self.connections.insert(device_handle, connection_task);
+        self.injected_packet_txs
+            .insert(device_handle, injected_packet_tx);
+    }
+
+    fn session_updated(
+        &mut self,
+        device_handle: usize,
+        session_id: u32,
+        state: SessionState,
+        reason: ReasonCode,
+    ) {
+        let Some(device) = self.get_device(device_handle) else {
+            return;
+        };
+        self.send_event(PicaEvent::SessionUpdated {
+            mac_address: device.mac_address,
+            session_id,
+            state: session::state_name(state),
+            reason: format!("{:?}", reason),
         });
     }
 
     fn disconnect(&mut self, device_handle: usize) {
-        println!("[{}] Disconnecting device", device_handle);
+        info!("Disconnecting device");
 
         match self
             .devices
@@ -544,136 +2001,955 @@ impl Pica {
             .ok_or_else(|| PicaCommandError::DeviceNotFound(device_handle.into()))
         {
             Ok(device) => {
+                let mac_address = device.mac_address;
+                let retained = RetainedDevice {
+                    position: device.position,
+                    field_of_view: device.field_of_view,
+                    aoa_capability: device.aoa_capability,
+                    clock: device.clock(),
+                    notification_latency: *device.notification_latency().borrow(),
+                    tx_power: device.tx_power,
+                };
                 self.send_event(PicaEvent::DeviceRemoved {
                     category: Category::Uci,
-                    mac_address: device.mac_address,
+                    mac_address,
                 });
                 self.devices.remove(&device_handle);
+                if self.device_handles.get(&mac_address) == Some(&device_handle) {
+                    self.device_handles.remove(&mac_address);
+                }
+                self.connections.remove(&device_handle);
+                self.injected_packet_txs.remove(&device_handle);
+                self.motions.remove(&mac_address);
+                if self.persistent_identity {
+                    // The statistics of the links with the anchors are
+                    // continued by the device claiming the address.
+                    let anchors = &self.anchors;
+                    self.statistics.retain(|(source, destination), _| {
+                        (*source != mac_address && *destination != mac_address)
+                            || anchors.contains_key(source)
+                            || anchors.contains_key(destination)
+                    });
+                    self.retained_devices.insert(mac_address, retained);
+                } else {
+                    self.remove_statistics(mac_address);
+                }
             }
-            Err(err) => println!("{}", err),
+            Err(err) => warn!("{}", err),
         }
     }
 
-    async fn ranging(&mut self, device_handle: usize, session_id: u32) {
-        println!("[{}] Ranging event", device_handle);
-        println!("  session_id={}", session_id);
+    async fn shutdown(&mut self, shutdown_tx: oneshot::Sender<()>) {
+        info!("Shutdown");
 
-        let device = self.get_device(device_handle).unwrap();
-        let session = device.get_session(session_id).unwrap();
+        // Reject new commands, pending commands are dropped.
+        self.rx.close();
 
-        let mut measurements = Vec::new();
+        // Removing the devices closes their packet channel,
+        // which terminates the connection tasks.
+        let connections = std::mem::take(&mut self.connections);
+        let device_handles: Vec<usize> = self.devices.keys().copied().collect();
+        for device_handle in device_handles {
+            self.disconnect(device_handle);
+        }
+        // The connection tasks still waiting for their host to read are
+        // aborted, after leaving time to the others to close.
+        let deadline = time::Instant::now() + 2 * CLOSE_TIMEOUT;
+        for (device_handle, mut connection) in connections {
+            match time::timeout_at(deadline, &mut connection).await {
+                Ok(result) => result.unwrap_or_else(|err| {
+                    error!(device = device_handle, "Connection task failed: {}", err)
+                }),
+                Err(_) => {
+                    warn!(
+                        device = device_handle,
+                        "Host not reading, aborting the connection"
+                    );
+                    connection.abort();
+                }
+            }
+        }
+
+        for mac_address in std::mem::take(&mut self.anchors).into_keys() {
+            self.send_event(PicaEvent::DeviceRemoved {
+                category: Category::Anchor,
+                mac_address,
+            });
+        }
+        self.statistics.clear();
+
+        shutdown_tx
+            .send(())
+            .unwrap_or_else(|err| warn!("Failed to send shutdown response: {:?}", err));
+    }
+
+    fn ranging(&mut self, device_handle: usize, session_id: u32) {
+        // The rounds of controlees ranging with an active anchor or device
+        // controller are initiated by the controller.
+        let driven_by_controller = self
+            .get_device(device_handle)
+            .and_then(|device| device.get_session(session_id))
+            .is_some_and(|session| {
+                session.is_controlee()
+                    && (!self
+                        .get_anchor_controllers(session_id, session.get_dst_mac_addresses())
+                        .is_empty()
+                        || session.get_dst_mac_addresses().iter().any(|mac_address| {
+                            self.get_device_by_mac(mac_address, &session.app_config, session_id)
+                                .is_some()
+                        }))
+            });
+        if !driven_by_controller {
+            self.ranging_round(device_handle, session_id)
+        }
+    }
+
+    /// Send the sweeps of the next burst of a radar session, sensing the
+    /// obstacles of the scene from the position of the device.
+    fn radar_burst(&mut self, device_handle: usize, session_id: u32) {
+        let Some(device) = self.devices.get_mut(&device_handle) else {
+            return;
+        };
+        let position = device.position;
+        let timestamp_us = device.uwbs_time_us();
+        let Some(session) = device.get_session_mut(session_id) else {
+            return;
+        };
+        let data = session.radar_burst(&position, &self.scene, timestamp_us);
+        debug!(
+            session_id,
+            number_of_sweeps = data.get_number_of_sweeps(),
+            "Radar burst"
+        );
+        match ControlPacket::parse(&data.to_vec()) {
+            Ok(packet) => device
+                .tx
+                .try_send(packet)
+                .unwrap_or_else(|err| warn!("Failed to send radar data: {}", err)),
+            Err(err) => warn!("Invalid radar data packet: {}", err),
+        }
+    }
+
+    /// Return the anchors in the list acting as controllers of the session.
+    fn get_anchor_controllers(
+        &self,
+        session_id: u32,
+        mac_addresses: &[MacAddress],
+    ) -> Vec<MacAddress> {
+        mac_addresses
+            .iter()
+            .filter(|mac_address| {
+                self.anchors.get(mac_address).is_some_and(|anchor| {
+                    anchor
+                        .controller
+                        .as_ref()
+                        .is_some_and(|controller| controller.session_id == session_id)
+                })
+            })
+            .copied()
+            .collect()
+    }
+
+    /// Return the handles of the devices with an active controlee session
+    /// ranging with the selected anchor.
+    fn get_anchor_controlees(&self, mac_address: MacAddress, session_id: u32) -> Vec<usize> {
+        self.devices
+            .iter()
+            .filter(|(_, device)| {
+                device.get_session(session_id).is_some_and(|session| {
+                    session.is_controlee()
+                        && session.session_state() == SessionState::SessionStateActive
+                        && session.get_dst_mac_addresses().contains(&mac_address)
+                })
+            })
+            .map(|(device_handle, _)| *device_handle)
+            .collect()
+    }
+
+    fn anchor_ranging(&mut self, mac_address: MacAddress) {
+        // The controller may have been stopped after the ranging
+        // event was queued.
+        let Some(session_id) = self
+            .anchors
+            .get(&mac_address)
+            .and_then(|anchor| anchor.controller.as_ref())
+            .map(|controller| controller.session_id)
+        else {
+            return;
+        };
+        for device_handle in self.get_anchor_controlees(mac_address, session_id) {
+            // The controlees skip the rounds of their strided blocks.
+            let strided = self
+                .get_device(device_handle)
+                .and_then(|device| device.get_session(session_id))
+                .is_some_and(|session| {
+                    session.is_strided_block(session.ranging_block().unwrap_or(0))
+                });
+            if !strided {
+                self.ranging_round(device_handle, session_id);
+            }
+        }
+    }
+
+    /// Return the sessions interfering with the current ranging round of
+    /// the session: the other sessions ranging on the same channel, in
+    /// rounds overlapping the round of the session.
+    fn interfering_sessions(&self, session: &Session, channel_number: u8) -> Vec<u32> {
+        let Some((start, end)) = session.round_span() else {
+            return vec![];
+        };
+        let mut session_ids = self
+            .devices
+            .values()
+            .flat_map(|device| device.sessions())
+            .filter(|other| other.id() != session.id())
+            .filter(|other| {
+                other.ranging_block().is_some_and(|block_index| {
+                    other.round_radio(block_index).channel_number == channel_number
+                }) && other
+                    .round_span()
+                    .is_some_and(|(other_start, other_end)| other_start < end && start < other_end)
+            })
+            .map(|other| other.id())
+            .collect::<Vec<_>>();
+        session_ids.sort_unstable();
+        session_ids.dedup();
+        session_ids
+    }
+
+    fn ranging_round(&mut self, device_handle: usize, session_id: u32) {
+        debug!(
+            session_id = format_args!("0x{:x}", session_id),
+            "Ranging round"
+        );
+
+        // The device or session may have been removed after the ranging
+        // event was queued.
+        let Some(device) = self.get_device(device_handle) else {
+            debug!("Device not found, ignoring");
+            return;
+        };
+        let Some(session) = device.get_session(session_id) else {
+            debug!("Session not found, ignoring");
+            return;
+        };
+
+        let mut control_messages = Vec::new();
+        let mut peers = Vec::new();
+        let session_mac_address = session.app_config.device_mac_address;
+        let block_index = session.ranging_block().unwrap_or(0);
+        let radio = session.round_radio(block_index);
+        let hopping = session.is_hopping();
+        let control = session.pending_control();
         session
             .get_dst_mac_addresses()
             .iter()
             .for_each(|mac_address| {
+                // Ranges to the anchor and device with the peer address,
+                // or None if the peer is out of range, with the handle of
+                // the peer device if it is a controlee.
+                let mut ranges = Vec::new();
                 if let Some(anchor) = self.anchors.get(mac_address) {
-                    let local = device
-                        .position
-                        .compute_range_azimuth_elevation(&anchor.position);
-                    let remote = anchor
-                        .position
-                        .compute_range_azimuth_elevation(&device.position);
-
-                    assert!(local.0 == remote.0);
-                    measurements.push(make_measurement(mac_address, local, remote));
-                }
-                if let Some(peer_device) =
-                    self.get_device_by_mac(mac_address, &session.app_config, session_id)
-                {
-                    let local: (u16, i16, i8) = device
-                        .position
-                        .compute_range_azimuth_elevation(&peer_device.position);
-                    let remote = peer_device
-                        .position
-                        .compute_range_azimuth_elevation(&device.position);
+                    let range = PeerRange::new(
+                        (
+                            &device.position,
+                            &device.field_of_view,
+                            device.aoa_capability,
+                        ),
+                        (
+                            &anchor.position,
+                            &anchor.field_of_view,
+                            anchor.aoa_capability,
+                        ),
+                        &self.scene,
+                    );
+                    // Anchors transmit without a country code.
+                    let max_range = self
+                        .max_range
+                        .min(device.max_range())
+                        .min(regulatory::max_range_at_tx_power([0, 0], anchor.tx_power));
+                    if range.local.0 > max_range {
+                        ranges.push((None, None));
+                    } else {
+                        ranges.push((Some(range), None));
+                    }
+                }
+                // Controlees sleep through the blocks skipped by their
+                // block striding, and peers only receive the rounds on
+                // their channel and preamble code.
+                let peer_device = self
+                    .get_device_by_mac(mac_address, &session.app_config, session_id)
+                    .filter(|peer_device| {
+                        peer_device
+                            .get_session(session_id)
+                            .is_some_and(|peer_session| {
+                                !(peer_session.is_controlee()
+                                    && peer_session.is_strided_block(block_index))
+                                    && peer_session.round_radio(block_index) == radio
+                            })
+                    });
+                if let Some(control) = control {
+                    control_messages.push((*mac_address, control, peer_device.is_some()));
+                }
+                if let Some(peer_device) = peer_device {
+                    let range = PeerRange::new(
+                        (
+                            &device.position,
+                            &device.field_of_view,
+                            device.aoa_capability,
+                        ),
+                        (
+                            &peer_device.position,
+                            &peer_device.field_of_view,
+                            peer_device.aoa_capability,
+                        ),
+                        &self.scene,
+                    );
+                    let max_range = self
+                        .max_range
+                        .min(device.max_range())
+                        .min(peer_device.max_range());
+                    let controlee = peer_device
+                        .get_session(session_id)
+                        .filter(|peer_session| peer_session.is_controlee())
+                        .map(|_| peer_device.handle());
+                    if range.local.0 > max_range {
+                        ranges.push((None, controlee));
+                    } else {
+                        ranges.push((Some(range), controlee));
+                    }
+                }
+                peers.push((*mac_address, ranges));
+            });
+        let source = device.mac_address;
+        let ground_truth = device.position;
+        let interfering_sessions = if self.interference.is_enabled() {
+            self.interfering_sessions(session, radio.channel_number)
+        } else {
+            vec![]
+        };
+        let interferers = interfering_sessions.len();
 
-                    assert!(local.0 == remote.0);
-                    measurements.push(make_measurement(mac_address, local, remote));
+        // Add the measurement errors, drawn from the session random
+        // generator.
+        let noise = self.noise;
+        let outliers = self.outliers;
+        let interference = self.interference;
+        let rssi_model = self.rssi_model;
+        let fom_model = self.fom_model;
+        let Some(rng) = self
+            .devices
+            .get_mut(&device_handle)
+            .and_then(|device| device.get_session_mut(session_id))
+            .map(|session| session.noise_rng())
+        else {
+            return;
+        };
+        let mut measurements = Vec::new();
+        let mut outcomes = Vec::new();
+        let mut references = Vec::new();
+        // Measurements of the same round reported to the peer controlees.
+        let mut controlee_reports = Vec::new();
+        let mut lost_peers = Vec::new();
+        for (mac_address, ranges) in peers {
+            // Each controlee is reported once per round, from the first
+            // peer in range with its address, and as lost if none is.
+            let mut measurement = None;
+            let mut outcome = None;
+            // The peers beyond the maximum range are not reported at all,
+            // rather than reported as lost.
+            let out_of_range =
+                !ranges.is_empty() && ranges.iter().all(|(range, _)| range.is_none());
+            for (range, controlee) in ranges {
+                // The measurements lost to the interference are reported
+                // as lost, like the peers which did not respond.
+                let lost = range.is_some() && interference.drops(rng, interferers);
+                if lost {
+                    lost_peers.push(mac_address);
+                }
+                match range.filter(|_| !lost) {
+                    Some(range) => {
+                        // The signal strength depends on the true distance,
+                        // the obstacles crossed by the link add a bias to
+                        // the measured distance.
+                        let (local, remote) = (range.local, range.remote);
+                        let distance = local.0;
+                        let nlos = range.nlos_bias.is_some();
+                        let bias = range.nlos_bias.unwrap_or(0);
+                        let local = (
+                            interference.degrade(rng, interferers, local.0.saturating_add(bias)),
+                            local.1,
+                            local.2,
+                        );
+                        let remote = (
+                            interference.degrade(rng, interferers, remote.0.saturating_add(bias)),
+                            remote.1,
+                            remote.2,
+                        );
+                        let local = noise.apply(rng, local);
+                        let remote = noise.apply(rng, remote);
+                        let local = outliers.apply(rng, local);
+                        let remote = outliers.apply(rng, remote);
+                        let local = (local.0, self.angle_convention.from_native(local.1), local.2);
+                        let remote = (
+                            remote.0,
+                            self.angle_convention.from_native(remote.1),
+                            remote.2,
+                        );
+                        let rssi = rssi_model.encoded_rssi(rng, distance);
+                        // The figures of merit depend on the true geometry.
+                        let local_fom = fom_model.fom(range.local, nlos, range.local_in_fov);
+                        let remote_fom = fom_model.fom(range.remote, nlos, range.remote_in_fov);
+                        if outcome.is_none() {
+                            measurement = Some(make_measurement(
+                                &mac_address,
+                                local,
+                                remote,
+                                rssi,
+                                nlos,
+                                (local_fom, remote_fom),
+                                range.aoa,
+                            ));
+                            references.push((range.translation, local.0 as f32));
+                            outcome = Some(local);
+                        }
+                        if let Some(controlee) = controlee {
+                            controlee_reports.push((
+                                controlee,
+                                make_measurement(
+                                    &session_mac_address,
+                                    remote,
+                                    local,
+                                    rssi,
+                                    nlos,
+                                    (remote_fom, local_fom),
+                                    (range.aoa.1, range.aoa.0),
+                                ),
+                                Some(remote),
+                            ));
+                        }
+                    }
+                    None => {
+                        if let Some(controlee) = controlee {
+                            controlee_reports.push((
+                                controlee,
+                                make_lost_measurement(
+                                    &session_mac_address,
+                                    UciStatusCode::UciStatusRangingRxTimeout,
+                                ),
+                                None,
+                            ));
+                        }
+                    }
                 }
+            }
+            if out_of_range {
+                continue;
+            }
+            measurements.push(measurement.unwrap_or_else(|| {
+                make_lost_measurement(&mac_address, UciStatusCode::UciStatusRangingRxTimeout)
+            }));
+            outcomes.push((mac_address, outcome));
+        }
+        if hopping {
+            self.send_event(PicaEvent::ChannelHop {
+                mac_address: source,
+                session_id,
+                block_index,
+                channel_number: radio.channel_number,
+                preamble_code_index: radio.preamble_code_index,
             });
-        if session.is_ranging_data_ntf_enabled() != RangeDataNtfConfig::Disable {
-            device
-                .tx
-                .send(
-                    // TODO: support extended address
-                    ShortMacTwoWaySessionInfoNtfBuilder {
+        }
+        if !interfering_sessions.is_empty() {
+            self.send_event(PicaEvent::Interference {
+                mac_address: source,
+                session_id,
+                channel_number: radio.channel_number,
+                interfering_sessions,
+                lost_peers,
+            });
+        }
+        for (destination, control, received) in control_messages {
+            self.send_event(PicaEvent::RangingControlMessage {
+                source_mac_address: session_mac_address,
+                destination_mac_address: destination,
+                session_id,
+                control,
+                received,
+            });
+        }
+        if let Some(device) = self.get_device_mut(device_handle) {
+            device.record_ranging_round(outcomes.len());
+        }
+        self.report_ranging_round(
+            device_handle,
+            session_id,
+            measurements,
+            outcomes,
+            references,
+        );
+
+        // The controlees only range with the controller.
+        for (controlee, measurement, outcome) in controlee_reports {
+            let reference =
+                outcome.map(|(distance, _, _)| (ground_truth.translation(), distance as f32));
+            self.report_ranging_round(
+                controlee,
+                session_id,
+                vec![measurement],
+                vec![(session_mac_address, outcome)],
+                reference.into_iter().collect(),
+            );
+        }
+    }
+
+    /// Report the measurements of a ranging round to a participating
+    /// device, and transmit the data queued by the device for the round.
+    fn report_ranging_round(
+        &mut self,
+        device_handle: usize,
+        session_id: u32,
+        mut measurements: Vec<ExtendedAddressTwoWayRangingMeasurement>,
+        outcomes: Vec<(MacAddress, RangingOutcome)>,
+        references: Vec<(glam::Vec3, f32)>,
+    ) {
+        let Some(device) = self.get_device(device_handle) else {
+            return;
+        };
+        let Some(session) = device.get_session(session_id) else {
+            return;
+        };
+        let session_type = session.session_type();
+        let sequence_number = session.sequence_number;
+        let source = device.mac_address;
+        let ground_truth = device.position;
+
+        // CCC ranging results only report the distance to the peer.
+        if session_type == SessionType::Ccc {
+            measurements.iter_mut().for_each(strip_aoa);
+        }
+        self.send_event(PicaEvent::RangingData {
+            mac_address: source,
+            session_id,
+            sequence_number,
+            measurements: outcomes
+                .iter()
+                .map(|(mac_address, outcome)| {
+                    let angles = outcome
+                        .filter(|_| session_type != SessionType::Ccc)
+                        .map(|(_, azimuth, elevation)| (azimuth, elevation));
+                    RangingMeasurement {
+                        mac_address: *mac_address,
+                        distance: outcome.map(|(distance, _, _)| distance),
+                        azimuth: angles.map(|(azimuth, _)| azimuth),
+                        elevation: angles.map(|(_, elevation)| elevation),
+                    }
+                })
+                .collect(),
+        });
+        for (destination, outcome) in outcomes {
+            #[cfg(feature = "sqlite")]
+            if let Some(event_log) = &self.event_log {
+                let status = match outcome {
+                    Some(_) => UciStatusCode::UciStatusOk,
+                    None => UciStatusCode::UciStatusRangingRxTimeout,
+                };
+                event_log
+                    .record_measurement(
+                        source,
+                        session_id,
+                        destination,
+                        status.into(),
+                        outcome.unwrap_or_default(),
+                    )
+                    .unwrap_or_else(|err| warn!("Failed to log measurement: {}", err));
+            }
+            self.update_statistics(source, destination, outcome);
+        }
+        if self.position_solver {
+            if let Some(estimate) = solver::multilaterate(&references) {
+                self.send_event(PicaEvent::PositionEstimated {
+                    mac_address: source,
+                    estimate: Position::from_translation(estimate),
+                    ground_truth,
+                    error: estimate.distance(ground_truth.translation()),
+                });
+            }
+        }
+
+        let Some(device) = self.get_device_mut(device_handle) else {
+            return;
+        };
+        let tx = device.tx.clone();
+        let Some(session) = device.get_session_mut(session_id) else {
+            return;
+        };
+        let notification = if session.is_ranging_data_ntf_enabled() != RangeDataNtfConfig::Disable {
+            // The peer addresses have the same format as the session address.
+            let notification: UciNotification = if session.is_owr_aoa() {
+                // The angle of arrival of the advertisements received from
+                // outside the field of view is not measured: their results
+                // are omitted rather than reported with a zero figure of merit.
+                let frame_sequence_number = session.sequence_number as u8;
+                let block_index = session.ranging_block().unwrap_or(0) as u16;
+                let measurements = measurements
+                    .into_iter()
+                    .filter(|measurement| {
+                        measurement.status != UciStatusCode::UciStatusOk
+                            || measurement.aoa_azimuth_fom != OUT_OF_FOV_FOM
+                    })
+                    .map(|measurement| {
+                        make_owr_aoa_measurement(measurement, frame_sequence_number, block_index)
+                    });
+                match session.app_config.device_mac_address {
+                    MacAddress::Short(_) => ShortMacOwrAoaSessionInfoNtfBuilder {
+                        sequence_number: session.sequence_number,
+                        session_token: session_id,
+                        rcr_indicator: 0,
+                        current_ranging_interval: session.current_ranging_interval(),
+                        owr_aoa_ranging_measurements: measurements
+                            .map(make_short_owr_aoa_measurement)
+                            .collect(),
+                        vendor_data: vec![],
+                    }
+                    .build()
+                    .into(),
+                    MacAddress::Extend(_) => ExtendedMacOwrAoaSessionInfoNtfBuilder {
+                        sequence_number: session.sequence_number,
+                        session_token: session_id,
+                        rcr_indicator: 0,
+                        current_ranging_interval: session.current_ranging_interval(),
+                        owr_aoa_ranging_measurements: measurements.collect(),
+                        vendor_data: vec![],
+                    }
+                    .build()
+                    .into(),
+                }
+            } else {
+                match session.app_config.device_mac_address {
+                    MacAddress::Short(_) => ShortMacTwoWaySessionInfoNtfBuilder {
+                        sequence_number: session.sequence_number,
+                        session_token: session_id,
+                        rcr_indicator: 0, //TODO
+                        current_ranging_interval: session.current_ranging_interval(),
+                        two_way_ranging_measurements: measurements
+                            .into_iter()
+                            .map(make_short_measurement)
+                            .collect(),
+                        vendor_data: vec![],
+                    }
+                    .build()
+                    .into(),
+                    MacAddress::Extend(_) => ExtendedMacTwoWaySessionInfoNtfBuilder {
                         sequence_number: session.sequence_number,
                         session_token: session_id,
-                        rcr_indicator: 0,            //TODO
-                        current_ranging_interval: 0, //TODO
+                        rcr_indicator: 0, //TODO
+                        current_ranging_interval: session.current_ranging_interval(),
                         two_way_ranging_measurements: measurements,
                         vendor_data: vec![],
                     }
                     .build()
                     .into(),
-                )
-                .await
-                .unwrap();
+                }
+            };
+            session.sequence_number += 1;
+            Some(notification)
+        } else {
+            None
+        };
+        session.end_ranging_round();
 
-            let device = self.get_device_mut(device_handle).unwrap();
-            let session = device.get_session_mut(session_id).unwrap();
+        // Transmit the data packets queued since the previous ranging round,
+        // returning the consumed credits to the host.
+        let data_notifications = session.transmit_data();
 
-            session.sequence_number += 1;
+        self.metrics.ranging_rounds += 1;
+        if let Some(notification) = notification {
+            self.metrics.ranging_notifications += 1;
+            tx.try_send(notification.into())
+                .unwrap_or_else(|err| warn!("Failed to send ranging notification: {}", err));
+        }
+        for notification in data_notifications {
+            tx.try_send(notification.into())
+                .unwrap_or_else(|err| warn!("Failed to send data credit notification: {}", err));
+        }
+    }
+
+    fn update_statistics(
+        &mut self,
+        source: MacAddress,
+        destination: MacAddress,
+        outcome: Option<(u16, i16, i8)>,
+    ) {
+        let link = self.statistics.entry((source, destination)).or_default();
+        match outcome {
+            Some((distance, azimuth, elevation)) => link.record(distance, azimuth, elevation),
+            None => link.record_loss(),
+        }
+        if let Some(summary) = link.take_summary() {
+            self.send_event(PicaEvent::LinkStatisticsUpdated {
+                source_mac_address: source,
+                destination_mac_address: destination,
+                summary,
+            });
         }
     }
 
-    async fn uci_data(&mut self, device_handle: usize, data: DataPacket) {
+    fn remove_statistics(&mut self, mac_address: MacAddress) {
+        self.statistics.retain(|(source, destination), _| {
+            *source != mac_address && *destination != mac_address
+        });
+    }
+
+    fn uci_data(&mut self, device_handle: usize, data: DataPacket) {
         match self
             .get_device_mut(device_handle)
             .ok_or_else(|| PicaCommandError::DeviceNotFound(device_handle.into()))
         {
             Ok(device) => {
-                let response: SessionControlNotification = device.data_message_snd(data);
-                device.tx.send(response.into()).await.unwrap_or_else(|err| {
-                    println!("Failed to send UCI data packet response: {}", err)
-                });
+                for response in device.data_message_snd(data) {
+                    device.tx.try_send(response.into()).unwrap_or_else(|err| {
+                        warn!("Failed to send UCI data packet response: {}", err)
+                    });
+                }
             }
-            Err(err) => println!("{}", err),
+            Err(err) => warn!("{}", err),
         }
     }
-    async fn command(&mut self, device_handle: usize, cmd: UciCommand) {
+    fn malformed_packet(&mut self, device_handle: usize, reason: String) {
+        warn!(%reason, "Malformed packet");
+        self.metrics.malformed_packets += 1;
+        if let Some(device) = self.get_device(device_handle) {
+            let mac_address = device.mac_address;
+            self.send_event(PicaEvent::MalformedPacket {
+                mac_address,
+                reason,
+            });
+        }
+    }
+
+    fn vendor_command(&mut self, device_handle: usize, cmd: UciCommand) {
+        let gid = u8::from(cmd.get_gid());
+        let opcode = cmd.get_opcode();
+        let (Some(device), Some(handler)) = (
+            self.devices.get(&device_handle),
+            self.vendor_handlers.get_mut(&gid),
+        ) else {
+            return;
+        };
+
+        info!(
+            gid = format_args!("0x{:x}", gid),
+            opcode = format_args!("0x{:x}", opcode),
+            "Vendor command"
+        );
+
+        let output = handler(device_handle, &cmd.to_vec());
+        send_output(device, output, "vendor handler");
+    }
+
+    /// Run the command hooks, and return the command to process, or None
+    /// if a hook answered or dropped the command.
+    fn run_command_hooks(&mut self, device_handle: usize, cmd: UciCommand) -> Option<UciCommand> {
+        if self.command_hooks.is_empty() {
+            return Some(cmd);
+        }
+        let mut bytes = cmd.to_vec();
+        let mut output = None;
+        for hook in self.command_hooks.iter_mut() {
+            match hook(device_handle, &bytes) {
+                CommandHookAction::Continue => (),
+                CommandHookAction::Replace(replaced) => {
+                    if let Err(err) = UciCommand::parse(&replaced) {
+                        warn!("Invalid command returned by command hook: {}", err);
+                        return None;
+                    }
+                    bytes = replaced;
+                }
+                CommandHookAction::Respond(hook_output) => {
+                    output = Some(hook_output);
+                    break;
+                }
+                CommandHookAction::Drop => {
+                    info!("Command dropped by command hook");
+                    return None;
+                }
+            }
+        }
+        match (output, self.devices.get(&device_handle)) {
+            // The command was validated when replaced.
+            (None, _) => UciCommand::parse(&bytes).ok(),
+            (Some(output), Some(device)) => {
+                send_output(device, output, "command hook");
+                None
+            }
+            (Some(_), None) => None,
+        }
+    }
+
+    fn command(&mut self, device_handle: usize, cmd: UciCommand) {
+        let Some(cmd) = self.run_command_hooks(device_handle, cmd) else {
+            return;
+        };
+        if self.vendor_handlers.contains_key(&u8::from(cmd.get_gid())) {
+            return self.vendor_command(device_handle, cmd);
+        }
+
         match self
             .get_device_mut(device_handle)
             .ok_or_else(|| PicaCommandError::DeviceNotFound(device_handle.into()))
         {
             Ok(device) => {
+                let fault = device.response_fault(u8::from(cmd.get_gid()), cmd.get_opcode());
                 let response: ControlPacket = device.command(cmd).into();
-                device
-                    .tx
-                    .send(response)
-                    .await
-                    .unwrap_or_else(|err| println!("Failed to send UCI command response: {}", err));
+                match fault {
+                    None => device.tx.try_send(response).unwrap_or_else(|err| {
+                        warn!("Failed to send UCI command response: {}", err)
+                    }),
+                    Some(ResponseAction::Withhold) => info!("Withhold UCI command response"),
+                    Some(ResponseAction::Delay(delay)) => {
+                        info!(?delay, "Delay UCI command response");
+                        let tx = device.tx.clone();
+                        let timeline = self.timeline.clone();
+                        tokio::spawn(
+                            async move {
+                                timeline.sleep(delay).await;
+                                tx.send(response).await.unwrap_or_else(|err| {
+                                    warn!("Failed to send UCI command response: {}", err)
+                                })
+                            }
+                            .in_current_span(),
+                        );
+                    }
+                }
             }
-            Err(err) => println!("{}", err),
+            Err(err) => warn!("{}", err),
         }
     }
 
-    pub async fn run(&mut self) -> Result<()> {
+    /// Process commands until a [`PicaCommand::Shutdown`] is received.
+    pub async fn run(&mut self) -> PicaResult<()> {
         loop {
             use PicaCommand::*;
-            match self.rx.recv().await {
-                Some(Connect(stream)) => {
-                    self.connect(stream).await;
+            let command = self.rx.recv().await;
+            if let (Some(command_log), Some(command)) = (&mut self.command_log, &command) {
+                command_log
+                    .record(self.timeline.elapsed(), command)
+                    .unwrap_or_else(|err| warn!("Failed to log command: {}", err));
+            }
+            match command {
+                Some(Connect(stream, origin)) => {
+                    self.connect(stream, origin).await;
+                }
+                Some(Disconnect(device_handle)) => {
+                    device_span(device_handle).in_scope(|| self.disconnect(device_handle))
                 }
-                Some(Disconnect(device_handle)) => self.disconnect(device_handle),
                 Some(Ranging(device_handle, session_id)) => {
-                    self.ranging(device_handle, session_id).await;
+                    device_span(device_handle).in_scope(|| self.ranging(device_handle, session_id))
+                }
+                Some(RadarBurst(device_handle, session_id)) => {
+                    device_span(device_handle)
+                        .in_scope(|| self.radar_burst(device_handle, session_id));
+                }
+                Some(SessionUpdated(device_handle, session_id, state, reason)) => {
+                    self.session_updated(device_handle, session_id, state, reason)
                 }
-                Some(StopRanging(mac_address, session_id)) => {
-                    self.stop_controlee_ranging(&mac_address, session_id).await;
+                Some(StopRanging(controller_mac_address, mac_address, session_id)) => {
+                    self.stop_controlee_ranging(controller_mac_address, &mac_address, session_id)
+                        .await;
                 }
-                Some(UciData(device_handle, data)) => self.uci_data(device_handle, data).await,
-                Some(UciCommand(device_handle, cmd)) => self.command(device_handle, cmd).await,
+                Some(UciData(device_handle, data)) => {
+                    device_span(device_handle).in_scope(|| self.uci_data(device_handle, data))
+                }
+                Some(UciCommand(device_handle, cmd)) => {
+                    device_span(device_handle).in_scope(|| self.command(device_handle, cmd))
+                }
+                Some(MalformedPacket(device_handle, reason)) => device_span(device_handle)
+                    .in_scope(|| self.malformed_packet(device_handle, reason)),
                 Some(SetPosition(mac_address, position, pica_cmd_rsp_tx)) => {
                     self.set_position(mac_address, position, pica_cmd_rsp_tx)
                 }
+                Some(SetPositions(positions, pica_cmd_rsp_tx)) => {
+                    self.set_positions(positions, pica_cmd_rsp_tx)
+                }
+                Some(SetOrientation(mac_address, yaw, pitch, roll, pica_cmd_rsp_tx)) => {
+                    self.set_orientation(mac_address, yaw, pitch, roll, pica_cmd_rsp_tx)
+                }
+                Some(SetFieldOfView(mac_address, field_of_view, pica_cmd_rsp_tx)) => {
+                    self.set_field_of_view(mac_address, field_of_view, pica_cmd_rsp_tx)
+                }
+                Some(SetAoaCapability(mac_address, aoa_capability, pica_cmd_rsp_tx)) => {
+                    self.set_aoa_capability(mac_address, aoa_capability, pica_cmd_rsp_tx)
+                }
+                Some(SetClock(mac_address, clock, pica_cmd_rsp_tx)) => {
+                    self.set_clock(mac_address, clock, pica_cmd_rsp_tx)
+                }
+                Some(SetTxPower(mac_address, tx_power, pica_cmd_rsp_tx)) => {
+                    self.set_tx_power(mac_address, tx_power, pica_cmd_rsp_tx)
+                }
+                Some(SetNotificationLatency(mac_address, latency, pica_cmd_rsp_tx)) => {
+                    self.set_notification_latency(mac_address, latency, pica_cmd_rsp_tx)
+                }
+                Some(InjectPacket(mac_address, bytes, pica_cmd_rsp_tx)) => {
+                    self.inject_packet(mac_address, bytes, pica_cmd_rsp_tx)
+                }
+                Some(InjectUciPacket(device_handle, bytes, pica_cmd_rsp_tx)) => {
+                    self.inject_uci_packet(device_handle, bytes, pica_cmd_rsp_tx)
+                }
+                Some(SetResponseFaults(mac_address, faults, pica_cmd_rsp_tx)) => {
+                    self.set_response_faults(mac_address, faults, pica_cmd_rsp_tx)
+                }
+                Some(CrashDevice(mac_address, recovery, pica_cmd_rsp_tx)) => {
+                    self.crash_device(mac_address, recovery, pica_cmd_rsp_tx)
+                }
                 Some(CreateAnchor(mac_address, position, pica_cmd_rsp_tx)) => {
                     self.create_anchor(mac_address, position, pica_cmd_rsp_tx)
                 }
+                Some(CreateConstellation(mac_address, constellation, pica_cmd_rsp_tx)) => {
+                    self.create_constellation(mac_address, constellation, pica_cmd_rsp_tx)
+                }
                 Some(DestroyAnchor(mac_address, pica_cmd_rsp_tx)) => {
                     self.destroy_anchor(mac_address, pica_cmd_rsp_tx)
                 }
+                Some(StartAnchorRanging(mac_address, session_id, interval, pica_cmd_rsp_tx)) => {
+                    self.start_anchor_ranging(mac_address, session_id, interval, pica_cmd_rsp_tx)
+                }
+                Some(StopAnchorRanging(mac_address, pica_cmd_rsp_tx)) => {
+                    self.stop_anchor_ranging(mac_address, pica_cmd_rsp_tx).await
+                }
+                Some(AnchorRanging(mac_address)) => self.anchor_ranging(mac_address),
+                Some(SetScene(scene, pica_cmd_rsp_tx)) => self.set_scene(scene, pica_cmd_rsp_tx),
+                Some(SetZones(zones, pica_cmd_rsp_tx)) => self.set_zones(zones, pica_cmd_rsp_tx),
+                Some(SetPath(mac_address, path, pica_cmd_rsp_tx)) => {
+                    self.set_path(mac_address, path, pica_cmd_rsp_tx)
+                }
+                Some(SetKinematics(mac_address, kinematics, pica_cmd_rsp_tx)) => {
+                    self.set_kinematics(mac_address, kinematics, pica_cmd_rsp_tx)
+                }
+                Some(SetTrajectory(mac_address, trajectory, pica_cmd_rsp_tx)) => {
+                    self.set_trajectory(mac_address, trajectory, pica_cmd_rsp_tx)
+                }
+                Some(UpdateMotion(mac_address)) => self.update_motion(mac_address),
+                Some(SetSessionSeed(mac_address, session_id, seed, pica_cmd_rsp_tx)) => {
+                    self.set_session_seed(mac_address, session_id, seed, pica_cmd_rsp_tx)
+                }
+                Some(SetTimeMode(time_mode, pica_cmd_rsp_tx)) => {
+                    self.set_time_mode(time_mode, pica_cmd_rsp_tx)
+                }
+                Some(AdvanceTime(duration, pica_cmd_rsp_tx)) => {
+                    self.advance_time(duration, pica_cmd_rsp_tx)
+                }
                 Some(GetState(state_tx)) => self.get_state(state_tx),
+                Some(GetEventHistory(sequence_number, history_tx)) => {
+                    self.get_event_history(sequence_number, history_tx)
+                }
+                Some(GetLinkStatistics(statistics_tx)) => self.get_link_statistics(statistics_tx),
+                Some(GetSimulatorInfo(info_tx)) => self.get_simulator_info(info_tx),
+                Some(GetMetrics(metrics_tx)) => self.get_metrics(metrics_tx),
+                Some(Shutdown(shutdown_tx)) => {
+                    self.shutdown(shutdown_tx).await;
+                    return Ok(());
+                }
                 Some(InitUciDevice(mac_address, position, pica_cmd_rsp_tx)) => {
                     self.init_uci_device(mac_address, position, pica_cmd_rsp_tx);
                 }
@@ -684,19 +2960,38 @@ impl Pica {
 
     // Handle the in-band StopRanging command sent from controller to the controlee with
     // corresponding mac_address and session_id.
-    async fn stop_controlee_ranging(&mut self, mac_address: &MacAddress, session_id: u32) {
+    async fn stop_controlee_ranging(
+        &mut self,
+        controller_mac_address: MacAddress,
+        mac_address: &MacAddress,
+        session_id: u32,
+    ) {
+        let received = self
+            .get_device_mut_by_mac_and_session_id(mac_address, session_id)
+            .is_some();
+        self.send_event(PicaEvent::RangingControlMessage {
+            source_mac_address: controller_mac_address,
+            destination_mac_address: *mac_address,
+            session_id,
+            control: RangingControl::Stop,
+            received,
+        });
+
         if let Some(device) = self.get_device_mut_by_mac_and_session_id(mac_address, session_id) {
             // If such device with target session is found, stop the ranging session.
-            let session = device.get_session_mut(session_id).unwrap();
+            let Some(session) = device.get_session_mut(session_id) else {
+                return;
+            };
+            if session.session_state() != SessionState::SessionStateActive {
+                return;
+            }
             session.stop_ranging_task();
             session.set_state(
                 SessionState::SessionStateIdle,
                 ReasonCode::SessionStoppedDueToInbandSignal,
             );
-            device.n_active_sessions -= 1;
-            if device.n_active_sessions == 0 {
-                device.set_state(DeviceState::DeviceStateReady);
-            }
+            let personality = Personality::of_session_type(session.session_type());
+            device.session_stopped(personality);
         }
     }
 
@@ -706,24 +3001,77 @@ impl Pica {
     fn init_uci_device(
         &mut self,
         mac_address: MacAddress,
-        position: Position,
+        position: Option<Position>,
         pica_cmd_rsp_tx: oneshot::Sender<PicaCommandStatus>,
     ) {
-        println!("[_] Init device");
-        println!("  mac_address: {}", mac_address);
-        println!("  position={:?}", position);
+        info!(%mac_address, ?position, "Init device");
 
-        let status = self
-            .get_device_mut_by_mac(mac_address)
-            .ok_or(PicaCommandError::DeviceNotFound(mac_address))
-            .map(|uci_device| {
-                uci_device.mac_address = mac_address;
-                uci_device.position = position;
-            });
+        let status = if let Some(uci_device) = self.get_device_mut_by_mac(mac_address) {
+            uci_device.position = position.unwrap_or_default();
+            Ok(())
+        } else {
+            self.claim_retained_device(mac_address, position)
+        };
 
         pica_cmd_rsp_tx.send(status).unwrap_or_else(|err| {
-            println!("Failed to send init-uci-device command response: {:?}", err)
+            warn!("Failed to send init-uci-device command response: {:?}", err)
+        });
+    }
+
+    /// Assign the address of a disconnected device to the connected
+    /// device still using the address assigned when it connected, and
+    /// restore the properties of the disconnected device. The claim is
+    /// rejected when the address is now used by an anchor, or when
+    /// several connected devices could claim it.
+    fn claim_retained_device(
+        &mut self,
+        mac_address: MacAddress,
+        position: Option<Position>,
+    ) -> PicaCommandStatus {
+        if !self.retained_devices.contains_key(&mac_address) {
+            return Err(PicaCommandError::DeviceNotFound(mac_address));
+        }
+        if self.get_category(&mac_address).is_some() {
+            return Err(PicaCommandError::DeviceAlreadyExists(mac_address));
+        }
+        let mut device_handles = self
+            .devices
+            .iter()
+            .filter(|(_, device)| device.has_handle_mac_address())
+            .map(|(device_handle, _)| *device_handle);
+        let (Some(device_handle), None) = (device_handles.next(), device_handles.next()) else {
+            return Err(PicaCommandError::InvalidArgument(format!(
+                "no single device can claim the address {}",
+                mac_address
+            )));
+        };
+        let retained = self.retained_devices.remove(&mac_address).unwrap();
+        info!(device = device_handle, %mac_address, "Restore device");
+
+        let uci_device = self.devices.get_mut(&device_handle).unwrap();
+        let previous_mac_address = std::mem::replace(&mut uci_device.mac_address, mac_address);
+        self.device_handles.remove(&previous_mac_address);
+        self.device_handles.insert(mac_address, device_handle);
+        uci_device.position = position.unwrap_or(retained.position);
+        uci_device.field_of_view = retained.field_of_view;
+        uci_device.aoa_capability = retained.aoa_capability;
+        uci_device.set_clock(retained.clock);
+        uci_device.set_notification_latency(retained.notification_latency);
+        uci_device.tx_power = retained.tx_power;
+        let position = uci_device.position;
+        let origin = uci_device.origin.clone();
+
+        self.send_event(PicaEvent::DeviceRemoved {
+            category: Category::Uci,
+            mac_address: previous_mac_address,
+        });
+        self.send_event(PicaEvent::DeviceAdded {
+            category: Category::Uci,
+            mac_address,
+            position,
+            origin,
         });
+        Ok(())
     }
 
     fn set_position(
@@ -732,6 +3080,8 @@ impl Pica {
         position: Position,
         pica_cmd_rsp_tx: oneshot::Sender<PicaCommandStatus>,
     ) {
+        // Setting the position explicitly stops the motion of the node.
+        self.motions.remove(&mac_address);
         let mut status = if let Some(uci_device) = self.get_device_mut_by_mac(mac_address) {
             uci_device.position = position;
             Ok(())
@@ -746,136 +3096,2614 @@ impl Pica {
             status = self.update_position(mac_address, position)
         }
 
+        pica_cmd_rsp_tx
+            .send(status)
+            .unwrap_or_else(|err| warn!("Failed to send set-position command response: {:?}", err));
+    }
+
+    fn set_positions(
+        &mut self,
+        positions: Vec<(MacAddress, Position)>,
+        pica_cmd_rsp_tx: oneshot::Sender<PicaCommandStatus>,
+    ) {
+        debug!(count = positions.len(), "Set positions");
+
+        // The positions are all applied, or none of them.
+        let status = match positions
+            .iter()
+            .find(|(mac_address, _)| self.get_category(mac_address).is_none())
+        {
+            Some((mac_address, _)) => Err(PicaCommandError::DeviceNotFound(*mac_address)),
+            None => {
+                for (mac_address, position) in &positions {
+                    self.motions.remove(mac_address);
+                    if let Some(uci_device) = self.get_device_mut_by_mac(*mac_address) {
+                        uci_device.position = *position;
+                    } else if let Some(anchor) = self.anchors.get_mut(mac_address) {
+                        anchor.position = *position;
+                    }
+                }
+                self.update_positions(&positions)
+            }
+        };
+
         pica_cmd_rsp_tx.send(status).unwrap_or_else(|err| {
-            println!("Failed to send set-position command response: {:?}", err)
+            warn!("Failed to send set-positions command response: {:?}", err)
         });
     }
 
-    fn update_position(
-        &self,
+    fn start_anchor_ranging(
+        &mut self,
         mac_address: MacAddress,
-        position: Position,
-    ) -> Result<(), PicaCommandError> {
-        let category = match self.get_category(&mac_address) {
-            Some(category) => category,
-            None => {
-                return Err(PicaCommandError::DeviceNotFound(mac_address));
+        session_id: u32,
+        interval: Duration,
+        pica_cmd_rsp_tx: oneshot::Sender<PicaCommandStatus>,
+    ) {
+        info!(
+            %mac_address,
+            session_id = format_args!("0x{:x}", session_id),
+            ?interval,
+            "Start anchor ranging"
+        );
+
+        let tx = self.tx.clone();
+        let timeline = self.timeline.clone();
+        let status = match self.anchors.get_mut(&mac_address) {
+            Some(anchor) => {
+                // The rounds are at least 1 ms apart.
+                let interval = interval.max(Duration::from_millis(1));
+                anchor.controller = Some(AnchorController::new(
+                    tx,
+                    mac_address,
+                    session_id,
+                    interval,
+                    anchor.clock,
+                    timeline,
+                    self.seed,
+                ));
+                Ok(())
             }
+            None => Err(PicaCommandError::DeviceNotFound(mac_address)),
         };
-        self.send_event(PicaEvent::DeviceUpdated {
-            category,
-            mac_address,
-            position,
+        pica_cmd_rsp_tx.send(status).unwrap_or_else(|err| {
+            warn!(
+                "Failed to send start-anchor-ranging command response: {:?}",
+                err
+            )
         });
+    }
 
-        let devices = self.devices.values().map(|d| (d.mac_address, d.position));
-        let anchors = self.anchors.values().map(|b| (b.mac_address, b.position));
+    async fn stop_anchor_ranging(
+        &mut self,
+        mac_address: MacAddress,
+        pica_cmd_rsp_tx: oneshot::Sender<PicaCommandStatus>,
+    ) {
+        info!(%mac_address, "Stop anchor ranging");
 
-        let update_neighbors = |device_category, device_mac_address, device_position| {
-            if mac_address != device_mac_address {
-                let local = position.compute_range_azimuth_elevation(&device_position);
-                let remote = device_position.compute_range_azimuth_elevation(&position);
+        let controller = self
+            .anchors
+            .get_mut(&mac_address)
+            .map(|anchor| anchor.controller.take());
+        let status = match controller {
+            Some(controller) => {
+                // The controlees are stopped with an in-band message.
+                if let Some(controller) = controller {
+                    let session_id = controller.session_id;
+                    let controlees: Vec<_> = self
+                        .get_anchor_controlees(mac_address, session_id)
+                        .into_iter()
+                        .filter_map(|device_handle| self.get_device(device_handle))
+                        .map(|device| device.mac_address)
+                        .collect();
+                    for controlee in controlees {
+                        self.stop_controlee_ranging(mac_address, &controlee, session_id)
+                            .await;
+                    }
+                }
+                Ok(())
+            }
+            None => Err(PicaCommandError::DeviceNotFound(mac_address)),
+        };
+        pica_cmd_rsp_tx.send(status).unwrap_or_else(|err| {
+            warn!(
+                "Failed to send stop-anchor-ranging command response: {:?}",
+                err
+            )
+        });
+    }
 
-                assert!(local.0 == remote.0);
+    fn set_scene(&mut self, scene: Scene, pica_cmd_rsp_tx: oneshot::Sender<PicaCommandStatus>) {
+        info!(?scene, "Set scene");
 
-                self.send_event(PicaEvent::NeighborUpdated {
-                    source_category: category,
-                    source_mac_address: mac_address,
-                    destination_category: device_category,
-                    destination_mac_address: device_mac_address,
-                    distance: local.0,
-                    azimuth: local.1,
-                    elevation: local.2,
-                });
+        self.scene = scene;
+        pica_cmd_rsp_tx
+            .send(Ok(()))
+            .unwrap_or_else(|err| warn!("Failed to send set-scene command response: {:?}", err));
+    }
 
-                self.send_event(PicaEvent::NeighborUpdated {
-                    source_category: device_category,
-                    source_mac_address: device_mac_address,
-                    destination_category: category,
-                    destination_mac_address: mac_address,
-                    distance: remote.0,
-                    azimuth: remote.1,
-                    elevation: remote.2,
-                });
+    fn set_zones(&mut self, zones: Vec<Zone>, pica_cmd_rsp_tx: oneshot::Sender<PicaCommandStatus>) {
+        info!(?zones, "Set zones");
+
+        let duplicate = zones.iter().enumerate().find(|(index, zone)| {
+            zones[..*index]
+                .iter()
+                .any(|other| other.name() == zone.name())
+        });
+        let status = if let Some((_, zone)) = duplicate {
+            Err(PicaCommandError::InvalidArgument(format!(
+                "duplicate zone {}",
+                zone.name()
+            )))
+        } else {
+            self.zones = zones;
+            // The nodes are reported entering the new zones they are in,
+            // and leaving the zones which were removed.
+            let nodes: Vec<_> = self
+                .devices
+                .values()
+                .map(|device| (Category::Uci, device.mac_address, device.position))
+                .chain(
+                    self.anchors
+                        .values()
+                        .map(|anchor| (Category::Anchor, anchor.mac_address, anchor.position)),
+                )
+                .collect();
+            for (category, mac_address, position) in nodes {
+                self.update_zones(category, mac_address, Some(position));
             }
+            Ok(())
         };
+        pica_cmd_rsp_tx
+            .send(status)
+            .unwrap_or_else(|err| warn!("Failed to send set-zones command response: {:?}", err));
+    }
 
-        devices.for_each(|device| update_neighbors(Category::Uci, device.0, device.1));
-        anchors.for_each(|anchor| update_neighbors(Category::Anchor, anchor.0, anchor.1));
-        Ok(())
+    fn set_orientation(
+        &mut self,
+        mac_address: MacAddress,
+        yaw: i16,
+        pitch: i8,
+        roll: i16,
+        pica_cmd_rsp_tx: oneshot::Sender<PicaCommandStatus>,
+    ) {
+        let position = if let Some(uci_device) = self.get_device_mut_by_mac(mac_address) {
+            uci_device.position = uci_device.position.with_orientation(yaw, pitch, roll);
+            Some(uci_device.position)
+        } else if let Some(anchor) = self.anchors.get_mut(&mac_address) {
+            anchor.position = anchor.position.with_orientation(yaw, pitch, roll);
+            Some(anchor.position)
+        } else {
+            None
+        };
+        let status = match position {
+            Some(position) => self.update_position(mac_address, position),
+            None => Err(PicaCommandError::DeviceNotFound(mac_address)),
+        };
+        pica_cmd_rsp_tx.send(status).unwrap_or_else(|err| {
+            warn!("Failed to send set-orientation command response: {:?}", err)
+        });
     }
 
-    #[allow(clippy::map_entry)]
-    fn create_anchor(
+    fn set_field_of_view(
         &mut self,
         mac_address: MacAddress,
-        position: Position,
+        field_of_view: FieldOfView,
         pica_cmd_rsp_tx: oneshot::Sender<PicaCommandStatus>,
     ) {
-        println!("Create anchor: {} {}", mac_address, position);
-        let status = if self.get_category(&mac_address).is_some() {
-            Err(PicaCommandError::DeviceAlreadyExists(mac_address))
+        info!(%mac_address, ?field_of_view, "Set field of view");
+
+        let status = if let Some(uci_device) = self.get_device_mut_by_mac(mac_address) {
+            uci_device.field_of_view = field_of_view;
+            Ok(())
+        } else if let Some(anchor) = self.anchors.get_mut(&mac_address) {
+            anchor.field_of_view = field_of_view;
+            Ok(())
         } else {
-            self.send_event(PicaEvent::DeviceAdded {
-                category: Category::Anchor,
-                mac_address,
-                position,
-            });
-            assert!(self
-                .anchors
-                .insert(
+            Err(PicaCommandError::DeviceNotFound(mac_address))
+        };
+        pica_cmd_rsp_tx.send(status).unwrap_or_else(|err| {
+            warn!(
+                "Failed to send set-field-of-view command response: {:?}",
+                err
+            )
+        });
+    }
+
+    fn set_aoa_capability(
+        &mut self,
+        mac_address: MacAddress,
+        aoa_capability: AoaCapability,
+        pica_cmd_rsp_tx: oneshot::Sender<PicaCommandStatus>,
+    ) {
+        info!(%mac_address, ?aoa_capability, "Set AoA capability");
+
+        let status = if let Some(uci_device) = self.get_device_mut_by_mac(mac_address) {
+            uci_device.aoa_capability = aoa_capability;
+            Ok(())
+        } else if let Some(anchor) = self.anchors.get_mut(&mac_address) {
+            anchor.aoa_capability = aoa_capability;
+            Ok(())
+        } else {
+            Err(PicaCommandError::DeviceNotFound(mac_address))
+        };
+        pica_cmd_rsp_tx.send(status).unwrap_or_else(|err| {
+            warn!(
+                "Failed to send set-aoa-capability command response: {:?}",
+                err
+            )
+        });
+    }
+
+    fn set_clock(
+        &mut self,
+        mac_address: MacAddress,
+        clock: Clock,
+        pica_cmd_rsp_tx: oneshot::Sender<PicaCommandStatus>,
+    ) {
+        info!(%mac_address, ?clock, "Set clock");
+
+        let tx = self.tx.clone();
+        let timeline = self.timeline.clone();
+        let status = if let Some(uci_device) = self.get_device_mut_by_mac(mac_address) {
+            uci_device.set_clock(clock);
+            Ok(())
+        } else if let Some(anchor) = self.anchors.get_mut(&mac_address) {
+            anchor.clock = clock;
+            if let Some(controller) = &anchor.controller {
+                anchor.controller = Some(AnchorController::new(
+                    tx,
                     mac_address,
-                    Anchor {
-                        mac_address,
-                        position,
-                    },
-                )
-                .is_none());
+                    controller.session_id,
+                    controller.interval,
+                    clock,
+                    timeline,
+                    self.seed,
+                ));
+            }
+            Ok(())
+        } else {
+            Err(PicaCommandError::DeviceNotFound(mac_address))
+        };
+        pica_cmd_rsp_tx
+            .send(status)
+            .unwrap_or_else(|err| warn!("Failed to send set-clock command response: {:?}", err));
+    }
+
+    fn set_tx_power(
+        &mut self,
+        mac_address: MacAddress,
+        tx_power: Option<f32>,
+        pica_cmd_rsp_tx: oneshot::Sender<PicaCommandStatus>,
+    ) {
+        info!(%mac_address, ?tx_power, "Set TX power");
+
+        let status = if let Some(uci_device) = self.get_device_mut_by_mac(mac_address) {
+            uci_device.tx_power = tx_power;
+            Ok(())
+        } else if let Some(anchor) = self.anchors.get_mut(&mac_address) {
+            anchor.tx_power = tx_power;
             Ok(())
+        } else {
+            Err(PicaCommandError::DeviceNotFound(mac_address))
+        };
+        pica_cmd_rsp_tx
+            .send(status)
+            .unwrap_or_else(|err| warn!("Failed to send set-tx-power command response: {:?}", err));
+    }
+
+    fn set_notification_latency(
+        &mut self,
+        mac_address: MacAddress,
+        latency: NotificationLatency,
+        pica_cmd_rsp_tx: oneshot::Sender<PicaCommandStatus>,
+    ) {
+        info!(%mac_address, ?latency, "Set notification latency");
+
+        let status = match self.get_device_mut_by_mac(mac_address) {
+            Some(uci_device) => {
+                uci_device.set_notification_latency(latency);
+                Ok(())
+            }
+            None => Err(PicaCommandError::DeviceNotFound(mac_address)),
         };
+        pica_cmd_rsp_tx.send(status).unwrap_or_else(|err| {
+            warn!(
+                "Failed to send set-notification-latency command response: {:?}",
+                err
+            )
+        });
+    }
+
+    fn inject_packet(
+        &mut self,
+        mac_address: MacAddress,
+        bytes: Bytes,
+        pica_cmd_rsp_tx: oneshot::Sender<PicaCommandStatus>,
+    ) {
+        info!(%mac_address, bytes = hex::encode(&bytes), "Inject packet");
+
+        let status = self
+            .device_handles
+            .get(&mac_address)
+            .copied()
+            .ok_or(PicaCommandError::DeviceNotFound(mac_address))
+            .and_then(|device_handle| self.send_injected_packet(device_handle, bytes));
+        pica_cmd_rsp_tx.send(status).unwrap_or_else(|err| {
+            warn!("Failed to send inject-packet command response: {:?}", err)
+        });
+    }
+
+    fn inject_uci_packet(
+        &mut self,
+        device_handle: usize,
+        bytes: Bytes,
+        pica_cmd_rsp_tx: oneshot::Sender<PicaCommandStatus>,
+    ) {
+        info!(
+            device = device_handle,
+            bytes = hex::encode(&bytes),
+            "Inject UCI packet"
+        );
 
+        let status = self.send_injected_packet(device_handle, bytes);
         pica_cmd_rsp_tx.send(status).unwrap_or_else(|err| {
-            println!("Failed to send create-anchor command response: {:?}", err)
+            warn!(
+                "Failed to send inject-uci-packet command response: {:?}",
+                err
+            )
+        });
+    }
+
+    /// Queue the bytes for the connection task of the device.
+    fn send_injected_packet(&self, device_handle: usize, bytes: Bytes) -> PicaCommandStatus {
+        let injected_packet_tx = self
+            .injected_packet_txs
+            .get(&device_handle)
+            .ok_or_else(|| {
+                PicaCommandError::InvalidArgument(format!("device handle {}", device_handle))
+            })?;
+        // The connection task may be waiting for pica,
+        // the bytes are dropped rather than waiting for it.
+        injected_packet_tx.try_send(bytes).map_err(|_| {
+            PicaCommandError::LimitExceeded("injected packets pending", INJECTED_PACKET_CAPACITY)
         })
     }
 
-    fn destroy_anchor(
+    fn set_response_faults(
         &mut self,
         mac_address: MacAddress,
+        faults: Vec<ResponseFault>,
         pica_cmd_rsp_tx: oneshot::Sender<PicaCommandStatus>,
     ) {
-        println!("[_] Destroy anchor");
-        println!("  mac_address: {}", mac_address);
+        info!(%mac_address, ?faults, "Set response faults");
 
-        let status = if self.anchors.remove(&mac_address).is_none() {
+        let status = match self.get_device_mut_by_mac(mac_address) {
+            Some(uci_device) => {
+                uci_device.set_response_faults(faults);
+                Ok(())
+            }
+            None => Err(PicaCommandError::DeviceNotFound(mac_address)),
+        };
+        pica_cmd_rsp_tx.send(status).unwrap_or_else(|err| {
+            warn!(
+                "Failed to send set-response-faults command response: {:?}",
+                err
+            )
+        });
+    }
+
+    fn crash_device(
+        &mut self,
+        mac_address: MacAddress,
+        recovery: CrashRecovery,
+        pica_cmd_rsp_tx: oneshot::Sender<PicaCommandStatus>,
+    ) {
+        info!(%mac_address, ?recovery, "Crash device");
+
+        let device_handle = self.device_handles.get(&mac_address).copied();
+        let status = match device_handle {
+            Some(device_handle) => {
+                self.devices.get_mut(&device_handle).unwrap().crash();
+                // The error state notification is still delivered, as
+                // the packet channel is closed after it is sent.
+                if recovery == CrashRecovery::Disconnect {
+                    self.disconnect(device_handle);
+                }
+                Ok(())
+            }
+            None => Err(PicaCommandError::DeviceNotFound(mac_address)),
+        };
+        pica_cmd_rsp_tx
+            .send(status)
+            .unwrap_or_else(|err| warn!("Failed to send crash-device command response: {:?}", err));
+    }
+
+    fn set_path(
+        &mut self,
+        mac_address: MacAddress,
+        path: Option<MotionPath>,
+        pica_cmd_rsp_tx: oneshot::Sender<PicaCommandStatus>,
+    ) {
+        info!(%mac_address, ?path, "Set path");
+
+        let status = if self.get_category(&mac_address).is_none() {
             Err(PicaCommandError::DeviceNotFound(mac_address))
         } else {
-            self.send_event(PicaEvent::DeviceRemoved {
-                category: Category::Anchor,
-                mac_address,
-            });
+            self.start_motion(mac_address, path.map(Trajectory::Path));
             Ok(())
         };
+        pica_cmd_rsp_tx
+            .send(status)
+            .unwrap_or_else(|err| warn!("Failed to send set-path command response: {:?}", err));
+    }
+
+    fn set_kinematics(
+        &mut self,
+        mac_address: MacAddress,
+        kinematics: Option<Kinematics>,
+        pica_cmd_rsp_tx: oneshot::Sender<PicaCommandStatus>,
+    ) {
+        info!(%mac_address, ?kinematics, "Set kinematics");
+
+        let origin = if let Some(uci_device) = self.get_device_mut_by_mac(mac_address) {
+            Some(uci_device.position.translation())
+        } else {
+            self.anchors
+                .get(&mac_address)
+                .map(|anchor| anchor.position.translation())
+        };
+        let status = match origin {
+            None => Err(PicaCommandError::DeviceNotFound(mac_address)),
+            Some(origin) => {
+                self.start_motion(
+                    mac_address,
+                    kinematics.map(|kinematics| Trajectory::Kinematics(origin, kinematics)),
+                );
+                Ok(())
+            }
+        };
         pica_cmd_rsp_tx.send(status).unwrap_or_else(|err| {
-            println!("Failed to send destroy-anchor command response: {:?}", err)
-        })
+            warn!("Failed to send set-kinematics command response: {:?}", err)
+        });
     }
 
-    fn get_state(&self, state_tx: oneshot::Sender<Vec<(Category, MacAddress, Position)>>) {
-        println!("[_] Get State");
+    fn set_trajectory(
+        &mut self,
+        mac_address: MacAddress,
+        trajectory: Option<RecordedTrajectory>,
+        pica_cmd_rsp_tx: oneshot::Sender<PicaCommandStatus>,
+    ) {
+        info!(
+            %mac_address,
+            duration = ?trajectory.as_ref().map(RecordedTrajectory::duration),
+            "Set trajectory"
+        );
 
-        state_tx
-            .send(
-                self.anchors
-                    .values()
-                    .map(|anchor| (Category::Anchor, anchor.mac_address, anchor.position))
-                    .chain(
-                        self.devices
+        let status = if self.get_category(&mac_address).is_none() {
+            Err(PicaCommandError::DeviceNotFound(mac_address))
+        } else {
+            self.start_motion(mac_address, trajectory.map(Trajectory::Recorded));
+            Ok(())
+        };
+        pica_cmd_rsp_tx.send(status).unwrap_or_else(|err| {
+            warn!("Failed to send set-trajectory command response: {:?}", err)
+        });
+    }
+
+    /// Replace the motion of the node, or stop it if None. The task
+    /// triggering the updates of the position of the node is started
+    /// along with the motion.
+    fn start_motion(&mut self, mac_address: MacAddress, trajectory: Option<Trajectory>) {
+        self.motions.remove(&mac_address);
+        let Some(trajectory) = trajectory else {
+            return;
+        };
+        let tx = self.tx.clone();
+        let timeline = self.timeline.clone();
+        let start = timeline.now();
+        let task = tokio::spawn(async move {
+            for update_index in 0.. {
+                timeline
+                    .sleep_until(start + MOTION_UPDATE_INTERVAL * update_index)
+                    .await;
+                if tx
+                    .send(PicaCommand::UpdateMotion(mac_address))
+                    .await
+                    .is_err()
+                {
+                    // Pica is shutting down.
+                    break;
+                }
+            }
+        });
+        self.motions
+            .insert(mac_address, Motion::new(trajectory, start, task));
+    }
+
+    fn update_motion(&mut self, mac_address: MacAddress) {
+        // The motion may have been stopped after the update was queued.
+        let now = self.timeline.now();
+        let Some(translation) = self
+            .motions
+            .get(&mac_address)
+            .map(|motion| motion.translation(now))
+        else {
+            return;
+        };
+        let position = if let Some(uci_device) = self.get_device_mut_by_mac(mac_address) {
+            uci_device.position = uci_device.position.with_translation(translation);
+            uci_device.position
+        } else if let Some(anchor) = self.anchors.get_mut(&mac_address) {
+            anchor.position = anchor.position.with_translation(translation);
+            anchor.position
+        } else {
+            self.motions.remove(&mac_address);
+            return;
+        };
+        self.update_position(mac_address, position)
+            .unwrap_or_else(|err| warn!("Failed to update position: {}", err));
+    }
+
+    fn set_session_seed(
+        &mut self,
+        mac_address: MacAddress,
+        session_id: u32,
+        seed: Option<u64>,
+        pica_cmd_rsp_tx: oneshot::Sender<PicaCommandStatus>,
+    ) {
+        info!(
+            %mac_address,
+            session_id = format_args!("0x{:x}", session_id),
+            "Set session seed"
+        );
+
+        let status = match self.get_device_mut_by_mac(mac_address) {
+            Some(device) => match device.get_session_mut(session_id) {
+                Some(session) => {
+                    session.set_noise_seed(seed);
+                    Ok(())
+                }
+                None => Err(PicaCommandError::SessionNotFound(mac_address, session_id)),
+            },
+            None => Err(PicaCommandError::DeviceNotFound(mac_address)),
+        };
+        pica_cmd_rsp_tx.send(status).unwrap_or_else(|err| {
+            warn!(
+                "Failed to send set-session-seed command response: {:?}",
+                err
+            )
+        });
+    }
+
+    fn set_time_mode(
+        &mut self,
+        time_mode: TimeMode,
+        pica_cmd_rsp_tx: oneshot::Sender<PicaCommandStatus>,
+    ) {
+        info!(?time_mode, "Set time mode");
+
+        let status = match time_mode {
+            TimeMode::Scaled(speed) if !(speed.is_finite() && speed > 0.0) => Err(
+                PicaCommandError::InvalidArgument(format!("time speed {}", speed)),
+            ),
+            _ => {
+                self.timeline.set_mode(time_mode);
+                Ok(())
+            }
+        };
+        pica_cmd_rsp_tx.send(status).unwrap_or_else(|err| {
+            warn!("Failed to send set-time-mode command response: {:?}", err)
+        });
+    }
+
+    fn advance_time(
+        &mut self,
+        duration: Duration,
+        pica_cmd_rsp_tx: oneshot::Sender<PicaCommandStatus>,
+    ) {
+        debug!(?duration, "Advance time");
+
+        self.timeline.advance(duration);
+        pica_cmd_rsp_tx
+            .send(Ok(()))
+            .unwrap_or_else(|err| warn!("Failed to send advance-time command response: {:?}", err));
+    }
+
+    fn update_position(
+        &mut self,
+        mac_address: MacAddress,
+        position: Position,
+    ) -> Result<(), PicaCommandError> {
+        self.update_positions(&[(mac_address, position)])
+    }
+
+    /// Send the updates of the moved anchors and devices, and of their
+    /// neighbors. The neighbors of the moved nodes are updated in a single
+    /// pass, once for each pair of nodes. When a node is listed several
+    /// times, its last position is retained.
+    fn update_positions(
+        &mut self,
+        positions: &[(MacAddress, Position)],
+    ) -> Result<(), PicaCommandError> {
+        let mut moved: Vec<(Category, MacAddress, Position)> = Vec::new();
+        let mut moved_index: HashMap<MacAddress, usize> = HashMap::new();
+        for &(mac_address, position) in positions {
+            let category = self
+                .get_category(&mac_address)
+                .ok_or(PicaCommandError::DeviceNotFound(mac_address))?;
+            match moved_index.get(&mac_address) {
+                Some(&index) => moved[index].2 = position,
+                None => {
+                    moved_index.insert(mac_address, moved.len());
+                    moved.push((category, mac_address, position));
+                }
+            }
+        }
+
+        let now = self.timeline.now();
+        for &(category, mac_address, position) in &moved {
+            let kinematics = self
+                .motions
+                .get(&mac_address)
+                .map(|motion| motion.kinematics(now));
+            self.send_event(PicaEvent::DeviceUpdated {
+                category,
+                mac_address,
+                position,
+                kinematics,
+            });
+        }
+
+        let devices: Vec<_> = self
+            .devices
+            .values()
+            .map(|d| (Category::Uci, d.mac_address, d.position))
+            .chain(
+                self.anchors
+                    .values()
+                    .map(|b| (Category::Anchor, b.mac_address, b.position)),
+            )
+            .collect();
+
+        for (index, &(category, mac_address, position)) in moved.iter().enumerate() {
+            for &(device_category, device_mac_address, device_position) in &devices {
+                // The pairs of moved nodes are updated with the first
+                // node of the pair.
+                let device_position = match moved_index.get(&device_mac_address) {
+                    Some(&device_index) if device_index <= index => continue,
+                    Some(&device_index) => moved[device_index].2,
+                    None => device_position,
+                };
+                let local = position.compute_range_azimuth_elevation(&device_position);
+                let remote = device_position.compute_range_azimuth_elevation(&position);
+
+                assert!(local.0 == remote.0);
+
+                self.send_event(PicaEvent::NeighborUpdated {
+                    source_category: category,
+                    source_mac_address: mac_address,
+                    destination_category: device_category,
+                    destination_mac_address: device_mac_address,
+                    distance: local.0,
+                    azimuth: local.1,
+                    elevation: local.2,
+                });
+
+                self.send_event(PicaEvent::NeighborUpdated {
+                    source_category: device_category,
+                    source_mac_address: device_mac_address,
+                    destination_category: category,
+                    destination_mac_address: mac_address,
+                    distance: remote.0,
+                    azimuth: remote.1,
+                    elevation: remote.2,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    #[allow(clippy::map_entry)]
+    fn create_anchor(
+        &mut self,
+        mac_address: MacAddress,
+        position: Position,
+        pica_cmd_rsp_tx: oneshot::Sender<PicaCommandStatus>,
+    ) {
+        info!(%mac_address, %position, "Create anchor");
+        let status = if self.get_category(&mac_address).is_some() {
+            Err(PicaCommandError::DeviceAlreadyExists(mac_address))
+        } else if self.anchors.len() >= self.max_anchors {
+            Err(PicaCommandError::LimitExceeded("anchors", self.max_anchors))
+        } else {
+            self.add_anchor(mac_address, position);
+            Ok(())
+        };
+
+        pica_cmd_rsp_tx
+            .send(status)
+            .unwrap_or_else(|err| warn!("Failed to send create-anchor command response: {:?}", err))
+    }
+
+    fn add_anchor(&mut self, mac_address: MacAddress, position: Position) {
+        self.send_event(PicaEvent::DeviceAdded {
+            category: Category::Anchor,
+            mac_address,
+            position,
+            origin: None,
+        });
+        assert!(self
+            .anchors
+            .insert(
+                mac_address,
+                Anchor {
+                    mac_address,
+                    position,
+                    field_of_view: FieldOfView::default(),
+                    aoa_capability: AoaCapability::default(),
+                    clock: Clock::default(),
+                    tx_power: None,
+                    controller: None,
+                },
+            )
+            .is_none());
+    }
+
+    fn create_constellation(
+        &mut self,
+        mac_address: MacAddress,
+        constellation: Constellation,
+        pica_cmd_rsp_tx: oneshot::Sender<PicaCommandStatus>,
+    ) {
+        info!(%mac_address, ?constellation, "Create constellation");
+
+        // The anchors are all created, or none of them.
+        let status = (|| {
+            let positions = constellation.positions().ok_or_else(|| {
+                PicaCommandError::InvalidArgument(
+                    "the anchors exceed the range of the coordinates".to_owned(),
+                )
+            })?;
+            let mac_addresses = (0..constellation.count as u64)
+                .map(|index| mac_address.offset(index))
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(|| {
+                    PicaCommandError::InvalidArgument(
+                        "the anchors exceed the range of the MAC addresses".to_owned(),
+                    )
+                })?;
+            if let Some(mac_address) = mac_addresses
+                .iter()
+                .find(|mac_address| self.get_category(mac_address).is_some())
+            {
+                return Err(PicaCommandError::DeviceAlreadyExists(*mac_address));
+            }
+            if self.anchors.len() + mac_addresses.len() > self.max_anchors {
+                return Err(PicaCommandError::LimitExceeded("anchors", self.max_anchors));
+            }
+            for (mac_address, position) in mac_addresses.into_iter().zip(positions) {
+                self.add_anchor(mac_address, position);
+            }
+            Ok(())
+        })();
+
+        pica_cmd_rsp_tx.send(status).unwrap_or_else(|err| {
+            warn!(
+                "Failed to send create-constellation command response: {:?}",
+                err
+            )
+        })
+    }
+
+    fn destroy_anchor(
+        &mut self,
+        mac_address: MacAddress,
+        pica_cmd_rsp_tx: oneshot::Sender<PicaCommandStatus>,
+    ) {
+        info!(%mac_address, "Destroy anchor");
+
+        let status = if self.anchors.remove(&mac_address).is_none() {
+            Err(PicaCommandError::DeviceNotFound(mac_address))
+        } else {
+            self.motions.remove(&mac_address);
+            self.send_event(PicaEvent::DeviceRemoved {
+                category: Category::Anchor,
+                mac_address,
+            });
+            self.remove_statistics(mac_address);
+            Ok(())
+        };
+        pica_cmd_rsp_tx.send(status).unwrap_or_else(|err| {
+            warn!("Failed to send destroy-anchor command response: {:?}", err)
+        })
+    }
+
+    fn get_state(&self, state_tx: oneshot::Sender<PicaState>) {
+        debug!("Get state");
+
+        let (monotonic_us, timestamp_ms) = self.event_time();
+        state_tx
+            .send(PicaState {
+                sequence_number: self.sequence_number,
+                devices: self
+                    .anchors
+                    .values()
+                    .map(|anchor| (Category::Anchor, anchor.mac_address, anchor.position))
+                    .chain(
+                        self.devices
                             .values()
                             .map(|device| (Category::Uci, device.mac_address, device.position)),
                     )
                     .collect(),
+                sessions: {
+                    let mut sessions: Vec<_> = self
+                        .devices
+                        .values()
+                        .flat_map(|device| {
+                            device
+                                .sessions()
+                                .map(|session| (device.mac_address, session.info()))
+                        })
+                        .collect();
+                    sessions.sort_by_key(|(mac_address, session)| {
+                        (u64::from(mac_address), session.session_id)
+                    });
+                    sessions
+                },
+                origins: self
+                    .devices
+                    .values()
+                    .filter_map(|device| Some((device.mac_address, device.origin.clone()?)))
+                    .collect(),
+                monotonic_us,
+                timestamp_ms,
+            })
+            .unwrap_or_else(|err| warn!("Failed to send get-state response: {:?}", err));
+    }
+
+    /// Reply with the retained events following `sequence_number`.
+    /// The history is incomplete if the first event returned does not
+    /// directly follow `sequence_number`: observers then fetch a new
+    /// snapshot with GetState.
+    fn get_event_history(
+        &self,
+        sequence_number: u64,
+        history_tx: oneshot::Sender<Vec<SequencedEvent>>,
+    ) {
+        debug!(sequence_number, "Get event history");
+
+        history_tx
+            .send(
+                self.event_history
+                    .iter()
+                    .filter(|event| event.sequence_number > sequence_number)
+                    .cloned()
+                    .collect(),
             )
-            .unwrap();
+            .unwrap_or_else(|err| warn!("Failed to send event history: {:?}", err));
+    }
+
+    fn get_link_statistics(
+        &self,
+        statistics_tx: oneshot::Sender<Vec<(MacAddress, MacAddress, LinkSummary)>>,
+    ) {
+        debug!("Get link statistics");
+
+        statistics_tx
+            .send(
+                self.statistics
+                    .iter()
+                    .map(|((source, destination), link)| (*source, *destination, link.summary()))
+                    .collect(),
+            )
+            .unwrap_or_else(|err| warn!("Failed to send get-link-statistics response: {:?}", err));
+    }
+
+    fn get_simulator_info(&self, info_tx: oneshot::Sender<SimulatorInfo>) {
+        debug!("Get simulator info");
+
+        let info = SimulatorInfo {
+            max_devices: self.max_devices,
+            max_anchors: self.max_anchors,
+            max_sessions: self.max_sessions,
+            max_range: self.max_range.min(regulatory::default_max_range()),
+            seed: self.seed,
+            ..SimulatorInfo::new()
+        };
+        info_tx
+            .send(info)
+            .unwrap_or_else(|err| warn!("Failed to send get-simulator-info response: {:?}", err));
+    }
+
+    fn get_metrics(&self, metrics_tx: oneshot::Sender<Metrics>) {
+        let metrics = Metrics {
+            connected_devices: self.devices.len(),
+            anchors: self.anchors.len(),
+            active_sessions: self
+                .devices
+                .values()
+                .flat_map(|device| device.sessions())
+                .filter(|session| session.state == SessionState::SessionStateActive)
+                .count(),
+            events: self.sequence_number,
+            ..self.metrics.clone()
+        };
+        metrics_tx
+            .send(metrics)
+            .unwrap_or_else(|err| warn!("Failed to send get-metrics response: {:?}", err));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn state_snapshot_sequence_number() {
+        let mut pica = Pica::builder().build();
+        let mut event_rx = pica.event_tx().subscribe();
+        let mac_address = MacAddress::Short([0, 1]);
+
+        let (state_tx, mut state_rx) = oneshot::channel();
+        pica.get_state(state_tx);
+        assert_eq!(state_rx.try_recv().unwrap().sequence_number, 0);
+
+        let (status_tx, _) = oneshot::channel();
+        pica.create_anchor(mac_address, Position::default(), status_tx);
+        let (status_tx, _) = oneshot::channel();
+        pica.destroy_anchor(mac_address, status_tx);
+
+        let (state_tx, mut state_rx) = oneshot::channel();
+        pica.get_state(state_tx);
+        let state = state_rx.try_recv().unwrap();
+        assert_eq!(state.sequence_number, 2);
+        assert!(state.devices.is_empty());
+
+        assert_eq!(event_rx.try_recv().unwrap().sequence_number, 1);
+        assert_eq!(event_rx.try_recv().unwrap().sequence_number, 2);
+    }
+
+    #[test]
+    fn event_history() {
+        let mut pica = Pica::builder().build();
+        let mac_address = MacAddress::Short([0, 1]);
+
+        for _ in 0..EVENT_HISTORY_SIZE {
+            let (status_tx, _) = oneshot::channel();
+            pica.create_anchor(mac_address, Position::default(), status_tx);
+            let (status_tx, _) = oneshot::channel();
+            pica.destroy_anchor(mac_address, status_tx);
+        }
+
+        let (history_tx, mut history_rx) = oneshot::channel();
+        pica.get_event_history(0, history_tx);
+        let history = history_rx.try_recv().unwrap();
+        assert_eq!(history.len(), EVENT_HISTORY_SIZE);
+        assert_eq!(history[0].sequence_number, (EVENT_HISTORY_SIZE + 1) as u64);
+
+        let (history_tx, mut history_rx) = oneshot::channel();
+        pica.get_event_history(pica.sequence_number - 1, history_tx);
+        let history = history_rx.try_recv().unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].sequence_number, pica.sequence_number);
+    }
+
+    #[test]
+    fn event_timestamps() {
+        let mut pica = Pica::builder().build();
+        let mut event_rx = pica.event_tx().subscribe();
+        let mac_address = MacAddress::Short([0, 1]);
+
+        let (status_tx, _) = oneshot::channel();
+        pica.create_anchor(mac_address, Position::default(), status_tx);
+        let (status_tx, _) = oneshot::channel();
+        pica.destroy_anchor(mac_address, status_tx);
+
+        let first = event_rx.try_recv().unwrap();
+        let second = event_rx.try_recv().unwrap();
+        assert!(first.monotonic_us <= second.monotonic_us);
+        assert!(first.timestamp_ms > 0);
+    }
+
+    #[test]
+    fn event_filter() {
+        let (a, b, c) = (
+            MacAddress::Short([0, 1]),
+            MacAddress::Short([0, 2]),
+            MacAddress::Short([0, 3]),
+        );
+        let neighbor_updated = PicaEvent::NeighborUpdated {
+            source_category: Category::Uci,
+            source_mac_address: a,
+            destination_category: Category::Anchor,
+            destination_mac_address: b,
+            distance: 100,
+            azimuth: 0,
+            elevation: 0,
+        };
+        let device_removed = PicaEvent::DeviceRemoved {
+            category: Category::Anchor,
+            mac_address: c,
+        };
+        assert!(EventFilter::default().matches(&neighbor_updated));
+        let filter = EventFilter {
+            kinds: vec!["neighbor-updated".to_owned()],
+            mac_addresses: vec![b],
+        };
+        assert!(filter.matches(&neighbor_updated));
+        assert!(!filter.matches(&device_removed));
+        let filter = EventFilter {
+            mac_addresses: vec![c],
+            ..Default::default()
+        };
+        assert!(!filter.matches(&neighbor_updated));
+        assert!(filter.matches(&device_removed));
+    }
+
+    #[test]
+    fn parse_protocol_violation() {
+        // DEVICE_STATUS_NTF sent by the host.
+        let ntf = [0x60, 0x01, 0x00, 0x01, 0x01];
+        assert!(matches!(
+            parse_uci_packet(&ntf),
+            UciParseResult::Ignored(PicaError::Protocol(_))
+        ));
+        assert!(matches!(
+            parse_uci_packet(&ntf[..2]),
+            UciParseResult::Ignored(PicaError::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn parse_command_length() {
+        // SESSION_INIT with a session id and session type.
+        let cmd = [0x21, 0x00, 0x00, 0x05, 0x01, 0x00, 0x00, 0x00, 0x00];
+        assert!(matches!(
+            parse_uci_packet(&cmd),
+            UciParseResult::UciCommand(_)
+        ));
+
+        let syntax_error = u8::from(UciStatusCode::UciStatusSyntaxError);
+        for cmd in [&cmd[..8], &[&cmd[..], &[0x00]].concat()] {
+            match parse_uci_packet(cmd) {
+                UciParseResult::Malformed(response, _) => {
+                    assert_eq!(&response[..], &[0x41, 0x00, 0x00, 0x01, syntax_error])
+                }
+                _ => panic!("expected a malformed packet"),
+            }
+        }
+
+        // Unknown commands are not validated, and are answered
+        // with STATUS_UNKNOWN_OID by the device.
+        assert!(matches!(
+            parse_uci_packet(&[0x20, 0x3f, 0x00, 0x01, 0x00]),
+            UciParseResult::UciCommand(_)
+        ));
+    }
+
+    #[test]
+    fn parse_android_command_length() {
+        // ANDROID_SET_COUNTRY_CODE with a country code.
+        let cmd = [0x2c, 0x01, 0x00, 0x02, b'U', b'S'];
+        assert!(matches!(
+            parse_uci_packet(&cmd),
+            UciParseResult::UciCommand(_)
+        ));
+
+        let syntax_error = u8::from(UciStatusCode::UciStatusSyntaxError);
+        for cmd in [
+            &[0x2c, 0x01, 0x00, 0x01, b'U'][..],
+            &[0x2c, 0x00, 0x00, 0x01, 0x00],
+        ] {
+            match parse_uci_packet(cmd) {
+                UciParseResult::Malformed(response, _) => {
+                    assert_eq!(&response[..], &[0x4c, cmd[1], 0x00, 0x01, syntax_error])
+                }
+                _ => panic!("expected a malformed packet"),
+            }
+        }
+
+        // Android notification opcodes are not commands.
+        assert!(matches!(
+            parse_uci_packet(&[0x2c, 0x02, 0x00, 0x00]),
+            UciParseResult::UciCommand(_)
+        ));
+    }
+
+    #[test]
+    fn parse_arbitrary_bytes() {
+        use rand::{Rng, SeedableRng};
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        for _ in 0..10000 {
+            let length = rng.gen_range(0..16);
+            let bytes: Vec<u8> = (0..length).map(|_| rng.gen()).collect();
+            parse_uci_packet(&bytes);
+        }
+    }
+
+    #[test]
+    fn parse_data_length() {
+        // DATA_MESSAGE_SND with two bytes of application data.
+        let mut data = vec![0x01, 0x00, 0x12, 0x00];
+        data.extend([0x01, 0x00, 0x00, 0x00]);
+        data.extend([0x00; 8]);
+        data.extend([0x00, 0x00, 0x02, 0x00, 0xaa, 0xbb]);
+        assert!(matches!(
+            parse_uci_packet(&data),
+            UciParseResult::UciData(_)
+        ));
+
+        data[2] += 1;
+        data.push(0xcc);
+        assert!(matches!(
+            parse_uci_packet(&data),
+            UciParseResult::Malformed(_, _)
+        ));
+    }
+
+    #[test]
+    fn set_session_seed_unknown_device() {
+        let mut pica = Pica::builder().build();
+        let mac_address = MacAddress::Short([0, 1]);
+
+        let (status_tx, mut status_rx) = oneshot::channel();
+        pica.set_session_seed(mac_address, 1, Some(42), status_tx);
+        assert_eq!(
+            status_rx.try_recv().unwrap(),
+            Err(PicaCommandError::DeviceNotFound(mac_address))
+        );
+    }
+
+    #[test]
+    fn start_anchor_ranging_unknown_anchor() {
+        let mut pica = Pica::builder().build();
+        let mac_address = MacAddress::Short([0, 1]);
+
+        let (status_tx, mut status_rx) = oneshot::channel();
+        pica.start_anchor_ranging(mac_address, 1, Duration::from_millis(200), status_tx);
+        assert_eq!(
+            status_rx.try_recv().unwrap(),
+            Err(PicaCommandError::DeviceNotFound(mac_address))
+        );
+    }
+
+    #[test]
+    fn create_anchor_limit() {
+        let mut pica = Pica::builder().build();
+
+        for index in 0..=MAX_ANCHOR {
+            let (status_tx, mut status_rx) = oneshot::channel();
+            let mac_address = MacAddress::Short((index as u16).to_be_bytes());
+            pica.create_anchor(mac_address, Position::default(), status_tx);
+            let expected = if index < MAX_ANCHOR {
+                Ok(())
+            } else {
+                Err(PicaCommandError::LimitExceeded("anchors", MAX_ANCHOR))
+            };
+            assert_eq!(status_rx.try_recv().unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn create_constellation() {
+        let mut pica = Pica::builder().max_anchors(4).build();
+        let mut event_rx = pica.event_tx().subscribe();
+        let constellation = Constellation {
+            shape: Shape::Line { spacing: 100 },
+            count: 3,
+            origin: (0, 0),
+            height: 200,
+        };
+
+        let (status_tx, mut status_rx) = oneshot::channel();
+        pica.create_constellation(MacAddress::Short([0, 1]), constellation, status_tx);
+        assert_eq!(status_rx.try_recv().unwrap(), Ok(()));
+        for index in 1..=3 {
+            assert!(matches!(
+                event_rx.try_recv().unwrap().event,
+                PicaEvent::DeviceAdded { mac_address, .. }
+                    if mac_address == MacAddress::Short([0, index])
+            ));
+        }
+
+        // Constellations overlapping existing anchors, or exceeding the
+        // limit, are rejected without creating any anchor.
+        let (status_tx, mut status_rx) = oneshot::channel();
+        pica.create_constellation(MacAddress::Short([0, 3]), constellation, status_tx);
+        assert_eq!(
+            status_rx.try_recv().unwrap(),
+            Err(PicaCommandError::DeviceAlreadyExists(MacAddress::Short([
+                0, 3
+            ])))
+        );
+        let (status_tx, mut status_rx) = oneshot::channel();
+        pica.create_constellation(MacAddress::Short([0, 4]), constellation, status_tx);
+        assert_eq!(
+            status_rx.try_recv().unwrap(),
+            Err(PicaCommandError::LimitExceeded("anchors", 4))
+        );
+        assert!(event_rx.try_recv().is_err());
+        assert_eq!(pica.anchors.len(), 3);
+    }
+
+    #[test]
+    fn builder_limits() {
+        let mut pica = Pica::builder().max_devices(2).max_anchors(1).build();
+
+        let (status_tx, _) = oneshot::channel();
+        pica.create_anchor(MacAddress::Short([0, 1]), Position::default(), status_tx);
+        let (status_tx, mut status_rx) = oneshot::channel();
+        pica.create_anchor(MacAddress::Short([0, 2]), Position::default(), status_tx);
+        assert_eq!(
+            status_rx.try_recv().unwrap(),
+            Err(PicaCommandError::LimitExceeded("anchors", 1))
+        );
+
+        let (info_tx, mut info_rx) = oneshot::channel();
+        pica.get_simulator_info(info_tx);
+        let info = info_rx.try_recv().unwrap();
+        assert_eq!(info.max_devices, 2);
+        assert_eq!(info.max_anchors, 1);
+        assert_eq!(info.max_sessions, SimulatorInfo::new().max_sessions);
+    }
+
+    #[test]
+    fn metrics() {
+        let mut pica = Pica::builder().event_capacity(1).build();
+        let _event_rx = pica.event_tx().subscribe();
+
+        let (status_tx, _) = oneshot::channel();
+        pica.create_anchor(MacAddress::Short([0, 1]), Position::default(), status_tx);
+        let (status_tx, _) = oneshot::channel();
+        pica.destroy_anchor(MacAddress::Short([0, 1]), status_tx);
+        pica.malformed_packet(0, "truncated".to_owned());
+
+        let (metrics_tx, mut metrics_rx) = oneshot::channel();
+        pica.get_metrics(metrics_tx);
+        let metrics = metrics_rx.try_recv().unwrap();
+        assert_eq!(metrics.anchors, 0);
+        assert_eq!(metrics.events, 2);
+        assert_eq!(metrics.dropped_events, 1);
+        assert_eq!(metrics.malformed_packets, 1);
+    }
+
+    #[test]
+    fn aoa_capability() {
+        let measurement = make_measurement(
+            &MacAddress::Short([0, 1]),
+            (100, 30, 10),
+            (100, -30, -10),
+            0,
+            false,
+            (90, 80),
+            (AoaCapability::Azimuth, AoaCapability::None),
+        );
+        assert_eq!(measurement.aoa_azimuth, 30);
+        assert_eq!(measurement.aoa_azimuth_fom, 90);
+        assert_eq!(measurement.aoa_elevation, 0);
+        assert_eq!(measurement.aoa_elevation_fom, 0);
+        assert_eq!(measurement.aoa_destination_azimuth, 0);
+        assert_eq!(measurement.aoa_destination_azimuth_fom, 0);
+        assert_eq!(measurement.aoa_destination_elevation_fom, 0);
+    }
+
+    #[tokio::test]
+    async fn in_process_connection() {
+        let mut pica = Pica::builder().build();
+        let mut event_rx = pica.event_tx().subscribe();
+        let mut host = pica.connect_in_process().unwrap();
+        tokio::spawn(async move { pica.run().await });
+
+        // The answer to the command is interleaved with the notification
+        // of the initial device state.
+        host.write_all(&[0x20, 0x02, 0x00, 0x00]).await.unwrap();
+        let mut packets = Vec::new();
+        for _ in 0..2 {
+            let mut header = [0; 4];
+            host.read_exact(&mut header).await.unwrap();
+            let mut payload = vec![0; header[3] as usize];
+            host.read_exact(&mut payload).await.unwrap();
+            packets.push((header[0], header[1]));
+        }
+        packets.sort();
+        assert_eq!(packets, [(0x40, 0x02), (0x60, 0x01)]);
+
+        assert!(matches!(
+            event_rx.recv().await.unwrap().event,
+            PicaEvent::DeviceAdded { origin: Some(origin), .. } if origin == "in-process"
+        ));
+    }
+
+    /// Read the next control packet sent to the host.
+    async fn read_packet(host: &mut DuplexStream) -> Vec<u8> {
+        let mut packet = vec![0; 4];
+        host.read_exact(&mut packet).await.unwrap();
+        let mut payload = vec![0; packet[3] as usize];
+        host.read_exact(&mut payload).await.unwrap();
+        packet.extend(payload);
+        packet
+    }
+
+    #[tokio::test]
+    async fn traffic_tap() {
+        let mut pica = Pica::builder().build();
+        let mut tap_rx = pica.tap_tx().subscribe();
+        let mut host = pica.connect_in_process().unwrap();
+        tokio::spawn(async move { pica.run().await });
+
+        // Device status notification sent when connecting.
+        let packet = tap_rx.recv().await.unwrap();
+        assert_eq!(packet.direction, PacketDirection::DeviceToHost);
+        assert_eq!(packet.bytes[..], [0x60, 0x01, 0x00, 0x01, 0x01]);
+
+        // CORE_GET_DEVICE_INFO command and response.
+        host.write_all(&[0x20, 0x02, 0x00, 0x00]).await.unwrap();
+        let packet = tap_rx.recv().await.unwrap();
+        assert_eq!(packet.direction, PacketDirection::HostToDevice);
+        assert_eq!(packet.bytes[..], [0x20, 0x02, 0x00, 0x00]);
+        let packet = tap_rx.recv().await.unwrap();
+        assert_eq!(packet.direction, PacketDirection::DeviceToHost);
+        assert_eq!(packet.bytes[..2], [0x40, 0x02]);
+        assert_eq!(packet.device_handle, 0);
+    }
+
+    #[tokio::test]
+    async fn command_hooks() {
+        let mut pica = Pica::builder().build();
+        pica.register_command_hook(Box::new(|_, cmd| match cmd[..2] {
+            // CORE_GET_DEVICE_INFO is rejected.
+            [0x20, 0x02] => CommandHookAction::Respond(VendorCommandOutput {
+                response: vec![0x40, 0x02, 0x00, 0x01, 0x01],
+                notifications: vec![],
+            }),
+            // SESSION_GET_COUNT is not answered.
+            [0x21, 0x05] => CommandHookAction::Drop,
+            // SESSION_INIT opens session 2 instead of session 1.
+            [0x21, 0x00] => {
+                let mut replaced = cmd.to_vec();
+                replaced[4] = 0x02;
+                CommandHookAction::Replace(replaced)
+            }
+            _ => CommandHookAction::Continue,
+        }));
+        let mut host = pica.connect_in_process().unwrap();
+        tokio::spawn(async move { pica.run().await });
+        assert_eq!(read_packet(&mut host).await, [0x60, 0x01, 0x00, 0x01, 0x01]);
+
+        host.write_all(&[0x20, 0x02, 0x00, 0x00]).await.unwrap();
+        assert_eq!(read_packet(&mut host).await, [0x40, 0x02, 0x00, 0x01, 0x01]);
+
+        host.write_all(&[0x21, 0x05, 0x00, 0x00]).await.unwrap();
+        host.write_all(&[0x21, 0x00, 0x00, 0x05, 0x01, 0x00, 0x00, 0x00, 0x00])
+            .await
+            .unwrap();
+        let response = read_packet(&mut host).await;
+        assert_eq!(response[..2], [0x41, 0x00]);
+        let notification = read_packet(&mut host).await;
+        assert_eq!(notification[..2], [0x61, 0x02]);
+        assert_eq!(notification[4..8], [0x02, 0x00, 0x00, 0x00]);
+    }
+
+    #[tokio::test]
+    async fn device_suspend() {
+        let mut pica = Pica::builder().build();
+        let mut host = pica.connect_in_process().unwrap();
+        tokio::spawn(async move { pica.run().await });
+
+        assert_eq!(read_packet(&mut host).await, [0x60, 0x01, 0x00, 0x01, 0x01]);
+
+        host.write_all(&[0x20, 0x06, 0x00, 0x00]).await.unwrap();
+        assert_eq!(read_packet(&mut host).await, [0x40, 0x06, 0x00, 0x01, 0x00]);
+
+        // The next command wakes the device up, which reports that
+        // it is ready before answering.
+        host.write_all(&[0x20, 0x02, 0x00, 0x00]).await.unwrap();
+        assert_eq!(read_packet(&mut host).await, [0x60, 0x01, 0x00, 0x01, 0x01]);
+        assert_eq!(read_packet(&mut host).await[..2], [0x40, 0x02]);
+
+        // The suspension is rejected when the low power mode is disabled.
+        host.write_all(&[0x20, 0x04, 0x00, 0x04, 0x01, 0x01, 0x01, 0x00])
+            .await
+            .unwrap();
+        assert_eq!(
+            read_packet(&mut host).await[..5],
+            [0x40, 0x04, 0x00, 0x02, 0x00]
+        );
+        host.write_all(&[0x20, 0x06, 0x00, 0x00]).await.unwrap();
+        assert_eq!(read_packet(&mut host).await, [0x40, 0x06, 0x00, 0x01, 0x01]);
+    }
+
+    #[tokio::test]
+    async fn crash_device() {
+        let mut pica = Pica::builder().build();
+        let tx = pica.tx();
+        let mut host = pica.connect_in_process().unwrap();
+        tokio::spawn(async move { pica.run().await });
+
+        async fn crash(tx: &mpsc::Sender<PicaCommand>, recovery: CrashRecovery) {
+            let (status_tx, status_rx) = oneshot::channel();
+            tx.send(PicaCommand::CrashDevice(
+                MacAddress::Short([0, 0]),
+                recovery,
+                status_tx,
+            ))
+            .await
+            .unwrap();
+            assert!(status_rx.await.unwrap().is_ok());
+        }
+        assert_eq!(read_packet(&mut host).await, [0x60, 0x01, 0x00, 0x01, 0x01]);
+
+        // The commands fail until the device is reset.
+        crash(&tx, CrashRecovery::Reset).await;
+        assert_eq!(read_packet(&mut host).await, [0x60, 0x01, 0x00, 0x01, 0xff]);
+        host.write_all(&[0x20, 0x02, 0x00, 0x00]).await.unwrap();
+        assert_eq!(read_packet(&mut host).await, [0x40, 0x02, 0x00, 0x01, 0x02]);
+        host.write_all(&[0x20, 0x00, 0x00, 0x01, 0x00])
+            .await
+            .unwrap();
+        assert_eq!(read_packet(&mut host).await, [0x40, 0x00, 0x00, 0x01, 0x00]);
+        assert_eq!(read_packet(&mut host).await, [0x60, 0x01, 0x00, 0x01, 0x01]);
+
+        // The connection is closed after the error state is reported.
+        crash(&tx, CrashRecovery::Disconnect).await;
+        assert_eq!(read_packet(&mut host).await, [0x60, 0x01, 0x00, 0x01, 0xff]);
+        assert_eq!(host.read(&mut [0; 1]).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn one_measurement_per_controlee() {
+        let mut pica = Pica::builder().build();
+        let tx = pica.tx();
+        let mut host = pica.connect_in_process().unwrap();
+        tokio::spawn(async move { pica.run().await });
+
+        // Only the second controlee of the session is reachable.
+        let (status_tx, status_rx) = oneshot::channel();
+        tx.send(PicaCommand::CreateAnchor(
+            MacAddress::Short([0x0b, 0x00]),
+            Position::default(),
+            status_tx,
+        ))
+        .await
+        .unwrap();
+        assert!(status_rx.await.unwrap().is_ok());
+
+        // SESSION_INIT, SESSION_SET_APP_CONFIG as one-to-many controller
+        // of two controlees, and SESSION_START.
+        host.write_all(&[0x21, 0x00, 0x00, 0x05, 0x01, 0x00, 0x00, 0x00, 0x00])
+            .await
+            .unwrap();
+        host.write_all(&[
+            0x21, 0x03, 0x00, 0x24, 0x01, 0x00, 0x00, 0x00, 0x08, // Session 1, 8 parameters
+            0x00, 0x01, 0x01, // DEVICE_TYPE: controller
+            0x11, 0x01, 0x01, // DEVICE_ROLE: initiator
+            0x03, 0x01, 0x01, // MULTI_NODE_MODE: one-to-many
+            0x26, 0x01, 0x00, // MAC_ADDRESS_MODE: short addresses
+            0x06, 0x02, 0x01, 0x00, // DEVICE_MAC_ADDRESS
+            0x05, 0x01, 0x02, // NO_OF_CONTROLEE
+            0x07, 0x04, 0x0a, 0x00, 0x0b, 0x00, // DST_MAC_ADDRESS
+            0x09, 0x04, 0x64, 0x00, 0x00, 0x00, // RANGING_DURATION: 100 ms
+        ])
+        .await
+        .unwrap();
+        host.write_all(&[0x22, 0x00, 0x00, 0x04, 0x01, 0x00, 0x00, 0x00])
+            .await
+            .unwrap();
+
+        let notification = loop {
+            let packet = read_packet(&mut host).await;
+            if packet[..2] == [0x62, 0x00] {
+                break ShortMacTwoWaySessionInfoNtf::parse(&packet).unwrap();
+            }
+            // The configuration and start commands succeed.
+            if packet[0] >> 5 == 0x2 {
+                assert_eq!(packet[4], 0x00, "{:x?}", packet);
+            }
+        };
+        let measurements = notification.get_two_way_ranging_measurements();
+        assert_eq!(measurements.len(), 2);
+        assert_eq!(measurements[0].mac_address, 0x000a);
+        assert_eq!(
+            measurements[0].status,
+            UciStatusCode::UciStatusRangingRxTimeout
+        );
+        assert_eq!(measurements[1].mac_address, 0x000b);
+        assert_eq!(measurements[1].status, UciStatusCode::UciStatusOk);
+    }
+
+    #[tokio::test]
+    async fn out_of_range_peers() {
+        let mut pica = Pica::builder().max_range(1000).build();
+        let tx = pica.tx();
+        let mut host = pica.connect_in_process().unwrap();
+        tokio::spawn(async move { pica.run().await });
+
+        // The first anchor is beyond the maximum range, the second
+        // transmits at a power reaching ~1m, only the third is in range.
+        for (mac_address, z, tx_power) in [
+            ([0x0a, 0x00], 2000, None),
+            ([0x0b, 0x00], 500, Some(-81.3)),
+            ([0x0c, 0x00], 500, None),
+        ] {
+            let mac_address = MacAddress::Short(mac_address);
+            let (status_tx, status_rx) = oneshot::channel();
+            tx.send(PicaCommand::CreateAnchor(
+                mac_address,
+                Position::new(0, 0, z, 0, 0, 0),
+                status_tx,
+            ))
+            .await
+            .unwrap();
+            assert!(status_rx.await.unwrap().is_ok());
+            let (status_tx, status_rx) = oneshot::channel();
+            tx.send(PicaCommand::SetTxPower(mac_address, tx_power, status_tx))
+                .await
+                .unwrap();
+            assert!(status_rx.await.unwrap().is_ok());
+        }
+
+        // SESSION_INIT, SESSION_SET_APP_CONFIG as one-to-many controller
+        // of three controlees, and SESSION_START.
+        host.write_all(&[0x21, 0x00, 0x00, 0x05, 0x01, 0x00, 0x00, 0x00, 0x00])
+            .await
+            .unwrap();
+        host.write_all(&[
+            0x21, 0x03, 0x00, 0x26, 0x01, 0x00, 0x00, 0x00, 0x08, // Session 1, 8 parameters
+            0x00, 0x01, 0x01, // DEVICE_TYPE: controller
+            0x11, 0x01, 0x01, // DEVICE_ROLE: initiator
+            0x03, 0x01, 0x01, // MULTI_NODE_MODE: one-to-many
+            0x26, 0x01, 0x00, // MAC_ADDRESS_MODE: short addresses
+            0x06, 0x02, 0x01, 0x00, // DEVICE_MAC_ADDRESS
+            0x05, 0x01, 0x03, // NO_OF_CONTROLEE
+            0x07, 0x06, 0x0a, 0x00, 0x0b, 0x00, 0x0c, 0x00, // DST_MAC_ADDRESS
+            0x09, 0x04, 0x64, 0x00, 0x00, 0x00, // RANGING_DURATION: 100 ms
+        ])
+        .await
+        .unwrap();
+        host.write_all(&[0x22, 0x00, 0x00, 0x04, 0x01, 0x00, 0x00, 0x00])
+            .await
+            .unwrap();
+
+        let notification = loop {
+            let packet = read_packet(&mut host).await;
+            if packet[..2] == [0x62, 0x00] {
+                break ShortMacTwoWaySessionInfoNtf::parse(&packet).unwrap();
+            }
+            // The configuration and start commands succeed.
+            if packet[0] >> 5 == 0x2 {
+                assert_eq!(packet[4], 0x00, "{:x?}", packet);
+            }
+        };
+        let measurements = notification.get_two_way_ranging_measurements();
+        assert_eq!(measurements.len(), 1);
+        assert_eq!(measurements[0].mac_address, 0x000c);
+        assert_eq!(measurements[0].status, UciStatusCode::UciStatusOk);
+    }
+
+    #[tokio::test]
+    async fn interfering_sessions() {
+        let mut pica = Pica::builder()
+            .interference_model(InterferenceModel {
+                drop_probability: 1.0,
+                distance_noise: 0.0,
+            })
+            .build();
+        let tx = pica.tx();
+        let mut event_rx = pica.event_tx().subscribe();
+        let mut hosts = [
+            pica.connect_in_process().unwrap(),
+            pica.connect_in_process().unwrap(),
+        ];
+        tokio::spawn(async move { pica.run().await });
+
+        let (status_tx, status_rx) = oneshot::channel();
+        tx.send(PicaCommand::CreateAnchor(
+            MacAddress::Short([0x0b, 0x00]),
+            Position::default(),
+            status_tx,
+        ))
+        .await
+        .unwrap();
+        assert!(status_rx.await.unwrap().is_ok());
+
+        // Both devices range with the anchor on the default channel,
+        // in sessions started at the same time.
+        for (index, host) in hosts.iter_mut().enumerate() {
+            let id = index as u8 + 1;
+            host.write_all(&[0x21, 0x00, 0x00, 0x05, id, 0x00, 0x00, 0x00, 0x00])
+                .await
+                .unwrap();
+            host.write_all(&[
+                0x21, 0x03, 0x00, 0x22, id, 0x00, 0x00, 0x00,
+                0x08, // Session id, 8 parameters
+                0x00, 0x01, 0x01, // DEVICE_TYPE: controller
+                0x11, 0x01, 0x01, // DEVICE_ROLE: initiator
+                0x03, 0x01, 0x01, // MULTI_NODE_MODE: one-to-many
+                0x26, 0x01, 0x00, // MAC_ADDRESS_MODE: short addresses
+                0x06, 0x02, id, 0x00, // DEVICE_MAC_ADDRESS
+                0x05, 0x01, 0x01, // NO_OF_CONTROLEE
+                0x07, 0x02, 0x0b, 0x00, // DST_MAC_ADDRESS
+                0x09, 0x04, 0x64, 0x00, 0x00, 0x00, // RANGING_DURATION: 100 ms
+            ])
+            .await
+            .unwrap();
+        }
+        for (index, host) in hosts.iter_mut().enumerate() {
+            host.write_all(&[0x22, 0x00, 0x00, 0x04, index as u8 + 1, 0x00, 0x00, 0x00])
+                .await
+                .unwrap();
+        }
+
+        let notification = loop {
+            let packet = read_packet(&mut hosts[0]).await;
+            if packet[..2] == [0x62, 0x00] {
+                break ShortMacTwoWaySessionInfoNtf::parse(&packet).unwrap();
+            }
+            // The configuration and start commands succeed.
+            if packet[0] >> 5 == 0x2 {
+                assert_eq!(packet[4], 0x00, "{:x?}", packet);
+            }
+        };
+        let measurements = notification.get_two_way_ranging_measurements();
+        assert_eq!(measurements.len(), 1);
+        assert_eq!(
+            measurements[0].status,
+            UciStatusCode::UciStatusRangingRxTimeout
+        );
+
+        let event = loop {
+            let event = event_rx.recv().await.unwrap().event;
+            if matches!(event, PicaEvent::Interference { session_id: 1, .. }) {
+                break event;
+            }
+        };
+        let PicaEvent::Interference {
+            interfering_sessions,
+            lost_peers,
+            ..
+        } = event
+        else {
+            unreachable!()
+        };
+        assert_eq!(interfering_sessions, [2]);
+        assert_eq!(lost_peers, [MacAddress::Short([0x0b, 0x00])]);
+    }
+
+    #[tokio::test]
+    async fn radar_session() {
+        let mut pica = Pica::builder()
+            .device_profile(DeviceProfile::new(&[Personality::Radar]))
+            .build();
+        let mut host = pica.connect_in_process().unwrap();
+        tokio::spawn(async move { pica.run().await });
+
+        host.write_all(&[0x21, 0x00, 0x00, 0x05, 0x01, 0x00, 0x00, 0x00, 0xa1])
+            .await
+            .unwrap();
+        host.write_all(&[
+            0x2c, 0x11, 0x00, 0x15, 0x01, 0x00, 0x00, 0x00,
+            0x03, // Session token, 3 parameters
+            0x00, 0x07, 0x0a, 0x00, 0x00, 0x00, 0x28, 0x00, 0x01, // RADAR_TIMING_PARAMS
+            0x01, 0x01, 0x10, // SAMPLES_PER_SWEEP: 16
+            0x0a, 0x02, 0x01, 0x00, // NUMBER_OF_BURSTS: 1
+        ])
+        .await
+        .unwrap();
+        host.write_all(&[0x22, 0x00, 0x00, 0x04, 0x01, 0x00, 0x00, 0x00])
+            .await
+            .unwrap();
+
+        let packet = loop {
+            let mut packet = vec![0; 4];
+            host.read_exact(&mut packet).await.unwrap();
+            let payload_length = if packet[0] >> 5 == 0x0 {
+                u16::from_le_bytes([packet[2], packet[3]])
+            } else {
+                packet[3] as u16
+            };
+            let mut payload = vec![0; payload_length as usize];
+            host.read_exact(&mut payload).await.unwrap();
+            packet.extend(payload);
+            if packet[0] == 0x0f {
+                break packet;
+            }
+            // The configuration and start commands succeed.
+            if packet[0] >> 5 == 0x2 {
+                assert_eq!(packet[4], 0x00, "{:x?}", packet);
+            }
+        };
+        let DataPacketChild::RadarDataRcv(data) = DataPacket::parse(&packet).unwrap().specialize()
+        else {
+            panic!("unexpected data packet {:x?}", packet);
+        };
+        assert_eq!(data.get_session_handle(), 1);
+        assert_eq!(data.get_number_of_sweeps(), 1);
+        assert_eq!(data.get_samples_per_sweep(), 16);
+        assert_eq!(data.get_sweep_data().len(), 9 + 16 * 4);
+    }
+
+    #[tokio::test]
+    async fn zone_transitions() {
+        let mut pica = Pica::builder().build();
+        let tx = pica.tx();
+        let mut event_rx = pica.event_tx().subscribe();
+        tokio::spawn(async move { pica.run().await });
+
+        async fn next_transition(
+            event_rx: &mut broadcast::Receiver<SequencedEvent>,
+        ) -> (String, &'static str) {
+            loop {
+                if let PicaEvent::ZoneTransition {
+                    zone, transition, ..
+                } = event_rx.recv().await.unwrap().event
+                {
+                    return (zone, transition);
+                }
+            }
+        }
+
+        let mac_address = MacAddress::Short([0x0a, 0x00]);
+        let (status_tx, status_rx) = oneshot::channel();
+        tx.send(PicaCommand::CreateAnchor(
+            mac_address,
+            Position::new(100, 100, 100, 0, 0, 0),
+            status_tx,
+        ))
+        .await
+        .unwrap();
+        assert!(status_rx.await.unwrap().is_ok());
+
+        // Zone names are unique.
+        let (status_tx, status_rx) = oneshot::channel();
+        tx.send(PicaCommand::SetZones(
+            vec![
+                Zone::new("kitchen".to_owned(), (0, 0, 0), (200, 250, 200)),
+                Zone::new("kitchen".to_owned(), (200, 0, 0), (400, 250, 200)),
+            ],
+            status_tx,
+        ))
+        .await
+        .unwrap();
+        assert!(status_rx.await.unwrap().is_err());
+
+        // The anchor is reported entering the zone it is in.
+        let (status_tx, status_rx) = oneshot::channel();
+        tx.send(PicaCommand::SetZones(
+            vec![
+                Zone::new("kitchen".to_owned(), (0, 0, 0), (200, 250, 200)),
+                Zone::new("hall".to_owned(), (200, 0, 0), (400, 250, 200)),
+            ],
+            status_tx,
+        ))
+        .await
+        .unwrap();
+        assert!(status_rx.await.unwrap().is_ok());
+        assert_eq!(
+            next_transition(&mut event_rx).await,
+            ("kitchen".to_owned(), "enter")
+        );
+
+        let (status_tx, status_rx) = oneshot::channel();
+        tx.send(PicaCommand::SetPosition(
+            mac_address,
+            Position::new(300, 100, 100, 0, 0, 0),
+            status_tx,
+        ))
+        .await
+        .unwrap();
+        assert!(status_rx.await.unwrap().is_ok());
+        assert_eq!(
+            next_transition(&mut event_rx).await,
+            ("kitchen".to_owned(), "leave")
+        );
+        assert_eq!(
+            next_transition(&mut event_rx).await,
+            ("hall".to_owned(), "enter")
+        );
+
+        let (status_tx, status_rx) = oneshot::channel();
+        tx.send(PicaCommand::DestroyAnchor(mac_address, status_tx))
+            .await
+            .unwrap();
+        assert!(status_rx.await.unwrap().is_ok());
+        assert_eq!(
+            next_transition(&mut event_rx).await,
+            ("hall".to_owned(), "leave")
+        );
+    }
+
+    #[tokio::test]
+    async fn owr_aoa_field_of_view() {
+        let mut pica = Pica::builder().build();
+        let tx = pica.tx();
+        let mut host = pica.connect_in_process().unwrap();
+        tokio::spawn(async move { pica.run().await });
+
+        // The first anchor is in front of the device, within its field
+        // of view, the second is behind.
+        for (mac_address, z) in [([0x0a, 0x00], 100), ([0x0b, 0x00], -100)] {
+            let (status_tx, status_rx) = oneshot::channel();
+            tx.send(PicaCommand::CreateAnchor(
+                MacAddress::Short(mac_address),
+                Position::new(0, 0, z, 0, 0, 0),
+                status_tx,
+            ))
+            .await
+            .unwrap();
+            assert!(status_rx.await.unwrap().is_ok());
+        }
+        let (status_tx, status_rx) = oneshot::channel();
+        tx.send(PicaCommand::SetFieldOfView(
+            MacAddress::Short([0, 0]),
+            FieldOfView {
+                azimuth: 60,
+                elevation: 60,
+            },
+            status_tx,
+        ))
+        .await
+        .unwrap();
+        assert!(status_rx.await.unwrap().is_ok());
+
+        // SESSION_INIT, SESSION_SET_APP_CONFIG as one-way AoA observer
+        // of the two anchors, and SESSION_START.
+        host.write_all(&[0x21, 0x00, 0x00, 0x05, 0x01, 0x00, 0x00, 0x00, 0x00])
+            .await
+            .unwrap();
+        host.write_all(&[
+            0x21, 0x03, 0x00, 0x27, 0x01, 0x00, 0x00, 0x00, 0x09, // Session 1, 9 parameters
+            0x00, 0x01, 0x01, // DEVICE_TYPE: controller
+            0x11, 0x01, 0x01, // DEVICE_ROLE: initiator
+            0x01, 0x01, 0x06, // RANGING_ROUND_USAGE: OWR AoA measurement
+            0x03, 0x01, 0x01, // MULTI_NODE_MODE: one-to-many
+            0x26, 0x01, 0x00, // MAC_ADDRESS_MODE: short addresses
+            0x06, 0x02, 0x01, 0x00, // DEVICE_MAC_ADDRESS
+            0x05, 0x01, 0x02, // NO_OF_CONTROLEE
+            0x07, 0x04, 0x0a, 0x00, 0x0b, 0x00, // DST_MAC_ADDRESS
+            0x09, 0x04, 0x64, 0x00, 0x00, 0x00, // RANGING_DURATION: 100 ms
+        ])
+        .await
+        .unwrap();
+        host.write_all(&[0x22, 0x00, 0x00, 0x04, 0x01, 0x00, 0x00, 0x00])
+            .await
+            .unwrap();
+
+        let notification = loop {
+            let packet = read_packet(&mut host).await;
+            if packet[..2] == [0x62, 0x00] {
+                break ShortMacOwrAoaSessionInfoNtf::parse(&packet).unwrap();
+            }
+            // The configuration and start commands succeed.
+            if packet[0] >> 5 == 0x2 {
+                assert_eq!(packet[4], 0x00, "{:x?}", packet);
+            }
+        };
+        // The advertisements from outside the field of view are omitted.
+        let measurements = notification.get_owr_aoa_ranging_measurements();
+        assert_eq!(measurements.len(), 1);
+        assert_eq!(measurements[0].mac_address, 0x000a);
+        assert_eq!(measurements[0].status, UciStatusCode::UciStatusOk);
+        assert_ne!(measurements[0].aoa_azimuth_fom, OUT_OF_FOV_FOM);
+    }
+
+    #[tokio::test]
+    async fn hybrid_session() {
+        let mut pica = Pica::builder().build();
+        let mut host = pica.connect_in_process().unwrap();
+        tokio::spawn(async move { pica.run().await });
+
+        // Send a command and return the status of its response,
+        // skipping the notifications.
+        async fn command(host: &mut DuplexStream, bytes: &[u8]) -> u8 {
+            host.write_all(bytes).await.unwrap();
+            loop {
+                let packet = read_packet(host).await;
+                if packet[0] >> 5 == 0x2 {
+                    break packet[4];
+                }
+            }
+        }
+
+        // SESSION_INIT of the HUS primary session 1 and of the ranging
+        // phase session 2, SESSION_SET_APP_CONFIG of the primary session
+        // as controller.
+        assert_eq!(
+            command(&mut host, &[0x21, 0x00, 0x00, 0x05, 1, 0, 0, 0, 0x9f]).await,
+            0x00
+        );
+        assert_eq!(
+            command(&mut host, &[0x21, 0x00, 0x00, 0x05, 2, 0, 0, 0, 0x03]).await,
+            0x00
+        );
+        assert_eq!(
+            command(
+                &mut host,
+                &[0x21, 0x03, 0x00, 0x08, 1, 0, 0, 0, 0x01, 0x00, 0x01, 0x01]
+            )
+            .await,
+            0x00
+        );
+
+        // The primary session cannot start before its phases are configured.
+        let start = [0x22, 0x00, 0x00, 0x04, 1, 0, 0, 0];
+        assert_eq!(command(&mut host, &start).await, 0x01);
+
+        // SESSION_SET_HUS_CONTROLLER_CONFIG with one phase in the slots 0..=4.
+        let controller_config = |phase_session_id: u8| {
+            let mut bytes = vec![0x21, 0x0c, 0x00, 0x15, 1, 0, 0, 0, 0x01];
+            bytes.extend([0; 8]);
+            bytes.extend([phase_session_id, 0, 0, 0, 0x00, 0x00, 0x04, 0x00]);
+            bytes
+        };
+        assert_eq!(command(&mut host, &controller_config(3)).await, 0x11);
+        assert_eq!(command(&mut host, &controller_config(1)).await, 0x04);
+        // SESSION_SET_HUS_CONTROLEE_CONFIG is rejected for a controller.
+        assert_eq!(
+            command(
+                &mut host,
+                &[0x21, 0x0d, 0x00, 0x09, 1, 0, 0, 0, 0x01, 2, 0, 0, 0]
+            )
+            .await,
+            0x01
+        );
+        assert_eq!(command(&mut host, &controller_config(2)).await, 0x00);
+        assert_eq!(command(&mut host, &start).await, 0x00);
+    }
+
+    #[tokio::test]
+    async fn persistent_identity() {
+        let mut pica = Pica::builder().persistent_identity(true).build();
+        let mac_address = MacAddress::Short([0, 0]);
+        let position = Position::new(100, 200, 0, 0, 0, 0);
+        let clock = Clock {
+            drift_ppm: 20.0,
+            ..Default::default()
+        };
+
+        pica.connect(Box::new(tokio::io::duplex(64).0), "first".to_owned())
+            .await;
+        let (status_tx, _) = oneshot::channel();
+        pica.init_uci_device(mac_address, Some(position), status_tx);
+        let (status_tx, _) = oneshot::channel();
+        pica.set_clock(mac_address, clock, status_tx);
+        pica.disconnect(0);
+
+        pica.connect(Box::new(tokio::io::duplex(64).0), "second".to_owned())
+            .await;
+        let (status_tx, mut status_rx) = oneshot::channel();
+        pica.init_uci_device(mac_address, None, status_tx);
+        assert!(status_rx.try_recv().unwrap().is_ok());
+        let device = pica.get_device(1).unwrap();
+        assert_eq!(device.mac_address, mac_address);
+        assert_eq!(device.position.to_string(), position.to_string());
+        assert_eq!(device.clock(), clock);
+        // The device is indexed by the claimed address only.
+        assert_eq!(pica.device_handles, HashMap::from([(mac_address, 1)]));
+
+        // Only the addresses of disconnected devices can be claimed.
+        pica.connect(Box::new(tokio::io::duplex(64).0), "third".to_owned())
+            .await;
+        let (status_tx, mut status_rx) = oneshot::channel();
+        pica.init_uci_device(MacAddress::Short([0, 9]), None, status_tx);
+        assert!(status_rx.try_recv().unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn persistent_identity_collisions() {
+        let mut pica = Pica::builder().persistent_identity(true).build();
+        let mac_address = MacAddress::Short([0, 0]);
+        let init = |pica: &mut Pica| {
+            let (status_tx, mut status_rx) = oneshot::channel();
+            pica.init_uci_device(mac_address, None, status_tx);
+            status_rx.try_recv().unwrap()
+        };
+
+        pica.connect(Box::new(tokio::io::duplex(64).0), "first".to_owned())
+            .await;
+        assert!(init(&mut pica).is_ok());
+        pica.disconnect(0);
+
+        // The address is claimed by an anchor in the meantime.
+        pica.add_anchor(mac_address, Position::default());
+        pica.connect(Box::new(tokio::io::duplex(64).0), "second".to_owned())
+            .await;
+        assert_eq!(
+            init(&mut pica),
+            Err(PicaCommandError::DeviceAlreadyExists(mac_address))
+        );
+        assert!(pica.get_device(1).unwrap().has_handle_mac_address());
+        pica.anchors.remove(&mac_address);
+
+        // Two devices could claim the address.
+        pica.connect(Box::new(tokio::io::duplex(64).0), "third".to_owned())
+            .await;
+        assert!(matches!(
+            init(&mut pica),
+            Err(PicaCommandError::InvalidArgument(_))
+        ));
+        pica.disconnect(2);
+        assert!(init(&mut pica).is_ok());
+        assert_eq!(pica.get_device(1).unwrap().mac_address, mac_address);
+        assert_eq!(pica.device_handles, HashMap::from([(mac_address, 1)]));
+    }
+
+    #[tokio::test]
+    async fn device_index() {
+        let mut pica = Pica::builder().persistent_identity(true).build();
+        fn start_session(
+            pica: &mut Pica,
+            device_handle: usize,
+            tlvs: &[(AppConfigTlvType, &[u8])],
+        ) {
+            pica.command(
+                device_handle,
+                SessionInitCmdBuilder {
+                    session_id: 1,
+                    session_type: SessionType::FiraRangingSession,
+                }
+                .build()
+                .into(),
+            );
+            pica.command(
+                device_handle,
+                SessionSetAppConfigCmdBuilder {
+                    session_token: 1,
+                    tlvs: tlvs
+                        .iter()
+                        .map(|(cfg_id, v)| AppConfigTlv {
+                            cfg_id: *cfg_id,
+                            v: v.to_vec(),
+                        })
+                        .collect(),
+                }
+                .build()
+                .into(),
+            );
+            pica.command(
+                device_handle,
+                SessionStartCmdBuilder { session_id: 1 }.build().into(),
+            );
+        }
+        let peer = |pica: &Pica, mac_address, device_handle| {
+            let local_app_config = &pica.get_device(device_handle)?.get_session(1)?.app_config;
+            pica.get_device_by_mac(&mac_address, local_app_config, 1)
+                .map(|device| device.mac_address)
+        };
+        let claimed = MacAddress::Short([0, 0]);
+        let controller = MacAddress::Short([0, 2]);
+
+        // The first device disconnects, its address is claimed by the
+        // next device.
+        pica.connect(Box::new(tokio::io::duplex(64).0), "first".to_owned())
+            .await;
+        assert_eq!(pica.get_category(&claimed), Some(Category::Uci));
+        pica.disconnect(0);
+        assert_eq!(pica.get_category(&claimed), None);
+        pica.connect(Box::new(tokio::io::duplex(64).0), "second".to_owned())
+            .await;
+        let (status_tx, _) = oneshot::channel();
+        pica.init_uci_device(claimed, None, status_tx);
+        assert_eq!(pica.get_category(&MacAddress::Short([0, 1])), None);
+        assert!(pica.get_device_mut_by_mac(claimed).is_some());
+
+        // The claimed address is used to range with a new device.
+        pica.connect(Box::new(tokio::io::duplex(64).0), "third".to_owned())
+            .await;
+        let config: &[(AppConfigTlvType, &[u8])] = &[
+            (AppConfigTlvType::MacAddressMode, &[0x00]),
+            (AppConfigTlvType::NoOfControlee, &[0x01]),
+        ];
+        start_session(
+            &mut pica,
+            1,
+            &[
+                config,
+                &[
+                    (AppConfigTlvType::DeviceType, &[0x00]),
+                    (AppConfigTlvType::DeviceRole, &[0x00]),
+                    (AppConfigTlvType::DeviceMacAddress, &[0x00, 0x00]),
+                    (AppConfigTlvType::DstMacAddress, &[0x00, 0x02]),
+                ],
+            ]
+            .concat(),
+        );
+        start_session(
+            &mut pica,
+            2,
+            &[
+                config,
+                &[
+                    (AppConfigTlvType::DeviceType, &[0x01]),
+                    (AppConfigTlvType::DeviceRole, &[0x01]),
+                    (AppConfigTlvType::DeviceMacAddress, &[0x00, 0x02]),
+                    (AppConfigTlvType::DstMacAddress, &[0x00, 0x00]),
+                ],
+            ]
+            .concat(),
+        );
+        assert_eq!(peer(&pica, claimed, 2), Some(claimed));
+        assert_eq!(peer(&pica, controller, 1), Some(controller));
+
+        // The address is released when the device disconnects.
+        pica.disconnect(1);
+        assert_eq!(peer(&pica, claimed, 2), None);
+        assert_eq!(pica.get_category(&claimed), None);
+        assert_eq!(pica.device_handles, HashMap::from([(controller, 2)]));
+    }
+
+    #[test]
+    fn lost_measurement() {
+        // Lost peers are reported for the sessions with extended addresses.
+        let mac_address = MacAddress::Extend([1, 2, 3, 4, 5, 6, 7, 8]);
+        let measurement =
+            make_lost_measurement(&mac_address, UciStatusCode::UciStatusRangingRxTimeout);
+        assert_eq!(measurement.mac_address, u64::from(&mac_address));
+        assert_eq!(measurement.status, UciStatusCode::UciStatusRangingRxTimeout);
+        assert_eq!(measurement.rssi, u8::MAX);
+
+        let mac_address = MacAddress::Short([0x0a, 0x00]);
+        let measurement = make_short_measurement(make_lost_measurement(
+            &mac_address,
+            UciStatusCode::UciStatusRangingRxTimeout,
+        ));
+        assert_eq!(measurement.mac_address, 0x000a);
+    }
+
+    #[tokio::test]
+    async fn queued_writes() {
+        let mut pica = Pica::builder().build();
+        let mut host = pica.connect_in_process().unwrap();
+        tokio::spawn(async move { pica.run().await });
+
+        // The responses to the CORE_GET_DEVICE_INFO commands exceed the
+        // capacity of the stream while the host is not reading.
+        const COMMANDS: usize = 400;
+        host.write_all(&[0x20, 0x02, 0x00, 0x00].repeat(COMMANDS))
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let mut responses = 0;
+        while responses < COMMANDS {
+            let packet = read_packet(&mut host).await;
+            match packet[..2] {
+                [0x40, 0x02] => {
+                    assert_eq!(packet[4], 0x00);
+                    responses += 1;
+                }
+                [0x60, 0x01] => (),
+                _ => panic!("unexpected packet {:02x?}", packet),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn shutdown_with_stalled_host() {
+        let mut pica = Pica::builder().build();
+        let handle = pica.handle();
+        let tx = pica.tx();
+        let mut host = pica.connect_in_process().unwrap();
+        let pica = tokio::spawn(async move { pica.run().await });
+
+        // The host sends commands but stops reading once the stream is
+        // full, with responses left in the write queue of the connection.
+        assert_eq!(read_packet(&mut host).await[..2], [0x60, 0x01]);
+        let (_reader, mut writer) = tokio::io::split(host);
+        tokio::spawn(async move {
+            writer
+                .write_all(&[0x20, 0x02, 0x00, 0x00].repeat(5000))
+                .await
+        });
+
+        // The responses to the commands of the device fill the channel of
+        // the connection, and are dropped.
+        let commands = tokio::spawn(async move {
+            for _ in 0..5000 {
+                let cmd = GetDeviceInfoCmdBuilder {}.build().into();
+                tx.send(PicaCommand::UciCommand(0, cmd)).await.unwrap();
+            }
+        });
+        tokio::time::timeout(Duration::from_secs(5), commands)
+            .await
+            .expect("commands waiting for the host")
+            .unwrap();
+
+        tokio::time::timeout(Duration::from_secs(5), handle.get_state())
+            .await
+            .expect("get_state waiting for the host")
+            .unwrap();
+        tokio::time::timeout(3 * CLOSE_TIMEOUT, handle.shutdown())
+            .await
+            .expect("shutdown waiting for the host")
+            .unwrap();
+        pica.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn inject_packet() {
+        let mut pica = Pica::builder().build();
+        let tx = pica.tx();
+        let mut host = pica.connect_in_process().unwrap();
+        tokio::spawn(async move { pica.run().await });
+
+        let mut status = [0; 5];
+        host.read_exact(&mut status).await.unwrap();
+        assert_eq!(status[..2], [0x60, 0x01]);
+
+        // Notification header announcing a payload longer than sent.
+        let malformed = Bytes::from_static(&[0x60, 0x01, 0x00, 0x04, 0x01]);
+        let (status_tx, status_rx) = oneshot::channel();
+        tx.send(PicaCommand::InjectPacket(
+            MacAddress::Short([0, 0]),
+            malformed.clone(),
+            status_tx,
+        ))
+        .await
+        .unwrap();
+        assert!(status_rx.await.unwrap().is_ok());
+        let mut bytes = vec![0; malformed.len()];
+        host.read_exact(&mut bytes).await.unwrap();
+        assert_eq!(bytes, malformed);
+
+        let (status_tx, status_rx) = oneshot::channel();
+        tx.send(PicaCommand::InjectPacket(
+            MacAddress::Short([0, 1]),
+            malformed,
+            status_tx,
+        ))
+        .await
+        .unwrap();
+        assert!(matches!(
+            status_rx.await.unwrap(),
+            Err(PicaCommandError::DeviceNotFound(_))
+        ));
+
+        // Vendor notification sent to the device selected by its handle.
+        let vendor = Bytes::from_static(&[0x69, 0x01, 0x00, 0x02, 0xca, 0xfe]);
+        let (status_tx, status_rx) = oneshot::channel();
+        tx.send(PicaCommand::InjectUciPacket(0, vendor.clone(), status_tx))
+            .await
+            .unwrap();
+        assert!(status_rx.await.unwrap().is_ok());
+        let mut bytes = vec![0; vendor.len()];
+        host.read_exact(&mut bytes).await.unwrap();
+        assert_eq!(bytes, vendor);
+
+        let (status_tx, status_rx) = oneshot::channel();
+        tx.send(PicaCommand::InjectUciPacket(1, vendor, status_tx))
+            .await
+            .unwrap();
+        assert!(matches!(
+            status_rx.await.unwrap(),
+            Err(PicaCommandError::InvalidArgument(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn notification_latency() {
+        let mut pica = Pica::builder().build();
+        let tx = pica.tx();
+        let mut host = pica.connect_in_process().unwrap();
+        tokio::spawn(async move { pica.run().await });
+
+        async fn read_header(host: &mut DuplexStream) -> [u8; 4] {
+            let mut header = [0; 4];
+            host.read_exact(&mut header).await.unwrap();
+            let mut payload = vec![0; header[3] as usize];
+            host.read_exact(&mut payload).await.unwrap();
+            header
+        }
+        assert_eq!(read_header(&mut host).await[..2], [0x60, 0x01]);
+
+        let latency = NotificationLatency {
+            delay: Duration::from_millis(50),
+            ..Default::default()
+        };
+        let (status_tx, status_rx) = oneshot::channel();
+        let mac_address = MacAddress::Short([0, 0]);
+        tx.send(PicaCommand::SetNotificationLatency(
+            mac_address,
+            latency,
+            status_tx,
+        ))
+        .await
+        .unwrap();
+        assert!(status_rx.await.unwrap().is_ok());
+
+        // The response to the reset is not delayed, unlike the
+        // notification of the device state.
+        let start = time::Instant::now();
+        host.write_all(&[0x20, 0x00, 0x00, 0x01, 0x00])
+            .await
+            .unwrap();
+        assert_eq!(read_header(&mut host).await[..2], [0x40, 0x00]);
+        assert_eq!(read_header(&mut host).await[..2], [0x60, 0x01]);
+        assert!(start.elapsed() >= latency.delay);
+    }
+
+    #[tokio::test]
+    async fn handle() {
+        let mut pica = Pica::builder().build();
+        let handle = pica.handle();
+        let mut event_rx = handle.subscribe_events();
+        let pica = tokio::spawn(async move { pica.run().await });
+
+        let mac_address = MacAddress::Short([0x00, 0x01]);
+        handle
+            .create_anchor(mac_address, Position::new(100, 0, 0, 0, 0, 0))
+            .await
+            .unwrap();
+        assert!(matches!(
+            handle
+                .create_anchor(mac_address, Position::new(0, 0, 0, 0, 0, 0))
+                .await,
+            Err(PicaError::Command(PicaCommandError::DeviceAlreadyExists(_)))
+        ));
+        handle
+            .set_position(mac_address, Position::new(200, 0, 0, 0, 0, 0))
+            .await
+            .unwrap();
+        assert!(matches!(
+            event_rx.recv().await.unwrap().event,
+            PicaEvent::DeviceAdded { .. }
+        ));
+        assert!(matches!(
+            event_rx.recv().await.unwrap().event,
+            PicaEvent::DeviceUpdated { .. }
+        ));
+
+        let state = handle.get_state().await.unwrap();
+        assert_eq!(state.devices.len(), 1);
+        assert_eq!(state.devices[0].1, mac_address);
+
+        handle.shutdown().await.unwrap();
+        pica.await.unwrap().unwrap();
+        assert!(matches!(
+            handle.destroy_anchor(mac_address).await,
+            Err(PicaError::ShutDown)
+        ));
+    }
+
+    #[tokio::test]
+    async fn event_streams() {
+        use tokio_stream::StreamExt;
+
+        let mut pica = Pica::builder().event_capacity(1).build();
+        let handle = pica.handle();
+        let mut skipping = pica.events(LagPolicy::Skip);
+        let mut failing = handle.events(LagPolicy::Error);
+        tokio::spawn(async move { pica.run().await });
+
+        let anchor_a = MacAddress::Short([0x00, 0x01]);
+        let anchor_b = MacAddress::Short([0x00, 0x02]);
+        for mac_address in [anchor_a, anchor_b] {
+            handle
+                .create_anchor(mac_address, Position::new(0, 0, 0, 0, 0, 0))
+                .await
+                .unwrap();
+        }
+
+        // Only the last event is left in the channel.
+        assert!(matches!(skipping.next().await, Some(Ok(_))));
+        assert!(matches!(failing.next().await, Some(Err(EventsLagged(_)))));
+        assert!(matches!(failing.next().await, Some(Ok(_))));
+    }
+
+    #[tokio::test]
+    async fn set_positions() {
+        let mut pica = Pica::builder().build();
+        let handle = pica.handle();
+        tokio::spawn(async move { pica.run().await });
+
+        let anchors = [0x01, 0x02, 0x03].map(|index| MacAddress::Short([0x00, index]));
+        for mac_address in anchors {
+            handle
+                .create_anchor(mac_address, Position::default())
+                .await
+                .unwrap();
+        }
+        let mut event_rx = handle.subscribe_events();
+
+        // The positions are not applied if a node is not found.
+        assert!(handle
+            .set_positions(vec![
+                (anchors[0], Position::new(100, 0, 0, 0, 0, 0)),
+                (MacAddress::Short([0x00, 0x04]), Position::default()),
+            ])
+            .await
+            .is_err());
+        assert!(event_rx.try_recv().is_err());
+
+        handle
+            .set_positions(vec![
+                (anchors[0], Position::new(100, 0, 0, 0, 0, 0)),
+                (anchors[1], Position::new(0, 200, 0, 0, 0, 0)),
+            ])
+            .await
+            .unwrap();
+
+        // The two moved anchors are updated, then each of the three pairs
+        // of anchors in both directions.
+        let mut device_updates = 0;
+        let mut neighbor_updates = Vec::new();
+        while let Ok(event) = event_rx.try_recv() {
+            match event.event {
+                PicaEvent::DeviceUpdated { .. } => device_updates += 1,
+                PicaEvent::NeighborUpdated {
+                    source_mac_address,
+                    destination_mac_address,
+                    distance,
+                    ..
+                } => neighbor_updates.push((source_mac_address, destination_mac_address, distance)),
+                _ => (),
+            }
+        }
+        assert_eq!(device_updates, 2);
+        assert_eq!(neighbor_updates.len(), 6);
+        assert!(neighbor_updates.contains(&(anchors[0], anchors[1], 224)));
+        assert!(neighbor_updates.contains(&(anchors[2], anchors[1], 200)));
+    }
+
+    #[tokio::test]
+    async fn kinematics() {
+        let mut pica = Pica::builder().time_mode(TimeMode::Stepped).build();
+        let handle = pica.handle();
+        tokio::spawn(async move { pica.run().await });
+
+        let mac_address = MacAddress::Short([0x00, 0x01]);
+        handle
+            .create_anchor(mac_address, Position::default())
+            .await
+            .unwrap();
+        let mut event_rx = handle.subscribe_events();
+        let kinematics = Kinematics::new([100.0, 0.0, 0.0], [0.0, 0.0, 20.0]).unwrap();
+        handle
+            .set_kinematics(mac_address, Some(kinematics))
+            .await
+            .unwrap();
+        handle.advance_time(Duration::from_secs(1)).await.unwrap();
+
+        // The position and velocity are integrated at each update.
+        loop {
+            let event = tokio::time::timeout(Duration::from_secs(1), event_rx.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            if let PicaEvent::DeviceUpdated {
+                position,
+                kinematics: Some(kinematics),
+                ..
+            } = event.event
+            {
+                let (x, _, z) = position.coordinates(LengthUnit::Centimeter);
+                if x >= 100.0 {
+                    assert!((x - 100.0).abs() < 0.01 && (z - 10.0).abs() < 0.01);
+                    assert_eq!(kinematics.velocity, [100.0, 0.0, 20.0]);
+                    break;
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn command_log() {
+        let path = std::env::temp_dir().join(format!("pica-commands-{}.jsonl", std::process::id()));
+        let mut pica = Pica::builder()
+            .time_mode(TimeMode::Stepped)
+            .command_log(CommandLog::create(&path).unwrap())
+            .build();
+        let tx = pica.tx();
+        tokio::spawn(async move { pica.run().await });
+
+        let mac_address = MacAddress::Short([0x00, 0x01]);
+        let commands: Vec<fn(oneshot::Sender<PicaCommandStatus>) -> PicaCommand> = vec![
+            |status_tx| {
+                PicaCommand::CreateAnchor(
+                    MacAddress::Short([0x00, 0x01]),
+                    Position::new(100, 0, 0, 0, 0, 0),
+                    status_tx,
+                )
+            },
+            |status_tx| PicaCommand::AdvanceTime(Duration::from_secs(2), status_tx),
+            |status_tx| {
+                PicaCommand::SetPosition(
+                    MacAddress::Short([0x00, 0x01]),
+                    Position::new(200, 0, 0, 0, 0, 0),
+                    status_tx,
+                )
+            },
+            // Not replayed.
+            |status_tx| PicaCommand::SetScene(Scene::default(), status_tx),
+        ];
+        for command in commands {
+            let (status_tx, status_rx) = oneshot::channel();
+            tx.send(command(status_tx)).await.unwrap();
+            assert!(status_rx.await.unwrap().is_ok());
+        }
+        let log = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(log.lines().count(), 4);
+        assert!(log.lines().last().unwrap().contains(r#""command":"other""#));
+
+        // The commands are replayed at their original simulated time.
+        let mut pica = Pica::builder().time_mode(TimeMode::Stepped).build();
+        let replay = CommandReplay::open(&pica, &path).unwrap();
+        let mut event_rx = pica.event_tx().subscribe();
+        tokio::spawn(async move { pica.run().await });
+        replay.run().await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let event = event_rx.recv().await.unwrap();
+        assert!(matches!(
+            event.event,
+            PicaEvent::DeviceAdded { mac_address: added, .. } if added == mac_address
+        ));
+        assert_eq!(event.monotonic_us, 0);
+        let event = event_rx.recv().await.unwrap();
+        assert!(matches!(
+            event.event,
+            PicaEvent::DeviceUpdated { mac_address: updated, .. } if updated == mac_address
+        ));
+        assert!(event.monotonic_us >= 2_000_000);
+    }
+
+    #[cfg(feature = "scripting")]
+    #[tokio::test]
+    async fn script() {
+        let mut pica = Pica::builder().time_mode(TimeMode::Stepped).build();
+        let timeline = pica.timeline.clone();
+        let mut event_rx = pica.event_tx().subscribe();
+
+        // Syntax errors are reported when compiling the script.
+        assert!(Script::compile(&pica, "create_anchor(").is_err());
+        let script = Script::compile(
+            &pica,
+            r#"
+            create_anchor("00:01", 0, 0, 0);
+            create_anchor("00:02", 300, 0, 0);
+            sleep(30000);
+            destroy_anchor("00:02");
+
+            fn on_event(event) {
+                if event.kind == "device-added" && event.mac_address == "00:01" {
+                    set_position("00:01", 100, 0, 0);
+                }
+            }
+            "#,
+        )
+        .unwrap();
+        tokio::spawn(async move { pica.run().await });
+        tokio::spawn(script.run());
+
+        let anchor_a = MacAddress::Short([0x00, 0x01]);
+        let anchor_b = MacAddress::Short([0x00, 0x02]);
+        let mut added = vec![];
+        // The event handler is called while the script sleeps.
+        loop {
+            match event_rx.recv().await.unwrap().event {
+                PicaEvent::DeviceAdded { mac_address, .. } => added.push(mac_address),
+                PicaEvent::DeviceUpdated { mac_address, .. } => {
+                    assert_eq!(mac_address, anchor_a);
+                    break;
+                }
+                _ => (),
+            }
+        }
+        assert!(added.contains(&anchor_a));
+
+        // The script sleeps on the simulated timeline.
+        timeline.advance(Duration::from_secs(30));
+        loop {
+            let event = event_rx.recv().await.unwrap();
+            match event.event {
+                PicaEvent::DeviceAdded { mac_address, .. } => added.push(mac_address),
+                PicaEvent::DeviceRemoved { mac_address, .. } => {
+                    assert_eq!(mac_address, anchor_b);
+                    assert!(event.monotonic_us >= 30_000_000);
+                    break;
+                }
+                _ => (),
+            }
+        }
+        assert_eq!(added, vec![anchor_a, anchor_b]);
     }
 }
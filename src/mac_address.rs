@@ -13,21 +13,20 @@
 // limitations under the License.
 
 use std::fmt::Display;
+use std::str::FromStr;
 
-use hex::FromHex;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
 const SHORT_MAC_ADDRESS_SIZE: usize = 2;
-const STRING_SHORT_MAC_ADDRESS_SIZE: usize = 2 * SHORT_MAC_ADDRESS_SIZE;
-
 const EXTEND_MAC_ADDRESS_SIZE: usize = 8;
-const STRING_EXTEND_MAC_ADDRESS_SIZE: usize = 2 * EXTEND_MAC_ADDRESS_SIZE;
 
 #[derive(Error, Debug)]
 pub enum Error {
-    #[error("MacAddress has the wrong format: 0")]
+    #[error("MacAddress has the wrong format: {0}")]
     MacAddressWrongFormat(String),
+    #[error("MacAddress is not a short address: {0}")]
+    NotShortAddress(MacAddress),
 }
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 #[serde(try_from = "String", into = "String")]
@@ -40,6 +39,19 @@ impl MacAddress {
     pub fn new(mac_address: String) -> Result<Self, Error> {
         mac_address.try_into()
     }
+
+    /// Address `offset` after this one, in the order of the text
+    /// representations, or None if the address overflows.
+    pub fn offset(&self, offset: u64) -> Option<Self> {
+        match self {
+            MacAddress::Short(address) => u16::from_be_bytes(*address)
+                .checked_add(offset.try_into().ok()?)
+                .map(|address| MacAddress::Short(address.to_be_bytes())),
+            MacAddress::Extend(address) => u64::from_be_bytes(*address)
+                .checked_add(offset)
+                .map(|address| MacAddress::Extend(address.to_be_bytes())),
+        }
+    }
 }
 
 impl From<usize> for MacAddress {
@@ -49,23 +61,63 @@ impl From<usize> for MacAddress {
     }
 }
 
+/// Short address encoded in UCI packets as a 16-bit integer.
+impl From<u16> for MacAddress {
+    fn from(value: u16) -> Self {
+        MacAddress::Short(value.to_le_bytes())
+    }
+}
+
+/// Extended address encoded in UCI packets as a 64-bit integer.
+impl From<u64> for MacAddress {
+    fn from(value: u64) -> Self {
+        MacAddress::Extend(value.to_le_bytes())
+    }
+}
+
+impl FromStr for MacAddress {
+    type Err = Error;
+
+    /// Parse the hexadecimal representation of the address, with the
+    /// bytes separated by colons or dashes, e.g. `00:11` or
+    /// `00-11-22-33-44-55-66-77`, or the decimal value of the address
+    /// as encoded in UCI packets, e.g. `4352` for `00:11`. Decimal
+    /// values above 65535 are extended addresses.
+    fn from_str(mac_address: &str) -> Result<Self, Error> {
+        let wrong_format = || Error::MacAddressWrongFormat(mac_address.to_owned());
+        if !mac_address.is_empty() && mac_address.bytes().all(|byte| byte.is_ascii_digit()) {
+            let value: u64 = mac_address.parse().map_err(|_| wrong_format())?;
+            return Ok(match u16::try_from(value) {
+                Ok(value) => MacAddress::from(value),
+                Err(_) => MacAddress::from(value),
+            });
+        }
+        // Colons are percent-encoded in the paths of the HTTP commands.
+        let bytes = mac_address
+            .replace("%3A", ":")
+            .replace("%3a", ":")
+            .replace('-', ":")
+            .split(':')
+            .map(|byte| match byte.len() {
+                2 if byte.bytes().all(|digit| digit.is_ascii_hexdigit()) => {
+                    u8::from_str_radix(byte, 16).ok()
+                }
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(wrong_format)?;
+        match bytes.len() {
+            SHORT_MAC_ADDRESS_SIZE => Ok(MacAddress::Short(bytes.try_into().unwrap())),
+            EXTEND_MAC_ADDRESS_SIZE => Ok(MacAddress::Extend(bytes.try_into().unwrap())),
+            _ => Err(wrong_format()),
+        }
+    }
+}
+
 impl TryFrom<String> for MacAddress {
     type Error = Error;
     fn try_from(mac_address: String) -> std::result::Result<Self, Error> {
-        let mac_address = mac_address.replace(':', "");
-        let mac_address = mac_address.replace("%3A", "");
-        let uwb_mac_address = match mac_address.len() {
-            STRING_SHORT_MAC_ADDRESS_SIZE => MacAddress::Short(
-                <[u8; SHORT_MAC_ADDRESS_SIZE]>::from_hex(mac_address)
-                    .map_err(|err| Error::MacAddressWrongFormat(err.to_string()))?,
-            ),
-            STRING_EXTEND_MAC_ADDRESS_SIZE => MacAddress::Extend(
-                <[u8; EXTEND_MAC_ADDRESS_SIZE]>::from_hex(mac_address)
-                    .map_err(|err| Error::MacAddressWrongFormat(err.to_string()))?,
-            ),
-            _ => return Err(Error::MacAddressWrongFormat(mac_address)),
-        };
-        Ok(uwb_mac_address)
+        mac_address.parse()
     }
 }
 
@@ -92,6 +144,50 @@ impl From<MacAddress> for String {
     }
 }
 
+/// Integer value of the address, as encoded in UCI packets.
+impl From<&MacAddress> for u64 {
+    fn from(mac_address: &MacAddress) -> Self {
+        match mac_address {
+            MacAddress::Short(address) => u16::from_le_bytes(*address) as u64,
+            MacAddress::Extend(address) => u64::from_le_bytes(*address),
+        }
+    }
+}
+
+/// Integer value of the short address, as encoded in UCI packets.
+impl TryFrom<&MacAddress> for u16 {
+    type Error = Error;
+    fn try_from(mac_address: &MacAddress) -> Result<Self, Error> {
+        match mac_address {
+            MacAddress::Short(address) => Ok(u16::from_le_bytes(*address)),
+            MacAddress::Extend(_) => Err(Error::NotShortAddress(*mac_address)),
+        }
+    }
+}
+
+#[cfg(feature = "schema")]
+impl schemars::JsonSchema for MacAddress {
+    fn schema_name() -> String {
+        "MacAddress".to_owned()
+    }
+
+    fn json_schema(_: &mut schemars::gen::SchemaGenerator) -> schemars::schema::Schema {
+        schemars::schema::SchemaObject {
+            instance_type: Some(schemars::schema::InstanceType::String.into()),
+            metadata: Some(Box::new(schemars::schema::Metadata {
+                description: Some(
+                    "Short (XX:XX) or extended (XX:XX:XX:XX:XX:XX:XX:XX) address, \
+                     where X is an hexadecimal digit."
+                        .to_owned(),
+                ),
+                ..Default::default()
+            })),
+            ..Default::default()
+        }
+        .into()
+    }
+}
+
 impl Display for MacAddress {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", String::from(self))
@@ -116,6 +212,58 @@ mod tests {
         );
     }
 
+    #[test]
+    fn parse_mac_address() {
+        let short = MacAddress::Short([0x00, 0x11]);
+        let extend = MacAddress::Extend([0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0xaa]);
+        assert_eq!("00:11".parse::<MacAddress>().unwrap(), short);
+        assert_eq!("00-11".parse::<MacAddress>().unwrap(), short);
+        assert_eq!("00%3A11".parse::<MacAddress>().unwrap(), short);
+        assert_eq!("4352".parse::<MacAddress>().unwrap(), short);
+        assert_eq!(
+            "00:11:22:33:44:55:66:aa".parse::<MacAddress>().unwrap(),
+            extend
+        );
+        assert_eq!(
+            "12278595185476243712".parse::<MacAddress>().unwrap(),
+            extend
+        );
+        for invalid in [
+            "",
+            "0:11",
+            "00:1g",
+            "00:11:22",
+            "+1:00",
+            "18446744073709551616",
+        ] {
+            assert!(invalid.parse::<MacAddress>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn mac_address_integers() {
+        let short = MacAddress::from(0x1100u16);
+        assert_eq!(short, MacAddress::Short([0x00, 0x11]));
+        assert_eq!(u16::try_from(&short).unwrap(), 0x1100);
+        let extend = MacAddress::from(0x1100u64);
+        assert_eq!(extend, MacAddress::Extend([0x00, 0x11, 0, 0, 0, 0, 0, 0]));
+        assert_eq!(u64::from(&extend), 0x1100);
+        assert!(u16::try_from(&extend).is_err());
+    }
+
+    #[test]
+    fn offset() {
+        assert_eq!(
+            MacAddress::Short([0x00, 0xff]).offset(2),
+            Some(MacAddress::Short([0x01, 0x01]))
+        );
+        assert_eq!(MacAddress::Short([0xff, 0xff]).offset(1), None);
+        assert_eq!(
+            MacAddress::Extend([0, 0, 0, 0, 0, 0, 0, 1]).offset(1),
+            Some(MacAddress::Extend([0, 0, 0, 0, 0, 0, 0, 2]))
+        );
+    }
+
     #[test]
     #[should_panic]
     fn invalid_mac_address_short() {
@@ -144,4 +292,13 @@ mod tests {
         );
         assert_eq!(extend_mac_address.to_string(), extend_mac_address);
     }
+
+    #[test]
+    fn mac_address_to_u64() {
+        assert_eq!(u64::from(&MacAddress::Short([0x11, 0x22])), 0x2211);
+        assert_eq!(
+            u64::from(&MacAddress::Extend([1, 2, 3, 4, 5, 6, 7, 8])),
+            0x0807060504030201
+        );
+    }
 }
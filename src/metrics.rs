@@ -0,0 +1,126 @@
+// Copyright 2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Operational metrics of the simulator, for the monitoring of
+//! long-running simulations. Counters are cumulative since startup;
+//! rates such as the ranging notifications per second are derived
+//! by the monitoring system.
+
+use std::fmt::Write;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Metrics {
+    /// Number of connected UCI devices.
+    pub connected_devices: usize,
+    /// Number of anchors.
+    pub anchors: usize,
+    /// Number of sessions in the active state, across all devices.
+    pub active_sessions: usize,
+    /// Number of ranging rounds initiated by the UCI devices.
+    pub ranging_rounds: u64,
+    /// Number of ranging data notifications sent to the hosts.
+    pub ranging_notifications: u64,
+    /// Number of malformed UCI packets received from the hosts.
+    pub malformed_packets: u64,
+    /// Number of events sent.
+    pub events: u64,
+    /// Number of events evicted before being received by the slowest
+    /// subscriber. Only tracked for event channels created by the
+    /// [`crate::PicaBuilder`].
+    pub dropped_events: u64,
+}
+
+impl Metrics {
+    /// Format the metrics in the Prometheus text exposition format.
+    pub fn to_prometheus(&self) -> String {
+        let metrics: [(&str, &str, &str, u64); 8] = [
+            (
+                "pica_connected_devices",
+                "gauge",
+                "Number of connected UCI devices.",
+                self.connected_devices as u64,
+            ),
+            (
+                "pica_anchors",
+                "gauge",
+                "Number of anchors.",
+                self.anchors as u64,
+            ),
+            (
+                "pica_active_sessions",
+                "gauge",
+                "Number of active sessions.",
+                self.active_sessions as u64,
+            ),
+            (
+                "pica_ranging_rounds_total",
+                "counter",
+                "Number of ranging rounds initiated by the UCI devices.",
+                self.ranging_rounds,
+            ),
+            (
+                "pica_ranging_notifications_total",
+                "counter",
+                "Number of ranging data notifications sent.",
+                self.ranging_notifications,
+            ),
+            (
+                "pica_malformed_packets_total",
+                "counter",
+                "Number of malformed UCI packets received.",
+                self.malformed_packets,
+            ),
+            (
+                "pica_events_total",
+                "counter",
+                "Number of events sent.",
+                self.events,
+            ),
+            (
+                "pica_dropped_events_total",
+                "counter",
+                "Number of events missed by the slowest subscriber.",
+                self.dropped_events,
+            ),
+        ];
+        let mut text = String::new();
+        for (name, kind, help, value) in metrics {
+            let _ = write!(
+                text,
+                "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}\n"
+            );
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prometheus_format() {
+        let metrics = Metrics {
+            connected_devices: 2,
+            ranging_notifications: 42,
+            ..Default::default()
+        };
+        let text = metrics.to_prometheus();
+        assert!(text.contains("# TYPE pica_connected_devices gauge\npica_connected_devices 2\n"));
+        assert!(text.contains(
+            "# TYPE pica_ranging_notifications_total counter\npica_ranging_notifications_total 42\n"
+        ));
+        assert_eq!(text.lines().count(), 24);
+    }
+}
@@ -0,0 +1,320 @@
+// Copyright 2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Motion of anchors and devices constrained to a path, e.g. tags
+//! mounted on a conveyor or a vehicle, or moving freely with a velocity
+//! and acceleration.
+
+use glam::Vec3;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+use crate::RecordedTrajectory;
+
+/// Interval between two updates of the position of a moving node.
+pub const MOTION_UPDATE_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Path has no waypoints")]
+    NoWaypoints,
+    #[error("Path has an invalid speed: {0}")]
+    InvalidSpeed(f32),
+    #[error("Kinematic state is not finite")]
+    InvalidKinematics,
+}
+
+/// Behaviour of the node when reaching the last waypoint.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "kebab-case")]
+pub enum PathMode {
+    /// Move back to the first waypoint and start over.
+    #[default]
+    Loop,
+    /// Travel the path backwards to the first waypoint, and so on.
+    PingPong,
+}
+
+/// Polyline traveled at constant speed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MotionPath {
+    waypoints: Vec<Vec3>,
+    /// Speed along the path (cm/s).
+    speed: f32,
+    mode: PathMode,
+}
+
+impl MotionPath {
+    /// Create a path through the selected waypoints (cm), traveled
+    /// at the selected speed (cm/s).
+    pub fn new(waypoints: &[(i16, i16, i16)], speed: f32, mode: PathMode) -> Result<Self, Error> {
+        if waypoints.is_empty() {
+            return Err(Error::NoWaypoints);
+        }
+        if !speed.is_finite() || speed < 0.0 {
+            return Err(Error::InvalidSpeed(speed));
+        }
+        Ok(MotionPath {
+            waypoints: waypoints
+                .iter()
+                .map(|(x, y, z)| Vec3::new(*x as f32, *y as f32, *z as f32))
+                .collect(),
+            speed,
+            mode,
+        })
+    }
+
+    /// Segments of one traversal of the path.
+    fn segments(&self) -> impl Iterator<Item = (Vec3, Vec3)> + '_ {
+        let closing = match self.mode {
+            PathMode::Loop => Some((self.waypoints[self.waypoints.len() - 1], self.waypoints[0])),
+            PathMode::PingPong => None,
+        };
+        self.waypoints
+            .windows(2)
+            .map(|segment| (segment[0], segment[1]))
+            .chain(closing)
+    }
+
+    /// Location of the node after traveling the path for the selected time.
+    pub fn translation_at(&self, elapsed: Duration) -> Vec3 {
+        self.locate(elapsed).0
+    }
+
+    /// Location and velocity (cm/s) of the node after traveling the path
+    /// for the selected time.
+    fn locate(&self, elapsed: Duration) -> (Vec3, Vec3) {
+        let length: f32 = self.segments().map(|(a, b)| a.distance(b)).sum();
+        if length == 0.0 {
+            return (self.waypoints[0], Vec3::ZERO);
+        }
+        let traveled = self.speed * elapsed.as_secs_f32();
+        let (mut offset, backwards) = match self.mode {
+            PathMode::Loop => (traveled % length, false),
+            PathMode::PingPong => {
+                let offset = traveled % (2.0 * length);
+                if offset > length {
+                    (2.0 * length - offset, true)
+                } else {
+                    (offset, false)
+                }
+            }
+        };
+        let direction = if backwards { -1.0 } else { 1.0 };
+        for (start, end) in self.segments() {
+            let segment_length = start.distance(end);
+            if offset <= segment_length && segment_length > 0.0 {
+                let velocity = (end - start) / segment_length * self.speed * direction;
+                return (start.lerp(end, offset / segment_length), velocity);
+            }
+            offset -= segment_length;
+        }
+        (self.waypoints[self.waypoints.len() - 1], Vec3::ZERO)
+    }
+}
+
+/// Velocity and acceleration of a node moving freely, e.g. to exercise
+/// the distance rate and the prediction of the tracking filters.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Kinematics {
+    /// Velocity along the x, y, z axes (cm/s).
+    pub velocity: [f32; 3],
+    /// Acceleration along the x, y, z axes (cm/s²).
+    #[serde(default)]
+    pub acceleration: [f32; 3],
+}
+
+impl Kinematics {
+    pub fn new(velocity: [f32; 3], acceleration: [f32; 3]) -> Result<Self, Error> {
+        if velocity
+            .iter()
+            .chain(&acceleration)
+            .all(|value| value.is_finite())
+        {
+            Ok(Kinematics {
+                velocity,
+                acceleration,
+            })
+        } else {
+            Err(Error::InvalidKinematics)
+        }
+    }
+
+    /// Displacement from the initial location, and velocity, after the
+    /// selected time.
+    fn integrate(&self, elapsed: Duration) -> (Vec3, Vec3) {
+        let t = elapsed.as_secs_f32();
+        let velocity = Vec3::from(self.velocity);
+        let acceleration = Vec3::from(self.acceleration);
+        (
+            velocity * t + 0.5 * acceleration * t * t,
+            velocity + acceleration * t,
+        )
+    }
+}
+
+/// Trajectory of a moving node.
+pub(crate) enum Trajectory {
+    Path(MotionPath),
+    /// Free motion from the initial location of the node.
+    Kinematics(Vec3, Kinematics),
+    Recorded(RecordedTrajectory),
+}
+
+/// Trajectory followed by a node, and task triggering the updates of
+/// its position.
+pub struct Motion {
+    trajectory: Trajectory,
+    start: Instant,
+    task: JoinHandle<()>,
+}
+
+impl Motion {
+    pub(crate) fn new(trajectory: Trajectory, start: Instant, task: JoinHandle<()>) -> Self {
+        Motion {
+            trajectory,
+            start,
+            task,
+        }
+    }
+
+    fn locate(&self, now: Instant) -> (Vec3, Vec3) {
+        let elapsed = now.saturating_duration_since(self.start);
+        match &self.trajectory {
+            Trajectory::Path(path) => path.locate(elapsed),
+            Trajectory::Kinematics(origin, kinematics) => {
+                let (displacement, velocity) = kinematics.integrate(elapsed);
+                (*origin + displacement, velocity)
+            }
+            Trajectory::Recorded(trajectory) => trajectory.locate(elapsed),
+        }
+    }
+
+    /// Location of the node at the selected instant.
+    pub fn translation(&self, now: Instant) -> Vec3 {
+        self.locate(now).0
+    }
+
+    /// Velocity and acceleration of the node at the selected instant.
+    pub fn kinematics(&self, now: Instant) -> Kinematics {
+        let acceleration = match &self.trajectory {
+            Trajectory::Kinematics(_, kinematics) => kinematics.acceleration,
+            Trajectory::Path(_) | Trajectory::Recorded(_) => [0.0; 3],
+        };
+        Kinematics {
+            velocity: self.locate(now).1.into(),
+            acceleration,
+        }
+    }
+}
+
+impl Drop for Motion {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invalid_paths() {
+        assert!(MotionPath::new(&[], 100.0, PathMode::Loop).is_err());
+        assert!(MotionPath::new(&[(0, 0, 0)], -1.0, PathMode::Loop).is_err());
+        assert!(MotionPath::new(&[(0, 0, 0)], f32::NAN, PathMode::Loop).is_err());
+    }
+
+    #[test]
+    fn loop_path() {
+        // Square of 400 cm, traveled in 4 s.
+        let path = MotionPath::new(
+            &[(0, 0, 0), (100, 0, 0), (100, 100, 0), (0, 100, 0)],
+            100.0,
+            PathMode::Loop,
+        )
+        .unwrap();
+        assert_eq!(
+            path.translation_at(Duration::from_millis(500)),
+            Vec3::new(50.0, 0.0, 0.0)
+        );
+        assert_eq!(
+            path.translation_at(Duration::from_millis(3500)),
+            Vec3::new(0.0, 50.0, 0.0)
+        );
+        assert_eq!(
+            path.translation_at(Duration::from_millis(4500)),
+            Vec3::new(50.0, 0.0, 0.0)
+        );
+    }
+
+    #[test]
+    fn ping_pong_path() {
+        let path = MotionPath::new(
+            &[(0, 0, 0), (100, 0, 0), (100, 100, 0)],
+            100.0,
+            PathMode::PingPong,
+        )
+        .unwrap();
+        assert_eq!(
+            path.translation_at(Duration::from_millis(1500)),
+            Vec3::new(100.0, 50.0, 0.0)
+        );
+        assert_eq!(
+            path.translation_at(Duration::from_millis(2500)),
+            Vec3::new(100.0, 50.0, 0.0)
+        );
+        assert_eq!(
+            path.translation_at(Duration::from_millis(3500)),
+            Vec3::new(50.0, 0.0, 0.0)
+        );
+    }
+
+    #[test]
+    fn single_waypoint() {
+        let path = MotionPath::new(&[(10, 20, 30)], 100.0, PathMode::Loop).unwrap();
+        assert_eq!(
+            path.translation_at(Duration::from_secs(10)),
+            Vec3::new(10.0, 20.0, 30.0)
+        );
+    }
+
+    #[test]
+    fn path_velocity() {
+        let path = MotionPath::new(&[(0, 0, 0), (100, 0, 0)], 50.0, PathMode::PingPong).unwrap();
+        assert_eq!(
+            path.locate(Duration::from_millis(1000)),
+            (Vec3::new(50.0, 0.0, 0.0), Vec3::new(50.0, 0.0, 0.0))
+        );
+        assert_eq!(
+            path.locate(Duration::from_millis(3000)),
+            (Vec3::new(50.0, 0.0, 0.0), Vec3::new(-50.0, 0.0, 0.0))
+        );
+    }
+
+    #[test]
+    fn kinematics() {
+        assert!(Kinematics::new([f32::INFINITY, 0.0, 0.0], [0.0; 3]).is_err());
+        let kinematics = Kinematics::new([100.0, 0.0, 0.0], [0.0, 20.0, 0.0]).unwrap();
+        assert_eq!(
+            kinematics.integrate(Duration::from_secs(2)),
+            (Vec3::new(200.0, 40.0, 0.0), Vec3::new(100.0, 40.0, 0.0))
+        );
+    }
+}
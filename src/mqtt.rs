@@ -0,0 +1,234 @@
+// Copyright 2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Connections to the MQTT brokers: the subscription of the position
+//! feed, and the publication of the events.
+
+use std::fmt::Display;
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::Result;
+use rumqttc::{AsyncClient, EventLoop, MqttOptions, QoS};
+use serde::Serialize;
+use tokio::sync::broadcast;
+use tracing::{info, warn};
+
+use crate::{Pica, PicaEvent, SequencedEvent};
+
+/// Default port of the MQTT brokers.
+const DEFAULT_MQTT_PORT: u16 = 1883;
+/// Capacity of the request queue of the MQTT clients.
+const MQTT_REQUEST_CAPACITY: usize = 64;
+
+/// Broker and topic, written `mqtt://host[:port]/topic`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MqttEndpoint {
+    pub host: String,
+    pub port: u16,
+    pub topic: String,
+}
+
+impl MqttEndpoint {
+    /// Create a client of the broker. The connection is established,
+    /// and re-established after failures, while polling the event loop.
+    pub(crate) fn connect(&self, client_id: &str) -> (AsyncClient, EventLoop) {
+        let client_id = format!("{}-{}", client_id, std::process::id());
+        let mut options = MqttOptions::new(client_id, &self.host, self.port);
+        options.set_keep_alive(Duration::from_secs(30));
+        AsyncClient::new(options, MQTT_REQUEST_CAPACITY)
+    }
+}
+
+impl Display for MqttEndpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "mqtt://{}:{}/{}", self.host, self.port, self.topic)
+    }
+}
+
+impl FromStr for MqttEndpoint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid MQTT endpoint: {}", s);
+        let (authority, topic) = s
+            .strip_prefix("mqtt://")
+            .and_then(|rest| rest.split_once('/'))
+            .ok_or_else(invalid)?;
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(|_| invalid())?),
+            None => (authority, DEFAULT_MQTT_PORT),
+        };
+        if host.is_empty() || topic.is_empty() {
+            return Err(invalid());
+        }
+        Ok(MqttEndpoint {
+            host: host.to_owned(),
+            port,
+            topic: topic.to_owned(),
+        })
+    }
+}
+
+/// Payload of the published events: the event fields, completed with
+/// the sequence number and timestamps of the event.
+#[derive(Serialize)]
+struct EventMessage<'a> {
+    sequence_number: u64,
+    monotonic_us: u64,
+    timestamp_ms: u64,
+    #[serde(flatten)]
+    event: &'a PicaEvent,
+}
+
+/// Topic of an event. The `{mac_address}` and `{kind}` placeholders of
+/// the template are replaced with the MAC address of the device (the
+/// source device of the events between two devices) and the kind of
+/// the event. Templates without placeholders are prefixes of the
+/// topics `prefix/{mac_address}/{kind}`.
+fn event_topic(template: &str, event: &PicaEvent) -> String {
+    let (mac_address, _) = event.mac_addresses();
+    if template.contains("{mac_address}") || template.contains("{kind}") {
+        template
+            .replace("{mac_address}", &mac_address.to_string())
+            .replace("{kind}", event.name())
+    } else {
+        format!(
+            "{}/{}/{}",
+            template.trim_end_matches('/'),
+            mac_address,
+            event.name()
+        )
+    }
+}
+
+/// Publisher of the events of a simulation to an MQTT broker.
+pub struct EventPublisher {
+    endpoint: MqttEndpoint,
+    event_rx: broadcast::Receiver<SequencedEvent>,
+}
+
+impl EventPublisher {
+    /// Create a publisher of the events emitted from now on, to the
+    /// topics derived from the topic of the endpoint.
+    pub fn new(pica: &Pica, endpoint: MqttEndpoint) -> Self {
+        EventPublisher {
+            endpoint,
+            event_rx: pica.event_tx().subscribe(),
+        }
+    }
+
+    /// Publish the events until the simulation is shut down. The events
+    /// are dropped while the broker is unreachable.
+    pub async fn run(mut self) -> Result<()> {
+        let (client, mut eventloop) = self.endpoint.connect("pica-events");
+        info!("Publishing the events to {}", self.endpoint);
+        let mut dropping = false;
+        loop {
+            tokio::select! {
+                event = self.event_rx.recv() => match event {
+                    Ok(event) => {
+                        let topic = event_topic(&self.endpoint.topic, &event.event);
+                        let payload = serde_json::to_vec(&EventMessage {
+                            sequence_number: event.sequence_number,
+                            monotonic_us: event.monotonic_us,
+                            timestamp_ms: event.timestamp_ms,
+                            event: &event.event,
+                        })?;
+                        // The requests are queued until the event loop is
+                        // polled, the publication must not wait for it.
+                        match client.try_publish(topic, QoS::AtMostOnce, false, payload) {
+                            Ok(()) => dropping = false,
+                            Err(err) if !dropping => {
+                                warn!("Dropping the events published to {}: {}", self.endpoint, err);
+                                dropping = true;
+                            }
+                            Err(_) => (),
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(count)) => {
+                        warn!("MQTT publisher missed {} events", count)
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                },
+                notification = eventloop.poll() => if let Err(err) = notification {
+                    warn!("MQTT connection to {} failed: {}", self.endpoint, err);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Category, MacAddress, Position};
+
+    #[test]
+    fn event_topics() {
+        let mac_address = MacAddress::Short([0x00, 0x01]);
+        let event = PicaEvent::DeviceRemoved {
+            category: Category::Anchor,
+            mac_address,
+        };
+        assert_eq!(
+            event_topic("pica/{mac_address}/{kind}", &event),
+            "pica/00:01/device-removed"
+        );
+        assert_eq!(
+            event_topic("pica/events/{kind}", &event),
+            "pica/events/device-removed"
+        );
+        assert_eq!(event_topic("pica/", &event), "pica/00:01/device-removed");
+
+        let message = serde_json::to_value(EventMessage {
+            sequence_number: 3,
+            monotonic_us: 1500,
+            timestamp_ms: 1,
+            event: &PicaEvent::DeviceAdded {
+                category: Category::Anchor,
+                mac_address,
+                position: Position::new(100, 0, 0, 0, 0, 0),
+                origin: None,
+            },
+        })
+        .unwrap();
+        assert_eq!(message["sequence_number"], 3);
+        assert_eq!(message["kind"], "device-added");
+        assert_eq!(message["mac_address"], "00:01");
+        assert_eq!(message["x"], 100.0);
+    }
+
+    #[test]
+    fn parse_mqtt_endpoint() {
+        assert_eq!(
+            "mqtt://localhost/mocap/positions".parse(),
+            Ok(MqttEndpoint {
+                host: "localhost".to_owned(),
+                port: DEFAULT_MQTT_PORT,
+                topic: "mocap/positions".to_owned(),
+            })
+        );
+        let endpoint: MqttEndpoint = "mqtt://10.0.0.2:8883/pica/#".parse().unwrap();
+        assert_eq!(endpoint.port, 8883);
+        assert_eq!(endpoint.topic, "pica/#");
+        assert_eq!(endpoint.to_string().parse(), Ok(endpoint));
+        assert!("localhost/positions".parse::<MqttEndpoint>().is_err());
+        assert!("mqtt://localhost".parse::<MqttEndpoint>().is_err());
+        assert!("mqtt://localhost:port/positions"
+            .parse::<MqttEndpoint>()
+            .is_err());
+    }
+}
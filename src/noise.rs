@@ -0,0 +1,154 @@
+// Copyright 2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Random errors added to the ranging measurements.
+
+use rand::rngs::StdRng;
+use rand::Rng;
+use rand_distr::{Distribution, Normal};
+
+/// Standard deviation of the gaussian errors added to the ranging
+/// measurements. Measurements are exact with the default model.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MeasurementNoise {
+    /// Standard deviation of the distance error (cm).
+    pub distance: f32,
+    /// Standard deviation of the azimuth and elevation errors (degrees).
+    pub angle: f32,
+}
+
+impl MeasurementNoise {
+    /// Add random errors to a (distance, azimuth, elevation) measurement.
+    /// The values are clamped to the ranges of the measurement fields.
+    pub fn apply(&self, rng: &mut StdRng, measurement: (u16, i16, i8)) -> (u16, i16, i8) {
+        let (distance, azimuth, elevation) = measurement;
+        (
+            (distance as f32 + sample(rng, self.distance)).clamp(0.0, u16::MAX as f32) as u16,
+            (azimuth as f32 + sample(rng, self.angle)).clamp(-180.0, 180.0) as i16,
+            (elevation as f32 + sample(rng, self.angle)).clamp(-90.0, 90.0) as i8,
+        )
+    }
+}
+
+/// Gross errors added to a fraction of the ranging measurements, as
+/// caused by multipath propagation and by the ambiguity of the phase
+/// difference of arrival. Measurements are never outliers with the
+/// default model.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MeasurementOutliers {
+    /// Probability that the measured distance is an outlier.
+    pub distance_probability: f32,
+    /// Error added to the outlier distances (cm).
+    pub distance_offset: u16,
+    /// Probability that the measured azimuth is mirrored
+    /// across the boresight.
+    pub aoa_probability: f32,
+}
+
+impl Default for MeasurementOutliers {
+    fn default() -> Self {
+        MeasurementOutliers {
+            distance_probability: 0.0,
+            distance_offset: 500,
+            aoa_probability: 0.0,
+        }
+    }
+}
+
+impl MeasurementOutliers {
+    /// Replace a (distance, azimuth, elevation) measurement with an outlier
+    /// with the selected probabilities. No value is drawn from the generator
+    /// for the outliers with a zero probability, so that enabling them does
+    /// not change the other errors of a seeded scenario.
+    pub fn apply(&self, rng: &mut StdRng, measurement: (u16, i16, i8)) -> (u16, i16, i8) {
+        let (mut distance, mut azimuth, elevation) = measurement;
+        if draw(rng, self.distance_probability) {
+            distance = distance.saturating_add(self.distance_offset);
+        }
+        if draw(rng, self.aoa_probability) {
+            azimuth = -azimuth;
+        }
+        (distance, azimuth, elevation)
+    }
+}
+
+/// Draw an event with the selected probability.
+fn draw(rng: &mut StdRng, probability: f32) -> bool {
+    probability > 0.0 && rng.gen::<f32>() < probability
+}
+
+/// Draw a gaussian error with the selected standard deviation,
+/// or zero if the standard deviation is not positive.
+pub(crate) fn sample(rng: &mut StdRng, std_dev: f32) -> f32 {
+    match Normal::new(0.0, std_dev) {
+        Ok(normal) if std_dev > 0.0 => normal.sample(rng),
+        _ => 0.0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+
+    #[test]
+    fn default_noise_is_exact() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let measurement = (100, -45, 10);
+        assert_eq!(
+            MeasurementNoise::default().apply(&mut rng, measurement),
+            measurement
+        );
+    }
+
+    #[test]
+    fn outliers() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let measurement = (100, -45, 10);
+        assert_eq!(
+            MeasurementOutliers::default().apply(&mut rng, measurement),
+            measurement
+        );
+        let outliers = MeasurementOutliers {
+            distance_probability: 1.0,
+            aoa_probability: 1.0,
+            ..Default::default()
+        };
+        assert_eq!(outliers.apply(&mut rng, measurement), (600, 45, 10));
+        let outliers = MeasurementOutliers {
+            distance_probability: 0.2,
+            ..Default::default()
+        };
+        let count = (0..1000)
+            .filter(|_| outliers.apply(&mut rng, measurement).0 != 100)
+            .count();
+        assert!((150..250).contains(&count), "{}", count);
+    }
+
+    #[test]
+    fn same_seed_same_errors() {
+        let noise = MeasurementNoise {
+            distance: 10.0,
+            angle: 5.0,
+        };
+        let mut rng_a = StdRng::seed_from_u64(42);
+        let mut rng_b = StdRng::seed_from_u64(42);
+        for _ in 0..10 {
+            assert_eq!(
+                noise.apply(&mut rng_a, (100, 0, 0)),
+                noise.apply(&mut rng_b, (100, 0, 0))
+            );
+        }
+    }
+}
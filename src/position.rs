@@ -12,26 +12,78 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use glam::{EulerRot, Quat, Vec3};
-use serde::ser::{Serialize, SerializeStruct, Serializer};
+use glam::{DVec3, EulerRot, Quat, Vec3};
+use serde::ser::{SerializeStruct, Serializer};
+use serde::{Deserialize, Deserializer, Serialize};
 use std::default::Default;
 use std::fmt::Display;
+use std::str::FromStr;
+
+/// Unit of the lengths exchanged with pica. The coordinates are stored
+/// in meters, and converted to the selected unit when encoded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "kebab-case")]
+pub enum LengthUnit {
+    Meter,
+    /// Unit of the coordinates of the HTTP API, and of the distances
+    /// reported in the UCI ranging measurements.
+    #[default]
+    Centimeter,
+    Millimeter,
+}
+
+impl LengthUnit {
+    fn per_meter(self) -> f64 {
+        match self {
+            LengthUnit::Meter => 1.,
+            LengthUnit::Centimeter => 100.,
+            LengthUnit::Millimeter => 1000.,
+        }
+    }
+
+    /// Convert a length in meters to this unit.
+    pub fn from_meters(self, meters: f64) -> f64 {
+        meters * self.per_meter()
+    }
+
+    /// Convert a length in this unit to meters.
+    pub fn to_meters(self, length: f64) -> f64 {
+        length / self.per_meter()
+    }
+
+    /// Encode a length in meters as an integer count of this unit,
+    /// rounded to the nearest unit and saturated to the range of the
+    /// measurement fields.
+    pub fn encode(self, meters: f64) -> u16 {
+        self.from_meters(meters).round().clamp(0., u16::MAX as f64) as u16
+    }
+}
 
 #[derive(Debug, Clone, Copy)]
 pub struct Position {
-    position: Vec3,
+    /// Coordinates in meters.
+    position: DVec3,
     rotation: Quat,
 }
 
+/// Round a length for display and serialization, dropping the noise of
+/// the conversion from meters (precision of 1 µm).
+fn round_micrometers(length: f64, unit: LengthUnit) -> f64 {
+    let scale = 1e6 / unit.per_meter();
+    (length * scale).round() / scale
+}
+
 impl Display for Position {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let (roll, pitch, yaw) = self.rotation.to_euler(EulerRot::ZXY);
+        let (x, y, z) = self.coordinates(LengthUnit::Centimeter);
         write!(
             f,
             "Position: {}, {}, {} Rotation: {}, {}, {}",
-            self.position.x,
-            self.position.y,
-            self.position.z,
+            round_micrometers(x, LengthUnit::Centimeter),
+            round_micrometers(y, LengthUnit::Centimeter),
+            round_micrometers(z, LengthUnit::Centimeter),
             yaw.to_degrees().round(),
             pitch.to_degrees().round(),
             roll.to_degrees().round(),
@@ -44,10 +96,11 @@ impl Serialize for Position {
     where
         S: Serializer,
     {
-        let mut state = serializer.serialize_struct("Position", 5)?;
-        state.serialize_field("x", &(self.position.x as i16))?;
-        state.serialize_field("y", &(self.position.y as i16))?;
-        state.serialize_field("z", &(self.position.z as i16))?;
+        let mut state = serializer.serialize_struct("Position", 6)?;
+        let (x, y, z) = self.coordinates(LengthUnit::Centimeter);
+        state.serialize_field("x", &round_micrometers(x, LengthUnit::Centimeter))?;
+        state.serialize_field("y", &round_micrometers(y, LengthUnit::Centimeter))?;
+        state.serialize_field("z", &round_micrometers(z, LengthUnit::Centimeter))?;
 
         let (roll, pitch, yaw) = self.rotation.to_euler(EulerRot::ZXY);
 
@@ -58,7 +111,157 @@ impl Serialize for Position {
     }
 }
 
-fn checked_div(num: f32, den: f32) -> Option<f32> {
+impl<'de> Deserialize<'de> for Position {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        struct Fields {
+            x: f64,
+            y: f64,
+            z: f64,
+            yaw: i16,
+            pitch: i8,
+            roll: i16,
+        }
+        let Fields {
+            x,
+            y,
+            z,
+            yaw,
+            pitch,
+            roll,
+        } = Fields::deserialize(deserializer)?;
+        Ok(
+            Position::new(0, 0, 0, yaw, pitch, roll).with_coordinates_in(
+                x,
+                y,
+                z,
+                LengthUnit::Centimeter,
+            ),
+        )
+    }
+}
+
+#[cfg(feature = "schema")]
+impl schemars::JsonSchema for Position {
+    fn schema_name() -> String {
+        "Position".to_owned()
+    }
+
+    fn json_schema(generator: &mut schemars::gen::SchemaGenerator) -> schemars::schema::Schema {
+        // Fields of the serialized position: coordinates (cm)
+        // and orientation (degrees).
+        #[derive(schemars::JsonSchema)]
+        #[allow(dead_code)]
+        struct Position {
+            x: f64,
+            y: f64,
+            z: f64,
+            yaw: i16,
+            pitch: i8,
+            roll: i16,
+        }
+        Position::json_schema(generator)
+    }
+}
+
+/// Reference direction of the zero azimuth, in the frame of the node.
+/// The frame of a node without rotation is the frame of the simulation,
+/// in which x points east, y up and z north.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AzimuthReference {
+    /// Along the boresight of the antennas (z axis), e.g. north in NED.
+    #[default]
+    Boresight,
+    /// Along the x axis, perpendicular to the boresight, e.g. east in ENU.
+    XAxis,
+}
+
+/// Direction of the positive azimuth, seen from above (y axis).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Handedness {
+    /// From the z axis towards the x axis, e.g. from north towards east.
+    #[default]
+    Clockwise,
+    /// From the x axis towards the z axis, e.g. from east towards north.
+    CounterClockwise,
+}
+
+/// Convention of the azimuths reported to the hosts. The default
+/// convention measures the azimuth clockwise from the boresight, as
+/// the NED frame does from the north.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AngleConvention {
+    pub azimuth_reference: AzimuthReference,
+    pub handedness: Handedness,
+}
+
+/// Wrap an angle (degrees) to the range [-180, 180).
+fn wrap_degrees(angle: i32) -> i16 {
+    ((angle + 180).rem_euclid(360) - 180) as i16
+}
+
+impl AngleConvention {
+    /// Azimuth clockwise from the north, in the North-East-Down frame.
+    pub const NED: AngleConvention = AngleConvention {
+        azimuth_reference: AzimuthReference::Boresight,
+        handedness: Handedness::Clockwise,
+    };
+
+    /// Azimuth counter-clockwise from the east, in the East-North-Up frame.
+    pub const ENU: AngleConvention = AngleConvention {
+        azimuth_reference: AzimuthReference::XAxis,
+        handedness: Handedness::CounterClockwise,
+    };
+
+    /// Angle (degrees) from the boresight to the zero azimuth, clockwise.
+    fn reference_offset(&self) -> i32 {
+        match self.azimuth_reference {
+            AzimuthReference::Boresight => 0,
+            AzimuthReference::XAxis => 90,
+        }
+    }
+
+    fn sign(&self) -> i32 {
+        match self.handedness {
+            Handedness::Clockwise => 1,
+            Handedness::CounterClockwise => -1,
+        }
+    }
+
+    /// Convert an azimuth (degrees) computed by pica, clockwise from
+    /// the boresight, to this convention.
+    pub fn from_native(&self, azimuth: i16) -> i16 {
+        wrap_degrees(self.sign() * (azimuth as i32 - self.reference_offset()))
+    }
+
+    /// Convert an azimuth (degrees) in this convention to the convention
+    /// of pica, clockwise from the boresight.
+    pub fn to_native(&self, azimuth: i16) -> i16 {
+        wrap_degrees(self.sign() * azimuth as i32 + self.reference_offset())
+    }
+
+    /// Convert an azimuth (degrees) in this convention to another one.
+    pub fn convert(&self, azimuth: i16, to: &AngleConvention) -> i16 {
+        to.from_native(self.to_native(azimuth))
+    }
+}
+
+impl FromStr for AngleConvention {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ned" => Ok(AngleConvention::NED),
+            "enu" => Ok(AngleConvention::ENU),
+            _ => Err(format!("Invalid angle convention: {}", s)),
+        }
+    }
+}
+
+fn checked_div(num: f64, den: f64) -> Option<f64> {
     if den == 0. {
         None
     } else {
@@ -66,30 +269,32 @@ fn checked_div(num: f32, den: f32) -> Option<f32> {
     }
 }
 
-fn azimuth(delta: Vec3) -> f32 {
+fn azimuth(delta: DVec3) -> f64 {
     checked_div(delta.x, delta.z).map_or(
         if delta.x == 0. {
             0.
         } else {
-            delta.x.signum() * std::f32::consts::FRAC_PI_2
+            delta.x.signum() * std::f64::consts::FRAC_PI_2
         },
-        f32::atan,
+        f64::atan,
     ) + if delta.z >= 0. {
         0.
     } else {
-        delta.x.signum() * std::f32::consts::PI
+        delta.x.signum() * std::f64::consts::PI
     }
 }
 
-fn elevation(delta: Vec3) -> f32 {
-    checked_div(delta.y, f32::sqrt(delta.x.powi(2) + delta.z.powi(2)))
-        .map_or(delta.y.signum() * std::f32::consts::FRAC_PI_2, f32::atan)
+fn elevation(delta: DVec3) -> f64 {
+    checked_div(delta.y, f64::sqrt(delta.x.powi(2) + delta.z.powi(2)))
+        .map_or(delta.y.signum() * std::f64::consts::FRAC_PI_2, f64::atan)
 }
 
 impl Position {
+    /// Position at the selected coordinates (cm) and orientation (degrees).
     pub fn new(x: i16, y: i16, z: i16, yaw: i16, pitch: i8, roll: i16) -> Self {
+        let cm = |length: i16| LengthUnit::Centimeter.to_meters(length as f64);
         Self {
-            position: Vec3::new(x as f32, y as f32, z as f32),
+            position: DVec3::new(cm(x), cm(y), cm(z)),
             rotation: Quat::from_euler(
                 EulerRot::ZXY, // Rotation performed from right to left order
                 (roll as f32).to_radians(),
@@ -99,11 +304,112 @@ impl Position {
         }
     }
 
+    /// Position without rotation at the selected coordinates (m).
+    pub fn from_meters(x: f64, y: f64, z: f64) -> Self {
+        Self {
+            position: DVec3::new(x, y, z),
+            rotation: Quat::IDENTITY,
+        }
+    }
+
+    /// Position without rotation at the selected East-North-Up
+    /// coordinates (m).
+    pub fn from_enu(east: f64, north: f64, up: f64) -> Self {
+        Self::from_meters(east, up, north)
+    }
+
+    /// Position without rotation at the selected North-East-Down
+    /// coordinates (m).
+    pub fn from_ned(north: f64, east: f64, down: f64) -> Self {
+        Self::from_meters(east, -down, north)
+    }
+
+    /// East-North-Up coordinates (m).
+    pub fn enu(&self) -> (f64, f64, f64) {
+        (self.position.x, self.position.z, self.position.y)
+    }
+
+    /// North-East-Down coordinates (m).
+    pub fn ned(&self) -> (f64, f64, f64) {
+        (self.position.z, self.position.x, -self.position.y)
+    }
+
+    /// Position without rotation at the selected coordinates (cm).
+    pub(crate) fn from_translation(translation: Vec3) -> Self {
+        Self::default().with_translation(translation)
+    }
+
+    /// Same position with the rotation preserved, moved to the selected
+    /// coordinates (cm).
+    pub(crate) fn with_translation(&self, translation: Vec3) -> Self {
+        self.with_coordinates_in(
+            translation.x as f64,
+            translation.y as f64,
+            translation.z as f64,
+            LengthUnit::Centimeter,
+        )
+    }
+
+    /// Same position with the coordinates preserved, turned to the
+    /// selected orientation.
+    pub fn with_orientation(&self, yaw: i16, pitch: i8, roll: i16) -> Self {
+        Self {
+            position: self.position,
+            rotation: Self::new(0, 0, 0, yaw, pitch, roll).rotation,
+        }
+    }
+
+    /// Same position with the rotation preserved, moved to the selected
+    /// coordinates (cm).
+    pub fn with_coordinates(&self, x: i16, y: i16, z: i16) -> Self {
+        self.with_coordinates_in(x as f64, y as f64, z as f64, LengthUnit::Centimeter)
+    }
+
+    /// Same position with the rotation preserved, moved to the selected
+    /// coordinates expressed in the selected unit.
+    pub fn with_coordinates_in(&self, x: f64, y: f64, z: f64, unit: LengthUnit) -> Self {
+        Self {
+            position: DVec3::new(unit.to_meters(x), unit.to_meters(y), unit.to_meters(z)),
+            rotation: self.rotation,
+        }
+    }
+
+    /// Coordinates converted to the selected unit.
+    pub fn coordinates(&self, unit: LengthUnit) -> (f64, f64, f64) {
+        (
+            unit.from_meters(self.position.x),
+            unit.from_meters(self.position.y),
+            unit.from_meters(self.position.z),
+        )
+    }
+
+    /// Coordinates (cm), in the single precision frame of the motion
+    /// paths, obstacles and position solver.
+    pub(crate) fn translation(&self) -> Vec3 {
+        (self.position * LengthUnit::Centimeter.per_meter()).as_vec3()
+    }
+
+    /// Distance to the other position, converted to the selected unit.
+    pub fn range(&self, other: &Position, unit: LengthUnit) -> f64 {
+        unit.from_meters(self.position.distance(other.position))
+    }
+
+    /// Range (cm), azimuth and elevation (degrees) of the other position,
+    /// as encoded in the UCI ranging measurements.
     pub fn compute_range_azimuth_elevation(&self, other: &Position) -> (u16, i16, i8) {
         let delta = other.position - self.position;
 
-        let distance = delta.length();
-        let direction = self.rotation.mul_vec3(delta);
+        let distance = LengthUnit::Centimeter.encode(delta.length());
+        // The direction is computed in double precision, from coordinates
+        // scaled down to keep the distant positions in range.
+        let scale = self.position.abs().max(other.position.abs()).max_element();
+        let direction = if scale > 0. {
+            self.rotation
+                .as_f64()
+                .mul_vec3(other.position / scale - self.position / scale)
+        } else {
+            DVec3::ZERO
+        };
 
         let azimuth = azimuth(direction).to_degrees().round();
         let elevation = elevation(direction).to_degrees().round();
@@ -111,11 +417,18 @@ impl Position {
         assert!((-180. ..=180.).contains(&azimuth));
         assert!((-90. ..=90.).contains(&elevation));
 
-        (
-            f32::min(distance, u16::MAX as f32) as u16,
-            azimuth as i16,
-            elevation as i8,
-        )
+        (distance, azimuth as i16, elevation as i8)
+    }
+
+    /// Range, azimuth and elevation of the other position, with the
+    /// azimuth in the selected convention.
+    pub fn compute_range_azimuth_elevation_in(
+        &self,
+        other: &Position,
+        convention: &AngleConvention,
+    ) -> (u16, i16, i8) {
+        let (distance, azimuth, elevation) = self.compute_range_azimuth_elevation(other);
+        (distance, convention.from_native(azimuth), elevation)
     }
 }
 
@@ -125,9 +438,176 @@ impl Default for Position {
     }
 }
 
+/// Angular range around the boresight of the antennas in which the angle
+/// of arrival is measured reliably, as half-angles in degrees. The default
+/// field of view is unlimited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FieldOfView {
+    pub azimuth: u8,
+    pub elevation: u8,
+}
+
+impl Default for FieldOfView {
+    fn default() -> Self {
+        Self {
+            azimuth: 180,
+            elevation: 90,
+        }
+    }
+}
+
+impl FieldOfView {
+    /// Return true if the direction, in the rotated frame of the node,
+    /// is within the field of view.
+    pub(crate) fn contains(&self, azimuth: i16, elevation: i8) -> bool {
+        azimuth.unsigned_abs() <= self.azimuth as u16 && elevation.unsigned_abs() <= self.elevation
+    }
+}
+
+/// Angles of arrival measured by the antennas of a node.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "kebab-case")]
+pub enum AoaCapability {
+    /// Single antenna, no angle of arrival.
+    None,
+    /// Linear antenna array, measuring the azimuth only.
+    Azimuth,
+    /// Planar antenna array, measuring the azimuth and elevation.
+    #[default]
+    AzimuthElevation,
+}
+
+impl AoaCapability {
+    pub(crate) fn azimuth(&self) -> bool {
+        *self != AoaCapability::None
+    }
+
+    pub(crate) fn elevation(&self) -> bool {
+        *self == AoaCapability::AzimuthElevation
+    }
+}
+
+/// Axis aligned box blocking the line of sight between the nodes,
+/// e.g. a wall or a cabinet.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Obstacle {
+    min: Vec3,
+    max: Vec3,
+    /// Error added to the distances measured through the obstacle (cm).
+    bias: u16,
+}
+
+impl Obstacle {
+    /// Create an obstacle between the selected opposite corners.
+    pub fn new(corner_a: (i16, i16, i16), corner_b: (i16, i16, i16), bias: u16) -> Self {
+        let corner_a = Vec3::new(corner_a.0 as f32, corner_a.1 as f32, corner_a.2 as f32);
+        let corner_b = Vec3::new(corner_b.0 as f32, corner_b.1 as f32, corner_b.2 as f32);
+        Self {
+            min: corner_a.min(corner_b),
+            max: corner_a.max(corner_b),
+            bias,
+        }
+    }
+
+    /// Distance from the point to the nearest point of the box,
+    /// zero if the point is inside the box.
+    fn distance(&self, point: Vec3) -> f32 {
+        point.distance(point.clamp(self.min, self.max))
+    }
+
+    /// Return true if the segment between the two points crosses the box.
+    fn intersects(&self, start: Vec3, end: Vec3) -> bool {
+        let direction = end - start;
+        let (mut near, mut far) = (0f32, 1f32);
+        for axis in 0..3 {
+            if direction[axis] == 0. {
+                if start[axis] < self.min[axis] || start[axis] > self.max[axis] {
+                    return false;
+                }
+            } else {
+                let t1 = (self.min[axis] - start[axis]) / direction[axis];
+                let t2 = (self.max[axis] - start[axis]) / direction[axis];
+                near = near.max(t1.min(t2));
+                far = far.min(t1.max(t2));
+                if near > far {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+}
+
+/// Named axis aligned box of the simulated environment, e.g. a room,
+/// reporting the nodes entering and leaving it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Zone {
+    name: String,
+    min: Vec3,
+    max: Vec3,
+}
+
+impl Zone {
+    /// Create a zone between the selected opposite corners.
+    pub fn new(name: String, corner_a: (i16, i16, i16), corner_b: (i16, i16, i16)) -> Self {
+        let corner_a = Vec3::new(corner_a.0 as f32, corner_a.1 as f32, corner_a.2 as f32);
+        let corner_b = Vec3::new(corner_b.0 as f32, corner_b.1 as f32, corner_b.2 as f32);
+        Self {
+            name,
+            min: corner_a.min(corner_b),
+            max: corner_a.max(corner_b),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Return true if the position is inside the box, boundaries included.
+    pub(crate) fn contains(&self, position: &Position) -> bool {
+        let translation = position.translation();
+        translation.cmpge(self.min).all() && translation.cmple(self.max).all()
+    }
+}
+
+/// Obstacles of the simulated environment. All links are in line of sight
+/// in the default empty scene.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Scene {
+    obstacles: Vec<Obstacle>,
+}
+
+impl Scene {
+    pub fn new(obstacles: Vec<Obstacle>) -> Self {
+        Self { obstacles }
+    }
+
+    /// Return None if the two positions are in line of sight, or the sum of
+    /// the biases of the obstacles crossed by the link otherwise.
+    pub(crate) fn nlos_bias(&self, a: &Position, b: &Position) -> Option<u16> {
+        self.obstacles
+            .iter()
+            .filter(|obstacle| obstacle.intersects(a.translation(), b.translation()))
+            .map(|obstacle| obstacle.bias)
+            .reduce(u16::saturating_add)
+    }
+
+    /// Distances (cm) from the position to the nearest point
+    /// of each obstacle.
+    pub(crate) fn obstacle_distances<'a>(
+        &'a self,
+        position: &'a Position,
+    ) -> impl Iterator<Item = f32> + 'a {
+        self.obstacles
+            .iter()
+            .map(move |obstacle| obstacle.distance(position.translation()))
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::Position;
+    use super::{AngleConvention, FieldOfView, LengthUnit, Obstacle, Position, Scene, Zone};
 
     #[test]
     fn range() {
@@ -154,6 +634,26 @@ mod tests {
         }
     }
 
+    #[test]
+    fn distant_positions() {
+        // The angles match the angles of a close position in the same
+        // direction.
+        let position_a = Position::new(0, 0, 0, 30, 20, 10);
+        let (_, azimuth, elevation) =
+            position_a.compute_range_azimuth_elevation(&Position::from_meters(1., 1., 0.));
+        let position_b = Position::from_meters(1e300, 1e300, 0.);
+        assert_eq!(
+            position_a.compute_range_azimuth_elevation(&position_b),
+            (u16::MAX, azimuth, elevation)
+        );
+        let position_a = Position::from_meters(-f64::MAX, 0., -f64::MAX);
+        let position_b = Position::from_meters(f64::MAX, 0., f64::MAX);
+        assert_eq!(
+            position_a.compute_range_azimuth_elevation(&position_b),
+            (u16::MAX, 45, 0)
+        );
+    }
+
     #[test]
     fn azimuth_without_rotation() {
         let position_a = Position::new(0, 0, 0, 0, 0, 0);
@@ -275,4 +775,122 @@ mod tests {
             assert!(elevation == 0);
         }
     }
+
+    #[test]
+    fn line_of_sight() {
+        // Wall in the plane z = 100, 10 cm thick.
+        let scene = Scene::new(vec![
+            Obstacle::new((-100, -100, 95), (100, 100, 105), 30),
+            Obstacle::new((-100, -100, 195), (100, 100, 205), 20),
+        ]);
+        let position_a = Position::new(0, 0, 0, 0, 0, 0);
+        assert_eq!(
+            scene.nlos_bias(&position_a, &Position::new(0, 0, 50, 0, 0, 0)),
+            None
+        );
+        assert_eq!(
+            scene.nlos_bias(&position_a, &Position::new(500, 0, 150, 0, 0, 0)),
+            None
+        );
+        assert_eq!(
+            scene.nlos_bias(&position_a, &Position::new(0, 0, 150, 0, 0, 0)),
+            Some(30)
+        );
+        assert_eq!(
+            scene.nlos_bias(&Position::new(50, 50, 300, 0, 0, 0), &position_a),
+            Some(50)
+        );
+        assert_eq!(Scene::default().nlos_bias(&position_a, &position_a), None);
+    }
+
+    #[test]
+    fn field_of_view() {
+        let field_of_view = FieldOfView {
+            azimuth: 60,
+            elevation: 45,
+        };
+        assert!(FieldOfView::default().contains(-180, -90));
+        assert!(field_of_view.contains(-60, 45));
+        assert!(!field_of_view.contains(61, 0));
+        assert!(!field_of_view.contains(0, -46));
+
+        // The direction is measured in the rotated frame of the node.
+        let position_a = Position::new(0, 0, 0, 0, 0, 0).with_orientation(90, 0, 0);
+        let position_b = Position::new(0, 0, 10, 0, 0, 0);
+        let (_, azimuth, elevation) = position_a.compute_range_azimuth_elevation(&position_b);
+        assert!(!field_of_view.contains(azimuth, elevation));
+    }
+
+    #[test]
+    fn zone_contains() {
+        let zone = Zone::new("kitchen".to_owned(), (300, 0, 0), (0, 250, 400));
+        assert_eq!(zone.name(), "kitchen");
+        assert!(zone.contains(&Position::new(100, 100, 100, 0, 0, 0)));
+        assert!(zone.contains(&Position::new(300, 0, 400, 0, 0, 0)));
+        assert!(!zone.contains(&Position::new(100, 100, -1, 0, 0, 0)));
+        assert!(!zone.contains(&Position::new(301, 100, 100, 0, 0, 0)));
+    }
+
+    #[test]
+    fn sub_centimeter_precision() {
+        let origin = Position::default();
+        let position = Position::from_meters(0.0042, 0., 0.);
+        assert_eq!(position.coordinates(LengthUnit::Millimeter), (4.2, 0., 0.));
+        assert!((origin.range(&position, LengthUnit::Millimeter) - 4.2).abs() < 1e-9);
+        // The UCI measurements are encoded in centimeters.
+        assert_eq!(origin.compute_range_azimuth_elevation(&position).0, 0);
+
+        let moved = position.with_coordinates_in(0.6, 0., 0., LengthUnit::Centimeter);
+        assert_eq!(origin.compute_range_azimuth_elevation(&moved).0, 1);
+        assert_eq!(
+            serde_json::to_value(moved).unwrap()["x"],
+            serde_json::json!(0.6)
+        );
+    }
+
+    #[test]
+    fn length_units() {
+        assert_eq!(LengthUnit::Meter.to_meters(1.5), 1.5);
+        assert_eq!(LengthUnit::Centimeter.to_meters(150.), 1.5);
+        assert_eq!(LengthUnit::Millimeter.from_meters(1.5), 1500.);
+        assert_eq!(LengthUnit::Centimeter.encode(1.234), 123);
+        assert_eq!(LengthUnit::Millimeter.encode(100.), u16::MAX);
+        assert_eq!(LengthUnit::Meter.encode(-1.), 0);
+    }
+
+    #[test]
+    fn angle_conventions() {
+        let origin = Position::default();
+        // North east of the origin, at 45° clockwise from the north.
+        let position = Position::from_enu(1., 1., 0.);
+        assert_eq!(position.ned(), (1., 1., 0.));
+        let (_, azimuth, _) = origin.compute_range_azimuth_elevation(&position);
+        assert_eq!(azimuth, 45);
+        let (_, azimuth, _) =
+            origin.compute_range_azimuth_elevation_in(&position, &AngleConvention::ENU);
+        assert_eq!(azimuth, 45);
+
+        // West, and south of the origin.
+        let west = Position::from_ned(0., -1., 0.);
+        let (_, azimuth, _) =
+            origin.compute_range_azimuth_elevation_in(&west, &AngleConvention::NED);
+        assert_eq!(azimuth, -90);
+        let (_, azimuth, _) =
+            origin.compute_range_azimuth_elevation_in(&west, &AngleConvention::ENU);
+        assert_eq!(azimuth, -180);
+        let south = Position::from_enu(0., -1., 0.);
+        let (_, azimuth, _) =
+            origin.compute_range_azimuth_elevation_in(&south, &AngleConvention::ENU);
+        assert_eq!(azimuth, -90);
+
+        for azimuth in -180..180 {
+            let converted = AngleConvention::NED.convert(azimuth, &AngleConvention::ENU);
+            assert_eq!(
+                AngleConvention::ENU.convert(converted, &AngleConvention::NED),
+                azimuth
+            );
+        }
+        assert_eq!("enu".parse(), Ok(AngleConvention::ENU));
+        assert!("xyz".parse::<AngleConvention>().is_err());
+    }
 }
@@ -0,0 +1,101 @@
+// Copyright 2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Simulated radio activity, reported through ANDROID_GET_POWER_STATS.
+
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Air time of a single ranging frame.
+const FRAME_AIR_TIME: Duration = Duration::from_micros(200);
+
+/// Accumulated radio time of a device since it was created.
+/// The idle time is the elapsed time not spent transmitting or receiving.
+#[derive(Debug)]
+pub struct PowerStatistics {
+    start_time: Instant,
+    tx_time: Duration,
+    rx_time: Duration,
+    wake_count: u32,
+}
+
+impl PowerStatistics {
+    pub fn new(start_time: Instant) -> Self {
+        PowerStatistics {
+            start_time,
+            tx_time: Duration::ZERO,
+            rx_time: Duration::ZERO,
+            wake_count: 0,
+        }
+    }
+
+    /// Record a wake up of the device, i.e. a transition to the active state.
+    pub fn wake(&mut self) {
+        self.wake_count += 1;
+    }
+
+    /// Record a two-way ranging round with `peers` responders:
+    /// the initiator transmits the poll and final messages and
+    /// listens for one response per responder.
+    pub fn record_ranging_round(&mut self, peers: usize) {
+        self.tx_time += 2 * FRAME_AIR_TIME;
+        self.rx_time += FRAME_AIR_TIME * peers as u32;
+    }
+
+    pub fn tx_time_ms(&self) -> u32 {
+        self.tx_time.as_millis() as u32
+    }
+
+    pub fn rx_time_ms(&self) -> u32 {
+        self.rx_time.as_millis() as u32
+    }
+
+    pub fn idle_time_ms(&self, now: Instant) -> u32 {
+        let elapsed = now.saturating_duration_since(self.start_time);
+        elapsed
+            .saturating_sub(self.tx_time + self.rx_time)
+            .as_millis() as u32
+    }
+
+    pub fn wake_count(&self) -> u32 {
+        self.wake_count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranging_rounds() {
+        let start_time = Instant::now();
+        let mut stats = PowerStatistics::new(start_time);
+        stats.wake();
+        for _ in 0..10 {
+            stats.record_ranging_round(3);
+        }
+        assert_eq!(stats.tx_time_ms(), 4);
+        assert_eq!(stats.rx_time_ms(), 6);
+        assert_eq!(stats.wake_count(), 1);
+        assert_eq!(stats.idle_time_ms(start_time + Duration::from_secs(1)), 990);
+    }
+
+    #[test]
+    fn idle_time_saturates() {
+        let start_time = Instant::now();
+        let mut stats = PowerStatistics::new(start_time);
+        stats.record_ranging_round(1000);
+        assert_eq!(stats.idle_time_ms(start_time), 0);
+    }
+}
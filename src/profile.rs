@@ -0,0 +1,134 @@
+// Copyright 2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Functional personalities of the virtual UWBS. A multi-function chip
+//! hosts several personalities behind a single UCI transport, each with
+//! its own sessions.
+
+use crate::packets::uci::SessionType;
+use std::fmt::Display;
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Personality {
+    /// FiRa and CCC ranging sessions.
+    Ranging,
+    /// Radar sessions.
+    Radar,
+    /// Device test mode sessions, and the commands of the test group.
+    Test,
+}
+
+impl Personality {
+    /// Personality hosting the sessions of the selected type.
+    pub fn of_session_type(session_type: SessionType) -> Self {
+        match session_type {
+            SessionType::RadarSession => Personality::Radar,
+            SessionType::DeviceTestMode => Personality::Test,
+            _ => Personality::Ranging,
+        }
+    }
+}
+
+impl Display for Personality {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Personality::Ranging => "ranging",
+            Personality::Radar => "radar",
+            Personality::Test => "test",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for Personality {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ranging" => Ok(Personality::Ranging),
+            "radar" => Ok(Personality::Radar),
+            "test" => Ok(Personality::Test),
+            _ => Err(format!("unknown personality '{}'", s)),
+        }
+    }
+}
+
+/// Personalities exposed by the devices.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeviceProfile {
+    personalities: Vec<Personality>,
+}
+
+impl Default for DeviceProfile {
+    /// Ranging chip supporting the device test mode.
+    fn default() -> Self {
+        DeviceProfile {
+            personalities: vec![Personality::Ranging, Personality::Test],
+        }
+    }
+}
+
+impl DeviceProfile {
+    pub fn new(personalities: &[Personality]) -> Self {
+        let mut profile = DeviceProfile {
+            personalities: Vec::new(),
+        };
+        for personality in personalities {
+            if !profile.supports(*personality) {
+                profile.personalities.push(*personality);
+            }
+        }
+        profile
+    }
+
+    pub fn supports(&self, personality: Personality) -> bool {
+        self.personalities.contains(&personality)
+    }
+
+    pub fn personalities(&self) -> &[Personality] {
+        &self.personalities
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn session_type_personality() {
+        assert_eq!(
+            Personality::of_session_type(SessionType::Ccc),
+            Personality::Ranging
+        );
+        assert_eq!(
+            Personality::of_session_type(SessionType::RadarSession),
+            Personality::Radar
+        );
+        assert_eq!(
+            Personality::of_session_type(SessionType::DeviceTestMode),
+            Personality::Test
+        );
+    }
+
+    #[test]
+    fn profile() {
+        let profile = DeviceProfile::new(&[Personality::Radar, Personality::Radar]);
+        assert_eq!(profile.personalities(), &[Personality::Radar]);
+        assert!(!profile.supports(Personality::Ranging));
+        assert!(DeviceProfile::default().supports(Personality::Test));
+        assert_eq!("radar".parse(), Ok(Personality::Radar));
+        assert!("lidar".parse::<Personality>().is_err());
+    }
+}
@@ -0,0 +1,312 @@
+// Copyright 2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Python bindings, exposed as the `pica` module. The simulation runs
+//! on a background runtime, and the methods wait for the completion
+//! of the commands with the GIL released.
+
+use pyo3::exceptions::{PyRuntimeError, PyValueError};
+use pyo3::prelude::*;
+use serde::Serialize;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::runtime::{Handle, Runtime};
+use tokio::sync::{broadcast, mpsc, oneshot};
+
+use crate::{EventFilter, SequencedEvent, EVENT_HISTORY_SIZE};
+use crate::{MacAddress, Pica, PicaCommand, PicaCommandStatus, PicaEvent, Position, TimeMode};
+
+fn mac_address(mac_address: &str) -> PyResult<MacAddress> {
+    mac_address
+        .parse()
+        .map_err(|_| PyValueError::new_err(format!("invalid MAC address '{}'", mac_address)))
+}
+
+/// Event converted to a Python dictionary, tagged with its name.
+#[derive(Serialize)]
+struct EventDict<'a> {
+    #[serde(rename = "type")]
+    name: &'static str,
+    sequence_number: u64,
+    monotonic_us: u64,
+    timestamp_ms: u64,
+    #[serde(flatten)]
+    event: &'a PicaEvent,
+}
+
+fn event_dict(py: Python<'_>, event: &SequencedEvent) -> PyResult<PyObject> {
+    let json = serde_json::to_string(&EventDict {
+        name: event.event.name(),
+        sequence_number: event.sequence_number,
+        monotonic_us: event.monotonic_us,
+        timestamp_ms: event.timestamp_ms,
+        event: &event.event,
+    })
+    .map_err(|err| PyRuntimeError::new_err(err.to_string()))?;
+    Ok(py.import("json")?.call_method1("loads", (json,))?.unbind())
+}
+
+/// Running simulation. UCI hosts connect to the selected TCP port,
+/// if any. The simulated time runs `time_speed` times faster than the
+/// wall-clock time, or only advances with `advance_time` if `stepped`.
+#[pyclass(module = "pica")]
+struct Simulation {
+    runtime: Runtime,
+    tx: mpsc::Sender<PicaCommand>,
+    // Kept to subscribe to the events, closed when the simulation
+    // is shut down.
+    event_rx: broadcast::Receiver<SequencedEvent>,
+}
+
+impl Simulation {
+    /// Send a command and wait for its status.
+    fn command(
+        &self,
+        py: Python<'_>,
+        command: impl FnOnce(oneshot::Sender<PicaCommandStatus>) -> PicaCommand + Send,
+    ) -> PyResult<()> {
+        let (status_tx, status_rx) = oneshot::channel();
+        let status = py.allow_threads(|| {
+            self.runtime.block_on(async {
+                self.tx.send(command(status_tx)).await.ok()?;
+                status_rx.await.ok()
+            })
+        });
+        match status {
+            Some(Ok(())) => Ok(()),
+            Some(Err(err)) => Err(PyValueError::new_err(err.to_string())),
+            None => Err(PyRuntimeError::new_err("the simulation is shut down")),
+        }
+    }
+}
+
+#[pymethods]
+impl Simulation {
+    #[new]
+    #[pyo3(signature = (uci_port = None, seed = None, time_speed = 1.0, stepped = false))]
+    fn new(
+        py: Python<'_>,
+        uci_port: Option<u16>,
+        seed: Option<u64>,
+        time_speed: f64,
+        stepped: bool,
+    ) -> PyResult<Self> {
+        if !(time_speed.is_finite() && time_speed > 0.0) {
+            return Err(PyValueError::new_err(format!(
+                "invalid time speed {}",
+                time_speed
+            )));
+        }
+        let runtime = Runtime::new().map_err(|err| PyRuntimeError::new_err(err.to_string()))?;
+        let mut builder = Pica::builder()
+            .event_capacity(EVENT_HISTORY_SIZE)
+            .time_mode(if stepped {
+                TimeMode::Stepped
+            } else {
+                TimeMode::Scaled(time_speed)
+            });
+        if let Some(seed) = seed {
+            builder = builder.seed(seed);
+        }
+        let mut pica = builder.build();
+        let tx = pica.tx();
+        let event_rx = pica.event_tx().subscribe();
+
+        if let Some(port) = uci_port {
+            let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port);
+            let listener = py
+                .allow_threads(|| runtime.block_on(TcpListener::bind(addr)))
+                .map_err(|err| PyRuntimeError::new_err(err.to_string()))?;
+            let tx = tx.clone();
+            runtime.spawn(async move {
+                while let Ok((socket, _)) = listener.accept().await {
+                    let origin = format!("tcp:{}", addr);
+                    if tx
+                        .send(PicaCommand::Connect(Box::new(socket), origin))
+                        .await
+                        .is_err()
+                    {
+                        break;
+                    }
+                }
+            });
+        }
+        runtime.spawn(async move { pica.run().await });
+
+        Ok(Simulation {
+            runtime,
+            tx,
+            event_rx,
+        })
+    }
+
+    /// Create an anchor at the selected position.
+    #[pyo3(signature = (mac_address, x = 0, y = 0, z = 0, yaw = 0, pitch = 0, roll = 0))]
+    #[allow(clippy::too_many_arguments)]
+    fn create_anchor(
+        &self,
+        py: Python<'_>,
+        mac_address: &str,
+        x: i16,
+        y: i16,
+        z: i16,
+        yaw: i16,
+        pitch: i8,
+        roll: i16,
+    ) -> PyResult<()> {
+        let mac_address = self::mac_address(mac_address)?;
+        let position = Position::new(x, y, z, yaw, pitch, roll);
+        self.command(py, |status_tx| {
+            PicaCommand::CreateAnchor(mac_address, position, status_tx)
+        })
+    }
+
+    fn destroy_anchor(&self, py: Python<'_>, mac_address: &str) -> PyResult<()> {
+        let mac_address = self::mac_address(mac_address)?;
+        self.command(py, |status_tx| {
+            PicaCommand::DestroyAnchor(mac_address, status_tx)
+        })
+    }
+
+    /// Move the anchor or device to the selected position.
+    #[pyo3(signature = (mac_address, x, y, z, yaw = 0, pitch = 0, roll = 0))]
+    #[allow(clippy::too_many_arguments)]
+    fn set_position(
+        &self,
+        py: Python<'_>,
+        mac_address: &str,
+        x: i16,
+        y: i16,
+        z: i16,
+        yaw: i16,
+        pitch: i8,
+        roll: i16,
+    ) -> PyResult<()> {
+        let mac_address = self::mac_address(mac_address)?;
+        let position = Position::new(x, y, z, yaw, pitch, roll);
+        self.command(py, |status_tx| {
+            PicaCommand::SetPosition(mac_address, position, status_tx)
+        })
+    }
+
+    /// Advance the simulated time by the selected number of seconds.
+    fn advance_time(&self, py: Python<'_>, seconds: f64) -> PyResult<()> {
+        let duration = Duration::try_from_secs_f64(seconds)
+            .map_err(|_| PyValueError::new_err(format!("invalid duration {}", seconds)))?;
+        self.command(py, |status_tx| {
+            PicaCommand::AdvanceTime(duration, status_tx)
+        })
+    }
+
+    /// Subscribe to the events sent from now on, optionally only to
+    /// the selected kinds of events, e.g. `neighbor-updated`, and to the
+    /// events about the selected devices.
+    #[pyo3(signature = (kinds = None, mac_addresses = None))]
+    fn subscribe(
+        &self,
+        kinds: Option<Vec<String>>,
+        mac_addresses: Option<Vec<String>>,
+    ) -> PyResult<EventStream> {
+        let filter = EventFilter {
+            kinds: kinds.unwrap_or_default(),
+            mac_addresses: mac_addresses
+                .unwrap_or_default()
+                .iter()
+                .map(|mac_address| self::mac_address(mac_address))
+                .collect::<PyResult<_>>()?,
+        };
+        Ok(EventStream {
+            handle: self.runtime.handle().clone(),
+            event_rx: self.event_rx.resubscribe(),
+            filter,
+        })
+    }
+
+    /// Close all device connections and stop the simulation.
+    fn shutdown(&self, py: Python<'_>) {
+        let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        py.allow_threads(|| {
+            self.runtime.block_on(async {
+                if self
+                    .tx
+                    .send(PicaCommand::Shutdown(shutdown_tx))
+                    .await
+                    .is_ok()
+                {
+                    let _ = shutdown_rx.await;
+                }
+            })
+        })
+    }
+}
+
+/// Events of a simulation, as dictionaries with the event name
+/// in the `type` entry. Iterating blocks until the next event.
+#[pyclass(module = "pica")]
+struct EventStream {
+    handle: Handle,
+    event_rx: broadcast::Receiver<SequencedEvent>,
+    filter: EventFilter,
+}
+
+#[pymethods]
+impl EventStream {
+    /// Wait for the next event, at most `timeout` seconds if selected.
+    /// Return None on timeout or when the simulation is shut down.
+    /// Events missed by slow subscribers are skipped.
+    #[pyo3(signature = (timeout = None))]
+    fn next(&mut self, py: Python<'_>, timeout: Option<f64>) -> PyResult<Option<PyObject>> {
+        let event_rx = &mut self.event_rx;
+        let filter = &self.filter;
+        let handle = &self.handle;
+        let event = py.allow_threads(|| {
+            handle.block_on(async {
+                let recv = async {
+                    loop {
+                        match event_rx.recv().await {
+                            Ok(event) if filter.matches(&event.event) => return Some(event),
+                            Ok(_) => continue,
+                            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                            Err(broadcast::error::RecvError::Closed) => return None,
+                        }
+                    }
+                };
+                match timeout {
+                    Some(timeout) => tokio::time::timeout(Duration::from_secs_f64(timeout), recv)
+                        .await
+                        .ok()
+                        .flatten(),
+                    None => recv.await,
+                }
+            })
+        });
+        event.map(|event| event_dict(py, &event)).transpose()
+    }
+
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__(&mut self, py: Python<'_>) -> PyResult<Option<PyObject>> {
+        self.next(py, None)
+    }
+}
+
+#[pymodule]
+fn pica(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<Simulation>()?;
+    m.add_class::<EventStream>()?;
+    Ok(())
+}
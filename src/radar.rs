@@ -0,0 +1,328 @@
+// Copyright 2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Android radar session emulation.
+//!
+//! The sweeps reported in the radar data messages are synthetic channel
+//! impulse responses: each obstacle of the scene reflects the signal from
+//! its point nearest to the radar, with an amplitude decreasing with the
+//! square of the distance and a phase rotating with the round trip delay.
+
+use std::collections::HashMap;
+use std::f32::consts::PI;
+use std::time::Duration;
+
+use tracing::debug;
+
+use crate::packets::uci::*;
+use crate::position::{Position, Scene};
+
+/// Default radar configuration, and expected size of each parameter.
+const DEFAULT_RADAR_CONFIG: &[(RadarConfigTlvType, &[u8])] = &[
+    // Burst period of 100 ms, sweep period of 40 RSTU, 16 sweeps per burst.
+    (
+        RadarConfigTlvType::RadarTimingParams,
+        &[0x64, 0x00, 0x00, 0x00, 0x28, 0x00, 0x10],
+    ),
+    (RadarConfigTlvType::SamplesPerSweep, &[0x40]),
+    (RadarConfigTlvType::RadarChannelNumber, &[0x09]),
+    (RadarConfigTlvType::SweepOffset, &[0x00, 0x00]),
+    (RadarConfigTlvType::RadarRframeConfig, &[0x00]),
+    (RadarConfigTlvType::RadarPreambleDuration, &[0x01]),
+    (RadarConfigTlvType::RadarPreambleCodeIndex, &[0x0a]),
+    (RadarConfigTlvType::RadarSessionPriority, &[0x32]),
+    (RadarConfigTlvType::BitsPerSample, &[0x00]),
+    (RadarConfigTlvType::RadarPrfMode, &[0x00]),
+    // Bursts are repeated until the session is stopped.
+    (RadarConfigTlvType::NumberOfBursts, &[0x00, 0x00]),
+    (RadarConfigTlvType::RadarDataType, &[0x00]),
+];
+
+/// Channels supported by the radar.
+const CHANNEL_NUMBERS: &[u8] = &[5, 6, 8, 9, 10, 12, 13, 14];
+
+/// Round trip distance covered between two samples of a sweep,
+/// sampled at 499.2 MHz (cm).
+const SAMPLE_DISTANCE: f32 = 29979.246 / (2.0 * 499.2);
+
+/// Amplitude of the reflection of an obstacle at 1 m,
+/// relative to the full scale of the samples.
+const REFERENCE_AMPLITUDE: f32 = 0.25;
+
+/// Duration of a ranging scheduling time unit, in microseconds.
+const RSTU_MICROS: f64 = 416.0 / 499.2;
+
+fn default_value(id: RadarConfigTlvType) -> Option<&'static [u8]> {
+    DEFAULT_RADAR_CONFIG
+        .iter()
+        .find(|(default_id, _)| *default_id == id)
+        .map(|(_, value)| *value)
+}
+
+/// Return true if the value is supported for the radar parameter.
+fn is_valid(id: RadarConfigTlvType, value: &[u8]) -> bool {
+    if default_value(id).map(<[u8]>::len) != Some(value.len()) {
+        return false;
+    }
+    match id {
+        RadarConfigTlvType::RadarTimingParams => {
+            u32::from_le_bytes(value[0..4].try_into().unwrap()) > 0
+                && u16::from_le_bytes([value[4], value[5]]) > 0
+                && value[6] > 0
+        }
+        RadarConfigTlvType::SamplesPerSweep => value[0] > 0,
+        RadarConfigTlvType::RadarChannelNumber => CHANNEL_NUMBERS.contains(&value[0]),
+        RadarConfigTlvType::BitsPerSample => BitsPerSample::try_from(value[0]).is_ok(),
+        RadarConfigTlvType::RadarDataType => RadarDataType::try_from(value[0]).is_ok(),
+        _ => true,
+    }
+}
+
+/// Center frequency of the channel (MHz).
+fn center_frequency(channel_number: u8) -> f32 {
+    499.2
+        * match channel_number {
+            5 => 13.0,
+            6 => 14.0,
+            8 => 15.0,
+            9 => 16.0,
+            10 => 17.0,
+            12 => 18.0,
+            13 => 19.0,
+            _ => 20.0,
+        }
+}
+
+/// Radar configuration and sweep counter of a radar session.
+#[derive(Debug, Default)]
+pub struct Radar {
+    config: HashMap<RadarConfigTlvType, Vec<u8>>,
+    /// Sequence number of the next sweep.
+    sequence_number: u32,
+}
+
+impl Radar {
+    fn value(&self, id: RadarConfigTlvType) -> &[u8] {
+        self.config
+            .get(&id)
+            .map(Vec::as_slice)
+            .or_else(|| default_value(id))
+            .unwrap()
+    }
+
+    /// Apply the parameters if all are valid, and return the status
+    /// of the invalid parameters otherwise.
+    pub fn set_config(&mut self, tlvs: &[RadarConfigTlv]) -> Vec<RadarConfigStatus> {
+        let cfg_status: Vec<_> = tlvs
+            .iter()
+            .filter(|tlv| !is_valid(tlv.cfg_id, &tlv.v))
+            .map(|tlv| RadarConfigStatus {
+                cfg_id: tlv.cfg_id,
+                status: StatusCode::UciStatusInvalidParam,
+            })
+            .collect();
+        if cfg_status.is_empty() {
+            for tlv in tlvs {
+                debug!("{:?}={:?}", tlv.cfg_id, tlv.v);
+                self.config.insert(tlv.cfg_id, tlv.v.clone());
+            }
+        }
+        cfg_status
+    }
+
+    /// Return the value of the selected parameters, or None if one of
+    /// the identifiers is unknown.
+    pub fn get_config(&self, ids: &[u8]) -> Option<Vec<RadarConfigTlv>> {
+        ids.iter()
+            .map(|id| {
+                let cfg_id = RadarConfigTlvType::try_from(*id).ok()?;
+                Some(RadarConfigTlv {
+                    cfg_id,
+                    v: self.value(cfg_id).to_vec(),
+                })
+            })
+            .collect()
+    }
+
+    pub fn channel_number(&self) -> u8 {
+        self.value(RadarConfigTlvType::RadarChannelNumber)[0]
+    }
+
+    /// Interval between two bursts of sweeps.
+    pub fn burst_period(&self) -> Duration {
+        let timing = self.value(RadarConfigTlvType::RadarTimingParams);
+        Duration::from_millis(u32::from_le_bytes(timing[0..4].try_into().unwrap()) as u64)
+    }
+
+    /// Number of bursts until the radar stops, or zero to repeat the
+    /// bursts until the session is stopped.
+    pub fn number_of_bursts(&self) -> u16 {
+        let value = self.value(RadarConfigTlvType::NumberOfBursts);
+        u16::from_le_bytes([value[0], value[1]])
+    }
+
+    /// Sweeps of a burst of the radar at the selected position, starting
+    /// at the selected UWBS time (µs).
+    pub fn burst(
+        &mut self,
+        session_handle: u32,
+        position: &Position,
+        scene: &Scene,
+        timestamp_us: u64,
+    ) -> RadarDataRcv {
+        let timing = self.value(RadarConfigTlvType::RadarTimingParams);
+        let sweep_period = u16::from_le_bytes([timing[4], timing[5]]) as f64 * RSTU_MICROS;
+        let number_of_sweeps = timing[6];
+        let samples_per_sweep = self.value(RadarConfigTlvType::SamplesPerSweep)[0];
+        let sweep_offset = self.value(RadarConfigTlvType::SweepOffset);
+        let sweep_offset = u16::from_le_bytes([sweep_offset[0], sweep_offset[1]]);
+        let bits_per_sample =
+            BitsPerSample::try_from(self.value(RadarConfigTlvType::BitsPerSample)[0]).unwrap();
+
+        let samples = self.samples(position, scene, samples_per_sweep, sweep_offset as i16);
+        let encoded_samples = encode_samples(&samples, bits_per_sample);
+        let mut sweep_data = Vec::new();
+        for index in 0..number_of_sweeps {
+            let timestamp = timestamp_us + (index as f64 * sweep_period) as u64;
+            sweep_data.extend(self.sequence_number.to_le_bytes());
+            sweep_data.extend((timestamp as u32).to_le_bytes());
+            // No vendor specific data.
+            sweep_data.push(0);
+            sweep_data.extend(&encoded_samples);
+            self.sequence_number = self.sequence_number.wrapping_add(1);
+        }
+
+        RadarDataRcvBuilder {
+            pbf: PacketBoundaryFlag::Complete,
+            session_handle,
+            status: DataRcvStatusCode::UciStatusSuccess,
+            radar_data_type: RadarDataType::RadarSweepSamples,
+            number_of_sweeps,
+            samples_per_sweep,
+            bits_per_sample,
+            sweep_offset,
+            sweep_data,
+        }
+        .build()
+    }
+
+    /// Complex samples of the channel impulse response, relative to the
+    /// full scale. The reflection of each obstacle is spread over the
+    /// two samples nearest to its round trip delay.
+    fn samples(
+        &self,
+        position: &Position,
+        scene: &Scene,
+        samples_per_sweep: u8,
+        sweep_offset: i16,
+    ) -> Vec<(f32, f32)> {
+        let wavelength = 29979.246 / center_frequency(self.channel_number());
+        let mut samples = vec![(0.0, 0.0); samples_per_sweep as usize];
+        for distance in scene.obstacle_distances(position) {
+            let amplitude = (REFERENCE_AMPLITUDE * (100.0 / distance.max(1.0)).powi(2)).min(1.0);
+            let phase = -4.0 * PI * distance / wavelength;
+            let delay = distance / SAMPLE_DISTANCE - sweep_offset as f32;
+            let first = delay.floor();
+            for index in [first, first + 1.0] {
+                let weight = 1.0 - (index - delay).abs();
+                if let Some(sample) = samples.get_mut(index as isize as usize) {
+                    sample.0 += amplitude * weight * phase.cos();
+                    sample.1 += amplitude * weight * phase.sin();
+                }
+            }
+        }
+        samples
+    }
+}
+
+/// Encode the complex samples with the in-phase and quadrature
+/// components as little endian signed integers.
+fn encode_samples(samples: &[(f32, f32)], bits_per_sample: BitsPerSample) -> Vec<u8> {
+    let component_size = match bits_per_sample {
+        BitsPerSample::Value32 => 2,
+        BitsPerSample::Value48 => 3,
+        BitsPerSample::Value64 => 4,
+    };
+    let full_scale = ((1i64 << (8 * component_size - 1)) - 1) as f32;
+    samples
+        .iter()
+        .flat_map(|(i, q)| [*i, *q])
+        .flat_map(|component| {
+            let value = (component.clamp(-1.0, 1.0) * full_scale).round() as i32;
+            value.to_le_bytes().into_iter().take(component_size)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::position::Obstacle;
+
+    #[test]
+    fn config() {
+        let mut radar = Radar::default();
+        let cfg_status = radar.set_config(&[
+            RadarConfigTlv {
+                cfg_id: RadarConfigTlvType::RadarChannelNumber,
+                v: vec![5],
+            },
+            RadarConfigTlv {
+                cfg_id: RadarConfigTlvType::SamplesPerSweep,
+                v: vec![0],
+            },
+        ]);
+        assert_eq!(cfg_status.len(), 1);
+        assert_eq!(cfg_status[0].cfg_id, RadarConfigTlvType::SamplesPerSweep);
+        // The configuration is unchanged if a parameter is invalid.
+        assert_eq!(radar.channel_number(), 9);
+        assert_eq!(radar.burst_period(), Duration::from_millis(100));
+        assert_eq!(radar.get_config(&[0x20]), None);
+    }
+
+    #[test]
+    fn sweeps() {
+        let mut radar = Radar::default();
+        // A wall 3 m in front of the radar, i.e. ~10 samples away.
+        let scene = Scene::new(vec![Obstacle::new((-100, -100, 300), (100, 100, 310), 0)]);
+        let position = Position::default();
+        let data = radar.burst(1, &position, &scene, 1000);
+        assert_eq!(data.get_number_of_sweeps(), 16);
+        assert_eq!(data.get_samples_per_sweep(), 64);
+        let sweep_size = 9 + 64 * 4;
+        let sweep_data = data.get_sweep_data();
+        assert_eq!(sweep_data.len(), 16 * sweep_size);
+
+        // The energy of the first sweep is concentrated around
+        // the delay of the wall.
+        let magnitudes: Vec<_> = sweep_data[9..sweep_size]
+            .chunks(4)
+            .map(|sample| {
+                let i = i16::from_le_bytes([sample[0], sample[1]]) as f32;
+                let q = i16::from_le_bytes([sample[2], sample[3]]) as f32;
+                (i * i + q * q).sqrt()
+            })
+            .collect();
+        let peak = (0..magnitudes.len())
+            .max_by(|a, b| magnitudes[*a].total_cmp(&magnitudes[*b]))
+            .unwrap();
+        assert!((9..=10).contains(&peak), "{}", peak);
+        assert_eq!(magnitudes[40], 0.0);
+
+        // The sweeps are numbered across bursts.
+        let data = radar.burst(1, &position, &scene, 101000);
+        let sequence_number = u32::from_le_bytes(data.get_sweep_data()[0..4].try_into().unwrap());
+        assert_eq!(sequence_number, 16);
+    }
+}
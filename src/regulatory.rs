@@ -0,0 +1,125 @@
+// Copyright 2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Simulated regulatory TX power limits and channel restrictions.
+//!
+//! The limits below are meant to exercise host regulatory handling and
+//! are not an authoritative description of the regulations in each country.
+
+/// Mean EIRP limit applied when the country has no specific entry (dBm/MHz).
+pub const DEFAULT_TX_POWER_LIMIT: f32 = -41.3;
+
+/// Countries with a reduced mean EIRP limit (dBm/MHz).
+const TX_POWER_LIMITS: &[([u8; 2], f32)] = &[(*b"JP", -47.3), (*b"KR", -47.3), (*b"CN", -47.3)];
+
+/// Country code for which the UWB radio shall be turned off.
+const UWB_OFF_COUNTRY_CODE: [u8; 2] = *b"00";
+
+/// Countries prohibiting the transmission on some of the channels.
+const PROHIBITED_CHANNELS: &[([u8; 2], &[u8])] = &[(*b"JP", &[5])];
+
+/// Bandwidth of an UWB channel (MHz).
+const CHANNEL_BANDWIDTH: f32 = 500.;
+/// Receiver sensitivity (dBm).
+const RX_SENSITIVITY: f32 = -103.;
+/// Free space path loss at 1 meter for a 6.5 GHz carrier (dB).
+const PATH_LOSS_AT_1M: f32 = 48.7;
+
+/// Return the mean EIRP limit for the selected country (dBm/MHz).
+pub fn tx_power_limit(country_code: [u8; 2]) -> f32 {
+    TX_POWER_LIMITS
+        .iter()
+        .find(|(code, _)| *code == country_code)
+        .map_or(DEFAULT_TX_POWER_LIMIT, |(_, limit)| *limit)
+}
+
+/// Return true if the UWB radio shall be turned off in the selected country.
+pub fn is_uwb_off(country_code: [u8; 2]) -> bool {
+    country_code == UWB_OFF_COUNTRY_CODE
+}
+
+/// Return true if transmitting on the selected channel is allowed
+/// in the selected country.
+pub fn is_channel_allowed(country_code: [u8; 2], channel_number: u8) -> bool {
+    !is_uwb_off(country_code)
+        && !PROHIBITED_CHANNELS
+            .iter()
+            .any(|(code, channels)| *code == country_code && channels.contains(&channel_number))
+}
+
+/// Return the maximum range (cm) at which a frame transmitted at the
+/// selected mean EIRP (dBm/MHz) can still be received. The power is
+/// capped to the limit of the selected country, and defaults to it.
+pub fn max_range_at_tx_power(country_code: [u8; 2], tx_power: Option<f32>) -> u16 {
+    let tx_power_limit = tx_power_limit(country_code);
+    range_for_tx_power_limit(
+        tx_power.map_or(tx_power_limit, |tx_power| tx_power.min(tx_power_limit)),
+    )
+}
+
+/// Return the maximum range (cm) for devices without a country code,
+/// e.g. anchors.
+pub fn default_max_range() -> u16 {
+    range_for_tx_power_limit(DEFAULT_TX_POWER_LIMIT)
+}
+
+fn range_for_tx_power_limit(tx_power_limit: f32) -> u16 {
+    let tx_power = tx_power_limit + 10. * CHANNEL_BANDWIDTH.log10();
+    let link_budget = tx_power - RX_SENSITIVITY - PATH_LOSS_AT_1M;
+    let range = 100. * 10f32.powf(link_budget / 20.);
+    f32::min(range, u16::MAX as f32) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn max_range(country_code: [u8; 2]) -> u16 {
+        max_range_at_tx_power(country_code, None)
+    }
+
+    #[test]
+    fn default_limit() {
+        assert_eq!(tx_power_limit(*b"US"), DEFAULT_TX_POWER_LIMIT);
+        assert_eq!(tx_power_limit([0, 0]), DEFAULT_TX_POWER_LIMIT);
+        // ~100m with the default limit.
+        assert!((9500..10500).contains(&max_range(*b"US")));
+        assert_eq!(max_range(*b"US"), default_max_range());
+    }
+
+    #[test]
+    fn reduced_tx_power() {
+        // The power is capped to the limit of the country.
+        assert_eq!(max_range_at_tx_power(*b"JP", Some(0.)), max_range(*b"JP"));
+        let ratio = max_range(*b"US") as f32 / max_range_at_tx_power(*b"US", Some(-61.3)) as f32;
+        assert!((ratio - 10.).abs() < 0.1);
+    }
+
+    #[test]
+    fn reduced_limit() {
+        // Reducing the TX power by 6dB halves the range.
+        let ratio = max_range(*b"US") as f32 / max_range(*b"JP") as f32;
+        assert!((ratio - 2.).abs() < 0.01);
+    }
+
+    #[test]
+    fn channel_restrictions() {
+        assert!(is_channel_allowed(*b"US", 5));
+        assert!(is_channel_allowed([0, 0], 5));
+        assert!(!is_channel_allowed(*b"JP", 5));
+        assert!(is_channel_allowed(*b"JP", 9));
+        assert!(is_uwb_off(*b"00"));
+        assert!(!is_channel_allowed(*b"00", 9));
+    }
+}
@@ -0,0 +1,353 @@
+// Copyright 2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! [UCI] 7.5 RF test group emulation.
+//!
+//! No frame is actually exchanged: periodic TX and PER RX tests complete
+//! after the configured number of packets has elapsed and report an
+//! error free reception, the loopback test echoes the PSDU immediately.
+
+use crate::packets::uci::*;
+use crate::timeline::Timeline;
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time;
+use tracing::{debug, info, warn};
+
+/// Default test configuration, and expected size of each parameter.
+const DEFAULT_TEST_CONFIG: &[(TestConfigTlvType, &[u8])] = &[
+    (TestConfigTlvType::NumPackets, &[0xe8, 0x03, 0x00, 0x00]), // 1000 packets
+    (TestConfigTlvType::TGap, &[0xd0, 0x07, 0x00, 0x00]),       // 2000 us
+    (TestConfigTlvType::TStart, &[0x00, 0x00, 0x00, 0x00]),
+    (TestConfigTlvType::TWin, &[0x00, 0x00, 0x00, 0x00]),
+    (TestConfigTlvType::RandomizePsdu, &[0x00]),
+    (TestConfigTlvType::PhrRangingBit, &[0x00]),
+    (TestConfigTlvType::RmarkerRxStart, &[0x00, 0x00, 0x00, 0x00]),
+    (TestConfigTlvType::RmarkerTxStart, &[0x00, 0x00, 0x00, 0x00]),
+    (TestConfigTlvType::StsIndexAutoIncr, &[0x00]),
+    (TestConfigTlvType::StsDetectBitmapEn, &[0x00]),
+];
+
+/// Delay between the transmission and reception of a loopback frame,
+/// in units of 1/(128 * 499.2 MHz).
+const LOOPBACK_DELAY: u32 = 63898; // ~1 us
+
+/// Send a test notification after the response of the command that
+/// started the test, which is queued once the command handler returns.
+async fn send_notification(tx: mpsc::Sender<ControlPacket>, notification: ControlPacket) {
+    time::sleep(Duration::from_millis(1)).await;
+    tx.send(notification)
+        .await
+        .unwrap_or_else(|err| warn!("Failed to send test notification: {}", err))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum TestKind {
+    PeriodicTx,
+    PerRx,
+}
+
+pub struct RfTest {
+    tx: mpsc::Sender<ControlPacket>,
+    timeline: Timeline,
+    /// Test configuration of each session.
+    configs: HashMap<u32, HashMap<TestConfigTlvType, Vec<u8>>>,
+    task: Option<JoinHandle<()>>,
+}
+
+impl RfTest {
+    pub fn new(tx: mpsc::Sender<ControlPacket>, timeline: Timeline) -> Self {
+        RfTest {
+            tx,
+            timeline,
+            configs: HashMap::new(),
+            task: None,
+        }
+    }
+
+    fn is_running(&self) -> bool {
+        matches!(&self.task, Some(task) if !task.is_finished())
+    }
+
+    fn config_u32(&self, session_id: u32, id: TestConfigTlvType) -> u32 {
+        let value = self
+            .configs
+            .get(&session_id)
+            .and_then(|config| config.get(&id))
+            .map(Vec::as_slice)
+            .or_else(|| default_value(id))
+            .unwrap();
+        u32::from_le_bytes(value.try_into().unwrap())
+    }
+
+    pub fn remove_session(&mut self, session_id: u32) {
+        self.configs.remove(&session_id);
+    }
+
+    pub fn set_config(&mut self, session_id: u32, tlvs: &[TestConfigTlv]) -> TestConfigSetRsp {
+        info!(
+            session_id = format_args!("0x{:x}", session_id),
+            "Test config set"
+        );
+
+        let cfg_status: Vec<_> = tlvs
+            .iter()
+            .filter(|tlv| default_value(tlv.cfg_id).map(<[u8]>::len) != Some(tlv.v.len()))
+            .map(|tlv| TestConfigStatus {
+                cfg_id: tlv.cfg_id,
+                status: StatusCode::UciStatusInvalidParam,
+            })
+            .collect();
+
+        let status = if cfg_status.is_empty() {
+            let config = self.configs.entry(session_id).or_default();
+            for tlv in tlvs {
+                debug!("{:?}={:?}", tlv.cfg_id, tlv.v);
+                config.insert(tlv.cfg_id, tlv.v.clone());
+            }
+            StatusCode::UciStatusOk
+        } else {
+            StatusCode::UciStatusInvalidParam
+        };
+
+        TestConfigSetRspBuilder { status, cfg_status }.build()
+    }
+
+    pub fn get_config(&self, session_id: u32, ids: &[u8]) -> TestConfigGetRsp {
+        info!(
+            session_id = format_args!("0x{:x}", session_id),
+            "Test config get"
+        );
+
+        let config = self.configs.get(&session_id);
+        let tlvs: Option<Vec<_>> = ids
+            .iter()
+            .map(|id| {
+                let cfg_id = TestConfigTlvType::try_from(*id).ok()?;
+                let v = config
+                    .and_then(|config| config.get(&cfg_id))
+                    .cloned()
+                    .or_else(|| default_value(cfg_id).map(<[u8]>::to_vec))?;
+                Some(TestConfigTlv { cfg_id, v })
+            })
+            .collect();
+
+        match tlvs {
+            Some(tlvs) => TestConfigGetRspBuilder {
+                status: StatusCode::UciStatusOk,
+                tlvs,
+            },
+            None => TestConfigGetRspBuilder {
+                status: StatusCode::UciStatusInvalidParam,
+                tlvs: Vec::new(),
+            },
+        }
+        .build()
+    }
+
+    fn start(&mut self, session_id: u32, kind: TestKind) -> StatusCode {
+        if self.is_running() {
+            return StatusCode::UciStatusRejected;
+        }
+
+        let num_packets = self.config_u32(session_id, TestConfigTlvType::NumPackets);
+        let t_gap = self.config_u32(session_id, TestConfigTlvType::TGap);
+        debug!(num_packets, t_gap_us = t_gap, "Test started");
+
+        let duration = Duration::from_micros(num_packets as u64 * t_gap as u64);
+        let tx = self.tx.clone();
+        let timeline = self.timeline.clone();
+        self.task = Some(tokio::spawn(async move {
+            timeline.sleep(duration).await;
+            let notification: ControlPacket = match kind {
+                TestKind::PeriodicTx => TestPeriodicTxNtfBuilder {
+                    status: StatusCode::UciStatusOk,
+                    vendor_data: vec![],
+                }
+                .build()
+                .into(),
+                TestKind::PerRx => TestPerRxNtfBuilder {
+                    status: StatusCode::UciStatusOk,
+                    attempts: num_packets,
+                    acq_detect: num_packets,
+                    acq_reject: 0,
+                    rx_fail: 0,
+                    sync_cir_ready: num_packets,
+                    sfd_fail: 0,
+                    sfd_found: num_packets,
+                    phr_dec_error: 0,
+                    phr_bit_error: 0,
+                    psdu_dec_error: 0,
+                    psdu_bit_error: 0,
+                    sts_found: num_packets,
+                    eof: num_packets,
+                    vendor_data: vec![],
+                }
+                .build()
+                .into(),
+            };
+            send_notification(tx, notification).await
+        }));
+        StatusCode::UciStatusOk
+    }
+
+    pub fn periodic_tx(&mut self, session_id: u32) -> TestPeriodicTxRsp {
+        info!("Test periodic TX");
+        let status = self.start(session_id, TestKind::PeriodicTx);
+        TestPeriodicTxRspBuilder { status }.build()
+    }
+
+    pub fn per_rx(&mut self, session_id: u32) -> TestPerRxRsp {
+        info!("Test PER RX");
+        let status = self.start(session_id, TestKind::PerRx);
+        TestPerRxRspBuilder { status }.build()
+    }
+
+    pub fn loopback(&mut self, psdu_data: &[u8]) -> TestLoopbackRsp {
+        info!(?psdu_data, "Test loopback");
+
+        if self.is_running() {
+            return TestLoopbackRspBuilder {
+                status: StatusCode::UciStatusRejected,
+            }
+            .build();
+        }
+
+        let notification = TestLoopbackNtfBuilder {
+            status: StatusCode::UciStatusOk,
+            tx_ts_int: 0,
+            tx_ts_frac: 0,
+            rx_ts_int: LOOPBACK_DELAY,
+            rx_ts_frac: 0,
+            aoa_azimuth: 0,
+            aoa_elevation: 0,
+            // Frame length field of the PHR.
+            phr: (psdu_data.len() as u16 & 0x7f) << 3,
+            psdu_data: psdu_data.to_vec(),
+            vendor_data: vec![],
+        }
+        .build();
+        let tx = self.tx.clone();
+        self.task = Some(tokio::spawn(send_notification(tx, notification.into())));
+
+        TestLoopbackRspBuilder {
+            status: StatusCode::UciStatusOk,
+        }
+        .build()
+    }
+
+    pub fn stop(&mut self) -> TestStopSessionRsp {
+        info!("Test stop session");
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+        TestStopSessionRspBuilder {
+            status: StatusCode::UciStatusOk,
+        }
+        .build()
+    }
+}
+
+impl Drop for RfTest {
+    fn drop(&mut self) {
+        // Abort the pending test when the device is reset or disconnected.
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+    }
+}
+
+fn default_value(id: TestConfigTlvType) -> Option<&'static [u8]> {
+    DEFAULT_TEST_CONFIG
+        .iter()
+        .find(|(cfg_id, _)| *cfg_id == id)
+        .map(|(_, value)| *value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rf_test() -> RfTest {
+        let (tx, _) = mpsc::channel(1);
+        RfTest::new(tx, Timeline::default())
+    }
+
+    #[test]
+    fn set_and_get_config() {
+        let mut rf_test = rf_test();
+        let rsp = rf_test.set_config(
+            1,
+            &[TestConfigTlv {
+                cfg_id: TestConfigTlvType::NumPackets,
+                v: vec![10, 0, 0, 0],
+            }],
+        );
+        assert_eq!(rsp.get_status(), StatusCode::UciStatusOk);
+        assert_eq!(rf_test.config_u32(1, TestConfigTlvType::NumPackets), 10);
+        assert_eq!(rf_test.config_u32(1, TestConfigTlvType::TGap), 2000);
+        assert_eq!(rf_test.config_u32(2, TestConfigTlvType::NumPackets), 1000);
+
+        let rsp = rf_test.get_config(1, &[TestConfigTlvType::NumPackets.into()]);
+        assert_eq!(rsp.get_status(), StatusCode::UciStatusOk);
+        assert_eq!(rsp.get_tlvs()[0].v, vec![10, 0, 0, 0]);
+
+        let rsp = rf_test.get_config(1, &[0xff]);
+        assert_eq!(rsp.get_status(), StatusCode::UciStatusInvalidParam);
+    }
+
+    #[test]
+    fn set_config_invalid_length() {
+        let mut rf_test = rf_test();
+        let rsp = rf_test.set_config(
+            1,
+            &[TestConfigTlv {
+                cfg_id: TestConfigTlvType::TGap,
+                v: vec![10],
+            }],
+        );
+        assert_eq!(rsp.get_status(), StatusCode::UciStatusInvalidParam);
+        assert_eq!(rsp.get_cfg_status().len(), 1);
+        assert_eq!(rf_test.config_u32(1, TestConfigTlvType::TGap), 2000);
+    }
+
+    #[tokio::test]
+    async fn notifications_follow_responses() {
+        let (tx, mut rx) = mpsc::channel(1);
+        let mut rf_test = RfTest::new(tx, Timeline::default());
+
+        let rsp = rf_test.loopback(&[1, 2, 3]);
+        assert_eq!(rsp.get_status(), StatusCode::UciStatusOk);
+        tokio::task::yield_now().await;
+        assert!(rx.try_recv().is_err());
+        assert!(TestLoopbackNtf::try_from(rx.recv().await.unwrap()).is_ok());
+
+        rf_test.set_config(
+            1,
+            &[TestConfigTlv {
+                cfg_id: TestConfigTlvType::NumPackets,
+                v: vec![0, 0, 0, 0],
+            }],
+        );
+        let rsp = rf_test.periodic_tx(1);
+        assert_eq!(rsp.get_status(), StatusCode::UciStatusOk);
+        tokio::task::yield_now().await;
+        assert!(rx.try_recv().is_err());
+        assert!(TestPeriodicTxNtf::try_from(rx.recv().await.unwrap()).is_ok());
+    }
+}
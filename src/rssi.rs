@@ -0,0 +1,95 @@
+// Copyright 2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Received signal strength of the ranging measurements,
+//! computed with a log-distance path loss model.
+
+use crate::noise::sample;
+use rand::rngs::StdRng;
+
+/// Reference distance of the path loss model (cm).
+const REFERENCE_DISTANCE: f32 = 100.0;
+
+/// Log-distance path loss model:
+/// `RSSI(d) = reference_rssi - 10 * path_loss_exponent * log10(d / 1m) + X`
+/// where `X` is a gaussian error (shadowing).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RssiModel {
+    /// Signal strength received at 1 m (dBm).
+    pub reference_rssi: f32,
+    /// Path loss exponent, 2.0 in free space.
+    pub path_loss_exponent: f32,
+    /// Standard deviation of the shadowing error (dB).
+    pub noise: f32,
+}
+
+impl Default for RssiModel {
+    fn default() -> Self {
+        RssiModel {
+            reference_rssi: -40.0,
+            path_loss_exponent: 2.0,
+            noise: 0.0,
+        }
+    }
+}
+
+impl RssiModel {
+    /// Signal strength received at the selected distance (dBm).
+    pub fn rssi(&self, rng: &mut StdRng, distance: u16) -> f32 {
+        // Distances below 1 cm are rounded up to keep the loss finite.
+        let distance = (distance as f32).max(1.0);
+        self.reference_rssi
+            - 10.0 * self.path_loss_exponent * (distance / REFERENCE_DISTANCE).log10()
+            + sample(rng, self.noise)
+    }
+
+    /// Signal strength received at the selected distance, encoded as
+    /// in the ranging measurements: the negated value in dBm, in
+    /// unsigned 7.1 fixed point format.
+    pub fn encoded_rssi(&self, rng: &mut StdRng, distance: u16) -> u8 {
+        (-self.rssi(rng, distance) * 2.0)
+            .round()
+            .clamp(0.0, u8::MAX as f32) as u8
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+
+    #[test]
+    fn free_space_path_loss() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let model = RssiModel::default();
+        assert_eq!(model.rssi(&mut rng, 100), -40.0);
+        assert_eq!(model.rssi(&mut rng, 1000), -60.0);
+        assert_eq!(model.encoded_rssi(&mut rng, 1000), 120);
+    }
+
+    #[test]
+    fn encoded_rssi_is_clamped() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let model = RssiModel {
+            reference_rssi: 10.0,
+            ..Default::default()
+        };
+        assert_eq!(model.encoded_rssi(&mut rng, 0), 0);
+        let model = RssiModel {
+            reference_rssi: -120.0,
+            ..Default::default()
+        };
+        assert_eq!(model.encoded_rssi(&mut rng, u16::MAX), u8::MAX);
+    }
+}
@@ -0,0 +1,188 @@
+// Copyright 2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Timing of the ranging rounds of a session, derived from the
+//! RANGING_DURATION, SLOT_DURATION, SLOTS_PER_RR, BLOCK_STRIDE_LENGTH
+//! and UWB_INITIATION_TIME app config parameters.
+
+use std::time::Duration;
+
+/// Duration of a ranging scheduling time unit (RSTU):
+/// 416 chips at 499.2 MHz, i.e. 833.33 ns.
+const RSTU_NANOS: f64 = 416.0 * 1000.0 / 499.2;
+
+/// Ranging rounds are scheduled at the start of every active ranging block,
+/// and their results are reported at the end of the round.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RangingSchedule {
+    /// Offset of the first ranging block from the session start.
+    initiation_time: Duration,
+    /// Duration of a ranging block.
+    block_duration: Duration,
+    /// Duration of a ranging round, at most the duration of the block.
+    round_duration: Duration,
+    /// Number of ranging blocks skipped after each active block.
+    block_stride_length: u8,
+}
+
+impl RangingSchedule {
+    pub fn new(
+        initiation_time: Duration,
+        block_duration: Duration,
+        slot_duration: u16,
+        slots_per_rr: u8,
+        block_stride_length: u8,
+    ) -> Self {
+        let round_duration = Duration::from_nanos(
+            (slot_duration as f64 * slots_per_rr as f64 * RSTU_NANOS).round() as u64,
+        );
+        RangingSchedule {
+            initiation_time,
+            block_duration,
+            round_duration: round_duration.min(block_duration),
+            block_stride_length,
+        }
+    }
+
+    /// Interval between the start of two consecutive ranging rounds.
+    pub fn interval(&self) -> Duration {
+        self.block_duration * (self.block_stride_length as u32 + 1)
+    }
+
+    /// Offset from the session start of the selected active ranging block.
+    pub fn block_start(&self, round_index: u32) -> Duration {
+        self.initiation_time + self.interval() * round_index
+    }
+
+    /// Offset from the session start of the end of the selected
+    /// ranging round.
+    pub fn round_end(&self, round_index: u32) -> Duration {
+        self.block_start(round_index) + self.round_duration
+    }
+
+    /// Index of the first active ranging block starting at or after
+    /// the offset from the session start.
+    pub fn next_round(&self, offset: Duration) -> u32 {
+        let interval = self.interval().as_nanos();
+        match offset.checked_sub(self.initiation_time) {
+            Some(elapsed) if interval > 0 => elapsed.as_nanos().div_ceil(interval) as u32,
+            _ => 0,
+        }
+    }
+
+    /// Offsets from the session start of the start and end of the
+    /// ranging round in the selected ranging block.
+    pub fn round_span(&self, block_index: u32) -> (Duration, Duration) {
+        let start = self.initiation_time + self.block_duration * block_index;
+        (start, start + self.round_duration)
+    }
+
+    /// Index of the ranging block of the round ending at the offset
+    /// from the session start, rounded to absorb the jitter of the
+    /// round timers.
+    pub fn block_index(&self, offset: Duration) -> u32 {
+        if self.block_duration.is_zero() {
+            return 0;
+        }
+        let elapsed = offset.saturating_sub(self.initiation_time + self.round_duration);
+        (elapsed.as_secs_f64() / self.block_duration.as_secs_f64()).round() as u32
+    }
+
+    /// Whether the ranging block is skipped by the block striding.
+    pub fn is_strided(&self, block_index: u32) -> bool {
+        !block_index.is_multiple_of(self.block_stride_length as u32 + 1)
+    }
+
+    /// Same block timing, with the first block starting immediately.
+    pub fn without_initiation_time(self) -> Self {
+        RangingSchedule {
+            initiation_time: Duration::ZERO,
+            ..self
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_timing() {
+        // 200 ms blocks, 25 slots of 2400 RSTU (50 ms), every other block.
+        let schedule = RangingSchedule::new(
+            Duration::from_millis(10),
+            Duration::from_millis(200),
+            2400,
+            25,
+            1,
+        );
+        assert_eq!(schedule.interval(), Duration::from_millis(400));
+        assert_eq!(schedule.round_end(0), Duration::from_millis(60));
+        assert_eq!(schedule.round_end(2), Duration::from_millis(860));
+    }
+
+    #[test]
+    fn next_round() {
+        let schedule = RangingSchedule::new(
+            Duration::from_millis(10),
+            Duration::from_millis(200),
+            2400,
+            25,
+            1,
+        );
+        assert_eq!(schedule.next_round(Duration::ZERO), 0);
+        assert_eq!(schedule.next_round(Duration::from_millis(10)), 0);
+        assert_eq!(schedule.next_round(Duration::from_millis(11)), 1);
+        assert_eq!(schedule.next_round(Duration::from_millis(410)), 1);
+        assert_eq!(schedule.block_start(2), Duration::from_millis(810));
+    }
+
+    #[test]
+    fn strided_blocks() {
+        let schedule = RangingSchedule::new(
+            Duration::from_millis(10),
+            Duration::from_millis(200),
+            2400,
+            25,
+            2,
+        );
+        assert_eq!(schedule.block_index(schedule.round_end(0)), 0);
+        assert_eq!(schedule.block_index(schedule.round_end(1)), 3);
+        assert_eq!(schedule.block_index(Duration::from_millis(470)), 2);
+        assert!(!schedule.is_strided(3));
+        assert!(schedule.is_strided(4));
+    }
+
+    #[test]
+    fn round_span() {
+        let schedule = RangingSchedule::new(
+            Duration::from_millis(10),
+            Duration::from_millis(200),
+            2400,
+            25,
+            1,
+        );
+        assert_eq!(
+            schedule.round_span(2),
+            (Duration::from_millis(410), Duration::from_millis(460))
+        );
+        assert_eq!(schedule.block_index(schedule.round_span(2).1), 2);
+    }
+
+    #[test]
+    fn round_fits_in_block() {
+        let schedule = RangingSchedule::new(Duration::ZERO, Duration::from_millis(20), 2400, 25, 0);
+        assert_eq!(schedule.round_end(1), Duration::from_millis(40));
+    }
+}
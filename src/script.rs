@@ -0,0 +1,323 @@
+// Copyright 2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Dynamic scenarios written in [rhai](https://rhai.rs), e.g.
+//!
+//! ```rhai
+//! create_anchor("00:01", 0, 0, 0);
+//! create_anchor("00:02", 300, 0, 0);
+//! sleep(30000);
+//! destroy_anchor("00:02");
+//!
+//! fn on_event(event) {
+//!     if event.kind == "device-added" {
+//!         print(`device ${event.mac_address} joined`);
+//!     }
+//! }
+//! ```
+//!
+//! The script body runs once, on the simulated timeline. The events are
+//! passed to the `on_event` function while the body sleeps, and after
+//! it has completed.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use rhai::{Array, Dynamic, Engine, EvalAltResult, NativeCallContext, Scope, AST};
+use tokio::runtime::Handle;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tracing::{info, warn};
+
+use crate::{
+    MacAddress, MotionPath, PathMode, Pica, PicaCommand, PicaCommandStatus, Position,
+    SequencedEvent,
+};
+
+const EVENT_HANDLER: &str = "on_event";
+
+type ScriptResult<T> = Result<T, Box<EvalAltResult>>;
+
+/// Scenario script compiled for a simulation.
+pub struct Script {
+    engine: Engine,
+    ast: AST,
+    event_rx: Arc<Mutex<broadcast::Receiver<SequencedEvent>>>,
+    has_event_handler: bool,
+}
+
+fn mac_address(mac_address: &str) -> ScriptResult<MacAddress> {
+    mac_address
+        .parse()
+        .map_err(|_| format!("invalid MAC address '{}'", mac_address).into())
+}
+
+fn coordinate(value: i64) -> ScriptResult<i16> {
+    i16::try_from(value).map_err(|_| format!("invalid coordinate {}", value).into())
+}
+
+fn duration(ms: i64) -> ScriptResult<Duration> {
+    u64::try_from(ms)
+        .map(Duration::from_millis)
+        .map_err(|_| format!("invalid duration {} ms", ms).into())
+}
+
+fn waypoint(waypoint: Dynamic) -> ScriptResult<(i16, i16, i16)> {
+    let coordinates = waypoint
+        .into_array()
+        .map_err(|_| "expected a waypoint [x, y, z]")?
+        .into_iter()
+        .map(|value| {
+            value
+                .as_int()
+                .map_err(|_| "expected integer coordinates".into())
+                .and_then(coordinate)
+        })
+        .collect::<ScriptResult<Vec<_>>>()?;
+    match coordinates[..] {
+        [x, y, z] => Ok((x, y, z)),
+        _ => Err("expected a waypoint [x, y, z]".into()),
+    }
+}
+
+/// Send a command to pica and wait for its status.
+fn send(
+    tx: &mpsc::Sender<PicaCommand>,
+    command: impl FnOnce(oneshot::Sender<PicaCommandStatus>) -> PicaCommand,
+) -> ScriptResult<()> {
+    let (status_tx, status_rx) = oneshot::channel();
+    tx.blocking_send(command(status_tx))
+        .map_err(|_| "the simulation is shut down")?;
+    status_rx
+        .blocking_recv()
+        .map_err(|_| "no response from the simulation")?
+        .map_err(|err| err.to_string().into())
+}
+
+/// Pass the event to the event handler of the script.
+fn event_argument(event: &SequencedEvent) -> Dynamic {
+    let mut argument = rhai::serde::to_dynamic(&event.event).unwrap_or_default();
+    if let Some(mut map) = argument.write_lock::<rhai::Map>() {
+        map.insert("monotonic_us".into(), (event.monotonic_us as i64).into());
+    }
+    argument
+}
+
+impl Script {
+    /// Compile the script controlling the simulation. The events emitted
+    /// from now on are passed to the event handler of the script.
+    pub fn compile(pica: &Pica, source: &str) -> Result<Script> {
+        let tx = pica.tx();
+        let timeline = pica.timeline.clone();
+        let event_rx = Arc::new(Mutex::new(pica.event_tx().subscribe()));
+        let mut engine = Engine::new();
+
+        engine.on_print(|text| info!("script: {}", text));
+        engine.on_debug(|text, _, position| info!("script {}: {}", position, text));
+
+        {
+            let tx = tx.clone();
+            engine.register_fn(
+                "create_anchor",
+                move |mac: &str, x: i64, y: i64, z: i64| -> ScriptResult<()> {
+                    let mac_address = mac_address(mac)?;
+                    let position =
+                        Position::new(coordinate(x)?, coordinate(y)?, coordinate(z)?, 0, 0, 0);
+                    send(&tx, |status_tx| {
+                        PicaCommand::CreateAnchor(mac_address, position, status_tx)
+                    })
+                },
+            );
+        }
+        {
+            let tx = tx.clone();
+            engine.register_fn("destroy_anchor", move |mac: &str| -> ScriptResult<()> {
+                let mac_address = mac_address(mac)?;
+                send(&tx, |status_tx| {
+                    PicaCommand::DestroyAnchor(mac_address, status_tx)
+                })
+            });
+        }
+        {
+            let tx = tx.clone();
+            engine.register_fn(
+                "set_position",
+                move |mac: &str, x: i64, y: i64, z: i64| -> ScriptResult<()> {
+                    let mac_address = mac_address(mac)?;
+                    let position =
+                        Position::new(coordinate(x)?, coordinate(y)?, coordinate(z)?, 0, 0, 0);
+                    send(&tx, |status_tx| {
+                        PicaCommand::SetPosition(mac_address, position, status_tx)
+                    })
+                },
+            );
+        }
+        {
+            let tx = tx.clone();
+            engine.register_fn(
+                "set_path",
+                move |mac: &str, waypoints: Array, speed: f64| -> ScriptResult<()> {
+                    let mac_address = mac_address(mac)?;
+                    let waypoints = waypoints
+                        .into_iter()
+                        .map(waypoint)
+                        .collect::<ScriptResult<Vec<_>>>()?;
+                    let path = MotionPath::new(&waypoints, speed as f32, PathMode::Loop)
+                        .map_err(|err| err.to_string())?;
+                    send(&tx, |status_tx| {
+                        PicaCommand::SetPath(mac_address, Some(path), status_tx)
+                    })
+                },
+            );
+        }
+        {
+            let tx = tx.clone();
+            engine.register_fn("stop_motion", move |mac: &str| -> ScriptResult<()> {
+                let mac_address = mac_address(mac)?;
+                send(&tx, |status_tx| {
+                    PicaCommand::SetPath(mac_address, None, status_tx)
+                })
+            });
+        }
+        {
+            let tx = tx.clone();
+            engine.register_fn(
+                "start_anchor_ranging",
+                move |mac: &str, session_id: i64, interval_ms: i64| -> ScriptResult<()> {
+                    let mac_address = mac_address(mac)?;
+                    let session_id = u32::try_from(session_id)
+                        .map_err(|_| format!("invalid session id {}", session_id))?;
+                    let interval = duration(interval_ms)?;
+                    send(&tx, |status_tx| {
+                        PicaCommand::StartAnchorRanging(
+                            mac_address,
+                            session_id,
+                            interval,
+                            status_tx,
+                        )
+                    })
+                },
+            );
+        }
+        {
+            let tx = tx.clone();
+            engine.register_fn(
+                "stop_anchor_ranging",
+                move |mac: &str| -> ScriptResult<()> {
+                    let mac_address = mac_address(mac)?;
+                    send(&tx, |status_tx| {
+                        PicaCommand::StopAnchorRanging(mac_address, status_tx)
+                    })
+                },
+            );
+        }
+        {
+            let timeline = timeline.clone();
+            engine.register_fn("now", move || timeline.elapsed().as_millis() as i64);
+        }
+
+        let ast = engine.compile(source)?;
+        let has_event_handler = ast
+            .iter_functions()
+            .any(|function| function.name == EVENT_HANDLER && function.params.len() == 1);
+
+        {
+            let event_rx = event_rx.clone();
+            engine.register_fn(
+                "sleep",
+                move |context: NativeCallContext, ms: i64| -> ScriptResult<()> {
+                    let deadline = timeline.now() + duration(ms)?;
+                    // Events are not dispatched when sleeping from the
+                    // event handler, they are queued until it returns.
+                    let mut event_rx = match event_rx.try_lock() {
+                        Ok(event_rx) if has_event_handler => event_rx,
+                        _ => {
+                            Handle::current().block_on(timeline.sleep_until(deadline));
+                            return Ok(());
+                        }
+                    };
+                    loop {
+                        let event = Handle::current().block_on(async {
+                            tokio::select! {
+                                _ = timeline.sleep_until(deadline) => None,
+                                event = event_rx.recv() => Some(event),
+                            }
+                        });
+                        match event {
+                            None => return Ok(()),
+                            Some(Ok(event)) => {
+                                // The value returned by the handler is ignored.
+                                let _ = context
+                                    .call_fn::<Dynamic>(EVENT_HANDLER, (event_argument(&event),))?;
+                            }
+                            Some(Err(broadcast::error::RecvError::Lagged(count))) => {
+                                warn!("Script missed {} events", count)
+                            }
+                            Some(Err(broadcast::error::RecvError::Closed)) => {
+                                drop(event_rx);
+                                Handle::current().block_on(timeline.sleep_until(deadline));
+                                return Ok(());
+                            }
+                        }
+                    }
+                },
+            );
+        }
+
+        Ok(Script {
+            engine,
+            ast,
+            event_rx,
+            has_event_handler,
+        })
+    }
+
+    /// Run the script body, then pass the events to the event handler
+    /// until the simulation is shut down.
+    pub async fn run(self) -> Result<()> {
+        tokio::task::spawn_blocking(move || self.run_blocking()).await?
+    }
+
+    fn run_blocking(self) -> Result<()> {
+        let mut scope = Scope::new();
+        self.engine
+            .run_ast_with_scope(&mut scope, &self.ast)
+            .map_err(|err| anyhow!("script error: {}", err))?;
+        if !self.has_event_handler {
+            return Ok(());
+        }
+        let mut event_rx = self.event_rx.lock().unwrap();
+        loop {
+            match Handle::current().block_on(event_rx.recv()) {
+                Ok(event) => {
+                    let options = rhai::CallFnOptions::new().eval_ast(false);
+                    let _ = self
+                        .engine
+                        .call_fn_with_options::<Dynamic>(
+                            options,
+                            &mut scope,
+                            &self.ast,
+                            EVENT_HANDLER,
+                            (event_argument(&event),),
+                        )
+                        .map_err(|err| anyhow!("script error: {}", err))?;
+                }
+                Err(broadcast::error::RecvError::Lagged(count)) => {
+                    warn!("Script missed {} events", count)
+                }
+                Err(broadcast::error::RecvError::Closed) => return Ok(()),
+            }
+        }
+    }
+}
@@ -0,0 +1,79 @@
+// Copyright 2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Random generators of the simulation, all derived from a single seed.
+//! Each generator is derived from the seed and the identifiers of its
+//! owner, rather than drawn from a shared generator, so that the draws
+//! do not depend on the interleaving of the devices and sessions.
+
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+/// Randomized behavior a generator is derived for.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Stream {
+    /// Errors of the ranging measurements.
+    Measurements = 1,
+    /// Jitter of the ranging round timers.
+    RoundJitter = 2,
+    /// Latency of the notifications.
+    NotificationLatency = 3,
+    /// Channel and preamble code hops of the ranging rounds.
+    Hopping = 4,
+}
+
+fn splitmix64(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9e3779b97f4a7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+/// Generator derived from the simulation seed for the selected
+/// behavior of the owner identified by `ids`.
+pub(crate) fn derived_rng(seed: u64, stream: Stream, ids: &[u64]) -> StdRng {
+    let seed = ids
+        .iter()
+        .fold(splitmix64(seed ^ stream as u64), |seed, id| {
+            splitmix64(seed ^ id)
+        });
+    StdRng::seed_from_u64(seed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+
+    #[test]
+    fn derived_rng() {
+        let draw = |seed, stream, ids: &[u64]| super::derived_rng(seed, stream, ids).gen::<u64>();
+        assert_eq!(
+            draw(42, Stream::Measurements, &[1, 2]),
+            draw(42, Stream::Measurements, &[1, 2])
+        );
+        assert_ne!(
+            draw(42, Stream::Measurements, &[1, 2]),
+            draw(43, Stream::Measurements, &[1, 2])
+        );
+        assert_ne!(
+            draw(42, Stream::Measurements, &[1, 2]),
+            draw(42, Stream::RoundJitter, &[1, 2])
+        );
+        assert_ne!(
+            draw(42, Stream::Measurements, &[1, 2]),
+            draw(42, Stream::Measurements, &[2, 1])
+        );
+    }
+}
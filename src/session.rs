@@ -16,13 +16,24 @@
 //! - [MAC] FiRa Consortium UWB MAC Technical Requirements
 //! - [UCI] FiRa Consortium UWB Command Interface Generic Technical specification
 
+use crate::clock::Clock;
+use crate::hopping::{self, Hop};
 use crate::packets::uci::*;
-use crate::{MacAddress, PicaCommand};
+use crate::position::{Position, Scene};
+use crate::radar::Radar;
+use crate::scheduler::RangingSchedule;
+use crate::seed::{derived_rng, Stream};
+use crate::timeline::Timeline;
+use crate::{MacAddress, PicaCommand, RangingControl};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use serde::Serialize;
 use std::collections::HashMap;
 use std::time::Duration;
 use tokio::sync::mpsc;
 use tokio::task::JoinHandle;
 use tokio::time;
+use tracing::{debug, info, info_span, warn, Span};
 
 use num_derive::{FromPrimitive, ToPrimitive};
 use num_traits::FromPrimitive;
@@ -32,6 +43,10 @@ pub const DEFAULT_RANGING_INTERVAL: Duration = time::Duration::from_millis(200);
 pub const DEFAULT_SLOT_DURATION: u16 = 2400; // RTSU unit
 /// cf. [UCI] 8.3 Table 29
 pub const MAX_NUMBER_OF_CONTROLEES: usize = 8;
+/// Number of data credits granted to the host for each session.
+/// A credit is consumed by every data packet fragment, and returned
+/// with a SESSION_DATA_CREDIT_NTF once the fragment is transmitted.
+pub const MAX_DATA_CREDITS: usize = 1;
 
 #[derive(Copy, Clone, FromPrimitive, PartialEq, Eq)]
 pub enum DeviceType {
@@ -283,8 +298,37 @@ pub struct AppConfig {
     uwb_initiation_time: u32,
     vendor_id: Option<Vec<u8>>,
     static_sts_iv: Option<Vec<u8>>,
+    sub_session_id: Option<u32>,
+    session_key: Option<Vec<u8>>,
+    sub_session_key: Option<Vec<u8>>,
+    ccc: CccAppConfig,
 }
 
+/// CCC specific App Configuration parameters,
+/// only accepted in sessions of type CCC.
+#[derive(Clone, Default, PartialEq)]
+struct CccAppConfig {
+    hop_mode_key: u32,
+    uwb_time0: u64,
+    ranging_protocol_ver: u16,
+    uwb_config_id: u16,
+    pulseshape_combo: u8,
+    ursk_ttl: u16,
+    /// STS index of the last ranging block, incremented on each block
+    /// so that the host can resume the session from this index.
+    last_index_used: u32,
+}
+
+const CCC_APP_CONFIG_PARAMETERS: &[AppConfigTlvType] = &[
+    AppConfigTlvType::CccHopModeKey,
+    AppConfigTlvType::CccUwbTime0,
+    AppConfigTlvType::CccRangingProtocolVer,
+    AppConfigTlvType::CccUwbConfigId,
+    AppConfigTlvType::CccPulseshapeCombo,
+    AppConfigTlvType::CccUrskTtl,
+    AppConfigTlvType::CccLastIndexUsed,
+];
+
 impl Default for AppConfig {
     fn default() -> Self {
         AppConfig {
@@ -333,6 +377,46 @@ impl Default for AppConfig {
             uwb_initiation_time: 0,
             vendor_id: None,
             static_sts_iv: None,
+            sub_session_id: None,
+            session_key: None,
+            sub_session_key: None,
+            ccc: CccAppConfig::default(),
+        }
+    }
+}
+
+/// Reason why a session cannot range with the session of a peer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PeerMismatch {
+    /// The sessions are configured on different channels.
+    ChannelNumber { local: u8, peer: u8 },
+    /// The sessions are configured with different preamble codes.
+    PreambleCodeIndex { local: u8, peer: u8 },
+    /// Other App Configuration parameters differ between the sessions.
+    AppConfig,
+    /// The sessions do not generate the same STS.
+    Sts,
+    /// The sessions have the same device role or device type.
+    Role,
+    /// The sessions do not list the address of each other.
+    MacAddress,
+}
+
+impl std::fmt::Display for PeerMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PeerMismatch::ChannelNumber { local, peer } => {
+                write!(f, "channel {} differs from peer channel {}", local, peer)
+            }
+            PeerMismatch::PreambleCodeIndex { local, peer } => write!(
+                f,
+                "preamble code {} differs from peer preamble code {}",
+                local, peer
+            ),
+            PeerMismatch::AppConfig => write!(f, "app configuration differs from peer"),
+            PeerMismatch::Sts => write!(f, "STS differs from peer"),
+            PeerMismatch::Role => write!(f, "same device role or type as peer"),
+            PeerMismatch::MacAddress => write!(f, "MAC addresses do not match peer"),
         }
     }
 }
@@ -375,6 +459,46 @@ impl PartialEq for AppConfig {
             && self.uwb_initiation_time == other.uwb_initiation_time
             && self.vendor_id == other.vendor_id
             && self.static_sts_iv == other.static_sts_iv
+            && self.ccc.hop_mode_key == other.ccc.hop_mode_key
+            && self.ccc.ranging_protocol_ver == other.ccc.ranging_protocol_ver
+            && self.ccc.uwb_config_id == other.ccc.uwb_config_id
+            && self.ccc.pulseshape_combo == other.ccc.pulseshape_combo
+    }
+}
+
+/// Parse a single octet enumeration parameter.
+fn parse_enum<T: FromPrimitive>(value: &[u8]) -> std::result::Result<T, StatusCode> {
+    T::from_u8(parse_u8(value)?).ok_or(StatusCode::UciStatusInvalidParam)
+}
+
+fn parse_u8(value: &[u8]) -> std::result::Result<u8, StatusCode> {
+    match value {
+        [value] => Ok(*value),
+        _ => Err(StatusCode::UciStatusInvalidParam),
+    }
+}
+
+fn parse_bool(value: &[u8]) -> std::result::Result<bool, StatusCode> {
+    match value {
+        [0] => Ok(false),
+        [1] => Ok(true),
+        _ => Err(StatusCode::UciStatusInvalidParam),
+    }
+}
+
+/// Parse a fixed size parameter, e.g. a little endian integer or
+/// a MAC address.
+fn parse_array<const N: usize>(value: &[u8]) -> std::result::Result<[u8; N], StatusCode> {
+    value
+        .try_into()
+        .map_err(|_| StatusCode::UciStatusInvalidParam)
+}
+
+/// Parse a provisioned session or sub-session key, of 16 or 32 octets.
+fn parse_key(value: &[u8]) -> std::result::Result<Vec<u8>, StatusCode> {
+    match value.len() {
+        16 | 32 => Ok(value.to_vec()),
+        _ => Err(StatusCode::UciStatusInvalidParam),
     }
 }
 
@@ -394,6 +518,17 @@ fn app_config_has_mandatory_parameters(configs: &[AppConfigTlv]) -> bool {
 }
 
 impl AppConfig {
+    /// Timing of the ranging rounds configured by the host.
+    pub fn ranging_schedule(&self) -> RangingSchedule {
+        RangingSchedule::new(
+            time::Duration::from_millis(self.uwb_initiation_time as u64),
+            self.ranging_interval,
+            self.slot_duration,
+            self.slots_per_rr,
+            self.block_stride_length,
+        )
+    }
+
     fn set_config(
         &mut self,
         id: AppConfigTlvType,
@@ -401,42 +536,41 @@ impl AppConfig {
     ) -> std::result::Result<(), StatusCode> {
         match id {
             AppConfigTlvType::MacAddressMode => {
-                let mode = MacAddressMode::from_u8(value[0]).unwrap();
+                let mode = parse_enum::<MacAddressMode>(value)?;
                 if mode == MacAddressMode::AddressMode1 {
                     return Err(StatusCode::UciStatusInvalidParam);
                 }
                 self.mac_address_mode = mode;
             }
             AppConfigTlvType::RangingDuration => {
-                let interval = u32::from_le_bytes(value[..].try_into().unwrap());
+                let interval = u32::from_le_bytes(parse_array(value)?);
                 self.ranging_interval = time::Duration::from_millis(interval as u64)
             }
             AppConfigTlvType::SlotDuration => {
-                self.slot_duration = u16::from_le_bytes(value[..].try_into().unwrap())
+                self.slot_duration = u16::from_le_bytes(parse_array(value)?)
             }
             AppConfigTlvType::ChannelNumber => {
-                self.channel_number = ChannelNumber::from_u8(value[0]).unwrap()
+                self.channel_number = parse_enum::<ChannelNumber>(value)?
             }
             AppConfigTlvType::DeviceMacAddress => {
                 self.device_mac_address = match self.mac_address_mode {
-                    MacAddressMode::AddressMode0 => {
-                        MacAddress::Short(value[..].try_into().unwrap())
-                    }
-                    MacAddressMode::AddressMode2 => {
-                        MacAddress::Extend(value[..].try_into().unwrap())
-                    }
-                    _ => panic!("Unexpected MAC Address Mode"),
+                    MacAddressMode::AddressMode0 => MacAddress::Short(parse_array(value)?),
+                    MacAddressMode::AddressMode2 => MacAddress::Extend(parse_array(value)?),
+                    _ => return Err(StatusCode::UciStatusInvalidParam),
                 };
             }
             AppConfigTlvType::NoOfControlee => {
-                assert!(value[0] as usize <= MAX_NUMBER_OF_CONTROLEES);
-                self.number_of_controlees = value[0] as usize;
+                let number_of_controlees = parse_u8(value)? as usize;
+                if number_of_controlees > MAX_NUMBER_OF_CONTROLEES {
+                    return Err(StatusCode::UciStatusInvalidParam);
+                }
+                self.number_of_controlees = number_of_controlees;
             }
             AppConfigTlvType::DstMacAddress => {
                 let mac_address_size = match self.mac_address_mode {
                     MacAddressMode::AddressMode0 => 2,
                     MacAddressMode::AddressMode2 => 8,
-                    _ => panic!("Unexpected MAC Address Mode"),
+                    _ => return Err(StatusCode::UciStatusInvalidParam),
                 };
                 if value.len() % mac_address_size != 0
                     || (value.len() / mac_address_size) != self.number_of_controlees
@@ -446,120 +580,118 @@ impl AppConfig {
                 self.dst_mac_addresses = value
                     .chunks(mac_address_size)
                     .map(|c| match self.mac_address_mode {
-                        MacAddressMode::AddressMode0 => MacAddress::Short(c.try_into().unwrap()),
-                        MacAddressMode::AddressMode2 => MacAddress::Extend(c.try_into().unwrap()),
-                        _ => panic!("Unexpected MAC Address Mode"),
+                        MacAddressMode::AddressMode0 => parse_array(c).map(MacAddress::Short),
+                        _ => parse_array(c).map(MacAddress::Extend),
                     })
-                    .collect();
-                assert_eq!(self.dst_mac_addresses.len(), self.number_of_controlees);
+                    .collect::<std::result::Result<_, _>>()?;
             }
             AppConfigTlvType::MultiNodeMode => {
-                self.multi_node_mode = MultiNodeMode::from_u8(value[0]).unwrap()
+                self.multi_node_mode = parse_enum::<MultiNodeMode>(value)?
             }
             AppConfigTlvType::DeviceType => {
-                self.device_type = DeviceType::from_u8(value[0]).unwrap();
+                self.device_type = parse_enum::<DeviceType>(value)?;
             }
             AppConfigTlvType::RangingRoundUsage => {
-                self.ranging_round_usage = RangingRoundUsage::from_u8(value[0]).unwrap()
-            }
-            AppConfigTlvType::StsConfig => self.sts_config = StsConfig::from_u8(value[0]).unwrap(),
-            AppConfigTlvType::MacFcsType => {
-                self.mac_fcs_type = MacFcsType::from_u8(value[0]).unwrap()
+                self.ranging_round_usage = parse_enum::<RangingRoundUsage>(value)?
             }
-            AppConfigTlvType::RangingRoundControl => self.ranging_round_control = value[0],
+            AppConfigTlvType::StsConfig => self.sts_config = parse_enum::<StsConfig>(value)?,
+            AppConfigTlvType::MacFcsType => self.mac_fcs_type = parse_enum::<MacFcsType>(value)?,
+            AppConfigTlvType::RangingRoundControl => self.ranging_round_control = parse_u8(value)?,
             AppConfigTlvType::AoaResultReq => {
-                self.aoa_result_req = AoaResultReq::from_u8(value[0]).unwrap()
+                self.aoa_result_req = parse_enum::<AoaResultReq>(value)?
             }
             AppConfigTlvType::RngDataNtf => {
-                self.rng_data_ntf = RangeDataNtfConfig::from_u8(value[0]).unwrap()
+                self.rng_data_ntf = parse_enum::<RangeDataNtfConfig>(value)?
             }
             AppConfigTlvType::RngDataNtfProximityNear => {
-                self.rng_data_ntf_proximity_near = u16::from_le_bytes(value[..].try_into().unwrap())
+                self.rng_data_ntf_proximity_near = u16::from_le_bytes(parse_array(value)?)
             }
             AppConfigTlvType::RngDataNtfProximityFar => {
-                self.rng_data_ntf_proximity_far = u16::from_le_bytes(value[..].try_into().unwrap())
+                self.rng_data_ntf_proximity_far = u16::from_le_bytes(parse_array(value)?)
             }
             AppConfigTlvType::DeviceRole => {
-                self.device_role = DeviceRole::from_u8(value[0]).unwrap();
+                self.device_role = parse_enum::<DeviceRole>(value)?;
             }
             AppConfigTlvType::RframeConfig => {
-                self.r_frame_config = RframeConfig::from_u8(value[0]).unwrap()
-            }
-            AppConfigTlvType::RssiReporting => {
-                self.rssi_reporting = match value[0] {
-                    0 => false,
-                    1 => true,
-                    _ => panic!("Invalid rssi reporting value!"),
-                }
+                self.r_frame_config = parse_enum::<RframeConfig>(value)?
             }
-            AppConfigTlvType::PreambleCodeIndex => self.preamble_code_index = value[0],
-            AppConfigTlvType::SfdId => self.sfd_id = SfdIdValue::from_u8(value[0]).unwrap(),
+            AppConfigTlvType::RssiReporting => self.rssi_reporting = parse_bool(value)?,
+            AppConfigTlvType::PreambleCodeIndex => self.preamble_code_index = parse_u8(value)?,
+            AppConfigTlvType::SfdId => self.sfd_id = parse_enum::<SfdIdValue>(value)?,
             AppConfigTlvType::PsduDataRate => {
-                self.psdu_data_rate = PsduDataRate::from_u8(value[0]).unwrap()
+                self.psdu_data_rate = parse_enum::<PsduDataRate>(value)?
             }
             AppConfigTlvType::PreambleDuration => {
-                self.preamble_duration = PreambleDuration::from_u8(value[0]).unwrap()
+                self.preamble_duration = parse_enum::<PreambleDuration>(value)?
             }
             AppConfigTlvType::RangingTimeStruct => {
-                self.ranging_time_struct = RangingTimeStruct::from_u8(value[0]).unwrap()
+                self.ranging_time_struct = parse_enum::<RangingTimeStruct>(value)?
             }
-            AppConfigTlvType::SlotsPerRr => self.slots_per_rr = value[0],
+            AppConfigTlvType::SlotsPerRr => self.slots_per_rr = parse_u8(value)?,
             AppConfigTlvType::TxAdaptivePayloadPower => {
-                self.tx_adaptive_payload_power = match value[0] {
-                    0 => false,
-                    1 => true,
-                    _ => panic!("Invalid tx adaptive payload power value!"),
-                }
+                self.tx_adaptive_payload_power = parse_bool(value)?
             }
-            AppConfigTlvType::PrfMode => self.prf_mode = PrfMode::from_u8(value[0]).unwrap(),
+            AppConfigTlvType::PrfMode => self.prf_mode = parse_enum::<PrfMode>(value)?,
             AppConfigTlvType::ScheduledMode => {
-                self.schedule_mode = SchedulingMode::from_u8(value[0]).unwrap()
-            }
-            AppConfigTlvType::KeyRotation => {
-                self.key_rotation = match value[0] {
-                    0 => false,
-                    1 => true,
-                    _ => panic!("Invalid key rotation value!"),
-                }
+                self.schedule_mode = parse_enum::<SchedulingMode>(value)?
             }
-            AppConfigTlvType::KeyRotationRate => self.key_rotation_rate = value[0],
-            AppConfigTlvType::SessionPriority => self.session_priority = value[0],
+            AppConfigTlvType::KeyRotation => self.key_rotation = parse_bool(value)?,
+            AppConfigTlvType::KeyRotationRate => self.key_rotation_rate = parse_u8(value)?,
+            AppConfigTlvType::SessionPriority => self.session_priority = parse_u8(value)?,
             AppConfigTlvType::VendorId => {
-                self.vendor_id = Some(value.to_vec());
+                self.vendor_id = Some(parse_array::<2>(value)?.to_vec());
             }
             AppConfigTlvType::StaticStsIv => {
-                self.static_sts_iv = Some(value.to_vec());
+                self.static_sts_iv = Some(parse_array::<6>(value)?.to_vec());
+            }
+            AppConfigTlvType::SubSessionId => {
+                self.sub_session_id = Some(u32::from_le_bytes(parse_array(value)?))
             }
+            AppConfigTlvType::SessionKey => self.session_key = Some(parse_key(value)?),
+            AppConfigTlvType::SubsessionKey => self.sub_session_key = Some(parse_key(value)?),
             AppConfigTlvType::NumberOfStsSegments => {
-                self.number_of_sts_segments = StsSegmentCountValue::from_u8(value[0]).unwrap()
+                self.number_of_sts_segments = parse_enum::<StsSegmentCountValue>(value)?
             }
             AppConfigTlvType::MaxRrRetry => {
-                self.max_rr_retry = u16::from_le_bytes(value[..].try_into().unwrap())
+                self.max_rr_retry = u16::from_le_bytes(parse_array(value)?)
             }
             AppConfigTlvType::UwbInitiationTime => {
-                self.uwb_initiation_time = u32::from_le_bytes(value[..].try_into().unwrap())
-            }
-            AppConfigTlvType::HoppingMode => {
-                self.hopping_mode = HoppingMode::from_u8(value[0]).unwrap()
-            }
-            AppConfigTlvType::BlockStrideLength => self.block_stride_length = value[0],
-            AppConfigTlvType::ResultReportConfig => {
-                self.result_report_config = match value[0] {
-                    0 => false,
-                    1 => true,
-                    _ => panic!("Invalid result report config value!"),
-                }
+                self.uwb_initiation_time = u32::from_le_bytes(parse_array(value)?)
             }
+            AppConfigTlvType::HoppingMode => self.hopping_mode = parse_enum::<HoppingMode>(value)?,
+            AppConfigTlvType::BlockStrideLength => self.block_stride_length = parse_u8(value)?,
+            AppConfigTlvType::ResultReportConfig => self.result_report_config = parse_bool(value)?,
             AppConfigTlvType::BprfPhrDataRate => {
-                self.bprf_phr_data_rate = BprfPhrDataRate::from_u8(value[0]).unwrap()
+                self.bprf_phr_data_rate = parse_enum::<BprfPhrDataRate>(value)?
             }
-            AppConfigTlvType::MaxNumberOfMeasurements => self.max_number_of_measurements = value[0],
-            AppConfigTlvType::StsLength => self.sts_length = StsLength::from_u8(value[0]).unwrap(),
+            AppConfigTlvType::MaxNumberOfMeasurements => {
+                self.max_number_of_measurements = parse_u8(value)?
+            }
+            AppConfigTlvType::StsLength => self.sts_length = parse_enum::<StsLength>(value)?,
             AppConfigTlvType::InBandTerminationAttemptCount => {
-                self.in_band_termination_attempt_count = value[0]
+                self.in_band_termination_attempt_count = parse_u8(value)?
+            }
+            AppConfigTlvType::CccHopModeKey => {
+                self.ccc.hop_mode_key = u32::from_le_bytes(parse_array(value)?)
+            }
+            AppConfigTlvType::CccUwbTime0 => {
+                self.ccc.uwb_time0 = u64::from_le_bytes(parse_array(value)?)
+            }
+            AppConfigTlvType::CccRangingProtocolVer => {
+                self.ccc.ranging_protocol_ver = u16::from_le_bytes(parse_array(value)?)
+            }
+            AppConfigTlvType::CccUwbConfigId => {
+                self.ccc.uwb_config_id = u16::from_le_bytes(parse_array(value)?)
+            }
+            AppConfigTlvType::CccPulseshapeCombo => self.ccc.pulseshape_combo = parse_u8(value)?,
+            AppConfigTlvType::CccUrskTtl => {
+                self.ccc.ursk_ttl = u16::from_le_bytes(parse_array(value)?)
+            }
+            AppConfigTlvType::CccLastIndexUsed => {
+                self.ccc.last_index_used = u32::from_le_bytes(parse_array(value)?)
             }
             id => {
-                println!("Ignored AppConfig parameter {:?}", id);
+                warn!("Ignored AppConfig parameter {:?}", id);
                 return Err(StatusCode::UciStatusInvalidParam);
             }
         };
@@ -569,20 +701,94 @@ impl AppConfig {
         Ok(())
     }
 
+    /// Check that the keys required by the STS configuration are
+    /// provisioned, or return the reason of the failure.
+    fn check_sts_keys(&self) -> std::result::Result<(), ReasonCode> {
+        let provisioned = matches!(
+            self.sts_config,
+            StsConfig::Provisioned | StsConfig::ProvisionedForControleeIndividualKey
+        );
+        let individual_key = matches!(
+            self.sts_config,
+            StsConfig::DynamicForControleeIndividualKey
+                | StsConfig::ProvisionedForControleeIndividualKey
+        );
+        if provisioned && self.session_key.is_none() {
+            return Err(ReasonCode::ErrorStatusSessionKeyNotFound);
+        }
+        if individual_key && self.device_type == DeviceType::Controlee {
+            if self.sub_session_id.is_none() {
+                return Err(ReasonCode::ErrorInvalidOrNotFoundSubSessionId);
+            }
+            if provisioned && self.sub_session_key.is_none() {
+                return Err(ReasonCode::ErrorStatusSubSessionKeyNotFound);
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the peer generates the same STS, i.e. has the same STS
+    /// configuration and the same static or provisioned keys.
+    fn sts_matches(&self, peer_config: &Self) -> bool {
+        self.sts_config == peer_config.sts_config
+            && match self.sts_config {
+                StsConfig::Static => {
+                    self.vendor_id == peer_config.vendor_id
+                        && self.static_sts_iv == peer_config.static_sts_iv
+                }
+                StsConfig::Provisioned | StsConfig::ProvisionedForControleeIndividualKey => {
+                    self.session_key == peer_config.session_key
+                }
+                StsConfig::Dynamic | StsConfig::DynamicForControleeIndividualKey => true,
+            }
+    }
+
     fn get_config(&self, id: AppConfigTlvType) -> Option<Vec<u8>> {
         self.raw.get(&id).cloned()
     }
 
-    pub fn can_start_ranging_with_peer(&self, peer_config: &Self) -> bool {
-        self == peer_config
-            && self.device_role != peer_config.device_role
-            && self.device_type != peer_config.device_type
-            && peer_config
-                .dst_mac_addresses
-                .contains(&self.device_mac_address)
-            && self
+    /// Advance the CCC STS index at the end of a ranging block.
+    fn increment_ccc_sts_index(&mut self) {
+        self.ccc.last_index_used = self.ccc.last_index_used.wrapping_add(1);
+        self.raw.insert(
+            AppConfigTlvType::CccLastIndexUsed,
+            self.ccc.last_index_used.to_le_bytes().to_vec(),
+        );
+    }
+
+    /// Check that the peer can range with the session. The channel and
+    /// preamble code are checked first, as the most common misconfigurations
+    /// preventing the peers from receiving each other.
+    pub fn can_start_ranging_with_peer(&self, peer_config: &Self) -> Result<(), PeerMismatch> {
+        if self.channel_number != peer_config.channel_number {
+            Err(PeerMismatch::ChannelNumber {
+                local: self.channel_number as u8,
+                peer: peer_config.channel_number as u8,
+            })
+        } else if self.preamble_code_index != peer_config.preamble_code_index {
+            Err(PeerMismatch::PreambleCodeIndex {
+                local: self.preamble_code_index,
+                peer: peer_config.preamble_code_index,
+            })
+        } else if self != peer_config {
+            Err(PeerMismatch::AppConfig)
+        } else if !self.sts_matches(peer_config) {
+            Err(PeerMismatch::Sts)
+        } else if self.device_role == peer_config.device_role
+            || self.device_type == peer_config.device_type
+        {
+            Err(PeerMismatch::Role)
+        } else if !peer_config
+            .dst_mac_addresses
+            .contains(&self.device_mac_address)
+            || !self
                 .dst_mac_addresses
                 .contains(&peer_config.device_mac_address)
+        {
+            Err(PeerMismatch::MacAddress)
+        } else {
+            Ok(())
+        }
     }
 
     fn extend(&mut self, configs: &[AppConfigTlv]) -> Vec<AppConfigStatus> {
@@ -605,6 +811,16 @@ impl AppConfig {
     }
 }
 
+/// Phase of a hybrid session: a ranging or data phase session scheduled
+/// within the ranging rounds of a HUS primary session.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HybridPhase {
+    pub session_id: u32,
+    /// First and last slots of the primary ranging rounds allocated
+    /// to the phase. Only configured by the controller.
+    pub slots: Option<(u16, u16)>,
+}
+
 pub struct Session {
     /// cf. [UCI] 7.1
     pub state: SessionState,
@@ -616,8 +832,62 @@ pub struct Session {
     pub sequence_number: u32,
     pub app_config: AppConfig,
     ranging_task: Option<JoinHandle<()>>,
+    /// Timing of the ranging rounds of the active session, instant
+    /// the offsets of the schedule are relative to, and local clock
+    /// measuring the offsets.
+    ranging_timing: Option<(time::Instant, RangingSchedule, Clock)>,
+    /// Skew of the local clock of the device.
+    clock: Clock,
+    timeline: Timeline,
     tx: mpsc::Sender<ControlPacket>,
     pica_tx: mpsc::Sender<PicaCommand>,
+    /// Data credits currently available to the host.
+    data_credits: usize,
+    /// Data packet fragments received from the host and waiting to be
+    /// transmitted during the next ranging round.
+    data_fragments: Vec<DataMessageSnd>,
+    /// Ranging control message sent to the controlees in the next
+    /// ranging round, when the session is a controller.
+    pending_control: Option<RangingControl>,
+    /// Seed of the simulation, from which the generators are derived.
+    seed: u64,
+    /// Generator of the measurement errors.
+    noise_rng: StdRng,
+    /// Generator of the jitter of the ranging round timers.
+    jitter_rng: StdRng,
+    /// Phases scheduled by the session, when it is a HUS primary session.
+    hybrid_phases: Vec<HybridPhase>,
+    /// Radar configuration, when the session is a radar session.
+    radar: Radar,
+}
+
+/// Name of a session state, as reported to the observers.
+pub fn state_name(state: SessionState) -> &'static str {
+    match state {
+        SessionState::SessionStateInit => "init",
+        SessionState::SessionStateDeinit => "deinit",
+        SessionState::SessionStateActive => "active",
+        SessionState::SessionStateIdle => "idle",
+    }
+}
+
+/// Summary of a session and of its key App Configuration parameters,
+/// reported in the state snapshots.
+#[derive(Clone, Debug, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct SessionInfo {
+    pub session_id: u32,
+    pub session_type: String,
+    pub state: &'static str,
+    pub device_type: &'static str,
+    pub device_role: &'static str,
+    pub device_mac_address: MacAddress,
+    pub dst_mac_addresses: Vec<MacAddress>,
+    pub channel_number: u8,
+    /// Ranging interval (ms).
+    pub ranging_interval: u32,
+    /// Sequence number of the next ranging round.
+    pub sequence_number: u32,
 }
 
 impl Session {
@@ -627,7 +897,10 @@ impl Session {
         device_handle: usize,
         tx: mpsc::Sender<ControlPacket>,
         pica_tx: mpsc::Sender<PicaCommand>,
+        timeline: Timeline,
+        seed: u64,
     ) -> Self {
+        let ids = [device_handle as u64, id as u64];
         Self {
             state: SessionState::SessionStateDeinit,
             id,
@@ -636,8 +909,19 @@ impl Session {
             sequence_number: 0,
             app_config: AppConfig::default(),
             ranging_task: None,
+            ranging_timing: None,
+            clock: Clock::default(),
+            timeline,
             tx,
             pica_tx,
+            data_credits: MAX_DATA_CREDITS,
+            data_fragments: Vec::new(),
+            pending_control: None,
+            seed,
+            noise_rng: derived_rng(seed, Stream::Measurements, &ids),
+            jitter_rng: derived_rng(seed, Stream::RoundJitter, &ids),
+            hybrid_phases: Vec::new(),
+            radar: Radar::default(),
         }
     }
 
@@ -646,9 +930,14 @@ impl Session {
         if session_state == self.state {
             return;
         }
-
-        // Send status notification
+        self.report_state(session_state, reason_code);
         self.state = session_state;
+        self.send_status_notification(reason_code);
+    }
+
+    /// Send a status notification with the current state to the host.
+    fn send_status_notification(&self, reason_code: ReasonCode) {
+        let session_state = self.state;
         let tx = self.tx.clone();
         let session_id = self.id;
         tokio::spawn(async move {
@@ -663,10 +952,128 @@ impl Session {
                 .into(),
             )
             .await
-            .unwrap()
+            .unwrap_or_else(|err| warn!("Failed to send session status notification: {}", err))
         });
     }
 
+    /// Report a state transition to the observers. The command is queued
+    /// synchronously to preserve the order of the transitions.
+    fn report_state(&self, session_state: SessionState, reason_code: ReasonCode) {
+        self.pica_tx
+            .try_send(PicaCommand::SessionUpdated(
+                self.device_handle,
+                self.id,
+                session_state,
+                reason_code,
+            ))
+            .unwrap_or_else(|err| warn!("Failed to report session state: {}", err));
+    }
+
+    /// Deinitialize the session when the device is reset. The ranging
+    /// stops and the observers are notified, but the host is not sent
+    /// a status notification, as the session no longer exists.
+    pub fn deinit_on_reset(&mut self) {
+        self.stop_ranging_task();
+        if self.state != SessionState::SessionStateDeinit {
+            self.report_state(
+                SessionState::SessionStateDeinit,
+                ReasonCode::StateChangeWithSessionManagementCommands,
+            );
+            self.state = SessionState::SessionStateDeinit;
+        }
+    }
+
+    pub fn session_type(&self) -> SessionType {
+        self.session_type
+    }
+
+    /// Return true if the session is a HUS primary session,
+    /// scheduling the phases of a hybrid session.
+    pub fn is_hybrid_primary(&self) -> bool {
+        self.session_type == SessionType::FiraHusPrimarySession
+    }
+
+    /// Return true if the session can be scheduled as a phase
+    /// of a hybrid session.
+    pub fn is_hybrid_phase(&self) -> bool {
+        matches!(
+            self.session_type,
+            SessionType::FiraRangingOnlyPhase
+                | SessionType::FiraInBandDataPhase
+                | SessionType::FiraRangingWithDataPhase
+        )
+    }
+
+    /// Replace the phase list of the HUS primary session.
+    pub fn set_hybrid_phases(&mut self, phases: Vec<HybridPhase>) {
+        self.hybrid_phases = phases;
+    }
+
+    /// Remove a deinitialized phase session from the phase list.
+    pub fn remove_hybrid_phase(&mut self, session_id: u32) {
+        self.hybrid_phases
+            .retain(|phase| phase.session_id != session_id);
+    }
+
+    /// Number of slots in the ranging rounds of the session.
+    pub fn slots_per_rr(&self) -> u8 {
+        self.app_config.slots_per_rr
+    }
+
+    /// Return the ranging control message sent to the controlees
+    /// in the current ranging round, if any.
+    pub fn pending_control(&self) -> Option<RangingControl> {
+        self.pending_control
+            .filter(|_| self.app_config.device_type == DeviceType::Controller)
+    }
+
+    /// Schedule a ranging control message with updated parameters, unless
+    /// the ranging rounds have not started yet.
+    fn reconfigure(&mut self) {
+        if self.state == SessionState::SessionStateActive && self.pending_control.is_none() {
+            self.pending_control = Some(RangingControl::Reconfigure);
+        }
+    }
+
+    /// Update the session state at the end of a ranging round.
+    pub fn end_ranging_round(&mut self) {
+        self.pending_control = None;
+        if self.session_type == SessionType::Ccc {
+            self.app_config.increment_ccc_sts_index();
+        }
+    }
+
+    /// Draw the measurement errors of the session from a generator
+    /// initialized with `seed`, or derived from the simulation seed
+    /// if None.
+    pub fn set_noise_seed(&mut self, seed: Option<u64>) {
+        self.noise_rng = match seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => derived_rng(
+                self.seed,
+                Stream::Measurements,
+                &[self.device_handle as u64, self.id as u64],
+            ),
+        };
+    }
+
+    /// Replace the skew of the local clock, rescheduling the ranging
+    /// rounds of the active session.
+    pub fn set_clock(&mut self, clock: Clock) {
+        self.clock = clock;
+        if self.ranging_task.is_some() {
+            self.start_ranging_task(false);
+        }
+    }
+
+    pub fn noise_rng(&mut self) -> &mut StdRng {
+        &mut self.noise_rng
+    }
+
+    pub fn is_controlee(&self) -> bool {
+        self.app_config.device_type == DeviceType::Controlee
+    }
+
     pub fn get_dst_mac_addresses(&self) -> &Vec<MacAddress> {
         &self.app_config.dst_mac_addresses
     }
@@ -675,10 +1082,43 @@ impl Session {
         self.app_config.rng_data_ntf
     }
 
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
     pub fn session_state(&self) -> SessionState {
         self.state
     }
 
+    pub fn channel_number(&self) -> u8 {
+        if self.session_type == SessionType::RadarSession {
+            self.radar.channel_number()
+        } else {
+            self.app_config.channel_number as u8
+        }
+    }
+
+    pub fn info(&self) -> SessionInfo {
+        SessionInfo {
+            session_id: self.id,
+            session_type: format!("{:?}", self.session_type),
+            state: state_name(self.state),
+            device_type: match self.app_config.device_type {
+                DeviceType::Controlee => "controlee",
+                DeviceType::Controller => "controller",
+            },
+            device_role: match self.app_config.device_role {
+                DeviceRole::Initiator => "initiator",
+                DeviceRole::Responder => "responder",
+            },
+            device_mac_address: self.app_config.device_mac_address,
+            dst_mac_addresses: self.app_config.dst_mac_addresses.clone(),
+            channel_number: self.app_config.channel_number as u8,
+            ranging_interval: self.current_ranging_interval(),
+            sequence_number: self.sequence_number,
+        }
+    }
+
     pub fn init(&mut self) {
         self.set_state(
             SessionState::SessionStateInit,
@@ -687,18 +1127,45 @@ impl Session {
     }
 
     fn command_set_app_config(&mut self, cmd: SessionSetAppConfigCmd) -> SessionSetAppConfigRsp {
-        // TODO properly handle these asserts
-        println!(
-            "[{}:0x{:x}] Session Set App Config",
-            self.device_handle, self.id
-        );
+        info!("Session Set App Config");
         assert_eq!(self.id, cmd.get_session_token());
-        assert!(
-            self.session_type.eq(&SessionType::FiraRangingSession)
-                || self
-                    .session_type
-                    .eq(&SessionType::FiraRangingAndInBandDataSession)
-        );
+        if !matches!(
+            self.session_type,
+            SessionType::FiraRangingSession
+                | SessionType::FiraRangingAndInBandDataSession
+                | SessionType::FiraRangingOnlyPhase
+                | SessionType::FiraInBandDataPhase
+                | SessionType::FiraRangingWithDataPhase
+                | SessionType::FiraHusPrimarySession
+                | SessionType::Ccc
+                | SessionType::RadarSession
+        ) {
+            return SessionSetAppConfigRspBuilder {
+                cfg_status: Vec::new(),
+                status: StatusCode::UciStatusRejected,
+            }
+            .build();
+        }
+
+        // CCC parameters are rejected in FiRa sessions.
+        if self.session_type != SessionType::Ccc {
+            let cfg_status: Vec<_> = cmd
+                .get_tlvs()
+                .iter()
+                .filter(|cfg| CCC_APP_CONFIG_PARAMETERS.contains(&cfg.cfg_id))
+                .map(|cfg| AppConfigStatus {
+                    cfg_id: cfg.cfg_id,
+                    status: StatusCode::UciStatusInvalidParam,
+                })
+                .collect();
+            if !cfg_status.is_empty() {
+                return SessionSetAppConfigRspBuilder {
+                    status: StatusCode::UciStatusInvalidParam,
+                    cfg_status,
+                }
+                .build();
+            }
+        }
 
         if self.state == SessionState::SessionStateActive {
             const IMMUTABLE_PARAMETERS: &[AppConfigTlvType] = &[AppConfigTlvType::AoaResultReq];
@@ -723,7 +1190,15 @@ impl Session {
             let mut app_config = self.app_config.clone();
             let invalid_parameters = app_config.extend(cmd.get_tlvs());
             if invalid_parameters.is_empty() {
+                let schedule = self.app_config.ranging_schedule();
                 self.app_config = app_config;
+                self.reconfigure();
+                // Ranging rounds follow the updated timing from the next block.
+                if self.state == SessionState::SessionStateActive
+                    && self.app_config.ranging_schedule() != schedule
+                {
+                    self.start_ranging_task(false);
+                }
                 if self.state == SessionState::SessionStateInit {
                     self.set_state(
                         SessionState::SessionStateIdle,
@@ -744,10 +1219,7 @@ impl Session {
     }
 
     fn command_get_app_config(&self, cmd: SessionGetAppConfigCmd) -> SessionGetAppConfigRsp {
-        println!(
-            "[{}:0x{:x}] Session Get App Config",
-            self.device_handle, self.id
-        );
+        info!("Session Get App Config");
         assert_eq!(self.id, cmd.get_session_token());
 
         let (status, valid_parameters) = {
@@ -765,7 +1237,7 @@ impl Session {
                                 v: Vec::new(),
                             }),
                         },
-                        Err(_) => println!("Failed to parse AppConfigTlv: {:?}", *config_id),
+                        Err(_) => warn!("Failed to parse AppConfigTlv: {:?}", *config_id),
                     }
                     (valid_parameters, invalid_parameters)
                 },
@@ -784,7 +1256,7 @@ impl Session {
     }
 
     fn command_get_state(&self, cmd: SessionGetStateCmd) -> SessionGetStateRsp {
-        println!("[{}:0x{:x}] Session Get State", self.device_handle, self.id);
+        info!("Session Get State");
         assert_eq!(self.id, cmd.get_session_token());
         SessionGetStateRspBuilder {
             status: StatusCode::UciStatusOk,
@@ -797,10 +1269,7 @@ impl Session {
         &mut self,
         cmd: SessionUpdateControllerMulticastListCmd,
     ) -> SessionUpdateControllerMulticastListRsp {
-        println!(
-            "[{}:0x{:x}] Session Update Controller Multicast List",
-            self.device_handle, self.id
-        );
+        info!("Session Update Controller Multicast List");
         assert_eq!(self.id, cmd.get_session_token());
         if (self.state != SessionState::SessionStateActive
             && self.state != SessionState::SessionStateIdle)
@@ -813,10 +1282,16 @@ impl Session {
             }
             .build();
         }
-        let action = UpdateMulticastListAction::from_u8(cmd.get_action().into()).unwrap();
+        let (Some(action), Ok(packet)) = (
+            UpdateMulticastListAction::from_u8(cmd.get_action().into()),
+            SessionUpdateControllerMulticastListCmdPayload::parse(cmd.get_payload()),
+        ) else {
+            return SessionUpdateControllerMulticastListRspBuilder {
+                status: StatusCode::UciStatusInvalidParam,
+            }
+            .build();
+        };
         let mut dst_addresses = self.app_config.dst_mac_addresses.clone();
-        let packet =
-            SessionUpdateControllerMulticastListCmdPayload::parse(cmd.get_payload()).unwrap();
         let new_controlees = packet.controlees;
         let mut controlee_status = Vec::new();
 
@@ -847,6 +1322,7 @@ impl Session {
                     let pica_tx = self.pica_tx.clone();
                     let address = controlee.short_address;
                     let attempt_count = self.app_config.in_band_termination_attempt_count;
+                    let controller_mac_address = self.app_config.device_mac_address;
                     let mut update_status = MulticastUpdateStatusCode::StatusOkMulticastListUpdate;
                     if !dst_addresses.contains(&MacAddress::Short(address)) {
                         status = StatusCode::UciStatusAddressNotFound;
@@ -862,11 +1338,14 @@ impl Session {
                                 for _ in 0..attempt_count {
                                     pica_tx
                                         .send(PicaCommand::StopRanging(
+                                            controller_mac_address,
                                             MacAddress::Short(address),
                                             session_id,
                                         ))
                                         .await
-                                        .unwrap()
+                                        .unwrap_or_else(|err| {
+                                            warn!("Failed to send stop ranging: {}", err)
+                                        })
                                 }
                             });
                         }
@@ -881,6 +1360,9 @@ impl Session {
         }
         self.app_config.number_of_controlees = dst_addresses.len();
         self.app_config.dst_mac_addresses = dst_addresses.clone();
+        if action == UpdateMulticastListAction::Add {
+            self.reconfigure();
+        }
         // If the multicast list becomes empty, the UWBS shall move the session to
         // SESSION_STATE_IDLE by sending the SESSION_STATUS_NTF with Reason Code
         // set to ERROR_INVALID_NUM_OF_CONTROLEES.
@@ -902,50 +1384,257 @@ impl Session {
                 .into(),
             )
             .await
-            .unwrap()
+            .unwrap_or_else(|err| warn!("Failed to send multicast list notification: {}", err))
         });
         SessionUpdateControllerMulticastListRspBuilder { status }.build()
     }
 
     fn command_range_start(&mut self, cmd: SessionStartCmd) -> SessionStartRsp {
-        println!("[{}:0x{:x}] Range Start", self.device_handle, self.id);
+        info!("Range Start");
         assert_eq!(self.id, cmd.get_session_id());
 
         let status = if self.state != SessionState::SessionStateIdle {
             StatusCode::UciStatusSessionNotConfigured
+        } else if self.is_hybrid_primary() && self.hybrid_phases.is_empty() {
+            // The phases of hybrid sessions must be configured first.
+            StatusCode::UciStatusRejected
+        } else if let Err(reason_code) = self.app_config.check_sts_keys() {
+            // The session stays idle, the reason is reported
+            // in a status notification.
+            self.send_status_notification(reason_code);
+            StatusCode::UciStatusRejected
         } else {
-            assert!(self.ranging_task.is_none());
-            assert_eq!(self.state, SessionState::SessionStateIdle);
-
-            let session_id = self.id;
-            let ranging_interval = self.app_config.ranging_interval;
-            let device_handle = self.device_handle;
-            let tx = self.pica_tx.clone();
-            self.ranging_task = Some(tokio::spawn(async move {
-                loop {
-                    time::sleep(ranging_interval).await;
-                    tx.send(PicaCommand::Ranging(device_handle, session_id))
-                        .await
-                        .unwrap();
-                }
-            }));
+            self.start_ranging_task(true);
             self.set_state(
                 SessionState::SessionStateActive,
                 ReasonCode::StateChangeWithSessionManagementCommands,
             );
+            self.pending_control = Some(RangingControl::Start);
             StatusCode::UciStatusOk
         };
         SessionStartRspBuilder { status }.build()
     }
 
+    /// Spawn the task triggering the ranging rounds, replacing the
+    /// current task if any. The initiation time is only honored when the
+    /// session is starting; otherwise the updated timing applies from the
+    /// next ranging block of the current schedule, so that the rounds
+    /// stay aligned with the block boundaries.
+    fn start_ranging_task(&mut self, starting: bool) {
+        let current_timing = self.ranging_timing.filter(|_| !starting);
+        self.stop_ranging_task();
+        // Radar sessions sense the environment without ranging with peers,
+        // and HUS primary sessions only schedule the ranging of their phases.
+        if self.session_type == SessionType::RadarSession {
+            self.start_radar_task();
+            return;
+        }
+        if self.session_type == SessionType::FiraHusPrimarySession {
+            return;
+        }
+
+        let session_id = self.id;
+        let device_handle = self.device_handle;
+        let now = self.timeline.now();
+        let (start, schedule) = match current_timing {
+            Some((start, current, clock)) => {
+                let elapsed = clock.local_duration(now.saturating_duration_since(start));
+                (
+                    start
+                        + clock
+                            .simulation_duration(current.block_start(current.next_round(elapsed))),
+                    self.app_config.ranging_schedule().without_initiation_time(),
+                )
+            }
+            None => (now, self.app_config.ranging_schedule()),
+        };
+        let tx = self.pica_tx.clone();
+        let clock = self.clock;
+        let timeline = self.timeline.clone();
+        let mut rng = StdRng::from_rng(&mut self.jitter_rng).unwrap();
+        self.ranging_timing = Some((start, schedule, clock));
+        self.ranging_task = Some(tokio::spawn(async move {
+            // Rounds are scheduled from the session start rather than the
+            // previous round, so that processing delays do not accumulate
+            // and the notifications are sent at the end of each round.
+            for round_index in 0.. {
+                timeline
+                    .sleep_until(clock.deadline(start, schedule.round_end(round_index), &mut rng))
+                    .await;
+                if tx
+                    .send(PicaCommand::Ranging(device_handle, session_id))
+                    .await
+                    .is_err()
+                {
+                    // Pica is shutting down.
+                    break;
+                }
+            }
+        }));
+    }
+
+    /// Spawn the task triggering the radar bursts, in place of the
+    /// ranging task.
+    fn start_radar_task(&mut self) {
+        let session_id = self.id;
+        let device_handle = self.device_handle;
+        let start = self.timeline.now();
+        let burst_period = self.radar.burst_period();
+        let number_of_bursts = self.radar.number_of_bursts();
+        let tx = self.pica_tx.clone();
+        let clock = self.clock;
+        let timeline = self.timeline.clone();
+        let mut rng = StdRng::from_rng(&mut self.jitter_rng).unwrap();
+        self.ranging_task = Some(tokio::spawn(async move {
+            for burst_index in 1.. {
+                if number_of_bursts != 0 && burst_index > number_of_bursts as u32 {
+                    break;
+                }
+                timeline
+                    .sleep_until(clock.deadline(start, burst_period * burst_index, &mut rng))
+                    .await;
+                if tx
+                    .send(PicaCommand::RadarBurst(device_handle, session_id))
+                    .await
+                    .is_err()
+                {
+                    // Pica is shutting down.
+                    break;
+                }
+            }
+        }));
+    }
+
+    /// Generate the sweeps of the next radar burst, as sensed from the
+    /// position of the device.
+    pub fn radar_burst(
+        &mut self,
+        position: &Position,
+        scene: &Scene,
+        timestamp_us: u64,
+    ) -> RadarDataRcv {
+        self.radar.burst(self.id, position, scene, timestamp_us)
+    }
+
+    /// Index of the ranging block of the current round, counted from
+    /// the start of the current schedule, or None if not ranging.
+    pub fn ranging_block(&self) -> Option<u32> {
+        let (start, schedule, clock) = self.ranging_timing?;
+        let elapsed = clock.local_duration(self.timeline.now().saturating_duration_since(start));
+        Some(schedule.block_index(elapsed))
+    }
+
+    /// Span of the ranging round of the current block, or None if not
+    /// ranging or if the block is skipped by the block striding.
+    pub fn round_span(&self) -> Option<(time::Instant, time::Instant)> {
+        let (start, schedule, clock) = self.ranging_timing?;
+        let block_index = self.ranging_block()?;
+        if schedule.is_strided(block_index) {
+            return None;
+        }
+        let (round_start, round_end) = schedule.round_span(block_index);
+        Some((
+            start + clock.simulation_duration(round_start),
+            start + clock.simulation_duration(round_end),
+        ))
+    }
+
+    /// Whether the ranging block is skipped by the block striding
+    /// of the session.
+    pub fn is_strided_block(&self, block_index: u32) -> bool {
+        self.app_config.ranging_schedule().is_strided(block_index)
+    }
+
+    /// Return true if the session measures the angle of arrival of
+    /// one-way advertisements rather than ranging with the peers.
+    pub fn is_owr_aoa(&self) -> bool {
+        self.app_config.ranging_round_usage == RangingRoundUsage::OwrAoaMeasurement
+    }
+
+    pub fn is_hopping(&self) -> bool {
+        self.app_config.hopping_mode == HoppingMode::FiraEnable
+    }
+
+    /// Channel and preamble code of the ranging round in the selected
+    /// block: the hop of the block if the hopping mode is enabled, or
+    /// the configured ones.
+    pub fn round_radio(&self, block_index: u32) -> Hop {
+        if self.is_hopping() {
+            hopping::hop(
+                self.seed,
+                self.id,
+                block_index,
+                self.app_config.preamble_code_index,
+            )
+        } else {
+            Hop {
+                channel_number: self.app_config.channel_number as u8,
+                preamble_code_index: self.app_config.preamble_code_index,
+            }
+        }
+    }
+
+    /// Interval between two ranging rounds in milliseconds,
+    /// reported in the ranging notifications.
+    pub fn current_ranging_interval(&self) -> u32 {
+        self.app_config.ranging_schedule().interval().as_millis() as u32
+    }
+
     pub fn stop_ranging_task(&mut self) {
         if let Some(handle) = &self.ranging_task {
             handle.abort();
             self.ranging_task = None;
         }
+        self.ranging_timing = None;
     }
+    pub fn command_radar_set_app_config(
+        &mut self,
+        cmd: AndroidRadarSetAppConfigCmd,
+    ) -> AndroidRadarSetAppConfigRsp {
+        info!("Radar Set App Config");
+        assert_eq!(self.id, cmd.get_session_token());
+
+        let (status, cfg_status) = if self.session_type != SessionType::RadarSession {
+            (StatusCode::UciStatusRejected, Vec::new())
+        } else if self.state == SessionState::SessionStateActive {
+            (StatusCode::UciStatusSessionActive, Vec::new())
+        } else {
+            let cfg_status = self.radar.set_config(cmd.get_tlvs());
+            if cfg_status.is_empty() {
+                if self.state == SessionState::SessionStateInit {
+                    self.set_state(
+                        SessionState::SessionStateIdle,
+                        ReasonCode::StateChangeWithSessionManagementCommands,
+                    );
+                }
+                (StatusCode::UciStatusOk, cfg_status)
+            } else {
+                (StatusCode::UciStatusInvalidParam, cfg_status)
+            }
+        };
+        AndroidRadarSetAppConfigRspBuilder { status, cfg_status }.build()
+    }
+
+    pub fn command_radar_get_app_config(
+        &self,
+        cmd: AndroidRadarGetAppConfigCmd,
+    ) -> AndroidRadarGetAppConfigRsp {
+        info!("Radar Get App Config");
+        assert_eq!(self.id, cmd.get_session_token());
+
+        let (status, tlvs) = if self.session_type != SessionType::RadarSession {
+            (StatusCode::UciStatusRejected, Vec::new())
+        } else {
+            match self.radar.get_config(cmd.get_tlvs()) {
+                Some(tlvs) => (StatusCode::UciStatusOk, tlvs),
+                None => (StatusCode::UciStatusInvalidParam, Vec::new()),
+            }
+        };
+        AndroidRadarGetAppConfigRspBuilder { status, tlvs }.build()
+    }
+
     fn command_range_stop(&mut self, cmd: SessionStopCmd) -> SessionStopRsp {
-        println!("[{}:0x{:x}] Range Stop", self.device_handle, self.id);
+        info!("Range Stop");
         assert_eq!(self.id, cmd.get_session_id());
 
         let status = if self.state != SessionState::SessionStateActive {
@@ -965,10 +1654,7 @@ impl Session {
         &self,
         cmd: SessionGetRangingCountCmd,
     ) -> SessionGetRangingCountRsp {
-        println!(
-            "[{}:0x{:x}] Range Get Ranging Count",
-            self.device_handle, self.id
-        );
+        info!("Range Get Ranging Count");
         assert_eq!(self.id, cmd.get_session_id());
 
         SessionGetRangingCountRspBuilder {
@@ -978,7 +1664,14 @@ impl Session {
         .build()
     }
 
+    /// Span of the logs related to the session, nested in the span of
+    /// the device.
+    fn span(&self) -> Span {
+        info_span!("session", id = format_args!("0x{:x}", self.id))
+    }
+
     pub fn session_command(&mut self, cmd: SessionConfigCommand) -> SessionConfigResponse {
+        let _span = self.span().entered();
         match cmd.specialize() {
             SessionConfigCommandChild::SessionSetAppConfigCmd(cmd) => {
                 self.command_set_app_config(cmd).into()
@@ -997,6 +1690,7 @@ impl Session {
     }
 
     pub fn ranging_command(&mut self, cmd: SessionControlCommand) -> SessionControlResponse {
+        let _span = self.span().entered();
         match cmd.specialize() {
             SessionControlCommandChild::SessionStartCmd(cmd) => {
                 self.command_range_start(cmd).into()
@@ -1009,35 +1703,71 @@ impl Session {
         }
     }
 
-    pub fn data_message_snd(&mut self, data: DataMessageSnd) -> SessionControlNotification {
+    pub fn data_message_snd(&mut self, data: DataMessageSnd) -> Vec<SessionControlNotification> {
+        let _span = self.span().entered();
         let session_token = data.get_session_handle();
         let uci_sequence_number = data.get_data_sequence_number() as u8;
 
         if self.session_type != SessionType::FiraRangingAndInBandDataSession {
-            return DataTransferStatusNtfBuilder {
+            return vec![DataTransferStatusNtfBuilder {
                 session_token,
                 status: DataTransferNtfStatusCode::UciDataTransferStatusSessionTypeNotSupported,
                 tx_count: 1, // TODO: support for retries?
                 uci_sequence_number,
             }
             .build()
-            .into();
+            .into()];
         }
 
         assert_eq!(self.id, session_token);
 
-        // TODO: perform actual data transfer across devices
-        println!(
-            "Data packet received, payload bytes: {:?}",
-            data.get_application_data()
-        );
+        // The host sent a data packet without holding a credit:
+        // the fragment is dropped.
+        if self.data_credits == 0 {
+            info!("Data packet rejected, no credit available");
+            return vec![DataTransferStatusNtfBuilder {
+                session_token,
+                status: DataTransferNtfStatusCode::UciDataTransferStatusErrorNoCreditAvailable,
+                tx_count: 0,
+                uci_sequence_number,
+            }
+            .build()
+            .into()];
+        }
+
+        self.data_credits -= 1;
+        self.data_fragments.push(data);
 
-        DataCreditNtfBuilder {
-            credit_availability: CreditAvailability::CreditAvailable,
-            session_token,
+        // Fragments received during an active session are transmitted
+        // on the next ranging round, otherwise they are sent right away.
+        if self.state == SessionState::SessionStateActive {
+            Vec::new()
+        } else {
+            self.transmit_data()
         }
-        .build()
-        .into()
+    }
+
+    /// Transmit the outstanding data packet fragments, and replenish
+    /// the associated credits.
+    pub fn transmit_data(&mut self) -> Vec<SessionControlNotification> {
+        let _span = self.span().entered();
+        std::mem::take(&mut self.data_fragments)
+            .into_iter()
+            .map(|data| {
+                // TODO: perform actual data transfer across devices
+                debug!(
+                    "Data packet received, payload bytes: {:?}",
+                    data.get_application_data()
+                );
+                self.data_credits = usize::min(self.data_credits + 1, MAX_DATA_CREDITS);
+                DataCreditNtfBuilder {
+                    credit_availability: CreditAvailability::CreditAvailable,
+                    session_token: self.id,
+                }
+                .build()
+                .into()
+            })
+            .collect()
     }
 }
 
@@ -1053,3 +1783,257 @@ impl Drop for Session {
         );
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_config_rejects_malformed_values() {
+        let mut app_config = AppConfig::default();
+        assert_eq!(
+            app_config.set_config(AppConfigTlvType::ChannelNumber, &[]),
+            Err(StatusCode::UciStatusInvalidParam)
+        );
+        assert_eq!(
+            app_config.set_config(AppConfigTlvType::RangingDuration, &[200, 0]),
+            Err(StatusCode::UciStatusInvalidParam)
+        );
+        assert_eq!(
+            app_config.set_config(AppConfigTlvType::RssiReporting, &[2]),
+            Err(StatusCode::UciStatusInvalidParam)
+        );
+        assert_eq!(
+            app_config.set_config(AppConfigTlvType::NoOfControlee, &[255]),
+            Err(StatusCode::UciStatusInvalidParam)
+        );
+        assert_eq!(
+            app_config.set_config(AppConfigTlvType::DstMacAddress, &[0, 1, 2]),
+            Err(StatusCode::UciStatusInvalidParam)
+        );
+    }
+
+    #[test]
+    fn set_config_dst_mac_addresses() {
+        let mut app_config = AppConfig::default();
+        app_config
+            .set_config(AppConfigTlvType::NoOfControlee, &[2])
+            .unwrap();
+        app_config
+            .set_config(AppConfigTlvType::DstMacAddress, &[0, 1, 2, 3])
+            .unwrap();
+        assert_eq!(
+            app_config.dst_mac_addresses,
+            vec![MacAddress::Short([0, 1]), MacAddress::Short([2, 3])]
+        );
+    }
+
+    #[test]
+    fn peer_mismatch() {
+        let mut controller = AppConfig::default();
+        controller
+            .set_config(AppConfigTlvType::DeviceType, &[1])
+            .unwrap();
+        controller
+            .set_config(AppConfigTlvType::DeviceRole, &[1])
+            .unwrap();
+        controller
+            .set_config(AppConfigTlvType::DeviceMacAddress, &[0, 1])
+            .unwrap();
+        controller
+            .set_config(AppConfigTlvType::NoOfControlee, &[1])
+            .unwrap();
+        controller
+            .set_config(AppConfigTlvType::DstMacAddress, &[0, 2])
+            .unwrap();
+        let mut controlee = AppConfig::default();
+        controlee
+            .set_config(AppConfigTlvType::DeviceType, &[0])
+            .unwrap();
+        controlee
+            .set_config(AppConfigTlvType::DeviceRole, &[0])
+            .unwrap();
+        controlee
+            .set_config(AppConfigTlvType::DeviceMacAddress, &[0, 2])
+            .unwrap();
+        controlee
+            .set_config(AppConfigTlvType::NoOfControlee, &[1])
+            .unwrap();
+        controlee
+            .set_config(AppConfigTlvType::DstMacAddress, &[0, 1])
+            .unwrap();
+        assert_eq!(controller.can_start_ranging_with_peer(&controlee), Ok(()));
+
+        controlee
+            .set_config(AppConfigTlvType::PreambleCodeIndex, &[11])
+            .unwrap();
+        assert_eq!(
+            controller.can_start_ranging_with_peer(&controlee),
+            Err(PeerMismatch::PreambleCodeIndex {
+                local: 10,
+                peer: 11
+            })
+        );
+        controlee
+            .set_config(AppConfigTlvType::ChannelNumber, &[5])
+            .unwrap();
+        assert_eq!(
+            controller.can_start_ranging_with_peer(&controlee),
+            Err(PeerMismatch::ChannelNumber { local: 9, peer: 5 })
+        );
+    }
+
+    #[test]
+    fn sts_keys() {
+        let mut app_config = AppConfig::default();
+        assert_eq!(
+            app_config.set_config(AppConfigTlvType::SessionKey, &[1; 8]),
+            Err(StatusCode::UciStatusInvalidParam)
+        );
+        app_config
+            .set_config(AppConfigTlvType::StsConfig, &[3])
+            .unwrap();
+        assert_eq!(
+            app_config.check_sts_keys(),
+            Err(ReasonCode::ErrorStatusSessionKeyNotFound)
+        );
+        app_config
+            .set_config(AppConfigTlvType::SessionKey, &[1; 16])
+            .unwrap();
+        assert_eq!(app_config.check_sts_keys(), Ok(()));
+
+        let mut peer_config = app_config.clone();
+        peer_config
+            .set_config(AppConfigTlvType::SessionKey, &[2; 32])
+            .unwrap();
+        assert!(!app_config.sts_matches(&peer_config));
+        peer_config
+            .set_config(AppConfigTlvType::SessionKey, &[1; 16])
+            .unwrap();
+        assert!(app_config.sts_matches(&peer_config));
+
+        // Controlees with individual keys also need their sub-session.
+        app_config
+            .set_config(AppConfigTlvType::DeviceType, &[0])
+            .unwrap();
+        app_config
+            .set_config(AppConfigTlvType::StsConfig, &[4])
+            .unwrap();
+        assert_eq!(
+            app_config.check_sts_keys(),
+            Err(ReasonCode::ErrorInvalidOrNotFoundSubSessionId)
+        );
+        app_config
+            .set_config(AppConfigTlvType::SubSessionId, &[1, 0, 0, 0])
+            .unwrap();
+        assert_eq!(
+            app_config.check_sts_keys(),
+            Err(ReasonCode::ErrorStatusSubSessionKeyNotFound)
+        );
+        app_config
+            .set_config(AppConfigTlvType::SubsessionKey, &[3; 32])
+            .unwrap();
+        assert_eq!(app_config.check_sts_keys(), Ok(()));
+    }
+
+    #[test]
+    fn session_info() {
+        let (tx, _) = mpsc::channel(1);
+        let (pica_tx, _) = mpsc::channel(1);
+        let mut session = Session::new(
+            1,
+            SessionType::FiraRangingSession,
+            0,
+            tx,
+            pica_tx,
+            Timeline::default(),
+            0,
+        );
+        session
+            .app_config
+            .set_config(AppConfigTlvType::DeviceType, &[1])
+            .unwrap();
+        let info = session.info();
+        assert_eq!(info.session_id, 1);
+        assert_eq!(info.session_type, "FiraRangingSession");
+        assert_eq!(info.state, "deinit");
+        assert_eq!(info.device_type, "controller");
+        assert_eq!(info.channel_number, 9);
+        assert_eq!(info.ranging_interval, 200);
+    }
+
+    #[tokio::test]
+    async fn state_transitions_are_reported() {
+        let (tx, _rx) = mpsc::channel(1);
+        let (pica_tx, mut pica_rx) = mpsc::channel(1);
+        let mut session = Session::new(
+            1,
+            SessionType::FiraRangingSession,
+            2,
+            tx,
+            pica_tx,
+            Timeline::default(),
+            0,
+        );
+        session.init();
+        assert!(matches!(
+            pica_rx.try_recv(),
+            Ok(PicaCommand::SessionUpdated(
+                2,
+                1,
+                SessionState::SessionStateInit,
+                ReasonCode::StateChangeWithSessionManagementCommands
+            ))
+        ));
+        // No transition: nothing is reported.
+        session.init();
+        assert!(pica_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn data_credits() {
+        let (tx, _) = mpsc::channel(1);
+        let (pica_tx, _) = mpsc::channel(1);
+        let mut session = Session::new(
+            1,
+            SessionType::FiraRangingAndInBandDataSession,
+            0,
+            tx,
+            pica_tx,
+            Timeline::default(),
+            0,
+        );
+        session.state = SessionState::SessionStateActive;
+        let data = |data_sequence_number| {
+            DataMessageSndBuilder {
+                application_data: vec![1, 2, 3],
+                data_sequence_number,
+                destination_address: 0,
+                pbf: PacketBoundaryFlag::Complete,
+                session_handle: 1,
+            }
+            .build()
+        };
+
+        // The fragment is held until the next ranging round.
+        assert!(session.data_message_snd(data(0)).is_empty());
+        assert_eq!(session.data_credits, 0);
+
+        // The credits are exhausted: the fragment is dropped.
+        let ntf: ControlPacket = session.data_message_snd(data(1)).remove(0).into();
+        let ntf = DataTransferStatusNtf::try_from(ntf).unwrap();
+        assert_eq!(
+            ntf.get_status(),
+            DataTransferNtfStatusCode::UciDataTransferStatusErrorNoCreditAvailable
+        );
+        assert_eq!(ntf.get_uci_sequence_number(), 1);
+        assert_eq!(session.data_fragments.len(), 1);
+
+        // The credit is returned once the fragment is transmitted.
+        let ntfs = session.transmit_data();
+        assert_eq!(ntfs.len(), 1);
+        assert!(DataCreditNtf::try_from(ControlPacket::from(ntfs[0].clone())).is_ok());
+        assert_eq!(session.data_credits, MAX_DATA_CREDITS);
+        assert!(session.data_fragments.is_empty());
+    }
+}
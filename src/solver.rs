@@ -0,0 +1,110 @@
+// Copyright 2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Multilateration solver estimating the position of a device from
+//! the distances measured to references with known positions.
+
+use glam::{Mat3, Vec3};
+
+/// Minimum number of references required to solve a 3D position.
+pub const MIN_REFERENCES: usize = 4;
+
+const MAX_ITERATIONS: usize = 32;
+/// Stop iterating when the position update is below this distance (cm).
+const CONVERGENCE_THRESHOLD: f32 = 0.01;
+/// Damping factor added to the normal equations, keeps the solver
+/// stable when the references are coplanar.
+const DAMPING: f32 = 1e-3;
+
+/// Estimate a position from `(reference position, measured distance)`
+/// pairs using Gauss-Newton iterations starting from the centroid of
+/// the references. Return `None` when there are not enough references
+/// or the solver does not converge to a finite position.
+pub fn multilaterate(references: &[(Vec3, f32)]) -> Option<Vec3> {
+    if references.len() < MIN_REFERENCES {
+        return None;
+    }
+
+    let centroid = references
+        .iter()
+        .map(|(position, _)| *position)
+        .sum::<Vec3>()
+        / references.len() as f32;
+    // Offset the starting point, the gradient is undefined on a reference.
+    let mut estimate = centroid + Vec3::ONE;
+
+    for _ in 0..MAX_ITERATIONS {
+        let mut jtj = Mat3::ZERO;
+        let mut jtr = Vec3::ZERO;
+        for (position, distance) in references {
+            let delta = estimate - *position;
+            let range = delta.length();
+            if range == 0. {
+                continue;
+            }
+            let gradient = delta / range;
+            let residual = range - distance;
+            jtj += Mat3::from_cols(
+                gradient * gradient.x,
+                gradient * gradient.y,
+                gradient * gradient.z,
+            );
+            jtr += gradient * residual;
+        }
+
+        let jtj = jtj + Mat3::from_diagonal(Vec3::splat(DAMPING));
+        if jtj.determinant() == 0. {
+            return None;
+        }
+        let step = jtj.inverse() * jtr;
+        estimate -= step;
+        if step.length() < CONVERGENCE_THRESHOLD {
+            break;
+        }
+    }
+
+    estimate.is_finite().then_some(estimate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn references(target: Vec3, anchors: &[Vec3]) -> Vec<(Vec3, f32)> {
+        anchors
+            .iter()
+            .map(|anchor| (*anchor, anchor.distance(target)))
+            .collect()
+    }
+
+    #[test]
+    fn not_enough_references() {
+        let anchors = [Vec3::ZERO, Vec3::X * 100., Vec3::Y * 100.];
+        assert!(multilaterate(&references(Vec3::ONE, &anchors)).is_none());
+    }
+
+    #[test]
+    fn exact_distances() {
+        let anchors = [
+            Vec3::new(0., 0., 0.),
+            Vec3::new(500., 0., 0.),
+            Vec3::new(0., 300., 0.),
+            Vec3::new(0., 0., 400.),
+            Vec3::new(500., 300., 400.),
+        ];
+        let target = Vec3::new(120., 80., 250.);
+        let estimate = multilaterate(&references(target, &anchors)).unwrap();
+        assert!(estimate.distance(target) < 1., "{}", estimate);
+    }
+}
@@ -0,0 +1,203 @@
+// Copyright 2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Rolling statistics over the ranging measurements produced for each link.
+
+use serde::Serialize;
+use std::collections::VecDeque;
+
+/// Number of ranging rounds kept in the rolling window of a link.
+pub const STATISTICS_WINDOW_SIZE: usize = 64;
+/// Number of ranging rounds between two link summary events.
+pub const STATISTICS_SUMMARY_PERIOD: usize = 16;
+
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    distance: u16,
+    azimuth: i16,
+    elevation: i8,
+}
+
+/// Rolling window of ranging outcomes for a single (source, destination) link.
+/// Lost measurements are stored as `None` to compute the loss rate.
+#[derive(Debug, Default)]
+pub struct LinkStatistics {
+    window: VecDeque<Option<Sample>>,
+    rounds_since_summary: usize,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct LinkSummary {
+    /// Number of ranging rounds in the window.
+    pub rounds: usize,
+    pub mean_distance: f32,
+    pub median_distance: u16,
+    /// Circular variance of the azimuth (degrees²), the square of the
+    /// circular standard deviation, consistent across the ±180° wrap.
+    pub azimuth_variance: f32,
+    pub elevation_variance: f32,
+    /// Ratio of rounds without a measurement, in [0, 1].
+    pub loss_rate: f32,
+}
+
+fn mean(values: &[f32]) -> f32 {
+    if values.is_empty() {
+        0.
+    } else {
+        values.iter().sum::<f32>() / values.len() as f32
+    }
+}
+
+fn variance(values: &[f32]) -> f32 {
+    let mean = mean(values);
+    let deviations: Vec<f32> = values.iter().map(|value| (value - mean).powi(2)).collect();
+    self::mean(&deviations)
+}
+
+/// Square of the circular standard deviation of the angles (degrees),
+/// computed from the mean resultant length of their unit vectors.
+fn circular_variance(angles: &[f32]) -> f32 {
+    if angles.is_empty() {
+        return 0.;
+    }
+    let sin = mean(
+        &angles
+            .iter()
+            .map(|a| a.to_radians().sin())
+            .collect::<Vec<_>>(),
+    );
+    let cos = mean(
+        &angles
+            .iter()
+            .map(|a| a.to_radians().cos())
+            .collect::<Vec<_>>(),
+    );
+    let resultant_length = sin.hypot(cos).clamp(f32::MIN_POSITIVE, 1.);
+    (-2. * resultant_length.ln()).sqrt().to_degrees().powi(2)
+}
+
+impl LinkStatistics {
+    fn push(&mut self, sample: Option<Sample>) {
+        if self.window.len() == STATISTICS_WINDOW_SIZE {
+            self.window.pop_front();
+        }
+        self.window.push_back(sample);
+        self.rounds_since_summary += 1;
+    }
+
+    /// Record a successful ranging round.
+    pub fn record(&mut self, distance: u16, azimuth: i16, elevation: i8) {
+        self.push(Some(Sample {
+            distance,
+            azimuth,
+            elevation,
+        }))
+    }
+
+    /// Record a ranging round where the peer could not be measured.
+    pub fn record_loss(&mut self) {
+        self.push(None)
+    }
+
+    /// Return the link summary if a summary event is due for this link.
+    pub fn take_summary(&mut self) -> Option<LinkSummary> {
+        if self.rounds_since_summary < STATISTICS_SUMMARY_PERIOD {
+            return None;
+        }
+        self.rounds_since_summary = 0;
+        Some(self.summary())
+    }
+
+    pub fn summary(&self) -> LinkSummary {
+        let samples: Vec<Sample> = self.window.iter().flatten().copied().collect();
+        let mut distances: Vec<u16> = samples.iter().map(|s| s.distance).collect();
+        distances.sort_unstable();
+
+        let azimuths: Vec<f32> = samples.iter().map(|s| s.azimuth as f32).collect();
+        let elevations: Vec<f32> = samples.iter().map(|s| s.elevation as f32).collect();
+
+        LinkSummary {
+            rounds: self.window.len(),
+            mean_distance: mean(&distances.iter().map(|d| *d as f32).collect::<Vec<_>>()),
+            median_distance: distances.get(distances.len() / 2).copied().unwrap_or(0),
+            azimuth_variance: circular_variance(&azimuths),
+            elevation_variance: variance(&elevations),
+            loss_rate: if self.window.is_empty() {
+                0.
+            } else {
+                (self.window.len() - samples.len()) as f32 / self.window.len() as f32
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_link() {
+        let mut link = LinkStatistics::default();
+        assert_eq!(link.summary(), LinkSummary::default());
+        assert!(link.take_summary().is_none());
+    }
+
+    #[test]
+    fn mean_median_and_loss() {
+        let mut link = LinkStatistics::default();
+        link.record(10, 0, 0);
+        link.record(20, 10, 0);
+        link.record(60, -10, 0);
+        link.record_loss();
+
+        let summary = link.summary();
+        assert_eq!(summary.rounds, 4);
+        assert_eq!(summary.mean_distance, 30.);
+        assert_eq!(summary.median_distance, 20);
+        assert!((summary.azimuth_variance - 66.837).abs() < 1e-2);
+        assert_eq!(summary.elevation_variance, 0.);
+        assert_eq!(summary.loss_rate, 0.25);
+    }
+
+    #[test]
+    fn azimuth_wrap() {
+        let mut link = LinkStatistics::default();
+        link.record(100, 179, 0);
+        link.record(100, -179, 0);
+        assert!((link.summary().azimuth_variance - 1.).abs() < 1e-2);
+
+        let mut link = LinkStatistics::default();
+        for _ in 0..STATISTICS_WINDOW_SIZE {
+            link.record(100, 180, 0);
+        }
+        assert!(link.summary().azimuth_variance < 1e-2);
+    }
+
+    #[test]
+    fn rolling_window() {
+        let mut link = LinkStatistics::default();
+        for _ in 0..STATISTICS_WINDOW_SIZE {
+            link.record_loss();
+        }
+        for _ in 0..STATISTICS_WINDOW_SIZE {
+            link.record(100, 0, 0);
+        }
+        let summary = link.summary();
+        assert_eq!(summary.rounds, STATISTICS_WINDOW_SIZE);
+        assert_eq!(summary.loss_rate, 0.);
+        assert_eq!(link.take_summary(), Some(summary));
+        assert!(link.take_summary().is_none());
+    }
+}
@@ -0,0 +1,81 @@
+// Copyright 2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Copy of the UCI traffic exchanged with the hosts, broadcast to the
+//! embedders implementing live protocol analyzers.
+
+use bytes::Bytes;
+use tokio::sync::broadcast;
+
+use crate::timeline::Timeline;
+
+/// Direction of a UCI packet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PacketDirection {
+    /// Command or data packet sent by the host.
+    HostToDevice,
+    /// Response, notification or data packet sent to the host.
+    DeviceToHost,
+}
+
+/// UCI packet segment exchanged with the host of a device, as framed on
+/// the transport, i.e. as written to the capture files.
+#[derive(Clone, Debug)]
+pub struct TappedPacket {
+    pub direction: PacketDirection,
+    pub device_handle: usize,
+    pub bytes: Bytes,
+    /// Simulated time elapsed since the start of pica (µs), the same
+    /// clock as the `monotonic_us` of the events.
+    pub timestamp_us: u64,
+}
+
+/// Traffic tap of the connection of a device.
+pub(crate) struct Tap {
+    device_handle: usize,
+    tx: broadcast::Sender<TappedPacket>,
+    timeline: Timeline,
+}
+
+impl Tap {
+    pub fn new(
+        device_handle: usize,
+        tx: broadcast::Sender<TappedPacket>,
+        timeline: Timeline,
+    ) -> Self {
+        Tap {
+            device_handle,
+            tx,
+            timeline,
+        }
+    }
+
+    /// Return true if the packets are subscribed to. The packets are not
+    /// copied otherwise.
+    pub fn is_active(&self) -> bool {
+        self.tx.receiver_count() > 0
+    }
+
+    pub fn send(&self, direction: PacketDirection, bytes: &[u8]) {
+        if self.is_active() {
+            // An error here means that the last subscriber just left.
+            let _ = self.tx.send(TappedPacket {
+                direction,
+                device_handle: self.device_handle,
+                bytes: Bytes::copy_from_slice(bytes),
+                timestamp_us: self.timeline.elapsed().as_micros() as u64,
+            });
+        }
+    }
+}
@@ -0,0 +1,183 @@
+// Copyright 2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Simulated timeline, on which the ranging rounds, motion updates and
+//! other simulated delays are scheduled. The simulated time follows the
+//! wall-clock time at a selected speed, or only advances when stepped,
+//! so that long scenarios can run within seconds.
+
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::{self, Instant};
+
+/// Progression of the simulated time.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TimeMode {
+    /// The simulated time runs at the selected multiple of the
+    /// wall-clock time.
+    Scaled(f64),
+    /// The simulated time only advances when stepped.
+    Stepped,
+}
+
+impl Default for TimeMode {
+    fn default() -> Self {
+        TimeMode::Scaled(1.0)
+    }
+}
+
+/// Simulated time at a wall-clock instant, from which the current
+/// simulated time is extrapolated.
+#[derive(Clone, Copy, Debug)]
+struct Reference {
+    elapsed: Duration,
+    instant: Instant,
+    mode: TimeMode,
+}
+
+impl Reference {
+    fn elapsed_at(&self, now: Instant) -> Duration {
+        match self.mode {
+            TimeMode::Scaled(speed) => {
+                self.elapsed + now.saturating_duration_since(self.instant).mul_f64(speed)
+            }
+            TimeMode::Stepped => self.elapsed,
+        }
+    }
+}
+
+/// Shared handle to the simulated timeline. Simulated instants are
+/// expressed as offsets from the creation of the timeline, so that
+/// they match the wall-clock instants in the default mode.
+#[derive(Clone, Debug)]
+pub struct Timeline {
+    origin: Instant,
+    reference: Arc<watch::Sender<Reference>>,
+}
+
+impl Default for Timeline {
+    fn default() -> Self {
+        Timeline::new(TimeMode::default())
+    }
+}
+
+impl Timeline {
+    pub fn new(mode: TimeMode) -> Self {
+        let origin = Instant::now();
+        Timeline {
+            origin,
+            reference: Arc::new(
+                watch::channel(Reference {
+                    elapsed: Duration::ZERO,
+                    instant: origin,
+                    mode,
+                })
+                .0,
+            ),
+        }
+    }
+
+    /// Simulated time elapsed since the creation of the timeline.
+    pub fn elapsed(&self) -> Duration {
+        self.reference.borrow().elapsed_at(Instant::now())
+    }
+
+    /// Current simulated instant.
+    pub fn now(&self) -> Instant {
+        self.origin + self.elapsed()
+    }
+
+    /// Change the progression of the simulated time from now on.
+    pub fn set_mode(&self, mode: TimeMode) {
+        let now = Instant::now();
+        self.reference.send_modify(|reference| {
+            *reference = Reference {
+                elapsed: reference.elapsed_at(now),
+                instant: now,
+                mode,
+            }
+        });
+    }
+
+    /// Advance the simulated time by the selected duration. The timers
+    /// expiring within the step fire at the end of the step.
+    pub fn advance(&self, duration: Duration) {
+        let now = Instant::now();
+        self.reference.send_modify(|reference| {
+            reference.elapsed = reference.elapsed_at(now) + duration;
+            reference.instant = now;
+        });
+    }
+
+    /// Wait until the simulated time reaches the deadline.
+    pub async fn sleep_until(&self, deadline: Instant) {
+        let mut reference_rx = self.reference.subscribe();
+        loop {
+            let reference = *reference_rx.borrow_and_update();
+            let now = Instant::now();
+            let remaining =
+                deadline.saturating_duration_since(self.origin + reference.elapsed_at(now));
+            if remaining.is_zero() {
+                return;
+            }
+            match reference.mode {
+                TimeMode::Scaled(speed) => tokio::select! {
+                    _ = time::sleep_until(now + remaining.div_f64(speed)) => (),
+                    _ = reference_rx.changed() => (),
+                },
+                // The sender is owned by the timeline and never dropped
+                // while borrowed.
+                TimeMode::Stepped => {
+                    let _ = reference_rx.changed().await;
+                }
+            }
+        }
+    }
+
+    /// Wait until the selected simulated duration has elapsed.
+    pub async fn sleep(&self, duration: Duration) {
+        self.sleep_until(self.now() + duration).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn stepped() {
+        let timeline = Timeline::new(TimeMode::Stepped);
+        let deadline = timeline.now() + Duration::from_secs(60);
+        let sleep = tokio::spawn({
+            let timeline = timeline.clone();
+            async move { timeline.sleep_until(deadline).await }
+        });
+        timeline.advance(Duration::from_secs(30));
+        tokio::task::yield_now().await;
+        assert!(!sleep.is_finished());
+        timeline.advance(Duration::from_secs(30));
+        sleep.await.unwrap();
+        assert_eq!(timeline.elapsed(), Duration::from_secs(60));
+    }
+
+    #[tokio::test]
+    async fn scaled() {
+        let timeline = Timeline::new(TimeMode::Scaled(1000.0));
+        let start = Instant::now();
+        timeline.sleep(Duration::from_secs(10)).await;
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert!(timeline.elapsed() >= Duration::from_secs(10));
+    }
+}
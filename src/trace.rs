@@ -0,0 +1,248 @@
+// Copyright 2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Human readable trace of the UCI packets exchanged with a device,
+//! one line per packet segment.
+
+use crate::capture::Direction;
+use crate::packets::uci::{
+    CoreOpCode, DataPacketFormat, DeviceState, GroupId, MessageType, SessionConfigOpCode,
+    SessionControlOpCode, StatusCode, TestOpCode,
+};
+use std::path::Path;
+use std::time::Instant;
+use tokio::io::AsyncWriteExt;
+
+pub struct File {
+    file: tokio::fs::File,
+    start_time: Instant,
+}
+
+impl File {
+    pub async fn create<P: AsRef<Path>>(path: P) -> std::io::Result<File> {
+        Ok(File {
+            file: tokio::fs::File::create(path).await?,
+            start_time: Instant::now(),
+        })
+    }
+
+    /// Write the description of a packet segment, prefixed with the time
+    /// elapsed since the file creation in seconds.
+    pub async fn write(&mut self, packet: &[u8], dir: Direction) -> std::io::Result<()> {
+        let line = format!(
+            "{:>12.6} {} {}\n",
+            self.start_time.elapsed().as_secs_f64(),
+            match dir {
+                Direction::Tx => "H>C",
+                Direction::Rx => "C>H",
+            },
+            describe(packet)
+        );
+        self.file.write_all(line.as_bytes()).await?;
+        self.file.flush().await
+    }
+}
+
+fn read_u32(bytes: &[u8]) -> Option<u32> {
+    Some(u32::from_le_bytes(bytes.get(0..4)?.try_into().ok()?))
+}
+
+/// Identifier of the session a packet segment relates to, read from
+/// the payload of the data packets, and of the session commands and
+/// notifications. Only the first segment of a packet carries it.
+pub(crate) fn session_id(packet: &[u8]) -> Option<u32> {
+    let payload = packet.get(4..)?;
+    let opcode = packet[1] & 0x3f;
+    let mt = MessageType::try_from((packet[0] >> 5) & 0x7).ok()?;
+    match (mt, GroupId::try_from(packet[0] & 0xf).ok()) {
+        (MessageType::Data, _) => read_u32(payload),
+        (MessageType::Response, _) => None,
+        // Ranging notifications start with the sequence number.
+        (MessageType::Notification, Some(GroupId::SessionControl))
+            if opcode == SessionControlOpCode::SessionStart as u8 =>
+        {
+            payload.get(4..).and_then(read_u32)
+        }
+        (_, Some(GroupId::SessionConfig))
+            if opcode == SessionConfigOpCode::SessionGetCount as u8 =>
+        {
+            None
+        }
+        (_, Some(GroupId::SessionConfig | GroupId::SessionControl)) => read_u32(payload),
+        _ => None,
+    }
+}
+
+/// Name of the opcode in the selected group.
+fn opcode_name(gid: GroupId, opcode: u8) -> Option<String> {
+    match gid {
+        GroupId::Core => CoreOpCode::try_from(opcode)
+            .ok()
+            .map(|op| format!("{:?}", op)),
+        GroupId::SessionConfig => SessionConfigOpCode::try_from(opcode)
+            .ok()
+            .map(|op| format!("{:?}", op)),
+        GroupId::SessionControl => SessionControlOpCode::try_from(opcode)
+            .ok()
+            .map(|op| format!("{:?}", op)),
+        GroupId::Test => TestOpCode::try_from(opcode)
+            .ok()
+            .map(|op| format!("{:?}", op)),
+        _ => None,
+    }
+}
+
+/// Key fields of a control packet payload: the status of responses,
+/// and the session identifier or device state of commands and
+/// notifications.
+fn key_fields(mt: MessageType, gid: GroupId, opcode: u8, payload: &[u8]) -> Vec<String> {
+    let mut fields = Vec::new();
+    match (mt, gid) {
+        (MessageType::Response, _) => {
+            if let Some(status) = payload.first() {
+                fields.push(match StatusCode::try_from(*status) {
+                    Ok(status) => format!("status={:?}", status),
+                    Err(_) => format!("status=0x{:02x}", status),
+                });
+            }
+        }
+        (MessageType::Notification, GroupId::Core) => match CoreOpCode::try_from(opcode) {
+            Ok(CoreOpCode::CoreDeviceStatusNtf) => {
+                if let Some(Ok(state)) = payload.first().map(|state| DeviceState::try_from(*state))
+                {
+                    fields.push(format!("state={:?}", state));
+                }
+            }
+            Ok(CoreOpCode::CoreGenericErrorNtf) => {
+                if let Some(Ok(status)) =
+                    payload.first().map(|status| StatusCode::try_from(*status))
+                {
+                    fields.push(format!("status={:?}", status));
+                }
+            }
+            _ => (),
+        },
+        // Ranging notifications start with the sequence number.
+        (MessageType::Notification, GroupId::SessionControl)
+            if opcode == SessionControlOpCode::SessionStart as u8 =>
+        {
+            if let (Some(sequence_number), Some(session_id)) =
+                (read_u32(payload), payload.get(4..).and_then(read_u32))
+            {
+                fields.push(format!("sequence_number={}", sequence_number));
+                fields.push(format!("session_id=0x{:x}", session_id));
+            }
+        }
+        (_, GroupId::SessionConfig) if opcode == SessionConfigOpCode::SessionGetCount as u8 => (),
+        (_, GroupId::SessionConfig | GroupId::SessionControl) => {
+            if let Some(session_id) = read_u32(payload) {
+                fields.push(format!("session_id=0x{:x}", session_id));
+            }
+        }
+        _ => (),
+    }
+    fields
+}
+
+/// Describe a UCI packet segment: message type, group and opcode names,
+/// key fields, and the payload in hexadecimal.
+fn describe(packet: &[u8]) -> String {
+    if packet.len() < 4 {
+        return format!("TRUNCATED {}", hex::encode(packet));
+    }
+    let mt = MessageType::try_from((packet[0] >> 5) & 0x7);
+    let segment = if packet[0] & 0x10 != 0 {
+        " (segment)"
+    } else {
+        ""
+    };
+    let payload = &packet[4..];
+    let mut line = match mt {
+        Ok(MessageType::Data) => {
+            let dpf = match DataPacketFormat::try_from(packet[0] & 0xf) {
+                Ok(dpf) => format!("{:?}", dpf),
+                Err(_) => format!("0x{:x}", packet[0] & 0xf),
+            };
+            let mut line = format!("DATA {}", dpf);
+            if let Some(session_id) = read_u32(payload) {
+                line += &format!(" session_id=0x{:x}", session_id);
+            }
+            line
+        }
+        Ok(mt) => {
+            let opcode = packet[1] & 0x3f;
+            let (group, name, fields) = match GroupId::try_from(packet[0] & 0xf) {
+                Ok(gid) => (
+                    format!("{:?}", gid),
+                    opcode_name(gid, opcode).unwrap_or_else(|| format!("0x{:02x}", opcode)),
+                    key_fields(mt, gid, opcode, payload),
+                ),
+                Err(_) => (
+                    format!("0x{:x}", packet[0] & 0xf),
+                    format!("0x{:02x}", opcode),
+                    Vec::new(),
+                ),
+            };
+            let mt = match mt {
+                MessageType::Command => "CMD",
+                MessageType::Response => "RSP",
+                MessageType::Notification => "NTF",
+                _ => "RESERVED",
+            };
+            let mut line = format!("{} {} {}", mt, group, name);
+            for field in fields {
+                line += " ";
+                line += &field;
+            }
+            line
+        }
+        Err(_) => format!("MT 0x{:x}", (packet[0] >> 5) & 0x7),
+    };
+    line += &format!("{} payload={}", segment, hex::encode(payload));
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn describe_control_packets() {
+        assert_eq!(
+            describe(&[0x21, 0x00, 0x00, 0x05, 0x01, 0x00, 0x00, 0x00, 0x00]),
+            "CMD SessionConfig SessionInit session_id=0x1 payload=0100000000"
+        );
+        assert_eq!(
+            describe(&[0x41, 0x00, 0x00, 0x01, 0x00]),
+            "RSP SessionConfig SessionInit status=UciStatusOk payload=00"
+        );
+        assert_eq!(
+            describe(&[0x60, 0x01, 0x00, 0x01, 0x01]),
+            "NTF Core CoreDeviceStatusNtf state=DeviceStateReady payload=01"
+        );
+        assert_eq!(
+            describe(&[0x29, 0x3f, 0x00, 0x00]),
+            "CMD VendorReserved9 0x3f payload="
+        );
+    }
+
+    #[test]
+    fn describe_data_packets() {
+        assert_eq!(
+            describe(&[0x01, 0x00, 0x04, 0x00, 0x02, 0x00, 0x00, 0x00]),
+            "DATA DataSnd session_id=0x2 payload=02000000"
+        );
+        assert_eq!(describe(&[0x01, 0x00]), "TRUNCATED 0100");
+    }
+}
@@ -0,0 +1,404 @@
+// Copyright 2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Timestamped trajectories recorded from real motions, e.g. walks
+//! captured by a motion-capture system or a GPS logger, and played back
+//! by the nodes of the simulation.
+//!
+//! The trajectories are loaded from CSV files, with a header naming the
+//! columns `time,x,y,z` (cm) or `time,latitude,longitude,altitude`, or
+//! from the track points of GPX files. The time is either in seconds or
+//! an RFC 3339 timestamp.
+
+use std::path::Path;
+use std::time::Duration;
+
+use glam::Vec3;
+use thiserror::Error;
+
+use crate::{GeodeticPosition, LengthUnit, Position, TangentPlane};
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Trajectory has no samples")]
+    NoSamples,
+    #[error("Timestamp of sample {0} is earlier than the previous sample")]
+    NotMonotonic(usize),
+    #[error("Line {0}: {1}")]
+    Csv(usize, String),
+    #[cfg(feature = "gpx")]
+    #[error("Invalid GPX file: {0}")]
+    Gpx(String),
+    #[error("Unsupported trajectory format: {0}")]
+    UnsupportedFormat(String),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+/// Locations of a node at increasing times, played back from the first
+/// sample with linear interpolation. The node stays at the last sample
+/// when the playback is over.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecordedTrajectory {
+    /// Time from the first sample and location (cm) of each sample.
+    samples: Vec<(Duration, Vec3)>,
+}
+
+impl RecordedTrajectory {
+    /// Create a trajectory from the locations of the node, timestamped
+    /// from the start of the playback.
+    pub fn new(samples: &[(Duration, Position)]) -> Result<Self, Error> {
+        if samples.is_empty() {
+            return Err(Error::NoSamples);
+        }
+        if let Some(index) = samples.windows(2).position(|pair| pair[1].0 < pair[0].0) {
+            return Err(Error::NotMonotonic(index + 1));
+        }
+        let start = samples[0].0;
+        Ok(RecordedTrajectory {
+            samples: samples
+                .iter()
+                .map(|(time, position)| (*time - start, position.translation()))
+                .collect(),
+        })
+    }
+
+    /// Load a trajectory from a CSV or GPX file, see [`Self::parse`].
+    pub fn load<P: AsRef<Path>>(
+        path: P,
+        tangent_plane: Option<&TangentPlane>,
+    ) -> Result<Self, Error> {
+        Self::parse(&std::fs::read_to_string(path)?, tangent_plane)
+    }
+
+    /// Parse the content of a GPX file, starting with an XML tag, or of a
+    /// CSV file otherwise. The geographic coordinates are converted to the
+    /// selected tangent plane, or to the tangent plane at the first sample
+    /// if None.
+    pub fn parse(text: &str, tangent_plane: Option<&TangentPlane>) -> Result<Self, Error> {
+        if !text.trim_start().starts_with('<') {
+            return Self::from_csv(text, tangent_plane);
+        }
+        #[cfg(feature = "gpx")]
+        return Self::from_gpx(text, tangent_plane);
+        #[cfg(not(feature = "gpx"))]
+        Err(Error::UnsupportedFormat(
+            "GPX requires the gpx feature".to_owned(),
+        ))
+    }
+
+    /// Parse the samples of a CSV file.
+    pub fn from_csv(text: &str, tangent_plane: Option<&TangentPlane>) -> Result<Self, Error> {
+        let mut lines = text
+            .lines()
+            .enumerate()
+            .map(|(index, line)| (index + 1, line.trim()))
+            .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'));
+        let Some((line_number, header)) = lines.next() else {
+            return Err(Error::NoSamples);
+        };
+        let columns: Vec<_> = header.split(',').map(str::trim).collect();
+        let geodetic = match columns[..] {
+            ["time", "x", "y", "z"] => false,
+            ["time", "latitude", "longitude", "altitude"] => true,
+            _ => {
+                return Err(Error::Csv(
+                    line_number,
+                    format!("unexpected columns '{}'", header),
+                ))
+            }
+        };
+
+        let mut timestamps = Vec::new();
+        let mut coordinates = Vec::new();
+        for (line_number, line) in lines {
+            let fields: Vec<_> = line.split(',').map(str::trim).collect();
+            let [time, a, b, c] = fields[..] else {
+                return Err(Error::Csv(line_number, "expected 4 fields".to_owned()));
+            };
+            let number = |field: &str| {
+                field
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|value| value.is_finite())
+                    .ok_or_else(|| Error::Csv(line_number, format!("invalid number '{}'", field)))
+            };
+            timestamps.push(
+                parse_time(time)
+                    .ok_or_else(|| Error::Csv(line_number, format!("invalid time '{}'", time)))?,
+            );
+            coordinates.push((number(a)?, number(b)?, number(c)?));
+        }
+
+        let positions: Vec<_> = if geodetic {
+            let geodetic_positions: Vec<_> = coordinates
+                .into_iter()
+                .map(|(latitude, longitude, altitude)| GeodeticPosition {
+                    latitude,
+                    longitude,
+                    altitude,
+                })
+                .collect();
+            to_local(&geodetic_positions, tangent_plane)
+        } else {
+            coordinates
+                .into_iter()
+                .map(|(x, y, z)| {
+                    Position::default().with_coordinates_in(x, y, z, LengthUnit::Centimeter)
+                })
+                .collect()
+        };
+        Self::from_timestamps(&timestamps, &positions)
+    }
+
+    /// Parse the track points of a GPX file, in order of appearance.
+    #[cfg(feature = "gpx")]
+    pub fn from_gpx(text: &str, tangent_plane: Option<&TangentPlane>) -> Result<Self, Error> {
+        let document =
+            roxmltree::Document::parse(text).map_err(|err| Error::Gpx(err.to_string()))?;
+        let mut timestamps = Vec::new();
+        let mut geodetic_positions = Vec::new();
+        for point in document
+            .descendants()
+            .filter(|node| node.has_tag_name("trkpt"))
+        {
+            let attribute = |name: &str| {
+                point
+                    .attribute(name)
+                    .and_then(|value| value.parse::<f64>().ok())
+                    .ok_or_else(|| Error::Gpx(format!("track point without {}", name)))
+            };
+            let child = |name: &str| {
+                point
+                    .children()
+                    .find(|node| node.has_tag_name(name))
+                    .and_then(|node| node.text())
+                    .map(str::trim)
+            };
+            let time = child("time")
+                .and_then(parse_time)
+                .ok_or_else(|| Error::Gpx("track point without a valid time".to_owned()))?;
+            // The elevation is optional in GPX files.
+            let altitude = match child("ele") {
+                Some(elevation) => elevation
+                    .parse::<f64>()
+                    .map_err(|_| Error::Gpx(format!("invalid elevation '{}'", elevation)))?,
+                None => 0.0,
+            };
+            timestamps.push(time);
+            geodetic_positions.push(GeodeticPosition {
+                latitude: attribute("lat")?,
+                longitude: attribute("lon")?,
+                altitude,
+            });
+        }
+        let positions = to_local(&geodetic_positions, tangent_plane);
+        Self::from_timestamps(&timestamps, &positions)
+    }
+
+    fn from_timestamps(timestamps: &[f64], positions: &[Position]) -> Result<Self, Error> {
+        let Some(first) = timestamps.first() else {
+            return Err(Error::NoSamples);
+        };
+        let samples: Vec<_> = timestamps
+            .iter()
+            .zip(positions)
+            .map(|(time, position)| (Duration::from_secs_f64((time - first).max(0.0)), *position))
+            .collect();
+        match timestamps.windows(2).position(|pair| pair[1] < pair[0]) {
+            Some(index) => Err(Error::NotMonotonic(index + 1)),
+            None => Self::new(&samples),
+        }
+    }
+
+    /// Duration of the playback.
+    pub fn duration(&self) -> Duration {
+        self.samples[self.samples.len() - 1].0
+    }
+
+    /// Location and velocity (cm/s) of the node after playing the
+    /// trajectory for the selected time.
+    pub(crate) fn locate(&self, elapsed: Duration) -> (Vec3, Vec3) {
+        let next = self.samples.partition_point(|(time, _)| *time <= elapsed);
+        if next == 0 {
+            return (self.samples[0].1, Vec3::ZERO);
+        }
+        if next == self.samples.len() {
+            return (self.samples[next - 1].1, Vec3::ZERO);
+        }
+        let (start_time, start) = self.samples[next - 1];
+        let (end_time, end) = self.samples[next];
+        let interval = (end_time - start_time).as_secs_f32();
+        let ratio = (elapsed - start_time).as_secs_f32() / interval;
+        (start.lerp(end, ratio), (end - start) / interval)
+    }
+}
+
+/// Convert geographic coordinates to the tangent plane, or to the tangent
+/// plane at the first position if None.
+fn to_local(positions: &[GeodeticPosition], tangent_plane: Option<&TangentPlane>) -> Vec<Position> {
+    let Some(first) = positions.first() else {
+        return vec![];
+    };
+    let tangent_plane = tangent_plane
+        .copied()
+        .unwrap_or_else(|| TangentPlane::new(*first));
+    positions
+        .iter()
+        .map(|position| tangent_plane.to_local(position))
+        .collect()
+}
+
+/// Parse a time in seconds, or an RFC 3339 timestamp converted to seconds
+/// from the Unix epoch.
+fn parse_time(time: &str) -> Option<f64> {
+    time.parse::<f64>()
+        .ok()
+        .filter(|time| time.is_finite())
+        .or_else(|| parse_rfc3339(time))
+}
+
+/// Parse an RFC 3339 timestamp, e.g. `2024-05-01T10:15:30.5Z`.
+fn parse_rfc3339(timestamp: &str) -> Option<f64> {
+    let (date, time) = timestamp.split_once(['T', 't', ' '])?;
+    let mut date = date.splitn(3, '-').map(|field| field.parse::<i64>().ok());
+    let (year, month, day) = (date.next()??, date.next()??, date.next()??);
+
+    // The UTC offset follows the seconds: Z, +hh:mm or -hh:mm.
+    let (time, offset) = if let Some(time) = time.strip_suffix(['Z', 'z']) {
+        (time, 0)
+    } else {
+        let split = time.rfind(['+', '-'])?;
+        let (time, offset) = time.split_at(split);
+        let sign = if offset.starts_with('-') { -1 } else { 1 };
+        let (hours, minutes) = offset[1..].split_once(':')?;
+        (
+            time,
+            sign * (hours.parse::<i64>().ok()? * 3600 + minutes.parse::<i64>().ok()? * 60),
+        )
+    };
+    let mut time = time.splitn(3, ':');
+    let hours = time.next()?.parse::<i64>().ok()?;
+    let minutes = time.next()?.parse::<i64>().ok()?;
+    let seconds = time.next()?.parse::<f64>().ok()?;
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    // Days from the epoch in the proleptic Gregorian calendar.
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+
+    Some((days * 86_400 + hours * 3600 + minutes * 60 - offset) as f64 + seconds)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timestamps() {
+        assert_eq!(parse_time("12.5"), Some(12.5));
+        assert_eq!(parse_time("1970-01-01T00:00:00Z"), Some(0.0));
+        assert_eq!(parse_time("2024-05-01T10:15:30.5Z"), Some(1_714_558_530.5));
+        assert_eq!(
+            parse_time("2024-05-01T12:15:30.5+02:00"),
+            Some(1_714_558_530.5)
+        );
+        assert_eq!(parse_time("2024-13-01T00:00:00Z"), None);
+        assert_eq!(parse_time("yesterday"), None);
+    }
+
+    #[test]
+    fn csv_trajectory() {
+        let trajectory = RecordedTrajectory::from_csv(
+            "# Walk along the x axis\ntime,x,y,z\n10,0,0,0\n12,200,0,0\n13,200,100,0\n",
+            None,
+        )
+        .unwrap();
+        assert_eq!(trajectory.duration(), Duration::from_secs(3));
+        assert_eq!(
+            trajectory.locate(Duration::from_secs(1)),
+            (Vec3::new(100.0, 0.0, 0.0), Vec3::new(100.0, 0.0, 0.0))
+        );
+        assert_eq!(
+            trajectory.locate(Duration::from_millis(2500)),
+            (Vec3::new(200.0, 50.0, 0.0), Vec3::new(0.0, 100.0, 0.0))
+        );
+        assert_eq!(
+            trajectory.locate(Duration::from_secs(10)),
+            (Vec3::new(200.0, 100.0, 0.0), Vec3::ZERO)
+        );
+
+        assert!(matches!(
+            RecordedTrajectory::from_csv("time,x,y,z\n", None),
+            Err(Error::NoSamples)
+        ));
+        assert!(matches!(
+            RecordedTrajectory::from_csv("time,x,y,z\n1,0,0,0\n0,0,0,0\n", None),
+            Err(Error::NotMonotonic(1))
+        ));
+        assert!(matches!(
+            RecordedTrajectory::from_csv("time,x,y\n1,0,0\n", None),
+            Err(Error::Csv(1, _))
+        ));
+        assert!(matches!(
+            RecordedTrajectory::from_csv("time,x,y,z\n1,0,zero,0\n", None),
+            Err(Error::Csv(2, _))
+        ));
+    }
+
+    #[test]
+    fn geodetic_csv_trajectory() {
+        // About 11.1 m north of the first sample, in 10 s.
+        let trajectory = RecordedTrajectory::from_csv(
+            "time,latitude,longitude,altitude\n\
+             2024-05-01T10:00:00Z,0.0,0.0,0.0\n\
+             2024-05-01T10:00:10Z,0.0001,0.0,0.0\n",
+            None,
+        )
+        .unwrap();
+        assert_eq!(trajectory.duration(), Duration::from_secs(10));
+        let (end, _) = trajectory.locate(Duration::from_secs(10));
+        assert!(end.x.abs() < 0.1 && (end.z - 1105.7).abs() < 1.0, "{}", end);
+    }
+
+    #[cfg(feature = "gpx")]
+    #[test]
+    fn gpx_trajectory() {
+        let gpx = r#"<?xml version="1.0"?>
+            <gpx version="1.1" xmlns="http://www.topografix.com/GPX/1/1">
+              <trk><trkseg>
+                <trkpt lat="0.0" lon="0.0"><ele>10</ele>
+                  <time>2024-05-01T10:00:00Z</time></trkpt>
+                <trkpt lat="0.0" lon="0.0001"><ele>10</ele>
+                  <time>2024-05-01T10:00:05Z</time></trkpt>
+              </trkseg></trk>
+            </gpx>"#;
+        let trajectory = RecordedTrajectory::from_gpx(gpx, None).unwrap();
+        assert_eq!(trajectory.duration(), Duration::from_secs(5));
+        let (end, velocity) = trajectory.locate(Duration::from_secs(4));
+        assert!((velocity.x - 222.6).abs() < 1.0, "{}", velocity);
+        assert!((end.x - 890.6).abs() < 1.0, "{}", end);
+
+        assert!(matches!(
+            RecordedTrajectory::from_gpx("<gpx><trk>", None),
+            Err(Error::Gpx(_))
+        ));
+    }
+}
@@ -0,0 +1,1748 @@
+// Copyright 2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::convert::Infallible;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{body, Body, Request, Response, Server, StatusCode as HttpStatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::error::Category as SerdeErrorCategory;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio_stream::{wrappers::BroadcastStream, StreamExt};
+use tracing::{debug, info, warn};
+use tracing_subscriber::{reload, EnvFilter, Registry};
+
+use crate::Pica;
+use crate::{
+    AoaCapability, Category, Clock, Constellation, CrashRecovery, EventFilter, FieldOfView,
+    GeodeticPosition, JitterDistribution, Kinematics, LengthUnit, LinkSummary, MacAddress,
+    MotionPath, NotificationLatency, Obstacle, PathMode, PicaCommand, PicaCommandError,
+    PicaCommandStatus, PicaEvent, Position, RecordedTrajectory, ResponseAction, ResponseFault,
+    Scene, SequencedEvent, SessionInfo, Shape, TangentPlane, TimeMode, Zone, EVENT_VERSION,
+    MAX_DRIFT_PPM,
+};
+
+/// Handle replacing the filter of the logs, changed with the
+/// `set-log-filter` route.
+pub type LogFilter = reload::Handle<EnvFilter, Registry>;
+
+const STATIC_FILES: &[(&str, &str, &str)] = &[
+    ("/", "text/html", include_str!("../static/index.html")),
+    (
+        "/openapi",
+        "text/html",
+        include_str!("../static/openapi.html"),
+    ),
+    (
+        "/openapi.yaml",
+        "text/yaml",
+        include_str!("../static/openapi.yaml"),
+    ),
+    (
+        "/src/components/Map.js",
+        "application/javascript",
+        include_str!("../static/src/components/Map.js"),
+    ),
+    (
+        "/src/components/DeviceInfo.js",
+        "application/javascript",
+        include_str!("../static/src/components/DeviceInfo.js"),
+    ),
+    (
+        "/src/components/Orientation.js",
+        "application/javascript",
+        include_str!("../static/src/components/Orientation.js"),
+    ),
+];
+
+/// Coordinates of a position: Cartesian coordinates (cm), or WGS84
+/// coordinates converted to the tangent plane at the geodetic origin.
+#[derive(Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(untagged)]
+enum Coordinates {
+    Cartesian { x: f64, y: f64, z: f64 },
+    Geodetic(GeodeticPosition),
+}
+
+#[derive(Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+struct PositionBody {
+    #[serde(flatten)]
+    coordinates: Coordinates,
+    yaw: i16,
+    pitch: i8,
+    roll: i16,
+}
+
+/// Payload of the events sent to the event stream: the event fields,
+/// completed with the timestamps of the event, and the version of the
+/// encoding unless the untagged encoding is selected.
+#[derive(Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+struct EventBody<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version: Option<u32>,
+    monotonic_us: u64,
+    timestamp_ms: u64,
+    #[serde(flatten)]
+    event: &'a PicaEvent,
+}
+
+/// Error payload returned by the failed commands.
+#[derive(Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+struct ErrorBody {
+    /// Identifier of the error kind.
+    error: &'static str,
+    /// Human readable description of the error.
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mac_address: Option<MacAddress>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    session_id: Option<u32>,
+}
+
+fn error_response(err: PicaCommandError) -> Response<Body> {
+    let (status, error, mac_address, session_id) = match err {
+        PicaCommandError::DeviceAlreadyExists(mac_address) => (
+            HttpStatusCode::CONFLICT,
+            "device-already-exists",
+            Some(mac_address),
+            None,
+        ),
+        PicaCommandError::DeviceNotFound(mac_address) => (
+            HttpStatusCode::NOT_FOUND,
+            "device-not-found",
+            Some(mac_address),
+            None,
+        ),
+        PicaCommandError::SessionNotFound(mac_address, session_id) => (
+            HttpStatusCode::NOT_FOUND,
+            "session-not-found",
+            Some(mac_address),
+            Some(session_id),
+        ),
+        PicaCommandError::InvalidPosition(_) => (
+            HttpStatusCode::NOT_ACCEPTABLE,
+            "invalid-position",
+            None,
+            None,
+        ),
+        PicaCommandError::InvalidMacFormat(_) => (
+            HttpStatusCode::NOT_ACCEPTABLE,
+            "invalid-mac-format",
+            None,
+            None,
+        ),
+        PicaCommandError::LimitExceeded(_, _) => {
+            (HttpStatusCode::CONFLICT, "limit-exceeded", None, None)
+        }
+        PicaCommandError::InvalidArgument(_) => (
+            HttpStatusCode::NOT_ACCEPTABLE,
+            "invalid-argument",
+            None,
+            None,
+        ),
+    };
+    let body = ErrorBody {
+        error,
+        message: err.to_string(),
+        mac_address,
+        session_id,
+    };
+    Response::builder()
+        .status(status)
+        .header("content-type", "application/json")
+        .body(serde_json::to_string(&body).unwrap().into())
+        .unwrap()
+}
+
+/// Return the error response of a command rejected before being sent.
+macro_rules! reject {
+    ($err: expr) => {{
+        let err = $err;
+        warn!("Rejected web command: {}", err);
+        return Ok(error_response(err));
+    }};
+}
+
+macro_rules! position {
+    ($body: ident, $tangent_plane: ident) => {
+        position!($body, $tangent_plane, false)
+    };
+    ($body: ident, $tangent_plane: ident, $mandatory: ident) => {
+        match serde_json::from_slice::<PositionBody>(&$body) {
+            Ok(body) if !(-180..=180).contains(&body.yaw) => reject!(
+                PicaCommandError::InvalidPosition(format!("yaw {} out of range", body.yaw))
+            ),
+            Ok(body) if !(-90..=90).contains(&body.pitch) => reject!(
+                PicaCommandError::InvalidPosition(format!("pitch {} out of range", body.pitch))
+            ),
+            Ok(body) if !(-180..=180).contains(&body.roll) => reject!(
+                PicaCommandError::InvalidPosition(format!("roll {} out of range", body.roll))
+            ),
+            Ok(body) => {
+                let orientation = Position::new(0, 0, 0, body.yaw, body.pitch, body.roll);
+                let (coordinates, unit) = match (body.coordinates, $tangent_plane.as_ref()) {
+                    (Coordinates::Cartesian { x, y, z }, _) => ((x, y, z), LengthUnit::Centimeter),
+                    (Coordinates::Geodetic(geodetic), Some(tangent_plane)) => (
+                        tangent_plane
+                            .to_local(&geodetic)
+                            .coordinates(LengthUnit::Meter),
+                        LengthUnit::Meter,
+                    ),
+                    (Coordinates::Geodetic(_), None) => reject!(PicaCommandError::InvalidPosition(
+                        "geodetic coordinates without a geodetic origin".to_owned()
+                    )),
+                };
+                let (x, y, z) = coordinates;
+                orientation.with_coordinates_in(x, y, z, unit)
+            }
+            Err(err) => {
+                if !$mandatory && err.classify() == SerdeErrorCategory::Eof {
+                    Position::default()
+                } else {
+                    reject!(PicaCommandError::InvalidPosition(err.to_string()))
+                }
+            }
+        }
+    };
+}
+
+macro_rules! mac_address {
+    ($mac_address: ident) => {
+        match $mac_address.parse::<MacAddress>() {
+            Ok(mac_address) => mac_address,
+            Err(_) => reject!(PicaCommandError::InvalidMacFormat($mac_address.to_string())),
+        }
+    };
+}
+
+macro_rules! session_id {
+    ($session_id: ident) => {
+        match $session_id.parse::<u32>() {
+            Ok(session_id) => session_id,
+            Err(_) => reject!(PicaCommandError::InvalidArgument(format!(
+                "session_id {}",
+                $session_id
+            ))),
+        }
+    };
+}
+
+#[derive(Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+struct SeedBody {
+    seed: Option<u64>,
+}
+
+#[derive(Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+struct TimeModeBody {
+    #[serde(default)]
+    stepped: bool,
+    speed: Option<f64>,
+}
+
+#[derive(Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+struct AdvanceTimeBody {
+    duration_ms: f64,
+}
+
+#[derive(Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+struct OrientationBody {
+    yaw: i16,
+    pitch: i8,
+    roll: i16,
+}
+
+#[derive(Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+struct FieldOfViewBody {
+    azimuth: u8,
+    elevation: u8,
+}
+
+#[derive(Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+struct AoaCapabilityBody {
+    aoa: AoaCapability,
+}
+
+#[derive(Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+struct ClockBody {
+    #[serde(default)]
+    offset_us: u64,
+    #[serde(default)]
+    drift_ppm: f64,
+    #[serde(default)]
+    jitter_us: u32,
+}
+
+#[derive(Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+struct TxPowerBody {
+    /// Mean EIRP of the transmitted frames (dBm/MHz).
+    tx_power: Option<f32>,
+}
+
+#[derive(Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+struct LatencyBody {
+    delay_ms: f64,
+    #[serde(default)]
+    jitter_ms: f64,
+    #[serde(default)]
+    distribution: JitterDistribution,
+}
+
+#[derive(Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+struct ConstellationBody {
+    #[serde(flatten)]
+    shape: Shape,
+    count: u16,
+    /// Origin of the constellation in the horizontal plane (cm).
+    #[serde(default)]
+    x: i16,
+    #[serde(default)]
+    z: i16,
+    /// Height of the anchors (cm).
+    #[serde(default)]
+    height: i16,
+}
+
+#[derive(Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+struct InjectPacketBody {
+    /// Hexadecimal encoding of the bytes.
+    packet: String,
+}
+
+#[derive(Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+struct ResponseFaultBody {
+    gid: u8,
+    opcode: u8,
+    nth: u32,
+    /// Delay of the response (ms), or None to withhold the response.
+    delay_ms: Option<f64>,
+}
+
+#[derive(Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+struct ResponseFaultsBody {
+    faults: Vec<ResponseFaultBody>,
+}
+
+#[derive(Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+struct CrashBody {
+    #[serde(default)]
+    recovery: CrashRecovery,
+}
+
+#[derive(Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+struct PointBody {
+    x: i16,
+    y: i16,
+    z: i16,
+}
+
+#[derive(Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+struct PathBody {
+    waypoints: Vec<PointBody>,
+    speed: f32,
+    #[serde(default)]
+    mode: PathMode,
+}
+
+#[derive(Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+struct ObstacleBody {
+    min: PointBody,
+    max: PointBody,
+    #[serde(default)]
+    bias: u16,
+}
+
+#[derive(Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+struct SceneBody {
+    obstacles: Vec<ObstacleBody>,
+}
+
+#[derive(Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+struct ZoneBody {
+    name: String,
+    min: PointBody,
+    max: PointBody,
+}
+
+#[derive(Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+struct ZonesBody {
+    zones: Vec<ZoneBody>,
+}
+
+#[derive(Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+struct AnchorRangingBody {
+    /// Interval between the ranging rounds, in milliseconds.
+    #[serde(default = "default_ranging_interval")]
+    ranging_interval: u32,
+}
+
+fn default_ranging_interval() -> u32 {
+    200
+}
+
+#[derive(Debug, Serialize, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+struct Device {
+    pub category: Category,
+    pub mac_address: String,
+    #[serde(flatten)]
+    pub position: Position,
+    /// Sessions of UCI devices, omitted for anchors.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sessions: Option<Vec<SessionInfo>>,
+    /// Endpoint of UCI devices, omitted for anchors.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub origin: Option<String>,
+}
+
+#[derive(Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+struct GetStateResponse {
+    sequence_number: u64,
+    devices: Vec<Device>,
+}
+
+#[derive(Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+struct Link {
+    source_mac_address: String,
+    destination_mac_address: String,
+    #[serde(flatten)]
+    summary: LinkSummary,
+}
+
+#[derive(Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+struct GetLinkStatisticsResponse {
+    links: Vec<Link>,
+}
+
+/// Format an event as an entry of the event stream.
+fn event_stream_entry(
+    SequencedEvent {
+        sequence_number,
+        monotonic_us,
+        timestamp_ms,
+        event,
+    }: SequencedEvent,
+    untagged_events: bool,
+) -> String {
+    let mut data = serde_json::to_value(EventBody {
+        version: (!untagged_events).then_some(EVENT_VERSION),
+        monotonic_us,
+        timestamp_ms,
+        event: &event,
+    })
+    .unwrap();
+    if untagged_events {
+        data.as_object_mut().unwrap().remove("kind");
+    }
+    format!(
+        "id: {}\nevent: {}\ndata: {}\n\n",
+        sequence_number,
+        event.name(),
+        data
+    )
+}
+
+/// Sequence number after which the events are replayed to a new subscriber,
+/// selected with the `Last-Event-ID` header set by reconnecting event
+/// sources, or the `since` query parameter.
+fn replay_from(req: &Request<Body>) -> Option<u64> {
+    req.headers()
+        .get("last-event-id")
+        .and_then(|value| value.to_str().ok())
+        .or_else(|| {
+            req.uri()
+                .query()?
+                .split('&')
+                .find_map(|param| param.strip_prefix("since="))
+        })
+        .and_then(|value| value.parse().ok())
+}
+
+/// Whether a new subscriber requested a snapshot of the scene before
+/// the live events, with the `snapshot=true` query parameter.
+fn snapshot_requested(req: &Request<Body>) -> bool {
+    req.uri()
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .any(|param| param == "snapshot=true")
+}
+
+/// Selection of the events sent to a new subscriber, from the `kind`
+/// and `mac-address` query parameters, each a comma separated list.
+fn event_filter(req: &Request<Body>) -> Result<EventFilter, PicaCommandError> {
+    let mut filter = EventFilter::default();
+    let params = req.uri().query().unwrap_or_default().split('&');
+    for (name, value) in params.filter_map(|param| param.split_once('=')) {
+        let values = value.split(',').filter(|value| !value.is_empty());
+        match name {
+            "kind" => filter.kinds.extend(values.map(str::to_owned)),
+            "mac-address" => {
+                for value in values {
+                    filter.mac_addresses.push(
+                        value
+                            .parse()
+                            .map_err(|_| PicaCommandError::InvalidMacFormat(value.to_owned()))?,
+                    )
+                }
+            }
+            _ => (),
+        }
+    }
+    Ok(filter)
+}
+
+/// Router of the HTTP control API of the simulator: the routes documented
+/// in `static/openapi.yaml`, the event stream and the web interface.
+/// Embedders either serve it on a port, or dispatch the requests of
+/// their own server to `handle`.
+#[derive(Clone)]
+pub struct Router {
+    tx: mpsc::Sender<PicaCommand>,
+    events: broadcast::Sender<SequencedEvent>,
+    log_filter: Option<LogFilter>,
+    untagged_events: bool,
+    tangent_plane: Option<TangentPlane>,
+}
+
+impl Router {
+    /// Router sending the commands to the selected simulator.
+    pub fn new(pica: &Pica) -> Self {
+        Router {
+            tx: pica.tx(),
+            events: pica.event_tx(),
+            log_filter: None,
+            untagged_events: false,
+            tangent_plane: None,
+        }
+    }
+
+    /// Let the clients replace the filter of the logs. The
+    /// `set-log-filter` route is not implemented otherwise.
+    pub fn log_filter(mut self, log_filter: LogFilter) -> Self {
+        self.log_filter = Some(log_filter);
+        self
+    }
+
+    /// Send the events in the untagged encoding of the earlier versions,
+    /// without the `kind` and `version` fields.
+    pub fn untagged_events(mut self, untagged_events: bool) -> Self {
+        self.untagged_events = untagged_events;
+        self
+    }
+
+    /// Accept the positions in WGS84 coordinates, converted to the local
+    /// tangent plane at the selected origin.
+    pub fn geodetic_origin(mut self, origin: GeodeticPosition) -> Self {
+        self.tangent_plane = Some(TangentPlane::new(origin));
+        self
+    }
+
+    /// Handle a request to the control API.
+    pub async fn handle(&self, req: Request<Body>) -> Response<Body> {
+        handle(req, self.clone())
+            .await
+            .unwrap_or_else(|err| match err {})
+    }
+
+    /// Serve the control API on the selected port.
+    pub async fn serve(self, web_port: u16) -> Result<()> {
+        let addr = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, web_port);
+
+        let make_svc = make_service_fn(move |_conn| {
+            let router = self.clone();
+            async move { Ok::<_, Infallible>(service_fn(move |req| handle(req, router.clone()))) }
+        });
+
+        let server = Server::bind(&addr.into()).serve(make_svc);
+
+        info!("Web server started on http://0.0.0.0:{}", web_port);
+
+        server.await.context("Web Server Error")
+    }
+}
+
+async fn handle(mut req: Request<Body>, router: Router) -> Result<Response<Body>, Infallible> {
+    let Router {
+        tx,
+        events,
+        log_filter,
+        untagged_events,
+        tangent_plane,
+    } = router;
+    let static_file = STATIC_FILES
+        .iter()
+        .find(|(path, _, _)| req.uri().path() == *path);
+
+    if let Some((_, mime, content)) = static_file {
+        return Ok(Response::builder()
+            .header("content-type", *mime)
+            .body((*content).into())
+            .unwrap());
+    }
+
+    let body = body::to_bytes(req.body_mut()).await.unwrap();
+    let (pica_cmd_rsp_tx, pica_cmd_rsp_rx) = oneshot::channel::<PicaCommandStatus>();
+
+    let send_cmd = |pica_cmd| async {
+        info!("PicaCommand: {}", pica_cmd);
+        tx.send(pica_cmd).await.unwrap();
+        match pica_cmd_rsp_rx.await {
+            Ok(Ok(_)) => {
+                debug!("PicaCommand status: {}, success", HttpStatusCode::OK);
+                Response::builder()
+                    .status(HttpStatusCode::OK)
+                    .body("success".into())
+                    .unwrap()
+            }
+            Ok(Err(err)) => {
+                warn!("PicaCommand status: {}", err);
+                error_response(err)
+            }
+            Err(err) => {
+                let description = format!("Error getting command response: {}", err);
+                warn!("PicaCommand status: {}", description);
+                Response::builder()
+                    .status(HttpStatusCode::INTERNAL_SERVER_ERROR)
+                    .body(description.into())
+                    .unwrap()
+            }
+        }
+    };
+
+    match req
+        .uri_mut()
+        .path()
+        .trim_start_matches('/')
+        .split('/')
+        .collect::<Vec<_>>()[..]
+    {
+        ["events"] => {
+            // The sequence number is reported as the event id. Events dropped
+            // because the stream is lagging are skipped: the client detects
+            // the gap in the ids and fetches a new snapshot with get-state.
+            let filter = match event_filter(&req) {
+                Ok(filter) => filter,
+                Err(err) => reject!(err),
+            };
+            let receiver = events.subscribe();
+            // Subscribers reconnecting with the id of the last event received,
+            // or selecting a starting point, are sent the retained events
+            // emitted in the meantime before the live events. New subscribers
+            // may instead request a snapshot of the scene, sent as
+            // device-added events.
+            let (history, last_replayed) = match replay_from(&req) {
+                Some(sequence_number) => {
+                    let (history_tx, history_rx) = oneshot::channel();
+                    tx.send(PicaCommand::GetEventHistory(sequence_number, history_tx))
+                        .await
+                        .unwrap();
+                    let history = history_rx.await.unwrap_or_default();
+                    let last_replayed = history.last().map(|event| event.sequence_number);
+                    (history, last_replayed)
+                }
+                None if snapshot_requested(&req) => {
+                    let (state_tx, state_rx) = oneshot::channel();
+                    tx.send(PicaCommand::GetState(state_tx)).await.unwrap();
+                    match state_rx.await {
+                        Ok(state) => (state.events(), Some(state.sequence_number)),
+                        Err(_) => (vec![], None),
+                    }
+                }
+                None => (vec![], None),
+            };
+            // Live events already replayed from the history, or included
+            // in the snapshot, are filtered out.
+            let stream = tokio_stream::iter(history)
+                .chain(BroadcastStream::new(receiver).filter_map(move |result| {
+                    result
+                        .ok()
+                        .filter(|event| Some(event.sequence_number) > last_replayed)
+                }))
+                .filter(move |event| filter.matches(&event.event))
+                .map(move |event| Ok::<_, Infallible>(event_stream_entry(event, untagged_events)));
+            return Ok(Response::builder()
+                .header("content-type", "text/event-stream")
+                .body(Body::wrap_stream(stream))
+                .unwrap());
+        }
+        ["init-uci-device", mac_address] => {
+            let position = if body.is_empty() {
+                None
+            } else {
+                Some(position!(body, tangent_plane))
+            };
+            return Ok(send_cmd(PicaCommand::InitUciDevice(
+                mac_address!(mac_address),
+                position,
+                pica_cmd_rsp_tx,
+            ))
+            .await);
+        }
+        ["set-position", mac_address] => {
+            return Ok(send_cmd(PicaCommand::SetPosition(
+                mac_address!(mac_address),
+                position!(body, tangent_plane),
+                pica_cmd_rsp_tx,
+            ))
+            .await);
+        }
+        ["set-orientation", mac_address] => {
+            let (yaw, pitch, roll) = match serde_json::from_slice::<OrientationBody>(&body) {
+                Ok(body)
+                    if (-180..=180).contains(&body.yaw)
+                        && (-90..=90).contains(&body.pitch)
+                        && (-180..=180).contains(&body.roll) =>
+                {
+                    (body.yaw, body.pitch, body.roll)
+                }
+                Ok(body) => reject!(PicaCommandError::InvalidPosition(format!(
+                    "orientation {}, {}, {} out of range",
+                    body.yaw, body.pitch, body.roll
+                ))),
+                Err(err) => reject!(PicaCommandError::InvalidPosition(err.to_string())),
+            };
+            return Ok(send_cmd(PicaCommand::SetOrientation(
+                mac_address!(mac_address),
+                yaw,
+                pitch,
+                roll,
+                pica_cmd_rsp_tx,
+            ))
+            .await);
+        }
+        ["set-field-of-view", mac_address] => {
+            // An empty body restores the unlimited field of view.
+            let field_of_view = match serde_json::from_slice::<FieldOfViewBody>(&body) {
+                Ok(body) if body.azimuth <= 180 && body.elevation <= 90 => FieldOfView {
+                    azimuth: body.azimuth,
+                    elevation: body.elevation,
+                },
+                Ok(body) => reject!(PicaCommandError::InvalidArgument(format!(
+                    "field of view {}, {} out of range",
+                    body.azimuth, body.elevation
+                ))),
+                Err(err) if err.classify() == SerdeErrorCategory::Eof => FieldOfView::default(),
+                Err(err) => reject!(PicaCommandError::InvalidArgument(format!(
+                    "field of view: {}",
+                    err
+                ))),
+            };
+            return Ok(send_cmd(PicaCommand::SetFieldOfView(
+                mac_address!(mac_address),
+                field_of_view,
+                pica_cmd_rsp_tx,
+            ))
+            .await);
+        }
+        ["set-aoa-capability", mac_address] => {
+            // An empty body restores the azimuth and elevation measurements.
+            let aoa_capability = match serde_json::from_slice::<AoaCapabilityBody>(&body) {
+                Ok(body) => body.aoa,
+                Err(err) if err.classify() == SerdeErrorCategory::Eof => AoaCapability::default(),
+                Err(err) => reject!(PicaCommandError::InvalidArgument(format!(
+                    "aoa capability: {}",
+                    err
+                ))),
+            };
+            return Ok(send_cmd(PicaCommand::SetAoaCapability(
+                mac_address!(mac_address),
+                aoa_capability,
+                pica_cmd_rsp_tx,
+            ))
+            .await);
+        }
+        ["set-clock", mac_address] => {
+            // An empty body restores the exact clock.
+            let clock = match serde_json::from_slice::<ClockBody>(&body) {
+                Ok(body) if body.drift_ppm.abs() <= MAX_DRIFT_PPM => Clock {
+                    offset_us: body.offset_us,
+                    drift_ppm: body.drift_ppm,
+                    jitter_us: body.jitter_us,
+                },
+                Ok(body) => reject!(PicaCommandError::InvalidArgument(format!(
+                    "drift {} ppm out of range",
+                    body.drift_ppm
+                ))),
+                Err(err) if err.classify() == SerdeErrorCategory::Eof => Clock::default(),
+                Err(err) => reject!(PicaCommandError::InvalidArgument(format!("clock: {}", err))),
+            };
+            return Ok(send_cmd(PicaCommand::SetClock(
+                mac_address!(mac_address),
+                clock,
+                pica_cmd_rsp_tx,
+            ))
+            .await);
+        }
+        ["set-tx-power", mac_address] => {
+            // An empty body restores the transmission at the power limit.
+            let tx_power = match serde_json::from_slice::<TxPowerBody>(&body) {
+                Ok(TxPowerBody {
+                    tx_power: Some(tx_power),
+                }) if !tx_power.is_finite() => reject!(PicaCommandError::InvalidArgument(format!(
+                    "tx_power {}",
+                    tx_power
+                ))),
+                Ok(body) => body.tx_power,
+                Err(err) if err.classify() == SerdeErrorCategory::Eof => None,
+                Err(err) => reject!(PicaCommandError::InvalidArgument(format!(
+                    "tx power: {}",
+                    err
+                ))),
+            };
+            return Ok(send_cmd(PicaCommand::SetTxPower(
+                mac_address!(mac_address),
+                tx_power,
+                pica_cmd_rsp_tx,
+            ))
+            .await);
+        }
+        ["set-notification-latency", mac_address] => {
+            // An empty body restores the immediate delivery.
+            let latency = match serde_json::from_slice::<LatencyBody>(&body) {
+                Ok(body) => match (
+                    Duration::try_from_secs_f64(body.delay_ms / 1000.0),
+                    Duration::try_from_secs_f64(body.jitter_ms / 1000.0),
+                ) {
+                    (Ok(delay), Ok(jitter)) => NotificationLatency {
+                        delay,
+                        jitter,
+                        distribution: body.distribution,
+                    },
+                    _ => reject!(PicaCommandError::InvalidArgument(format!(
+                        "latency {} ms, {} ms out of range",
+                        body.delay_ms, body.jitter_ms
+                    ))),
+                },
+                Err(err) if err.classify() == SerdeErrorCategory::Eof => {
+                    NotificationLatency::default()
+                }
+                Err(err) => reject!(PicaCommandError::InvalidArgument(format!(
+                    "latency: {}",
+                    err
+                ))),
+            };
+            return Ok(send_cmd(PicaCommand::SetNotificationLatency(
+                mac_address!(mac_address),
+                latency,
+                pica_cmd_rsp_tx,
+            ))
+            .await);
+        }
+        ["set-response-faults", mac_address] => {
+            // An empty body clears the faults.
+            let faults = match serde_json::from_slice::<ResponseFaultsBody>(&body) {
+                Ok(body) => body.faults,
+                Err(err) if err.classify() == SerdeErrorCategory::Eof => vec![],
+                Err(err) => reject!(PicaCommandError::InvalidArgument(format!(
+                    "faults: {}",
+                    err
+                ))),
+            };
+            let mut response_faults = Vec::with_capacity(faults.len());
+            for fault in faults {
+                let action = match fault.delay_ms.map(|delay_ms| delay_ms / 1000.0) {
+                    None => ResponseAction::Withhold,
+                    Some(delay) => match Duration::try_from_secs_f64(delay) {
+                        Ok(delay) => ResponseAction::Delay(delay),
+                        Err(_) => reject!(PicaCommandError::InvalidArgument(format!(
+                            "delay {} ms out of range",
+                            delay * 1000.0
+                        ))),
+                    },
+                };
+                if fault.gid > 0xf || fault.opcode > 0x3f || fault.nth == 0 {
+                    reject!(PicaCommandError::InvalidArgument(format!(
+                        "command {:x}:{:x} #{} out of range",
+                        fault.gid, fault.opcode, fault.nth
+                    )));
+                }
+                response_faults.push(ResponseFault {
+                    gid: fault.gid,
+                    opcode: fault.opcode,
+                    nth: fault.nth,
+                    action,
+                });
+            }
+            return Ok(send_cmd(PicaCommand::SetResponseFaults(
+                mac_address!(mac_address),
+                response_faults,
+                pica_cmd_rsp_tx,
+            ))
+            .await);
+        }
+        ["crash-device", mac_address] => {
+            // An empty body requires a reset of the device.
+            let recovery = match serde_json::from_slice::<CrashBody>(&body) {
+                Ok(body) => body.recovery,
+                Err(err) if err.classify() == SerdeErrorCategory::Eof => CrashRecovery::default(),
+                Err(err) => reject!(PicaCommandError::InvalidArgument(format!(
+                    "recovery: {}",
+                    err
+                ))),
+            };
+            return Ok(send_cmd(PicaCommand::CrashDevice(
+                mac_address!(mac_address),
+                recovery,
+                pica_cmd_rsp_tx,
+            ))
+            .await);
+        }
+        ["inject-packet", mac_address] => {
+            let bytes = match serde_json::from_slice::<InjectPacketBody>(&body)
+                .map_err(|err| err.to_string())
+                .and_then(|body| hex::decode(body.packet).map_err(|err| err.to_string()))
+            {
+                Ok(bytes) if !bytes.is_empty() => bytes,
+                Ok(_) => reject!(PicaCommandError::InvalidArgument(
+                    "packet: empty".to_owned()
+                )),
+                Err(err) => reject!(PicaCommandError::InvalidArgument(format!(
+                    "packet: {}",
+                    err
+                ))),
+            };
+            return Ok(send_cmd(PicaCommand::InjectPacket(
+                mac_address!(mac_address),
+                bytes.into(),
+                pica_cmd_rsp_tx,
+            ))
+            .await);
+        }
+        ["create-anchor", mac_address] => {
+            return Ok(send_cmd(PicaCommand::CreateAnchor(
+                mac_address!(mac_address),
+                position!(body, tangent_plane),
+                pica_cmd_rsp_tx,
+            ))
+            .await);
+        }
+        ["create-constellation", mac_address] => {
+            let constellation = match serde_json::from_slice::<ConstellationBody>(&body) {
+                Ok(body) if body.count == 0 => reject!(PicaCommandError::InvalidArgument(
+                    "count shall not be zero".to_string()
+                )),
+                Ok(body) => Constellation {
+                    shape: body.shape,
+                    count: body.count,
+                    origin: (body.x, body.z),
+                    height: body.height,
+                },
+                Err(err) => reject!(PicaCommandError::InvalidArgument(format!(
+                    "constellation: {}",
+                    err
+                ))),
+            };
+            return Ok(send_cmd(PicaCommand::CreateConstellation(
+                mac_address!(mac_address),
+                constellation,
+                pica_cmd_rsp_tx,
+            ))
+            .await);
+        }
+        ["destroy-anchor", mac_address] => {
+            return Ok(send_cmd(PicaCommand::DestroyAnchor(
+                mac_address!(mac_address),
+                pica_cmd_rsp_tx,
+            ))
+            .await);
+        }
+        ["start-anchor-ranging", mac_address, session_id] => {
+            let session_id = session_id!(session_id);
+            let ranging_interval = match serde_json::from_slice::<AnchorRangingBody>(&body) {
+                Ok(body) if body.ranging_interval > 0 => body.ranging_interval,
+                Ok(_) => reject!(PicaCommandError::InvalidArgument(
+                    "ranging_interval shall not be zero".to_string()
+                )),
+                Err(err) if err.classify() == SerdeErrorCategory::Eof => default_ranging_interval(),
+                Err(err) => reject!(PicaCommandError::InvalidArgument(format!(
+                    "ranging interval: {}",
+                    err
+                ))),
+            };
+            return Ok(send_cmd(PicaCommand::StartAnchorRanging(
+                mac_address!(mac_address),
+                session_id,
+                Duration::from_millis(ranging_interval as u64),
+                pica_cmd_rsp_tx,
+            ))
+            .await);
+        }
+        ["stop-anchor-ranging", mac_address] => {
+            return Ok(send_cmd(PicaCommand::StopAnchorRanging(
+                mac_address!(mac_address),
+                pica_cmd_rsp_tx,
+            ))
+            .await);
+        }
+        ["set-log-filter"] => {
+            let filter = match std::str::from_utf8(&body)
+                .map_err(|err| err.to_string())
+                .and_then(|filter| EnvFilter::try_new(filter.trim()).map_err(|err| err.to_string()))
+            {
+                Ok(filter) => filter,
+                Err(err) => reject!(PicaCommandError::InvalidArgument(format!(
+                    "log filter: {}",
+                    err
+                ))),
+            };
+            info!("Log filter: {}", filter);
+            let Some(log_filter) = log_filter else {
+                return Ok(Response::builder()
+                    .status(HttpStatusCode::NOT_IMPLEMENTED)
+                    .body("the log filter is not configurable".into())
+                    .unwrap());
+            };
+            return Ok(match log_filter.reload(filter) {
+                Ok(()) => Response::builder()
+                    .status(HttpStatusCode::OK)
+                    .body("success".into())
+                    .unwrap(),
+                Err(err) => Response::builder()
+                    .status(HttpStatusCode::INTERNAL_SERVER_ERROR)
+                    .body(err.to_string().into())
+                    .unwrap(),
+            });
+        }
+        ["set-scene"] => {
+            // An empty body removes all the obstacles.
+            let scene = match serde_json::from_slice::<SceneBody>(&body) {
+                Ok(body) => Scene::new(
+                    body.obstacles
+                        .iter()
+                        .map(|obstacle| {
+                            Obstacle::new(
+                                (obstacle.min.x, obstacle.min.y, obstacle.min.z),
+                                (obstacle.max.x, obstacle.max.y, obstacle.max.z),
+                                obstacle.bias,
+                            )
+                        })
+                        .collect(),
+                ),
+                Err(err) if err.classify() == SerdeErrorCategory::Eof => Scene::default(),
+                Err(err) => reject!(PicaCommandError::InvalidArgument(format!("scene: {}", err))),
+            };
+            return Ok(send_cmd(PicaCommand::SetScene(scene, pica_cmd_rsp_tx)).await);
+        }
+        ["set-zones"] => {
+            // An empty body removes all the zones.
+            let zones = match serde_json::from_slice::<ZonesBody>(&body) {
+                Ok(body) => body
+                    .zones
+                    .into_iter()
+                    .map(|zone| {
+                        Zone::new(
+                            zone.name,
+                            (zone.min.x, zone.min.y, zone.min.z),
+                            (zone.max.x, zone.max.y, zone.max.z),
+                        )
+                    })
+                    .collect(),
+                Err(err) if err.classify() == SerdeErrorCategory::Eof => vec![],
+                Err(err) => reject!(PicaCommandError::InvalidArgument(format!("zones: {}", err))),
+            };
+            return Ok(send_cmd(PicaCommand::SetZones(zones, pica_cmd_rsp_tx)).await);
+        }
+        ["set-path", mac_address] => {
+            // An empty body stops the motion.
+            let path = match serde_json::from_slice::<PathBody>(&body) {
+                Ok(body) => {
+                    let waypoints: Vec<_> = body
+                        .waypoints
+                        .iter()
+                        .map(|waypoint| (waypoint.x, waypoint.y, waypoint.z))
+                        .collect();
+                    match MotionPath::new(&waypoints, body.speed, body.mode) {
+                        Ok(path) => Some(path),
+                        Err(err) => {
+                            reject!(PicaCommandError::InvalidArgument(format!("path: {}", err)))
+                        }
+                    }
+                }
+                Err(err) if err.classify() == SerdeErrorCategory::Eof => None,
+                Err(err) => reject!(PicaCommandError::InvalidArgument(format!("path: {}", err))),
+            };
+            return Ok(send_cmd(PicaCommand::SetPath(
+                mac_address!(mac_address),
+                path,
+                pica_cmd_rsp_tx,
+            ))
+            .await);
+        }
+        ["set-kinematics", mac_address] => {
+            // An empty body stops the motion.
+            let kinematics = match serde_json::from_slice::<Kinematics>(&body) {
+                Ok(body) => match Kinematics::new(body.velocity, body.acceleration) {
+                    Ok(kinematics) => Some(kinematics),
+                    Err(err) => reject!(PicaCommandError::InvalidArgument(format!(
+                        "kinematics: {}",
+                        err
+                    ))),
+                },
+                Err(err) if err.classify() == SerdeErrorCategory::Eof => None,
+                Err(err) => reject!(PicaCommandError::InvalidArgument(format!(
+                    "kinematics: {}",
+                    err
+                ))),
+            };
+            return Ok(send_cmd(PicaCommand::SetKinematics(
+                mac_address!(mac_address),
+                kinematics,
+                pica_cmd_rsp_tx,
+            ))
+            .await);
+        }
+        ["set-trajectory", mac_address] => {
+            // An empty body stops the motion.
+            let trajectory = if body.is_empty() {
+                None
+            } else {
+                let text = match std::str::from_utf8(&body) {
+                    Ok(text) => text,
+                    Err(err) => reject!(PicaCommandError::InvalidArgument(format!(
+                        "trajectory: {}",
+                        err
+                    ))),
+                };
+                match RecordedTrajectory::parse(text, tangent_plane.as_ref()) {
+                    Ok(trajectory) => Some(trajectory),
+                    Err(err) => reject!(PicaCommandError::InvalidArgument(format!(
+                        "trajectory: {}",
+                        err
+                    ))),
+                }
+            };
+            return Ok(send_cmd(PicaCommand::SetTrajectory(
+                mac_address!(mac_address),
+                trajectory,
+                pica_cmd_rsp_tx,
+            ))
+            .await);
+        }
+        ["set-session-seed", mac_address, session_id] => {
+            let session_id = session_id!(session_id);
+            // An empty body restores the global generator.
+            let seed = match serde_json::from_slice::<SeedBody>(&body) {
+                Ok(body) => body.seed,
+                Err(err) if err.classify() == SerdeErrorCategory::Eof => None,
+                Err(err) => reject!(PicaCommandError::InvalidArgument(format!("seed: {}", err))),
+            };
+            return Ok(send_cmd(PicaCommand::SetSessionSeed(
+                mac_address!(mac_address),
+                session_id,
+                seed,
+                pica_cmd_rsp_tx,
+            ))
+            .await);
+        }
+        ["set-time-mode"] => {
+            // An empty body restores the wall-clock time.
+            let time_mode = match serde_json::from_slice::<TimeModeBody>(&body) {
+                Ok(TimeModeBody { stepped: true, .. }) => TimeMode::Stepped,
+                Ok(body) => TimeMode::Scaled(body.speed.unwrap_or(1.0)),
+                Err(err) if err.classify() == SerdeErrorCategory::Eof => TimeMode::default(),
+                Err(err) => reject!(PicaCommandError::InvalidArgument(format!(
+                    "time mode: {}",
+                    err
+                ))),
+            };
+            return Ok(send_cmd(PicaCommand::SetTimeMode(time_mode, pica_cmd_rsp_tx)).await);
+        }
+        ["advance-time"] => {
+            let duration = match serde_json::from_slice::<AdvanceTimeBody>(&body) {
+                Ok(body) => match Duration::try_from_secs_f64(body.duration_ms / 1000.0) {
+                    Ok(duration) => duration,
+                    Err(_) => reject!(PicaCommandError::InvalidArgument(format!(
+                        "duration {} ms out of range",
+                        body.duration_ms
+                    ))),
+                },
+                Err(err) => reject!(PicaCommandError::InvalidArgument(format!(
+                    "duration: {}",
+                    err
+                ))),
+            };
+            return Ok(send_cmd(PicaCommand::AdvanceTime(duration, pica_cmd_rsp_tx)).await);
+        }
+        ["get-state"] => {
+            debug!("PicaCommand: GetState");
+            let (state_tx, state_rx) = oneshot::channel();
+            tx.send(PicaCommand::GetState(state_tx)).await.unwrap();
+            let devices = match state_rx.await {
+                Ok(state) => GetStateResponse {
+                    sequence_number: state.sequence_number,
+                    devices: state
+                        .devices
+                        .into_iter()
+                        .map(|(category, mac_address, position)| Device {
+                            category,
+                            mac_address: mac_address.into(),
+                            position,
+                            sessions: (category == Category::Uci).then(|| {
+                                state
+                                    .sessions
+                                    .iter()
+                                    .filter(|(device, _)| *device == mac_address)
+                                    .map(|(_, session)| session.clone())
+                                    .collect()
+                            }),
+                            origin: state
+                                .origins
+                                .iter()
+                                .find(|(device, _)| *device == mac_address)
+                                .map(|(_, origin)| origin.clone()),
+                        })
+                        .collect(),
+                },
+                Err(_) => GetStateResponse {
+                    sequence_number: 0,
+                    devices: vec![],
+                },
+            };
+            let body = serde_json::to_string(&devices).unwrap();
+            return Ok(Response::builder().status(200).body(body.into()).unwrap());
+        }
+        ["get-link-statistics"] => {
+            debug!("PicaCommand: GetLinkStatistics");
+            let (statistics_tx, statistics_rx) = oneshot::channel::<Vec<_>>();
+            tx.send(PicaCommand::GetLinkStatistics(statistics_tx))
+                .await
+                .unwrap();
+            let links = GetLinkStatisticsResponse {
+                links: statistics_rx
+                    .await
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|(source, destination, summary)| Link {
+                        source_mac_address: source.into(),
+                        destination_mac_address: destination.into(),
+                        summary,
+                    })
+                    .collect(),
+            };
+            let body = serde_json::to_string(&links).unwrap();
+            return Ok(Response::builder().status(200).body(body.into()).unwrap());
+        }
+        ["get-simulator-info"] => {
+            debug!("PicaCommand: GetSimulatorInfo");
+            let (info_tx, info_rx) = oneshot::channel();
+            tx.send(PicaCommand::GetSimulatorInfo(info_tx))
+                .await
+                .unwrap();
+            return Ok(match info_rx.await {
+                Ok(info) => Response::builder()
+                    .status(200)
+                    .body(serde_json::to_string(&info).unwrap().into())
+                    .unwrap(),
+                Err(err) => Response::builder()
+                    .status(HttpStatusCode::INTERNAL_SERVER_ERROR)
+                    .body(format!("Error getting command response: {}", err).into())
+                    .unwrap(),
+            });
+        }
+        #[cfg(feature = "schema")]
+        ["openapi.json"] => {
+            return Ok(Response::builder()
+                .status(200)
+                .header("content-type", "application/json")
+                .body(openapi().to_string().into())
+                .unwrap());
+        }
+
+        _ => (),
+    }
+
+    Ok(Response::builder().status(404).body("".into()).unwrap())
+}
+
+/// Content of a request or response body: media type and schema.
+#[cfg(feature = "schema")]
+type Content = Option<(&'static str, schemars::schema::Schema)>;
+
+#[cfg(feature = "schema")]
+fn json<T: schemars::JsonSchema>(generator: &mut schemars::gen::SchemaGenerator) -> Content {
+    Some(("application/json", generator.subschema_for::<T>()))
+}
+
+/// Routes matched by `handle`, with their method and the content of
+/// their request and response bodies.
+#[cfg(feature = "schema")]
+fn route_schemas(
+    generator: &mut schemars::gen::SchemaGenerator,
+) -> Vec<(&'static str, &'static str, Content, Content)> {
+    let event_stream = Some(("text/event-stream", generator.subschema_for::<EventBody>()));
+    let text = Some(("text/plain", generator.subschema_for::<String>()));
+    vec![
+        ("get", "/events", None, event_stream),
+        (
+            "post",
+            "/init-uci-device/{mac-address}",
+            json::<PositionBody>(generator),
+            None,
+        ),
+        (
+            "post",
+            "/set-position/{mac-address}",
+            json::<PositionBody>(generator),
+            None,
+        ),
+        (
+            "post",
+            "/set-orientation/{mac-address}",
+            json::<OrientationBody>(generator),
+            None,
+        ),
+        (
+            "post",
+            "/set-field-of-view/{mac-address}",
+            json::<FieldOfViewBody>(generator),
+            None,
+        ),
+        (
+            "post",
+            "/set-aoa-capability/{mac-address}",
+            json::<AoaCapabilityBody>(generator),
+            None,
+        ),
+        (
+            "post",
+            "/set-clock/{mac-address}",
+            json::<ClockBody>(generator),
+            None,
+        ),
+        (
+            "post",
+            "/set-tx-power/{mac-address}",
+            json::<TxPowerBody>(generator),
+            None,
+        ),
+        (
+            "post",
+            "/set-notification-latency/{mac-address}",
+            json::<LatencyBody>(generator),
+            None,
+        ),
+        (
+            "post",
+            "/set-response-faults/{mac-address}",
+            json::<ResponseFaultsBody>(generator),
+            None,
+        ),
+        (
+            "post",
+            "/crash-device/{mac-address}",
+            json::<CrashBody>(generator),
+            None,
+        ),
+        (
+            "post",
+            "/inject-packet/{mac-address}",
+            json::<InjectPacketBody>(generator),
+            None,
+        ),
+        (
+            "post",
+            "/create-anchor/{mac-address}",
+            json::<PositionBody>(generator),
+            None,
+        ),
+        (
+            "post",
+            "/create-constellation/{mac-address}",
+            json::<ConstellationBody>(generator),
+            None,
+        ),
+        ("delete", "/destroy-anchor/{mac-address}", None, None),
+        (
+            "post",
+            "/start-anchor-ranging/{mac-address}/{session-id}",
+            json::<AnchorRangingBody>(generator),
+            None,
+        ),
+        ("post", "/stop-anchor-ranging/{mac-address}", None, None),
+        ("post", "/set-log-filter", text, None),
+        ("post", "/set-scene", json::<SceneBody>(generator), None),
+        ("post", "/set-zones", json::<ZonesBody>(generator), None),
+        (
+            "post",
+            "/set-path/{mac-address}",
+            json::<PathBody>(generator),
+            None,
+        ),
+        (
+            "post",
+            "/set-kinematics/{mac-address}",
+            json::<Kinematics>(generator),
+            None,
+        ),
+        (
+            "post",
+            "/set-trajectory/{mac-address}",
+            Some(("text/plain", generator.subschema_for::<String>())),
+            None,
+        ),
+        (
+            "post",
+            "/set-session-seed/{mac-address}/{session-id}",
+            json::<SeedBody>(generator),
+            None,
+        ),
+        (
+            "post",
+            "/set-time-mode",
+            json::<TimeModeBody>(generator),
+            None,
+        ),
+        (
+            "post",
+            "/advance-time",
+            json::<AdvanceTimeBody>(generator),
+            None,
+        ),
+        (
+            "get",
+            "/get-state",
+            None,
+            json::<GetStateResponse>(generator),
+        ),
+        (
+            "get",
+            "/get-link-statistics",
+            None,
+            json::<GetLinkStatisticsResponse>(generator),
+        ),
+        (
+            "get",
+            "/get-simulator-info",
+            None,
+            json::<crate::SimulatorInfo>(generator),
+        ),
+        (
+            "get",
+            "/openapi.json",
+            None,
+            json::<serde_json::Value>(generator),
+        ),
+    ]
+}
+
+/// OpenAPI document of the control API, generated from the types of
+/// the request and response bodies, for the client generators. The
+/// schemas of the events, positions and addresses are included in
+/// the components.
+#[cfg(feature = "schema")]
+pub fn openapi() -> serde_json::Value {
+    use serde_json::json;
+
+    let mut generator = schemars::gen::SchemaSettings::openapi3().into_generator();
+    let mac_address = generator.subschema_for::<MacAddress>();
+    let error = generator.subschema_for::<ErrorBody>();
+    // Flattened in the bodies, referenced for the clients decoding
+    // the events and positions on their own.
+    generator.subschema_for::<PicaEvent>();
+    generator.subschema_for::<Position>();
+    let mut paths = serde_json::Map::new();
+    for (method, path, request, response) in route_schemas(&mut generator) {
+        let parameters = path
+            .split('/')
+            .filter_map(|segment| segment.strip_prefix('{')?.strip_suffix('}'))
+            .map(|name| {
+                let schema = match name {
+                    "session-id" => json!({ "type": "integer", "format": "uint32" }),
+                    _ => json!(mac_address),
+                };
+                json!({ "name": name, "in": "path", "required": true, "schema": schema })
+            })
+            .collect::<Vec<_>>();
+        let mut operation = json!({
+            "parameters": parameters,
+            "responses": {
+                "200": { "description": "Success" },
+                "default": {
+                    "description": "Error",
+                    "content": { "application/json": { "schema": error } },
+                },
+            },
+        });
+        if let Some((media_type, schema)) = request {
+            operation["requestBody"] = json!({ "content": { media_type: { "schema": schema } } });
+        }
+        if let Some((media_type, schema)) = response {
+            operation["responses"]["200"]["content"] = json!({ media_type: { "schema": schema } });
+        }
+        paths.insert(path.to_owned(), json!({ method: operation }));
+    }
+    json!({
+        "openapi": "3.0.3",
+        "info": { "title": "Pica", "version": env!("CARGO_PKG_VERSION") },
+        "paths": paths,
+        "components": { "schemas": generator.take_definitions() },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OPENAPI: &str = include_str!("../static/openapi.yaml");
+
+    /// Paths of the routes matched by `handle`, in the OpenAPI format,
+    /// e.g. `/set-position/{mac-address}`.
+    fn routes() -> Vec<String> {
+        include_str!("web.rs")
+            .lines()
+            .filter_map(|line| line.trim().strip_prefix("[\"")?.strip_suffix("] => {"))
+            .map(|route| {
+                route
+                    .split(", ")
+                    .map(|segment| match segment.strip_suffix('"') {
+                        Some(name) => name.to_owned(),
+                        None => format!("{{{}}}", segment.replace('_', "-")),
+                    })
+                    .fold(String::new(), |path, segment| {
+                        format!("{}/{}", path, segment)
+                    })
+            })
+            .collect()
+    }
+
+    /// Paths documented in the OpenAPI description.
+    fn documented_routes() -> Vec<String> {
+        OPENAPI
+            .lines()
+            .filter_map(|line| line.strip_prefix("  /")?.strip_suffix(':'))
+            .map(|path| format!("/{}", path))
+            .collect()
+    }
+
+    #[test]
+    fn openapi_documents_routes() {
+        let mut routes = routes();
+        let mut documented_routes = documented_routes();
+        assert!(routes.contains(&"/set-position/{mac-address}".to_owned()));
+        routes.sort();
+        documented_routes.sort();
+        assert_eq!(routes, documented_routes);
+    }
+
+    #[tokio::test]
+    async fn router() {
+        let mut pica = Pica::builder().build();
+        let router = Router::new(&pica);
+        tokio::spawn(async move { pica.run().await });
+
+        let request = |path: &str| {
+            Request::builder()
+                .method("POST")
+                .uri(path)
+                .body(Body::empty())
+                .unwrap()
+        };
+        let response = router.handle(request("/create-anchor/00:01")).await;
+        assert_eq!(response.status(), HttpStatusCode::OK);
+        let response = router.handle(request("/get-state")).await;
+        let body = body::to_bytes(response.into_body()).await.unwrap();
+        let state = serde_json::from_slice::<serde_json::Value>(&body).unwrap();
+        assert_eq!(state["devices"][0]["mac_address"], "00:01");
+        let response = router.handle(request("/set-log-filter")).await;
+        assert_eq!(response.status(), HttpStatusCode::NOT_IMPLEMENTED);
+    }
+
+    #[tokio::test]
+    async fn geodetic_position() {
+        let mut pica = Pica::builder().build();
+        let router = Router::new(&pica);
+        let geodetic_router = router.clone().geodetic_origin(GeodeticPosition {
+            latitude: 48.8584,
+            longitude: 2.2945,
+            altitude: 35.0,
+        });
+        tokio::spawn(async move { pica.run().await });
+
+        let request = |path: &str, body: &'static str| {
+            Request::builder()
+                .method("POST")
+                .uri(path)
+                .body(Body::from(body))
+                .unwrap()
+        };
+        // About ten meters east of the origin.
+        let position = r#"{"latitude":48.8584,"longitude":2.29463642,"altitude":35.0,
+            "yaw":0,"pitch":0,"roll":0}"#;
+        let response = router
+            .handle(request("/create-anchor/00:01", position))
+            .await;
+        assert_eq!(response.status(), HttpStatusCode::NOT_ACCEPTABLE);
+        let response = geodetic_router
+            .handle(request("/create-anchor/00:01", position))
+            .await;
+        assert_eq!(response.status(), HttpStatusCode::OK);
+        let response = router.handle(request("/get-state", "")).await;
+        let body = body::to_bytes(response.into_body()).await.unwrap();
+        let state = serde_json::from_slice::<serde_json::Value>(&body).unwrap();
+        let x = state["devices"][0]["x"].as_f64().unwrap();
+        assert!((x - 1000.0).abs() < 5.0, "{}", x);
+    }
+
+    #[tokio::test]
+    async fn events_snapshot() {
+        use hyper::body::HttpBody;
+
+        let mut pica = Pica::builder().build();
+        let router = Router::new(&pica);
+        tokio::spawn(async move { pica.run().await });
+
+        let request = |method: &str, path: &str| {
+            Request::builder()
+                .method(method)
+                .uri(path)
+                .body(Body::empty())
+                .unwrap()
+        };
+        router.handle(request("POST", "/create-anchor/00:01")).await;
+        let mut events = router
+            .handle(request("GET", "/events?snapshot=true"))
+            .await
+            .into_body();
+        router.handle(request("POST", "/create-anchor/00:02")).await;
+
+        // The anchor created before the subscription is sent in the
+        // snapshot, followed by the live events.
+        for (sequence_number, mac_address) in [(1, "00:01"), (2, "00:02")] {
+            let entry = events.data().await.unwrap().unwrap();
+            let entry = std::str::from_utf8(&entry).unwrap();
+            assert!(entry.starts_with(&format!("id: {}\nevent: device-added\n", sequence_number)));
+            let data = entry.lines().find_map(|line| line.strip_prefix("data: "));
+            let data = serde_json::from_str::<serde_json::Value>(data.unwrap()).unwrap();
+            assert_eq!(data["mac_address"], mac_address);
+        }
+    }
+
+    #[test]
+    fn event_encoding() {
+        let event = SequencedEvent {
+            sequence_number: 1,
+            monotonic_us: 2,
+            timestamp_ms: 3,
+            event: PicaEvent::DeviceRemoved {
+                category: Category::Anchor,
+                mac_address: MacAddress::Short([0, 1]),
+            },
+        };
+        let data = |untagged_events| {
+            let entry = event_stream_entry(event.clone(), untagged_events);
+            let data = entry.lines().find_map(|line| line.strip_prefix("data: "));
+            serde_json::from_str::<serde_json::Value>(data.unwrap()).unwrap()
+        };
+        let tagged = data(false);
+        assert_eq!(tagged["kind"], "device-removed");
+        assert_eq!(tagged["version"], EVENT_VERSION);
+        assert_eq!(tagged["mac_address"], "00:01");
+        let untagged = data(true);
+        assert!(untagged.get("kind").is_none());
+        assert!(untagged.get("version").is_none());
+        assert_eq!(untagged["mac_address"], "00:01");
+    }
+
+    #[cfg(feature = "schema")]
+    #[test]
+    fn openapi_generation() {
+        let openapi = openapi();
+        let mut routes = routes();
+        let mut generated_routes = openapi["paths"]
+            .as_object()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+        routes.sort();
+        generated_routes.sort();
+        assert_eq!(routes, generated_routes);
+        let set_position = &openapi["paths"]["/set-position/{mac-address}"]["post"];
+        assert_eq!(
+            set_position["requestBody"]["content"]["application/json"]["schema"]["$ref"],
+            "#/components/schemas/PositionBody"
+        );
+        assert_eq!(set_position["parameters"][0]["name"], "mac-address");
+        for schema in ["PicaEvent", "Position", "MacAddress"] {
+            assert!(openapi["components"]["schemas"][schema].is_object());
+        }
+    }
+
+    #[cfg(feature = "schema")]
+    #[tokio::test]
+    async fn openapi_route() {
+        let pica = Pica::builder().build();
+        let router = Router::new(&pica);
+        let request = Request::builder()
+            .method("GET")
+            .uri("/openapi.json")
+            .body(Body::empty())
+            .unwrap();
+        let response = router.handle(request).await;
+        assert_eq!(response.status(), HttpStatusCode::OK);
+        let body = body::to_bytes(response.into_body()).await.unwrap();
+        let document = serde_json::from_slice::<serde_json::Value>(&body).unwrap();
+        assert_eq!(document, openapi());
+    }
+
+    #[test]
+    fn openapi_documents_events() {
+        let events = include_str!("lib.rs")
+            .lines()
+            .filter_map(|line| {
+                let line = line.trim().strip_prefix("PicaEvent::")?;
+                line.split_once("{ .. } => \"")?.1.strip_suffix("\",")
+            })
+            .collect::<Vec<_>>();
+        assert!(events.contains(&"device-added"));
+        for event in events {
+            assert!(
+                OPENAPI.contains(&format!("const: {}\n", event)),
+                "event {} is not documented",
+                event
+            );
+        }
+    }
+}